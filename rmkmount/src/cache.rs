@@ -0,0 +1,270 @@
+use crate::compress::{self, Compression};
+use crate::vault::Vault;
+use log::{debug, warn};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// What a purge drops from the cache
+#[derive(Debug, Clone, PartialEq)]
+pub enum PurgeFilter {
+    /// every cached file of the document with this uid
+    Document(String),
+    /// documents whose cached files were all written longer ago than this
+    OlderThan(Duration),
+    All,
+}
+
+/// Documents and bytes removed by a purge, and what is left
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PurgeReport {
+    pub removed: usize,
+    pub freed: u64,
+    pub kept: usize,
+    pub remaining: u64,
+}
+
+/// Local copies of tablet data, one folder per kind of cached data (payloads
+/// handed to the viewer in `open`...) holding one folder per document uid
+pub struct DocumentCache {
+    root: PathBuf,
+}
+
+/// A document folder in one of the cache areas
+struct CacheEntry {
+    path: PathBuf,
+    uid: String,
+    size: u64,
+    /// latest modification of its files
+    modified: SystemTime,
+}
+
+impl DocumentCache {
+    /// payloads opened with the desktop viewer
+    const OPEN_AREA: &'static str = "open";
+
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    /// folder receiving the copy of document `uid` opened with the viewer
+    pub fn open_dir(&self, uid: &str) -> PathBuf {
+        self.root.join(Self::OPEN_AREA).join(uid)
+    }
+
+    /// cached document folders of all areas
+    fn entries(&self) -> Vec<CacheEntry> {
+        let Ok(areas) = std::fs::read_dir(&self.root) else {
+            return vec![];
+        };
+        areas
+            .flatten()
+            .filter(|area| area.path().is_dir())
+            .filter_map(|area| std::fs::read_dir(area.path()).ok())
+            .flat_map(|docs| docs.flatten())
+            .map(|doc| {
+                let path = doc.path();
+                let (size, modified) = usage(&path);
+                CacheEntry {
+                    uid: doc.file_name().to_string_lossy().into_owned(),
+                    path,
+                    size,
+                    modified,
+                }
+            })
+            .collect()
+    }
+
+    /// Removes the cached files of the documents matching `filter`. A document
+    /// that cannot be removed is reported and counted as kept.
+    pub fn purge(&self, filter: &PurgeFilter) -> PurgeReport {
+        let now = SystemTime::now();
+        let mut report = PurgeReport::default();
+        for entry in self.entries() {
+            let matches = match filter {
+                PurgeFilter::Document(uid) => entry.uid.eq_ignore_ascii_case(uid),
+                PurgeFilter::OlderThan(age) => now
+                    .duration_since(entry.modified)
+                    .is_ok_and(|elapsed| elapsed > *age),
+                PurgeFilter::All => true,
+            };
+            let removed = matches
+                && match remove(&entry.path) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        warn!("unable to remove {}: {e}", entry.path.display());
+                        false
+                    }
+                };
+            if removed {
+                debug!("removed {} ({} bytes)", entry.path.display(), entry.size);
+                report.removed += 1;
+                report.freed += entry.size;
+            } else {
+                report.kept += 1;
+                report.remaining += entry.size;
+            }
+        }
+        report
+    }
+}
+
+/// How cached copies are stored: compressed, then encrypted, as enabled at startup
+#[derive(Clone, Copy)]
+pub struct Packing {
+    pub vault: Option<&'static Vault>,
+    pub compression: Option<&'static Compression>,
+}
+
+impl Packing {
+    /// the packing enabled at startup, None when copies are stored as is
+    pub fn enabled() -> Option<Self> {
+        let packing = Self {
+            vault: Vault::enabled(),
+            compression: Compression::enabled(),
+        };
+        (packing.vault.is_some() || packing.compression.is_some()).then_some(packing)
+    }
+
+    /// packing of a file of `size` bytes, too small ones being left uncompressed
+    pub fn for_size(self, size: u64) -> Self {
+        Self {
+            compression: self.compression.filter(|c| c.applies(size)),
+            ..self
+        }
+    }
+
+    /// name of the packed copy of the file `name`
+    pub fn file_name(&self, name: &str) -> String {
+        let mut packed = name.to_owned();
+        if let Some(compression) = self.compression {
+            packed = format!("{packed}.{}", compression.codec.extension());
+        }
+        if self.vault.is_some() {
+            packed.push_str(".age");
+        }
+        packed
+    }
+
+    /// stores the file `plain` as its packed copy `packed`, `work` being a
+    /// scratch path for the compressed file before it is encrypted
+    pub fn pack(&self, plain: &Path, packed: &Path, work: &Path) -> std::io::Result<()> {
+        match (self.vault, self.compression) {
+            (Some(vault), Some(compression)) => {
+                compression.compress_file(plain, work)?;
+                let encrypted = vault.encrypt_file(work, packed);
+                let _ = std::fs::remove_file(work);
+                encrypted
+            }
+            (Some(vault), None) => vault.encrypt_file(plain, packed),
+            (None, Some(compression)) => compression.compress_file(plain, packed),
+            (None, None) => std::fs::copy(plain, packed).map(|_| ()),
+        }
+    }
+
+    /// restores the file `plain` from its packed copy `packed`
+    pub fn unpack(&self, packed: &Path, plain: &Path, work: &Path) -> std::io::Result<()> {
+        match (self.vault, self.compression) {
+            (Some(vault), Some(_)) => {
+                vault.decrypt_to(packed, work)?;
+                let decompressed = compress::decompress_to(work, plain);
+                let _ = std::fs::remove_file(work);
+                decompressed
+            }
+            (Some(vault), None) => vault.decrypt_to(packed, plain),
+            (None, Some(_)) => compress::decompress_to(packed, plain),
+            (None, None) => std::fs::copy(packed, plain).map(|_| ()),
+        }
+    }
+}
+
+fn remove(path: &Path) -> std::io::Result<()> {
+    if path.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+}
+
+/// size in bytes and latest modification of the files under `path`
+fn usage(path: &Path) -> (u64, SystemTime) {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return (0, SystemTime::UNIX_EPOCH);
+    };
+    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+    if !metadata.is_dir() {
+        return (metadata.len(), modified);
+    }
+    std::fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|child| usage(&child.path()))
+        .fold((0, modified), |(size, latest), (s, m)| {
+            (size + s, latest.max(m))
+        })
+}
+
+/// parses an age such as 30d, 12h, 45m, 2w or 90s (seconds without a unit)
+pub fn parse_age(age: &str) -> Result<Duration, String> {
+    let age = age.trim();
+    let split = age.find(|c: char| !c.is_ascii_digit()).unwrap_or(age.len());
+    let (count, unit) = age.split_at(split);
+    let count = count
+        .parse::<u64>()
+        .map_err(|_| format!("invalid age {age:?}, expected e.g. 30d"))?;
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 7 * 86400,
+        _ => {
+            return Err(format!(
+                "unknown unit {unit:?} in {age:?}, use s, m, h, d or w"
+            ))
+        }
+    };
+    Ok(Duration::from_secs(count * seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_age() {
+        assert_eq!(parse_age("30d"), Ok(Duration::from_secs(30 * 86400)));
+        assert_eq!(parse_age("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_age("2w"), Ok(Duration::from_secs(14 * 86400)));
+        assert!(parse_age("d").is_err());
+        assert!(parse_age("3y").is_err());
+    }
+
+    #[test]
+    fn test_purge() {
+        let root = std::env::temp_dir().join(format!("rmkmount-cache-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let cache = DocumentCache::new(root.clone());
+        for uid in ["a1", "b2"] {
+            let dir = cache.open_dir(uid);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("doc.pdf"), b"12345").unwrap();
+        }
+        let recent = cache.purge(&PurgeFilter::OlderThan(Duration::from_secs(3600)));
+        assert_eq!((recent.removed, recent.remaining), (0, 10));
+        let one = cache.purge(&PurgeFilter::Document("A1".to_string()));
+        assert_eq!(
+            one,
+            PurgeReport {
+                removed: 1,
+                freed: 5,
+                kept: 1,
+                remaining: 5
+            }
+        );
+        assert!(!cache.open_dir("a1").exists());
+        assert_eq!(cache.purge(&PurgeFilter::All).freed, 5);
+        assert_eq!(cache.purge(&PurgeFilter::All), PurgeReport::default());
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,120 @@
+use sftp_rkfs::fs::date_from_ms;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// What calibre's "add books from folders" needs to know about a document,
+/// written as the `metadata.opf` of its book folder
+pub struct BookMetadata<'a> {
+    pub uid: &'a str,
+    pub title: &'a str,
+    pub tags: &'a [String],
+    pub created: Option<SystemTime>,
+    pub modified: Option<SystemTime>,
+}
+
+/// author calibre itself uses for books without one
+const UNKNOWN_AUTHOR: &str = "Unknown";
+pub const OPF_FILE: &str = "metadata.opf";
+
+/// `<library>/Unknown/<title>`, one folder per book as in a calibre library. The
+/// uid disambiguates documents with the same title.
+pub fn book_dir(library: &Path, title: &str, uid: &str) -> PathBuf {
+    let title = title
+        .chars()
+        .map(|c| if c == '/' || c.is_control() { '_' } else { c })
+        .collect::<String>();
+    let short_uid = uid.split('-').next().unwrap_or(uid);
+    library
+        .join(UNKNOWN_AUTHOR)
+        .join(format!("{} ({short_uid})", title.trim()))
+}
+
+impl BookMetadata<'_> {
+    /// OPF 2.0 package metadata, as read by calibre
+    pub fn to_opf(&self) -> String {
+        let mut fields = vec![
+            format!(
+                "<dc:identifier opf:scheme=\"uuid\" id=\"uuid_id\">{}</dc:identifier>",
+                escape(self.uid)
+            ),
+            format!("<dc:title>{}</dc:title>", escape(self.title)),
+            format!("<dc:creator opf:role=\"aut\">{UNKNOWN_AUTHOR}</dc:creator>"),
+        ];
+        if let Some(created) = self.created {
+            fields.push(format!("<dc:date>{}</dc:date>", iso_date(created)));
+        }
+        fields.extend(
+            self.tags
+                .iter()
+                .map(|t| format!("<dc:subject>{}</dc:subject>", escape(t))),
+        );
+        if let Some(modified) = self.modified {
+            fields.push(format!(
+                "<meta name=\"calibre:timestamp\" content=\"{}\"/>",
+                iso_date(modified)
+            ));
+        }
+        format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+             <package xmlns=\"http://www.idpf.org/2007/opf\" unique-identifier=\"uuid_id\" version=\"2.0\">\n\
+             \x20 <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\" xmlns:opf=\"http://www.idpf.org/2007/opf\">\n\
+             {}\
+             \x20 </metadata>\n\
+             </package>\n",
+            fields
+                .iter()
+                .map(|f| format!("    {f}\n"))
+                .collect::<String>()
+        )
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// `YYYY-MM-DDTHH:MM:SS+00:00`
+fn iso_date(time: SystemTime) -> String {
+    let ms = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let (year, month, day) = date_from_ms(ms);
+    let secs = ms / 1000 % 86400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}+00:00",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_opf() {
+        let tags = ["work & co".to_string()];
+        let book = BookMetadata {
+            uid: "0b5d7d31-6f7a-4c55-9a4e-6b6e9f6c1a01",
+            title: "Spec <draft>",
+            tags: &tags,
+            created: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            modified: None,
+        };
+        let opf = book.to_opf();
+        assert!(opf.contains("<dc:title>Spec &lt;draft&gt;</dc:title>"));
+        assert!(opf.contains("<dc:subject>work &amp; co</dc:subject>"));
+        assert!(opf.contains("<dc:date>2023-11-14T22:13:20+00:00</dc:date>"));
+        assert!(opf.contains("\n  <metadata "));
+        assert_eq!(
+            book_dir(Path::new("lib"), "a/b", book.uid),
+            Path::new("lib/Unknown/a_b (0b5d7d31)")
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use log::debug;
+use std::fs::File;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Compressor of cached files
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Zstd,
+}
+
+impl std::str::FromStr for Codec {
+    type Err = String;
+
+    fn from_str(codec: &str) -> Result<Self, Self::Err> {
+        match codec {
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(format!("unknown compression {codec}, expected zstd")),
+        }
+    }
+}
+
+impl Codec {
+    /// suffix of the compressed files
+    pub fn extension(self) -> &'static str {
+        match self {
+            Codec::Zstd => "zst",
+        }
+    }
+
+    /// first bytes of the files it writes
+    fn magic(self) -> &'static [u8] {
+        match self {
+            Codec::Zstd => &[0x28, 0xb5, 0x2f, 0xfd],
+        }
+    }
+}
+
+/// Compression of the cached documents, set once at startup by
+/// `--cache-compression`. Compressed files are recognised by their magic, so
+/// they are read whether or not compression is still enabled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Compression {
+    pub codec: Codec,
+    pub level: i32,
+    /// smaller files are stored as is, compressing them saves little
+    pub min_size: u64,
+}
+
+static COMPRESSION: OnceLock<Compression> = OnceLock::new();
+
+const CODECS: [Codec; 1] = [Codec::Zstd];
+
+impl Compression {
+    /// Compresses the cached files written from now on
+    pub fn enable(self) -> &'static Compression {
+        COMPRESSION.get_or_init(|| self)
+    }
+
+    /// the compression when enabled
+    pub fn enabled() -> Option<&'static Compression> {
+        COMPRESSION.get()
+    }
+
+    /// is a file of `size` bytes worth compressing ?
+    pub fn applies(&self, size: u64) -> bool {
+        size >= self.min_size
+    }
+
+    /// compresses the file `plain` into `compressed`
+    pub fn compress_file(&self, plain: &Path, compressed: &Path) -> std::io::Result<()> {
+        match self.codec {
+            Codec::Zstd => {
+                zstd::stream::copy_encode(File::open(plain)?, File::create(compressed)?, self.level)
+            }
+        }
+    }
+}
+
+/// codec of the file starting with `head`, None when it is not compressed
+fn codec_of(head: &[u8]) -> Option<Codec> {
+    CODECS.into_iter().find(|c| head.starts_with(c.magic()))
+}
+
+/// Decompresses `compressed` into `plain`, the codec being told by the file
+/// content. A file that is not compressed is copied.
+pub fn decompress_to(compressed: &Path, plain: &Path) -> std::io::Result<()> {
+    let mut head = [0u8; 8];
+    let read = std::io::Read::read(&mut File::open(compressed)?, &mut head)?;
+    let Some(codec) = codec_of(&head[..read]) else {
+        std::fs::copy(compressed, plain)?;
+        return Ok(());
+    };
+    debug!("decompressing {}", compressed.display());
+    match codec {
+        Codec::Zstd => zstd::stream::copy_decode(File::open(compressed)?, File::create(plain)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codec_of() {
+        assert_eq!(
+            codec_of(&[0x28, 0xb5, 0x2f, 0xfd, 0x24, 0x05]),
+            Some(Codec::Zstd)
+        );
+        assert_eq!(codec_of(b"%PDF-1.7"), None);
+        assert_eq!(codec_of(&[0x28]), None);
+        assert_eq!("zstd".parse::<Codec>(), Ok(Codec::Zstd));
+        assert!("lzma".parse::<Codec>().is_err());
+        let compression = Compression {
+            codec: Codec::Zstd,
+            level: 3,
+            min_size: 64 * 1024,
+        };
+        assert!(compression.applies(1024 * 1024));
+        assert!(!compression.applies(512));
+    }
+
+    #[test]
+    fn test_compress_file() {
+        let dir =
+            std::env::temp_dir().join(format!("rmkmount-compress-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (plain, compressed, restored) =
+            (dir.join("a.pdf"), dir.join("a.pdf.zst"), dir.join("b.pdf"));
+        let data = b"%PDF-1.7 ".repeat(1000);
+        std::fs::write(&plain, &data).unwrap();
+        let compression = Compression {
+            codec: Codec::Zstd,
+            level: 3,
+            min_size: 0,
+        };
+        compression.compress_file(&plain, &compressed).unwrap();
+        let head = std::fs::read(&compressed).unwrap();
+        assert_eq!(codec_of(&head), Some(Codec::Zstd));
+        assert!(head.len() < data.len());
+        decompress_to(&compressed, &restored).unwrap();
+        assert_eq!(std::fs::read(&restored).unwrap(), data);
+        // files that are not compressed are copied
+        decompress_to(&plain, &restored).unwrap();
+        assert_eq!(std::fs::read(&restored).unwrap(), data);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
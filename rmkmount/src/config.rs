@@ -0,0 +1,137 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Why the configuration file cannot be used
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error(transparent)]
+    Invalid(#[from] toml::de::Error),
+    #[error("no profile {0} in the configuration file")]
+    UnknownProfile(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Settings of a tablet, used for the options not given on the command line
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub identity: Option<PathBuf>,
+    pub document_root: Option<String>,
+    pub mountpoint: Option<String>,
+    /// octal, as a string such as "444"
+    pub file_mode: Option<String>,
+    pub dir_mode: Option<String>,
+    pub memory_budget: Option<usize>,
+    pub strict_names: Option<bool>,
+    pub raw_pages: Option<bool>,
+    pub write_back: Option<bool>,
+    pub read_only: Option<bool>,
+    pub scan_jobs: Option<usize>,
+    pub volume_name: Option<String>,
+}
+
+/// `config.toml` of the configuration folder: named tablet profiles, and the
+/// one used without `--profile`
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub default: Option<String>,
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+impl Config {
+    const FILE: &'static str = "config.toml";
+
+    pub fn path() -> PathBuf {
+        crate::config_dir().join(Self::FILE)
+    }
+
+    /// Reads the configuration file, empty when there is none
+    pub fn load() -> Result<Self, ConfigError> {
+        match std::fs::read_to_string(Self::path()) {
+            Ok(text) => Self::parse(&text),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let mut config: Self = toml::from_str(text)?;
+        for profile in config.profiles.values_mut() {
+            profile.identity = profile
+                .identity
+                .as_deref()
+                .map(|p| expand_home(&p.to_string_lossy()).into());
+            profile.mountpoint = profile.mountpoint.as_deref().map(expand_home);
+        }
+        Ok(config)
+    }
+
+    /// Profile `name`, or the default one without a name. None when no
+    /// profile applies
+    pub fn profile(&self, name: Option<&str>) -> Result<Option<&Profile>, ConfigError> {
+        match name.or(self.default.as_deref()) {
+            Some(name) => self
+                .profiles
+                .get(name)
+                .map(Some)
+                .ok_or_else(|| ConfigError::UnknownProfile(name.to_owned())),
+            None => Ok(None),
+        }
+    }
+}
+
+/// `path` with a leading `~/` replaced by the home folder
+fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), std::env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{home}/{rest}"),
+        _ => path.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile() {
+        let config = Config::parse(
+            "default = \"desk\"\n\
+             [profiles.desk]\n\
+             address = \"192.168.1.20\" # wifi\n\
+             port = 2_222\n\
+             file_mode = \"644\"\n\
+             [profiles.usb]\n\
+             user = \"root\"\n",
+        )
+        .unwrap();
+        let desk = config.profile(None).unwrap().unwrap();
+        assert_eq!(desk.address.as_deref(), Some("192.168.1.20"));
+        assert_eq!(desk.port, Some(2222));
+        assert_eq!(desk.file_mode.as_deref(), Some("644"));
+        let usb = config.profile(Some("usb")).unwrap().unwrap();
+        assert_eq!(usb.user.as_deref(), Some("root"));
+        assert!(matches!(
+            config.profile(Some("car")),
+            Err(ConfigError::UnknownProfile(_))
+        ));
+        assert_eq!(Config::default().profile(None).unwrap(), None);
+        for bad in [
+            "[profiles.desk]\nadress = \"x\"\n",
+            "[profiles.desk]\nport = \"22\"\n",
+            "[profiles",
+            "default = \"a\"\ndefault = \"b\"\n",
+        ] {
+            assert!(
+                matches!(Config::parse(bad), Err(ConfigError::Invalid(_))),
+                "{bad}"
+            );
+        }
+    }
+}
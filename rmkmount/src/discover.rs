@@ -0,0 +1,162 @@
+use log::debug;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// address of the tablet on its USB network
+const USB_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 11, 99, 1);
+const MDNS_GROUP: (Ipv4Addr, u16) = (Ipv4Addr::new(224, 0, 0, 251), 5353);
+const SSDP_GROUP: (Ipv4Addr, u16) = (Ipv4Addr::new(239, 255, 255, 250), 1900);
+/// names asked over mDNS: the tablet host name, and ssh services
+const MDNS_NAMES: [(&str, u16); 2] = [("reMarkable.local", 1), ("_ssh._tcp.local", 12)];
+const SSH_PORT: u16 = 22;
+/// how long each socket is waited for in turn
+const POLL: Duration = Duration::from_millis(50);
+
+/// How a device was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Source {
+    Usb,
+    Mdns,
+    Ssdp,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Source::Usb => "usb",
+            Source::Mdns => "mdns",
+            Source::Ssdp => "ssdp",
+        })
+    }
+}
+
+/// A tablet answering on the network
+#[derive(Debug)]
+pub struct Device {
+    pub address: IpAddr,
+    pub source: Source,
+    /// banner of its ssh server, None when port 22 is closed
+    pub ssh: Option<String>,
+}
+
+/// Looks for tablets for `timeout`: on the USB network, then among the mDNS and
+/// SSDP answers of the local network naming a reMarkable
+pub fn discover(timeout: Duration) -> Vec<Device> {
+    let mut found = BTreeMap::new();
+    if let Some(banner) = ssh_banner(IpAddr::V4(USB_ADDRESS), timeout) {
+        found.insert(IpAddr::V4(USB_ADDRESS), (Source::Usb, Some(banner)));
+    }
+    let announced = match announcements(timeout) {
+        Ok(announced) => announced,
+        Err(e) => {
+            debug!("network discovery failed: {e}");
+            vec![]
+        }
+    };
+    for (address, source) in announced {
+        found.entry(address).or_insert((source, None));
+    }
+    found
+        .into_iter()
+        .map(|(address, (source, ssh))| Device {
+            address,
+            source,
+            ssh: ssh.or_else(|| ssh_banner(address, timeout)),
+        })
+        .collect()
+}
+
+/// the identification line of the ssh server at `address`
+fn ssh_banner(address: IpAddr, timeout: Duration) -> Option<String> {
+    let stream = TcpStream::connect_timeout(&SocketAddr::new(address, SSH_PORT), timeout).ok()?;
+    stream.set_read_timeout(Some(timeout)).ok()?;
+    let mut banner = String::new();
+    BufReader::new(stream).read_line(&mut banner).ok()?;
+    Some(banner.trim().to_owned()).filter(|b| b.starts_with("SSH-"))
+}
+
+/// senders of the mDNS and SSDP answers mentioning a reMarkable within `timeout`.
+/// The mDNS queries are sent from another port than 5353, so they are answered
+/// to this socket rather than to the multicast group
+fn announcements(timeout: Duration) -> std::io::Result<Vec<(IpAddr, Source)>> {
+    let mdns = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    mdns.send_to(&mdns_query(&MDNS_NAMES), MDNS_GROUP)?;
+    let ssdp = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    ssdp.send_to(ssdp_search().as_bytes(), SSDP_GROUP)?;
+    let sockets = [(&mdns, Source::Mdns), (&ssdp, Source::Ssdp)];
+    for (socket, _) in sockets {
+        socket.set_read_timeout(Some(POLL))?;
+    }
+    let mut found = vec![];
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0u8; 9000];
+    while Instant::now() < deadline {
+        for (socket, source) in sockets {
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                if mentions_remarkable(&buf[..len]) {
+                    debug!("{source} answer from {from}");
+                    found.push((from.ip(), source));
+                }
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// DNS query packet asking for `names`, each with its record type
+fn mdns_query(names: &[(&str, u16)]) -> Vec<u8> {
+    // id 0, standard query, no flags
+    let mut packet = vec![0, 0, 0, 0];
+    packet.extend((names.len() as u16).to_be_bytes());
+    packet.extend([0; 6]);
+    for (name, record) in names {
+        for label in name.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend(label.as_bytes());
+        }
+        packet.push(0);
+        packet.extend(record.to_be_bytes());
+        // class IN
+        packet.extend(1u16.to_be_bytes());
+    }
+    packet
+}
+
+fn ssdp_search() -> String {
+    format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}:{}\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\nST: ssdp:all\r\n\r\n",
+        SSDP_GROUP.0, SSDP_GROUP.1
+    )
+}
+
+/// does the answer `packet` name a reMarkable (host name, service or server) ?
+fn mentions_remarkable(packet: &[u8]) -> bool {
+    packet
+        .windows(b"remarkable".len())
+        .any(|w| w.eq_ignore_ascii_case(b"remarkable"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mdns_query() {
+        assert_eq!(
+            mdns_query(&[("rm.local", 1)]),
+            [
+                &[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0][..],
+                b"\x02rm\x05local\x00",
+                &[0, 1, 0, 1],
+            ]
+            .concat()
+        );
+        assert!(mentions_remarkable(b"\x0areMarkable\x05local\x00"));
+        assert!(mentions_remarkable(
+            b"SERVER: Linux UPnP/1.0 REMARKABLE/3.5"
+        ));
+        assert!(!mentions_remarkable(b"\x06kindle\x05local\x00"));
+    }
+}
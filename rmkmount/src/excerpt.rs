@@ -0,0 +1,218 @@
+use crate::process::run;
+use crate::transfer::TransferQueue;
+use indicatif::ProgressBar;
+use log::{debug, info};
+use sftp_rkfs::fs::RemarkableFs;
+use sftp_rkfs::{rkids, ErrorContext, FsError, RemarkableError};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Pages `first` to `last` of a document, counted from 1 as in viewers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageRange {
+    pub first: usize,
+    pub last: usize,
+}
+
+impl FromStr for PageRange {
+    type Err = String;
+
+    fn from_str(range: &str) -> Result<Self, Self::Err> {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        let page = |p: &str| {
+            p.trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|p| *p > 0)
+                .ok_or_else(|| format!("invalid page {p:?}, pages count from 1"))
+        };
+        let (first, last) = (page(first)?, page(last)?);
+        if last < first {
+            return Err(format!("page range {range} ends before it starts"));
+        }
+        Ok(Self { first, last })
+    }
+}
+
+/// Exports pages `range` of the tablet document `source` as a standalone pdf in
+/// `destination`, its annotations drawn over the pages when `annotated`. The
+/// whole pdf of a document is fetched, along with the stroke files of those
+/// pages only; `qpdf` cuts and overlays the pages, `rmc` renders the strokes.
+/// Returns the file written.
+pub fn export_pages(
+    rfs: &mut RemarkableFs,
+    source: &str,
+    range: PageRange,
+    annotated: bool,
+    destination: &Path,
+) -> Result<PathBuf, RemarkableError> {
+    let ino = rfs.resolve_path(source)?;
+    if !rfs.is_document(ino) {
+        return Err(FsError::NotADocument(source.to_owned()).into());
+    }
+    if let Some(count) = rfs.page_count(ino) {
+        if range.last > usize::from(count) {
+            return Err(FsError::InvalidPath(format!("{source} has {count} pages")).into());
+        }
+    }
+    let name = rfs.visible_name(ino).unwrap_or_default();
+    let notebook = rfs.size(ino).unwrap_or(0) == 0;
+    if !notebook && name.extension().is_none_or(|e| e != "pdf") {
+        return Err(FsError::Unsupported(format!(
+            "{source}: pages of pdf documents and notebooks only"
+        ))
+        .into());
+    }
+    if notebook && !annotated {
+        return Err(FsError::Unsupported(format!(
+            "{source}: a notebook only has annotations, add --annotated"
+        ))
+        .into());
+    }
+    let work = work_dir().context("creating the work folder")?;
+    let exported = export_in(rfs, ino, source, range, annotated, notebook, &work);
+    if let Err(e) = std::fs::remove_dir_all(&work) {
+        debug!("unable to remove {work:?}: {e}");
+    }
+    let exported = exported?;
+    std::fs::create_dir_all(destination)?;
+    let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+    let target = destination.join(format!("{stem} p{}-{}.pdf", range.first, range.last));
+    std::fs::write(&target, exported).with_context(|| format!("writing {target:?}"))?;
+    info!(
+        "pages {}-{} of {source} exported to {target:?}",
+        range.first, range.last
+    );
+    Ok(target)
+}
+
+/// A private folder (mode 0700) for the pages being cut, created afresh so that
+/// no other user can read them or plant files there
+fn work_dir() -> std::io::Result<PathBuf> {
+    let runtime = crate::runtime_dir();
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&runtime)?;
+    let work = runtime.join(format!("pages-{}", rkids::new_uid()));
+    std::fs::DirBuilder::new().mode(0o700).create(&work)?;
+    Ok(work)
+}
+
+/// the exported pdf, built in the folder `work`
+fn export_in(
+    rfs: &mut RemarkableFs,
+    ino: usize,
+    source: &str,
+    range: PageRange,
+    annotated: bool,
+    notebook: bool,
+    work: &Path,
+) -> Result<Vec<u8>, RemarkableError> {
+    let mut overlays = vec![];
+    if annotated {
+        for (index, strokes) in rfs.page_annotations(ino, range.first - 1..=range.last - 1)? {
+            let (rm, pdf) = (
+                work.join(format!("{index}.rm")),
+                work.join(format!("{index}.pdf")),
+            );
+            std::fs::write(&rm, strokes).with_context(|| format!("writing {rm:?}"))?;
+            let (rm_arg, pdf_arg) = (rm.to_string_lossy(), pdf.to_string_lossy());
+            run("rmc", &["-t", "pdf", "-o", &pdf_arg, &rm_arg], None)
+                .with_context(|| format!("rendering page {} of {source}", index + 1))?;
+            overlays.push((index + 1, pdf));
+        }
+        debug!("{} annotated pages in the range", overlays.len());
+    }
+    let excerpt = work.join("excerpt.pdf");
+    let args = if notebook {
+        if overlays.is_empty() {
+            return Err(
+                FsError::Unsupported(format!("{source}: nothing written on those pages")).into(),
+            );
+        }
+        notebook_args(&overlays, &excerpt)
+    } else {
+        let document = work.join("document.pdf");
+        TransferQueue::pull_document(rfs, source, work, &ProgressBar::hidden())?;
+        let name = rfs.visible_name(ino).unwrap_or_default();
+        std::fs::rename(work.join(name), &document)?;
+        pdf_args(&document, range, &overlays, &excerpt)
+    };
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    run("qpdf", &args, None).with_context(|| format!("cutting pages of {source}"))?;
+    Ok(std::fs::read(&excerpt)?)
+}
+
+/// qpdf arguments keeping pages `range` of `document` into `output`, each page
+/// of `overlays` (page number, rendered strokes) drawn over its page
+fn pdf_args(
+    document: &Path,
+    range: PageRange,
+    overlays: &[(usize, PathBuf)],
+    output: &Path,
+) -> Vec<String> {
+    let document = document.to_string_lossy().into_owned();
+    let mut args = vec![
+        "--empty".to_owned(),
+        "--pages".to_owned(),
+        document,
+        format!("{}-{}", range.first, range.last),
+        "--".to_owned(),
+    ];
+    for (page, strokes) in overlays {
+        args.push("--overlay".to_owned());
+        args.push(strokes.to_string_lossy().into_owned());
+        // pages are renumbered from 1 in the excerpt
+        args.push(format!("--to={}", page - range.first + 1));
+        args.push("--".to_owned());
+    }
+    args.push(output.to_string_lossy().into_owned());
+    args
+}
+
+/// qpdf arguments joining the rendered pages of a notebook into `output`
+fn notebook_args(pages: &[(usize, PathBuf)], output: &Path) -> Vec<String> {
+    let mut args = vec!["--empty".to_owned(), "--pages".to_owned()];
+    args.extend(pages.iter().map(|(_, p)| p.to_string_lossy().into_owned()));
+    args.push("--".to_owned());
+    args.push(output.to_string_lossy().into_owned());
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_range() {
+        assert_eq!(
+            "12-18".parse::<PageRange>(),
+            Ok(PageRange {
+                first: 12,
+                last: 18
+            })
+        );
+        assert_eq!(
+            "3".parse::<PageRange>(),
+            Ok(PageRange { first: 3, last: 3 })
+        );
+        for bad in ["0-2", "5-4", "a-b", "", "1-"] {
+            assert!(bad.parse::<PageRange>().is_err(), "{bad}");
+        }
+        let args = pdf_args(
+            Path::new("/t/doc.pdf"),
+            PageRange {
+                first: 12,
+                last: 18,
+            },
+            &[(13, PathBuf::from("/t/12.pdf"))],
+            Path::new("/t/out.pdf"),
+        );
+        assert_eq!(
+            args.join(" "),
+            "--empty --pages /t/doc.pdf 12-18 -- --overlay /t/12.pdf --to=2 -- /t/out.pdf"
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// Tablet given as `[USER@]HOST[:PORT]`, IPv6 addresses being bracketed to add
+/// a port (`root@[fe80::1%wlan0]:22`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostSpec {
+    pub user: Option<String>,
+    pub address: String,
+    pub port: Option<u16>,
+}
+
+impl FromStr for HostSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (user, host) = match spec.rsplit_once('@') {
+            Some(("", _)) => return Err("empty user before @".to_owned()),
+            Some((user, host)) => (Some(user.to_owned()), host),
+            None => (None, spec),
+        };
+        let parse_port = |p: &str| {
+            p.parse::<u16>()
+                .ok()
+                .filter(|p| *p != 0)
+                .ok_or_else(|| format!("invalid port {p}"))
+        };
+        let (address, port) = if let Some(rest) = host.strip_prefix('[') {
+            let (inner, after) = rest.split_once(']').ok_or("missing ] after the address")?;
+            match after.strip_prefix(':') {
+                Some(p) => (inner, Some(parse_port(p)?)),
+                None if after.is_empty() => (inner, None),
+                None => return Err(format!("unexpected {after} after ]")),
+            }
+        } else {
+            // a single colon separates a port, several make an IPv6 address
+            match host.split_once(':') {
+                Some((name, p)) if !p.contains(':') => (name, Some(parse_port(p)?)),
+                _ => (host, None),
+            }
+        };
+        if address.is_empty() {
+            return Err("empty host".to_owned());
+        }
+        Ok(Self {
+            user,
+            address: address.to_owned(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for HostSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(user) = &self.user {
+            write!(f, "{user}@")?;
+        }
+        match self.port {
+            Some(port) if self.address.contains(':') => write!(f, "[{}]:{port}", self.address),
+            Some(port) => write!(f, "{}:{port}", self.address),
+            None => write!(f, "{}", self.address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_spec() {
+        let spec = "root@10.11.99.1:22".parse::<HostSpec>().unwrap();
+        assert_eq!(
+            spec,
+            HostSpec {
+                user: Some("root".to_owned()),
+                address: "10.11.99.1".to_owned(),
+                port: Some(22),
+            }
+        );
+        assert_eq!(spec.to_string(), "root@10.11.99.1:22");
+        let spec = "me@[fe80::1%wlan0]:2222".parse::<HostSpec>().unwrap();
+        assert_eq!(spec.address, "fe80::1%wlan0");
+        assert_eq!(spec.port, Some(2222));
+        assert_eq!(spec.to_string(), "me@[fe80::1%wlan0]:2222");
+        assert_eq!(
+            "fe80::1".parse::<HostSpec>(),
+            Ok(HostSpec {
+                user: None,
+                address: "fe80::1".to_owned(),
+                port: None,
+            })
+        );
+        assert_eq!("remarkable".parse::<HostSpec>().unwrap().user, None);
+        for bad in [
+            "@host", "root@", "host:ssh", "host:0", "[::1", "[::1]x", ":22",
+        ] {
+            assert!(bad.parse::<HostSpec>().is_err(), "{bad}");
+        }
+    }
+}
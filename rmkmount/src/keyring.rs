@@ -0,0 +1,106 @@
+/// Secrets of the rmkmount service in the OS keyring: the Secret Service or
+/// the macOS keychain through the keyring crate, or, without the `keyring`
+/// feature, libsecret's `secret-tool` and macOS `security` programs. Secrets
+/// are never passed on the command line, where other users would see them.
+const KEYRING_SERVICE: &str = "rmkmount";
+
+/// the secret stored for `account`, None when there is none
+#[cfg(feature = "keyring")]
+pub fn lookup(account: &str) -> std::io::Result<Option<String>> {
+    match entry(account)?.get_password() {
+        Ok(secret) => Ok(Some(secret)),
+        Err(::keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(std::io::Error::other(e)),
+    }
+}
+
+/// stores `secret` for `account`, replacing the former one
+#[cfg(feature = "keyring")]
+pub fn store(account: &str, _label: &str, secret: &str) -> std::io::Result<()> {
+    entry(account)?
+        .set_password(secret)
+        .map_err(std::io::Error::other)
+}
+
+#[cfg(feature = "keyring")]
+fn entry(account: &str) -> std::io::Result<::keyring::Entry> {
+    ::keyring::Entry::new(KEYRING_SERVICE, account).map_err(std::io::Error::other)
+}
+
+/// the secret stored for `account`, None when there is none
+#[cfg(not(feature = "keyring"))]
+pub fn lookup(account: &str) -> std::io::Result<Option<String>> {
+    let out = if cfg!(target_os = "macos") {
+        std::process::Command::new("security")
+            .args(["find-generic-password", "-s", KEYRING_SERVICE])
+            .args(["-a", account, "-w"])
+            .output()?
+    } else {
+        std::process::Command::new("secret-tool")
+            .args(["lookup", "service", KEYRING_SERVICE])
+            .args(["account", account])
+            .output()?
+    };
+    let secret = String::from_utf8_lossy(&out.stdout).trim().to_owned();
+    Ok(Some(secret).filter(|s| out.status.success() && !s.is_empty()))
+}
+
+/// stores `secret` for `account`, replacing the former one. The secret goes
+/// through stdin: `secret-tool` reads it there, and `security -i` reads its
+/// whole command line there
+#[cfg(not(feature = "keyring"))]
+pub fn store(account: &str, label: &str, secret: &str) -> std::io::Result<()> {
+    if cfg!(target_os = "macos") {
+        // -U updates the item when there already is one
+        let command = format!(
+            "add-generic-password -U -s {} -a {} -w {}\n",
+            security_quote(KEYRING_SERVICE),
+            security_quote(account),
+            security_quote(secret)
+        );
+        crate::process::run("security", &["-i"], Some(command.as_bytes()))?;
+    } else {
+        let label = format!("--label={label}");
+        crate::process::run(
+            "secret-tool",
+            &[
+                "store",
+                &label,
+                "service",
+                KEYRING_SERVICE,
+                "account",
+                account,
+            ],
+            Some(secret.as_bytes()),
+        )?;
+    }
+    Ok(())
+}
+
+/// `arg` as one word of a `security -i` command line
+#[cfg(not(feature = "keyring"))]
+fn security_quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// keyring account of the ssh password of the tablet at `address`
+#[cfg(feature = "keyring")]
+fn password_account(address: &str) -> String {
+    format!("password@{address}")
+}
+
+/// Password saved by `rmkmount login` for the tablet at `address`
+#[cfg(feature = "keyring")]
+pub fn password(address: &str) -> std::io::Result<Option<String>> {
+    lookup(&password_account(address))
+}
+
+/// Saves the ssh `password` of the tablet at `address`
+#[cfg(feature = "keyring")]
+pub fn save_password(address: &str, password: &str) -> std::io::Result<()> {
+    store(
+        &password_account(address),
+        &format!("rmkmount password of {address}"),
+        password,
+    )
+}
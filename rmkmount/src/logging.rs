@@ -0,0 +1,178 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Logger writing to stderr and to a log file kept for `rmkmount debug-bundle`.
+/// Levels come from -v/-q and can be overridden per module with RUST_LOG
+/// (e.g. `RUST_LOG=info,sftp_rkfs::sshutils=trace`).
+pub struct CliLogger {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+    file: Option<Mutex<File>>,
+}
+
+impl CliLogger {
+    const LOG_FILE: &'static str = "rmkmount.log";
+    /// the log file is rotated on startup above this size
+    const LOG_FILE_MAX_SIZE: u64 = 5 * 1024 * 1024;
+
+    /// log file location, next to the transfer queue
+    pub fn log_path() -> PathBuf {
+        crate::state_dir().join(Self::LOG_FILE)
+    }
+
+    /// installs the logger : Info by default, -v for Debug, -vv for Trace, -q for errors only
+    pub fn init(verbose: u8, quiet: bool) {
+        let mut default = match (quiet, verbose) {
+            (true, _) => LevelFilter::Error,
+            (false, 0) => LevelFilter::Info,
+            (false, 1) => LevelFilter::Debug,
+            (false, _) => LevelFilter::Trace,
+        };
+        let mut modules = vec![];
+        if let Ok(directives) = std::env::var("RUST_LOG") {
+            for directive in directives.split(',').map(str::trim) {
+                match directive.split_once('=') {
+                    Some((module, level)) => {
+                        if let Ok(level) = level.parse() {
+                            modules.push((module.to_owned(), level));
+                        }
+                    }
+                    None => {
+                        if let Ok(level) = directive.parse() {
+                            default = level;
+                        }
+                    }
+                }
+            }
+        }
+        // most specific module first
+        modules.sort_by_key(|m| std::cmp::Reverse(m.0.len()));
+        let max_level = modules.iter().map(|(_, l)| *l).fold(default, std::cmp::max);
+
+        let logger = Self {
+            default,
+            modules,
+            file: Self::open_log_file(&Self::log_path()).map(Mutex::new),
+        };
+        if log::set_boxed_logger(Box::new(logger)).is_ok() {
+            log::set_max_level(max_level);
+        }
+    }
+
+    fn open_log_file(path: &Path) -> Option<File> {
+        std::fs::create_dir_all(path.parent()?).ok()?;
+        if std::fs::metadata(path).is_ok_and(|m| m.len() > Self::LOG_FILE_MAX_SIZE) {
+            let _ = std::fs::rename(path, path.with_extension("log.1"));
+        }
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .ok()
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(m, _)| target == m || target.starts_with(&format!("{m}::")))
+            .map(|(_, l)| *l)
+            .unwrap_or(self.default)
+    }
+}
+
+impl Log for CliLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let secs = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let line = format!(
+            "{secs} {:<5} [{}] {}",
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        eprintln!("{line}");
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                let _ = writeln!(file, "{line}");
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                let _ = file.flush();
+            }
+        }
+    }
+}
+
+/// Masks host addresses, user names and home folders so logs can be shared
+pub fn redact(text: &str, secrets: &[&str]) -> String {
+    let mut redacted = text.to_owned();
+    if let Some(home) = std::env::var_os("HOME") {
+        let home = home.to_string_lossy();
+        if home.len() > 1 {
+            redacted = redacted.replace(home.as_ref(), "~");
+        }
+    }
+    for secret in secrets.iter().filter(|s| !s.is_empty()) {
+        redacted = redacted.replace(secret, "<redacted>");
+    }
+    redact_ipv4(&redacted)
+}
+
+/// replaces anything looking like an IPv4 address with `<ip>`
+fn redact_ipv4(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(|c: char| c.is_ascii_digit()) {
+        let (before, candidate) = rest.split_at(start);
+        out.push_str(before);
+        let len = candidate
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(candidate.len());
+        let token = candidate[..len].trim_end_matches('.');
+        let is_ip = token.split('.').count() == 4
+            && token
+                .split('.')
+                .all(|o| !o.is_empty() && o.len() <= 3 && o.parse::<u8>().is_ok());
+        if is_ip {
+            out.push_str("<ip>");
+            rest = &candidate[token.len()..];
+        } else {
+            out.push_str(&candidate[..len]);
+            rest = &candidate[len..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact("Connecting to alice@10.11.99.1:22", &["alice"]),
+            "Connecting to <redacted>@<ip>:22"
+        );
+        assert_eq!(redact("version 3.10.2.2063", &[]), "version 3.10.2.2063");
+        assert_eq!(redact("read 12 bytes.", &[]), "read 12 bytes.");
+    }
+}
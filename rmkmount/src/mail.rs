@@ -0,0 +1,201 @@
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{Message, SmtpTransport, Transport};
+use log::info;
+use std::net::IpAddr;
+
+/// A document sent by email, as the tablet's own "send by email" does
+pub struct Mail<'a> {
+    pub from: &'a str,
+    pub to: &'a [String],
+    pub subject: &'a str,
+    pub text: &'a str,
+    pub file_name: &'a str,
+    pub attachment: &'a [u8],
+}
+
+/// How the connection to the SMTP relay is secured
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum SmtpSecurity {
+    /// STARTTLS, required unless the relay is on this computer
+    #[default]
+    Starttls,
+    /// TLS from the start of the connection (smtps, port 465)
+    Tls,
+    /// clear text, only allowed with credentials for a relay on this computer
+    None,
+}
+
+/// Submits mails to an SMTP relay
+pub struct SmtpClient {
+    /// host:port of the relay
+    pub server: String,
+    /// user and password, if the relay requires authentication
+    pub credentials: Option<(String, String)>,
+    pub security: SmtpSecurity,
+}
+
+impl Mail<'_> {
+    /// multipart message : the text, then the attachment
+    fn to_message(&self) -> std::io::Result<Message> {
+        let mut builder = Message::builder()
+            .from(mailbox(self.from)?)
+            .subject(self.subject.replace(['\r', '\n'], " "));
+        for to in self.to {
+            builder = builder.to(mailbox(to)?);
+        }
+        let attachment = Attachment::new(self.file_name.replace(['"', '\r', '\n'], "_")).body(
+            self.attachment.to_vec(),
+            ContentType::parse("application/octet-stream").map_err(std::io::Error::other)?,
+        );
+        builder
+            .multipart(
+                MultiPart::mixed()
+                    .singlepart(SinglePart::plain(self.text.to_owned()))
+                    .singlepart(attachment),
+            )
+            .map_err(std::io::Error::other)
+    }
+}
+
+impl SmtpClient {
+    /// Sends `mail` to all its recipients in one SMTP transaction
+    pub fn send(&self, mail: &Mail) -> std::io::Result<()> {
+        let message = mail.to_message()?;
+        let (host, port) = split_server(&self.server, self.security)?;
+        let tls = tls_mode(host, self.security, self.credentials.is_some())?;
+        let mut transport = SmtpTransport::builder_dangerous(host).port(port).tls(tls);
+        if let Some((user, password)) = &self.credentials {
+            transport = transport.credentials(Credentials::new(user.clone(), password.clone()));
+        }
+        transport
+            .build()
+            .send(&message)
+            .map_err(std::io::Error::other)?;
+        info!("{} sent to {}", mail.file_name, mail.to.join(", "));
+        Ok(())
+    }
+}
+
+/// `address` as a mailbox, refused when it could inject SMTP commands or
+/// headers
+fn mailbox(address: &str) -> std::io::Result<Mailbox> {
+    if address.contains(['\r', '\n']) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("line break in the mail address {address:?}"),
+        ));
+    }
+    address.parse().map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid mail address {address:?}: {e}"),
+        )
+    })
+}
+
+/// (host, port) of `server`, given as HOST[:PORT], the port defaulting to the
+/// one of `security`
+fn split_server(server: &str, security: SmtpSecurity) -> std::io::Result<(&str, u16)> {
+    let default_port = if security == SmtpSecurity::Tls {
+        465
+    } else {
+        25
+    };
+    let (host, port) = match server.rsplit_once(':') {
+        // bare IPv6 addresses have no port
+        Some((host, port)) if !host.contains(':') || host.starts_with('[') => {
+            let port = port.parse().map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("invalid smtp port in {server}"),
+                )
+            })?;
+            (host, port)
+        }
+        _ => (server, default_port),
+    };
+    Ok((host.trim_start_matches('[').trim_end_matches(']'), port))
+}
+
+/// is `host` this computer ? Clear text to it does not leave the machine
+fn is_loopback(host: &str) -> bool {
+    host.eq_ignore_ascii_case("localhost")
+        || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// TLS of the connection to `host`. Credentials are never sent in clear text
+/// over the network : STARTTLS is required from remote relays, and clear text
+/// with credentials is refused but for a relay on this computer
+fn tls_mode(host: &str, security: SmtpSecurity, credentials: bool) -> std::io::Result<Tls> {
+    let parameters = || TlsParameters::new(host.to_owned()).map_err(std::io::Error::other);
+    match security {
+        SmtpSecurity::Starttls if is_loopback(host) => Ok(Tls::Opportunistic(parameters()?)),
+        SmtpSecurity::Starttls => Ok(Tls::Required(parameters()?)),
+        SmtpSecurity::Tls => Ok(Tls::Wrapper(parameters()?)),
+        SmtpSecurity::None if credentials && !is_loopback(host) => Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!(
+                "credentials not sent in clear text to {host}, use --smtp-security starttls or tls"
+            ),
+        )),
+        SmtpSecurity::None => Ok(Tls::None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mailbox() {
+        assert!(mailbox("me@example.org").is_ok());
+        assert!(mailbox("Me <me@example.org>").is_ok());
+        assert!(mailbox("me@example.org>\r\nRCPT TO:<other@example.org").is_err());
+        assert!(mailbox("me@example.org\n").is_err());
+        assert!(mailbox("not an address").is_err());
+    }
+
+    #[test]
+    fn test_smtp_security() {
+        assert_eq!(
+            split_server("smtp.example.org", SmtpSecurity::Tls).unwrap(),
+            ("smtp.example.org", 465)
+        );
+        assert_eq!(
+            split_server("[::1]:2525", SmtpSecurity::None).unwrap(),
+            ("::1", 2525)
+        );
+        assert!(split_server("localhost:smtp", SmtpSecurity::None).is_err());
+        assert!(is_loopback("localhost") && is_loopback("127.0.0.1") && is_loopback("::1"));
+        assert!(!is_loopback("smtp.example.org"));
+        assert!(tls_mode("smtp.example.org", SmtpSecurity::None, true).is_err());
+        assert!(matches!(
+            tls_mode("smtp.example.org", SmtpSecurity::None, false),
+            Ok(Tls::None)
+        ));
+        assert!(matches!(
+            tls_mode("localhost", SmtpSecurity::None, true),
+            Ok(Tls::None)
+        ));
+    }
+
+    #[test]
+    fn test_message() {
+        let to = ["you@example.org".to_owned()];
+        let mail = Mail {
+            from: "me@example.org",
+            to: &to,
+            subject: "Spec\r\nBcc: x@example.org",
+            text: "Spec, sent from the tablet",
+            file_name: "Spec.pdf",
+            attachment: b"%PDF",
+        };
+        let formatted = String::from_utf8(mail.to_message().unwrap().formatted()).unwrap();
+        assert!(formatted.contains("To: you@example.org\r\n"));
+        assert!(!formatted.contains("\r\nBcc:"));
+        assert!(formatted.contains("filename=\"Spec.pdf\""));
+    }
+}
@@ -1,26 +1,130 @@
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 
+use indicatif::ProgressBar;
 use log::{debug, error, info, trace, warn, LevelFilter};
+use sftp_rkfs::fs::{ChangeEvent, ChangeKind, WritePolicy};
+use sftp_rkfs::names::NamePolicy;
+use sftp_rkfs::{ErrorContext, FsError};
+use std::io::Write;
+
+mod cache;
+mod calibre;
+mod compress;
+mod config;
+mod discover;
+mod excerpt;
+mod host;
+mod keyring;
+mod logging;
+mod mail;
+mod mounts;
+mod paperless;
+mod process;
+mod profile;
+mod publish;
+mod shell;
+mod transfer;
+mod trust;
+mod vault;
+use cache::{DocumentCache, Packing, PurgeFilter};
+use logging::CliLogger;
+use paperless::{PaperlessClient, TagMapping, Upload};
+use transfer::{map_directory_push, TransferItem, TransferKind, TransferQueue};
 
 /// Remarkable tablet fuse driver
 #[derive(Parser, Debug)]
 #[command(version,about,long_about=None)]
 struct Args {
-    /// remarkable tablet IP address (defaults to 10.x.x.x)
+    /// remarkable tablet address (defaults to 10.x.x.x): host name, IPv4 or IPv6 with an
+    /// optional interface scope such as fe80::1%wlan0, bracketed to add a port ([::1]:2222),
+    /// or a Host alias of ~/.ssh/config whose HostName, Port, User and IdentityFile apply
     #[arg(short, long, default_value = "10.11.99.1")]
     address: String,
-    /// port number for ssh to remarkable tablet
-    #[arg(short, long, default_value = "22")]
+    /// port number for ssh to remarkable tablet [default: 22]
+    #[arg(short, long)]
     port: Option<u16>,
-    /// username
-    #[arg(short, long, default_value = "root")]
+    /// username [default: root]
+    #[arg(short, long)]
     username: Option<String>,
-    /// hostname and user login as <[USER@]HOST[:PORT]>
-    #[arg(long, default_value = "root@10.11.99.1:22")]
-    host: String,
-    /// ssh password to remarkable tablet
-    #[arg(long, default_value = "xxx")]
-    password: String,
+    /// tablet as <[USER@]HOST[:PORT]>, in place of --address, --username and --port;
+    /// it takes precedence over the profile, and conflicts with different values
+    /// given with those options
+    #[arg(long, value_name = "[USER@]HOST[:PORT]")]
+    host: Option<host::HostSpec>,
+    /// ssh password to remarkable tablet. Otherwise read from --password-file, the
+    /// RMK_PASSWORD environment variable, the keyring (`rmkmount login`), or asked
+    /// on the terminal
+    #[arg(long)]
+    password: Option<String>,
+    /// file whose first line is the ssh password
+    #[arg(long, value_name = "FILE", conflicts_with = "password")]
+    password_file: Option<std::path::PathBuf>,
+    /// ssh private key tried before the password, its passphrase (if any) is read
+    /// from RMKMOUNT_PASSPHRASE
+    #[arg(short, long, value_name = "FILE")]
+    identity: Option<std::path::PathBuf>,
+    /// only accept the tablet presenting this host key (SHA256:... as printed by
+    /// ssh-keygen -l), instead of the keys of ~/.ssh/known_hosts
+    #[arg(long, value_name = "FINGERPRINT")]
+    host_key: Option<String>,
+    /// authenticate with the keys of ssh-agent or gpg-agent (SSH_AUTH_SOCK) first
+    #[arg(long)]
+    agent: bool,
+    /// keep Nagle's algorithm on the ssh connection (fewer packets, slower small requests)
+    #[arg(long)]
+    nagle: bool,
+    /// seconds of idle time before TCP keepalive probes are sent
+    #[arg(long)]
+    tcp_keepalive: Option<u64>,
+    /// seconds between ssh keepalive messages on an idle session, 0 for none
+    /// (tablets on USB drop idle sessions after a few minutes)
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    ssh_keepalive: u64,
+    /// seconds given to the connection to the tablet, 0 for the system timeout
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    connect_timeout: u64,
+    /// seconds without an answer of the tablet after which a request fails
+    /// (EIO once reconnecting failed too), 0 to wait forever
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    io_timeout: u64,
+    /// times a failed first connection is tried again, waiting longer each time
+    #[arg(long, default_value_t = 2)]
+    retries: u32,
+    /// send and receive socket buffer size in KiB
+    #[arg(long)]
+    socket_buffer: Option<usize>,
+    /// set the tablet clock to this computer's time at connection when they are
+    /// more than this many seconds apart
+    #[arg(long, value_name = "SECONDS")]
+    max_clock_skew: Option<u64>,
+    /// encrypt cached documents and state files with age, the key being kept in
+    /// the OS keyring (secret-tool or the macOS keychain without the keyring feature)
+    #[arg(long)]
+    encrypt_cache: bool,
+    /// compress cached documents with this codec (zstd),
+    /// compressed copies are read back whatever this setting
+    #[arg(long, value_name = "CODEC")]
+    cache_compression: Option<compress::Codec>,
+    /// compression level of cached documents
+    #[arg(long, default_value_t = 3)]
+    compression_level: i32,
+    /// cached documents smaller than this many KiB are stored uncompressed
+    #[arg(long, value_name = "KIB", default_value_t = 64)]
+    compression_min_size: u64,
+    /// more verbose output (-v for debug, -vv for trace), RUST_LOG overrides per module
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// only report errors
+    #[arg(short, long)]
+    quiet: bool,
+    /// profile of ~/.config/rmkmount/config.toml giving the options not on the
+    /// command line, its `default` profile being used otherwise
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+    /// folder of the xochitl documents on the tablet [default: detected on the
+    /// tablet among the known locations]
+    #[arg(long, value_name = "PATH")]
+    document_root: Option<String>,
 
     #[command(subcommand)]
     command: Commands,
@@ -30,57 +134,1940 @@ struct Args {
 enum Commands {
     /// List identities
     Identities {},
+    /// Check the ssh password of the tablet at --address and save it in the OS
+    /// keyring (Secret Service or macOS Keychain), later connections using it
+    /// when no password is given. Asked on the terminal when none is given
+    #[cfg(feature = "keyring")]
+    Login {},
+    /// Manage the tablets trusted so far and their host keys
+    Devices {
+        #[command(subcommand)]
+        action: DevicesCommand,
+    },
     /// Mount remarkable tablet documents
     Mount {
-        /// Mount point for documents
+        /// Mount point for documents, required unless given by the profile
         #[arg(short, long)]
-        mountpoint: String,
+        mountpoint: Option<String>,
+        /// Octal mode of documents
+        #[arg(long, default_value = "444", value_parser = parse_octal_mode)]
+        file_mode: u16,
+        /// Octal mode of collections
+        #[arg(long, default_value = "555", value_parser = parse_octal_mode)]
+        dir_mode: u16,
+        /// Memory in MiB kept for parsed document contents, colder ones are reloaded on demand
+        #[arg(long, default_value_t = 64)]
+        memory_budget: usize,
+        /// Hide entries whose names contain control characters or `/` instead of escaping them
+        #[arg(long)]
+        strict_names: bool,
+        /// Listing of entries sharing a name in a folder: uid or date appended to
+        /// the later ones, newest only, or error
+        #[arg(long, value_name = "POLICY", default_value = "uid")]
+        collisions: sftp_rkfs::names::CollisionPolicy,
+        /// Order of collection listings: tablet, name, modified (most recent
+        /// first) or pinned (favorites first, then by name)
+        #[arg(long, value_name = "ORDER", default_value = "tablet")]
+        sort: sftp_rkfs::fs::SortPolicy,
+        /// Seconds items missing from a listing are kept, in case a scan failure
+        /// hid them, before they are removed
+        #[arg(long, value_name = "SECONDS", default_value_t = 600)]
+        ghost_grace: u64,
+        /// Seconds folders are listed from the last fetch of the whole tree,
+        /// changes made on the tablet showing up after at most this long
+        #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+        tree_refresh: u64,
+        /// Seconds between polls of the tablet for changes, new documents then
+        /// showing up without listing their folder again, 0 for no polling
+        #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+        refresh_interval: u64,
+        /// Connections tried when the tablet dropped the session (sleep, Wi-Fi
+        /// roaming) before requests fail, waiting 1, 2, 4... seconds in between
+        #[arg(long, value_name = "COUNT", default_value_t = 4)]
+        reconnect_attempts: u32,
+        /// List the items missing from their folder as <name>.ghost until their
+        /// grace period ends
+        #[arg(long)]
+        show_ghosts: bool,
+        /// Expose the raw .rm stroke files of each document in a <name>.pages folder
+        #[arg(long)]
+        raw_pages: bool,
+        /// Merge the reads of documents arriving together (kernel readahead) into
+        /// fewer sftp requests, served from a thread of their own
+        #[arg(long)]
+        coalesce_reads: bool,
+        /// Keep the document blocks read on disk, in ~/.cache/rmkmount/<device>,
+        /// up to this many MiB [default: no disk cache]
+        #[arg(long, value_name = "MIB")]
+        disk_cache: Option<u64>,
+        /// Upload files copied into the mount when their last handle is released
+        /// rather than at each close, upload errors being only logged
+        #[arg(long)]
+        write_back: bool,
+        /// Mount read only: every write is refused, control files included
+        #[arg(long)]
+        read_only: bool,
+        /// Scan the whole library at mount with this many parallel fetches, 0 to
+        /// load collections when first listed
+        #[arg(long, default_value_t = 0)]
+        scan_jobs: usize,
+        /// Documents and collections fetched by each remote command of the scan
+        #[arg(long, default_value_t = 64)]
+        scan_batch_size: usize,
+        /// Fetch the metadata of the whole library in a single command when the
+        /// scan starts, even without --scan-jobs
+        #[arg(long)]
+        bulk_load: bool,
+        /// Report the json fields of metadata and content files that are not
+        /// parsed in /.control/schema, for developers following firmware changes
+        #[arg(long)]
+        strict_schema: bool,
+        /// SSH channels fetching the entries of a collection being listed at
+        /// once, 0 to fetch them one by one
+        #[arg(long, default_value_t = 4)]
+        listing_channels: usize,
+        /// Background jobs (such as the scan fetches) running at once
+        #[arg(long, default_value_t = 4)]
+        background_jobs: usize,
+        /// Bandwidth used by background jobs, in KiB/s [default: unlimited]
+        #[arg(long, value_name = "KIB")]
+        background_bandwidth: Option<u64>,
+        /// Filesystem requests per second above which background jobs wait, 0 for
+        /// never. Jobs are paused and resumed by writing pause or resume to
+        /// /.control/jobs
+        #[arg(long, default_value_t = 200)]
+        busy_requests: u32,
+        /// Creations, moves and deletions sent to the tablet per minute, beyond
+        /// bursts of --write-burst, 0 for no limit. Changes over the rate fail
+        /// with EAGAIN
+        #[arg(long, value_name = "CHANGES", default_value_t = 120)]
+        write_rate: u32,
+        /// Changes made at once above --write-rate
+        #[arg(long, value_name = "CHANGES", default_value_t = 60)]
+        write_burst: u32,
+        /// Changes refused in a row after which the mount turns read only until
+        /// remounted, 0 for never
+        #[arg(long, value_name = "CHANGES", default_value_t = 500)]
+        write_stop: u32,
+        /// Rhai script defining the folders of /.views with `view(name, filter, rename)`
+        /// calls (needs the scripting feature)
+        #[arg(long, value_name = "FILE")]
+        views: Option<std::path::PathBuf>,
+        /// Mount several tablets as <NAME> folders of the mount point, as NAME=ADDRESS
+        /// (repeatable, credentials and port are shared)
+        #[arg(long = "device")]
+        devices: Vec<String>,
+        /// Mount over a non empty mount point, or a device another rmkmount already mounts
+        #[arg(long)]
+        force: bool,
+        /// Unmount the FUSE file system already mounted on the mount point first
+        #[arg(long)]
+        remount: bool,
+        /// Label of the mount in file managers
+        #[arg(long, value_name = "NAME", default_value = "reMarkable")]
+        volume_name: String,
+        /// Bookmark the mount point in GNOME Files while mounted
+        #[arg(long)]
+        bookmark: bool,
     },
     /// Unmount remarkable tablet documents if previously mounted
     Umount {},
+    /// Export a document as an .rmdoc bundle
+    ExportRmdoc {
+        /// Visible path of the document on the tablet (e.g. "Work/Spec")
+        document: String,
+        /// Output bundle file, defaults to <document name>.rmdoc
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Upload an .rmdoc bundle to the tablet
+    ImportRmdoc {
+        /// .rmdoc bundle to upload
+        file: String,
+        /// Visible path of the destination collection, defaults to root
+        #[arg(long)]
+        into: Option<String>,
+    },
+    /// Download documents from the tablet, resuming interrupted transfers
+    Pull {
+        /// Visible paths of the documents on the tablet (e.g. "Work/Spec")
+        documents: Vec<String>,
+        /// Local destination folder
+        #[arg(short, long, default_value = ".")]
+        to: String,
+        /// Number of parallel transfers
+        #[arg(short, long, default_value_t = 1)]
+        jobs: usize,
+        /// Export only these pages (e.g. 12-18) as a standalone pdf
+        #[arg(long, value_name = "RANGE")]
+        pages: Option<excerpt::PageRange>,
+        /// Draw the annotations over the exported pages
+        #[arg(long, requires = "pages")]
+        annotated: bool,
+    },
+    /// Upload PDF/EPUB files to the tablet, resuming interrupted transfers
+    Push {
+        /// Local PDF or EPUB files, folders are mapped to collections
+        files: Vec<String>,
+        /// Visible path of the destination collection, defaults to root
+        #[arg(long)]
+        into: Option<String>,
+        /// Number of parallel transfers
+        #[arg(short, long, default_value_t = 1)]
+        jobs: usize,
+    },
+    /// Download a document into the local cache and open it with the desktop viewer
+    Open {
+        /// Visible path of the document on the tablet (e.g. "Work/Spec")
+        document: String,
+    },
+    /// Check the tablet storage for broken or orphaned documents
+    Fsck {
+        /// Move orphaned files and folders out of the document root, into the
+        /// tablet folder remarkable/quarantine
+        #[arg(long)]
+        quarantine: bool,
+    },
+    /// Forward a tablet port to localhost over the ssh connection
+    Forward {
+        /// Forward the tablet web interface (10.11.99.1:80, normally only reachable over USB)
+        #[arg(long, conflicts_with = "remote")]
+        web: bool,
+        /// HOST:PORT to reach from the tablet
+        #[arg(long, required_unless_present = "web")]
+        remote: Option<String>,
+        /// Local port to listen on (127.0.0.1 only)
+        #[arg(long, default_value_t = 8080)]
+        local_port: u16,
+    },
+    /// Stream document changes (added, modified, moved, trashed, removed) as they happen
+    Watch {
+        /// One JSON object per line instead of human readable lines
+        #[arg(long)]
+        json: bool,
+        /// Seconds between two checks of the tablet
+        #[arg(long, default_value_t = 10)]
+        interval: u64,
+    },
+    /// Archive new and modified PDF documents into paperless-ngx as they change,
+    /// or mirror PDF/EPUB documents into a folder calibre can import
+    #[command(group(clap::ArgGroup::new("target").required(true).args(["paperless", "calibre"])))]
+    Export {
+        /// Base url of the paperless-ngx instance (e.g. https://host:8000)
+        #[arg(long, value_name = "URL", requires = "token")]
+        paperless: Option<String>,
+        /// paperless api token
+        #[arg(long, requires = "paperless")]
+        token: Option<String>,
+        /// Local folder receiving one <title> folder with a metadata.opf per
+        /// document, to add to calibre with "Add books from folders"
+        #[arg(long, value_name = "DIR")]
+        calibre: Option<std::path::PathBuf>,
+        /// Tablet tag archived as a paperless tag, as NAME=ID (repeatable)
+        #[arg(long = "tag", value_name = "NAME=ID")]
+        tags: Vec<String>,
+        /// Tablet tag setting the paperless correspondent, as NAME=ID (repeatable)
+        #[arg(long = "correspondent", value_name = "NAME=ID")]
+        correspondents: Vec<String>,
+        /// Also archive the documents already on the tablet (paperless)
+        #[arg(long)]
+        all: bool,
+        /// Seconds between two checks of the tablet
+        #[arg(long, default_value_t = 60)]
+        interval: u64,
+    },
+    /// Email a document through an SMTP relay, like the tablet "send by email"
+    /// without the reMarkable cloud. The relay password is read from
+    /// RMKMOUNT_SMTP_PASSWORD
+    Send {
+        /// Visible path of the document on the tablet (e.g. "Work/Spec.pdf")
+        document: String,
+        /// Recipient address (repeatable)
+        #[arg(long, required = true)]
+        to: Vec<String>,
+        /// Sender address
+        #[arg(long, default_value = "rmkmount@localhost")]
+        from: String,
+        /// SMTP relay as HOST[:PORT]
+        #[arg(long, default_value = "localhost:25")]
+        smtp: String,
+        /// User name for the relay, when it requires authentication
+        #[arg(long)]
+        smtp_user: Option<String>,
+        /// Security of the connection to the relay. STARTTLS is only optional
+        /// for a relay on this computer, and credentials are never sent in
+        /// clear text to another one
+        #[arg(long, value_enum, default_value_t = mail::SmtpSecurity::Starttls)]
+        smtp_security: mail::SmtpSecurity,
+    },
+    /// Write recent logs, with hosts and user names redacted, into a shareable file
+    DebugBundle {
+        /// Output file, defaults to rmkmount-debug-<timestamp>.txt
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Number of most recent log lines to include
+        #[arg(long, default_value_t = 2000)]
+        lines: usize,
+    },
+    /// Resume pending or failed pulls and pushes
+    Resume {
+        /// Number of parallel transfers
+        #[arg(short, long, default_value_t = 1)]
+        jobs: usize,
+    },
+    /// Manage the local cache of documents
+    Cache {
+        #[command(subcommand)]
+        action: CacheCommand,
+    },
+    /// Inspect the tablet trash
+    Trash {
+        #[command(subcommand)]
+        action: TrashCommand,
+    },
+    /// Set the tablet clock to this computer's time
+    SetClock {},
+    /// List a collection of the tablet
+    Ls {
+        /// Visible path of the collection (e.g. "Work"), defaults to root
+        #[arg(default_value = "")]
+        path: String,
+        /// Show the storage used by each item and everything below it, largest first
+        #[arg(long)]
+        du: bool,
+    },
+    /// Run a command on the tablet with the configured credentials (rmkmount ssh --
+    /// df -h). Commands that may change the tablet are confirmed first
+    Ssh {
+        /// Run commands that may change the tablet without asking
+        #[arg(short, long)]
+        yes: bool,
+        /// Command and its arguments, given after --
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Show the journal of xochitl, the tablet interface, e.g. to see why an
+    /// uploaded document does not appear
+    Logs {
+        /// Keep streaming new entries until interrupted
+        #[arg(short, long)]
+        follow: bool,
+        /// Number of most recent entries shown first
+        #[arg(short = 'n', long, default_value_t = 100)]
+        lines: usize,
+        /// Systemd unit whose journal is shown
+        #[arg(long, default_value = "xochitl")]
+        unit: String,
+    },
+    /// Find the tablets reachable from this computer: over USB (10.11.99.1) and
+    /// on the local network from their mDNS and SSDP announcements, with whether
+    /// ssh answers. Their address can then be given to --address
+    Discover {
+        /// Seconds waited for each probe and for the announcements
+        #[arg(long, default_value_t = 2)]
+        timeout: u64,
+    },
+    /// Show the model, firmware, battery level and storage of the tablet
+    Info {
+        /// One JSON object instead of human readable lines
+        #[arg(long)]
+        json: bool,
+    },
+    /// Copy the documents of a collection into a folder as they are added or
+    /// changed, e.g. to publish class notes on a website. PDF and EPUB documents
+    /// are copied as is, notebooks are exported as .rmdoc bundles for the hook
+    /// to render
+    Publish {
+        /// Local folder receiving the documents, collection folders kept
+        #[arg(long, value_name = "DIR")]
+        to: std::path::PathBuf,
+        /// Visible path of the published collection, defaults to the whole library
+        #[arg(long, default_value = "")]
+        collection: String,
+        /// Shell command run after each document is written, given the file,
+        /// document path and uid in RMKMOUNT_FILE, RMKMOUNT_DOCUMENT and RMKMOUNT_UID
+        #[arg(long)]
+        hook: Option<String>,
+        /// Seconds between two checks of the tablet
+        #[arg(long, default_value_t = 60)]
+        interval: u64,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommand {
+    /// Remove cached documents and report the disk space reclaimed
+    #[command(group(clap::ArgGroup::new("selection").required(true).args(["document", "all", "older_than"])))]
+    Purge {
+        /// Uid of the document to remove
+        #[arg(long, value_name = "UUID")]
+        document: Option<String>,
+        /// Remove everything
+        #[arg(long)]
+        all: bool,
+        /// Remove documents cached longer ago than this age (e.g. 30d, 12h)
+        #[arg(long, value_name = "AGE", value_parser = cache::parse_age)]
+        older_than: Option<std::time::Duration>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum DevicesCommand {
+    /// List trusted tablets with their model, serial number and host key fingerprint
+    List {},
+    /// Forget the tablet at ADDRESS, whatever host key it presents next is trusted
+    Forget {
+        #[arg(value_name = "ADDRESS")]
+        address: String,
+    },
+    /// Connect to the tablet at --address and trust the host key it presents now,
+    /// after it changed
+    Trust {},
+}
+
+#[derive(Subcommand, Debug)]
+enum TrashCommand {
+    /// List trashed documents and collections with the time since their deletion
+    List {
+        /// Only list items trashed longer ago than this age (e.g. 90d)
+        #[arg(long, value_name = "AGE", value_parser = cache::parse_age)]
+        older_than: Option<std::time::Duration>,
+    },
 }
 
 // TODO handle password via ssh hosts ?
-// TODO handle Rk root path
-const RK_ROOTPATH: &str = "/home/root/.local/share/remarkable/xochitl/";
+/// where fsck moves orphaned files, next to the xochitl folder so that it ignores them
+const RK_QUARANTINE: &str = "/home/root/.local/share/remarkable/quarantine/";
+/// web interface of the tablet, as reached from the tablet itself
+const WEB_UI_ADDRESS: &str = "10.11.99.1:80";
+
+/// parses an octal file mode such as 444 or 0644
+fn parse_octal_mode(mode: &str) -> Result<u16, String> {
+    u16::from_str_radix(mode, 8)
+        .ok()
+        .filter(|m| *m <= 0o7777)
+        .ok_or(format!("{mode} is not an octal file mode"))
+}
+
+/// environment variable holding the ssh password
+const PASSWORD_VAR: &str = "RMK_PASSWORD";
+
+/// Password given by --password, --password-file or RMK_PASSWORD, in that order.
+/// None leaves it to the keyring or the terminal
+fn given_password(args: &Args) -> Option<String> {
+    if let Some(password) = &args.password {
+        return Some(password.clone());
+    }
+    if let Some(file) = &args.password_file {
+        match std::fs::read_to_string(file) {
+            Ok(content) => return Some(content.lines().next().unwrap_or_default().to_owned()),
+            Err(e) => error!("Unable to read the password from {}: {e}", file.display()),
+        }
+    }
+    std::env::var(PASSWORD_VAR).ok()
+}
+
+/// Builder preset with the connection settings given on the command line
+fn rkfs_builder(args: &Args) -> sftp_rkfs::RemarkableFsBuilder {
+    let builder = sftp_rkfs::RemarkableFsBuilder::new()
+        .host(&args.address)
+        .ssh_config(true)
+        .auth_provider(Box::new(trust::PromptingAuth {
+            address: args.address.clone(),
+            password: given_password(args),
+        }))
+        .use_agent(args.agent)
+        .client_version(concat!("rmkmount ", env!("CARGO_PKG_VERSION")))
+        .keepalive_interval(std::time::Duration::from_secs(args.ssh_keepalive))
+        .connect_timeout(std::time::Duration::from_secs(args.connect_timeout))
+        .io_timeout(std::time::Duration::from_secs(args.io_timeout))
+        .retries(args.retries)
+        .socket_options(sftp_rkfs::SocketOptions {
+            nodelay: !args.nagle,
+            keepalive: args.tcp_keepalive.map(std::time::Duration::from_secs),
+            send_buffer: args.socket_buffer.map(|kib| kib * 1024),
+            recv_buffer: args.socket_buffer.map(|kib| kib * 1024),
+        });
+    let builder = match &args.identity {
+        Some(identity) => {
+            let builder = builder.identity_file(identity);
+            match std::env::var("RMKMOUNT_PASSPHRASE") {
+                Ok(passphrase) => builder.passphrase(passphrase),
+                Err(_) => builder,
+            }
+        }
+        None => builder,
+    };
+    let builder = match &args.document_root {
+        Some(root) => builder.document_root(root),
+        None => builder,
+    };
+    // given on the command line, they take precedence over the ssh config
+    let builder = match args.port {
+        Some(port) => builder.port(port),
+        None => builder,
+    };
+    let builder = match &args.username {
+        Some(user) => builder.user(user),
+        None => builder,
+    };
+    let builder = match &args.host_key {
+        Some(fingerprint) => builder.host_key_fingerprint(fingerprint),
+        None => builder,
+    };
+    match args.max_clock_skew {
+        Some(seconds) => builder.max_clock_skew(std::time::Duration::from_secs(seconds)),
+        None => builder,
+    }
+}
 
-fn mount_rkfs(mountpoint: &str, addr: &str, port: u16, user: &str, password: &str) {
-    info!("Mounting to {mountpoint} from {user}@{addr}");
-    let _rfs = sftp_rkfs::RemarkableFsBuilder::new()
+fn mount_rkfs(
+    builder: sftp_rkfs::RemarkableFsBuilder,
+    address: &str,
+    mountpoint: &str,
+    force: bool,
+) {
+    info!("Mounting to {mountpoint}");
+    let _lock = match mounts::lock_device(address, std::path::Path::new(mountpoint), force) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("{e}");
+            return;
+        }
+    };
+    let _rfs = builder
         .mountpoint(mountpoint)
-        .host(addr)
-        .port(port)
-        .user(user)
-        .password(password)
-        .document_root(RK_ROOTPATH)
+        .control_socket(_lock.socket())
         .build()
         .expect("Failed to build RemarkableFs structure");
+    if let Err(e) = trust::verify(address, &_rfs, false) {
+        error!("Unable to mount: {e}");
+        return;
+    }
+    if let Err(e) = _rfs.check_document_root() {
+        error!("Unable to mount: {e}");
+        document_root_hint(&e);
+        return;
+    }
+    check_firmware(address, &_rfs);
     _rfs.mount()
         .expect("Mounting RemarkableFs encountered an unexpected error");
 }
 
-fn main() {
-    simple_logger::init_with_level(log::Level::Trace).unwrap();
+/// Mounts one `<NAME>` folder per `NAME=ADDRESS` device under `mountpoint`,
+/// devices that cannot be reached are left out
+fn mount_devices(
+    builder: impl Fn() -> sftp_rkfs::RemarkableFsBuilder,
+    devices: &[String],
+    mountpoint: &str,
+    force: bool,
+) {
+    info!("Mounting {} devices to {mountpoint}", devices.len());
+    let mut multi = sftp_rkfs::fs::MultiDeviceFs::new(mountpoint.into());
+    let mut locks = vec![];
+    for device in devices {
+        let Some((name, address)) = device.split_once('=') else {
+            error!("invalid device {device}, expected NAME=ADDRESS");
+            continue;
+        };
+        let socket = match mounts::lock_device(address, std::path::Path::new(mountpoint), force) {
+            Ok(lock) => {
+                let socket = lock.socket().to_path_buf();
+                locks.push(lock);
+                socket
+            }
+            Err(e) => {
+                error!("device {name} left out: {e}");
+                continue;
+            }
+        };
+        let builder = builder().host(address).control_socket(socket);
+        match builder.connect().and_then(|rfs| {
+            check_trust(address, &rfs)?;
+            rfs.check_document_root().inspect_err(document_root_hint)?;
+            check_firmware(address, &rfs);
+            multi.add_device(name, rfs)
+        }) {
+            Ok(()) => info!("device {name} at {address} added"),
+            Err(e) => error!("device {name} at {address} left out: {e}"),
+        }
+    }
+    multi
+        .mount()
+        .expect("Mounting devices encountered an unexpected error");
+}
+
+/// Refuses the tablet at `address` when it does not present its trusted host key
+fn check_trust(
+    address: &str,
+    rfs: &sftp_rkfs::fs::RemarkableFs,
+) -> Result<(), sftp_rkfs::RemarkableError> {
+    trust::verify(address, rfs, false)
+        .map(|_| ())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::PermissionDenied, e).into())
+}
+
+/// Tells how to fix the document root when `e` is about it
+fn document_root_hint(e: &sftp_rkfs::RemarkableError) {
+    if let Some(FsError::DocumentRoot(..)) = e.fs_error() {
+        error!("give the folder of the documents with --document-root, or leave it out to have it detected on the tablet");
+    }
+}
+
+/// Compares the tablet firmware with the one recorded in its profile. After an
+/// update, local caches built from the former storage format are dropped and
+/// all metadata is parsed again so that schema problems show up right away.
+fn check_firmware(address: &str, rfs: &sftp_rkfs::fs::RemarkableFs) {
+    let firmware = match rfs.firmware_version() {
+        Ok(firmware) => firmware,
+        Err(e) => {
+            warn!("unable to read the firmware version of {address}: {e}");
+            return;
+        }
+    };
+    let mut profile = profile::DeviceProfile::load(address);
+    let Some(previous) = profile.update_firmware(&firmware) else {
+        profile.save_or_warn(address);
+        return;
+    };
+    warn!("tablet firmware changed from {previous} to {firmware}: dropping caches and rescanning");
+    let purged = DocumentCache::new(cache_dir()).purge(&PurgeFilter::All);
+    debug!("dropped {} cached documents", purged.removed);
+    match rfs.snapshot() {
+        Ok(snapshot) => info!(
+            "rescanned {} items after the firmware update",
+            snapshot.len()
+        ),
+        Err(e) => warn!("rescan after the firmware update failed: {e}"),
+    }
+    profile.save_or_warn(address);
+}
+
+/// Folder for rmkmount state (transfer queue, logs) : $XDG_STATE_HOME/rmkmount
+/// or ~/.local/state/rmkmount
+fn state_dir() -> std::path::PathBuf {
+    std::env::var_os("XDG_STATE_HOME")
+        .map(std::path::PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME")
+                .map(|h| std::path::PathBuf::from(h).join(".local").join("state"))
+        })
+        .unwrap_or_else(std::env::temp_dir)
+        .join("rmkmount")
+}
+
+/// Folder for rmkmount settings (trusted tablets) : $XDG_CONFIG_HOME/rmkmount
+/// or ~/.config/rmkmount
+fn config_dir() -> std::path::PathBuf {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(std::path::PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| std::path::PathBuf::from(h).join(".config")))
+        .unwrap_or_else(std::env::temp_dir)
+        .join("rmkmount")
+}
+
+/// Connects to the tablet at --address with the password given, or one read
+/// from the terminal, and saves it in the keyring once accepted
+#[cfg(feature = "keyring")]
+fn login(args: &Args) {
+    let password = match given_password(args) {
+        Some(password) => password,
+        None => match shell::read_password(&format!("Password of {}: ", args.address)) {
+            Ok(password) => password,
+            Err(e) => {
+                error!("Unable to read the password: {e}");
+                return;
+            }
+        },
+    };
+    // the agent would log in without trying the password
+    let connected = rkfs_builder(args)
+        .use_agent(false)
+        .auth_provider(Box::new(trust::PromptingAuth {
+            address: args.address.clone(),
+            password: Some(password.clone()),
+        }))
+        .connect();
+    if let Err(e) = connected {
+        error!("Unable to log in to {}: {e}", args.address);
+        return;
+    }
+    match keyring::save_password(&args.address, &password) {
+        Ok(()) => println!("password of {} saved in the keyring", args.address),
+        Err(e) => error!("Unable to save the password of {}: {e}", args.address),
+    }
+}
+
+/// Lists the trusted tablets
+fn devices_list() {
+    let now = std::time::SystemTime::now();
+    let records = trust::TrustRecord::list();
+    for record in &records {
+        println!(
+            "{:<16}  {:<16}  {:<18}  {}  first seen {}d ago",
+            record.address,
+            record.model,
+            record.device_id.as_deref().unwrap_or("-"),
+            record.fingerprint,
+            record.age(now).as_secs() / 86400
+        );
+    }
+    println!("{} trusted devices", records.len());
+}
+
+/// Prints the consistency report of the tablet storage, optionally moving
+/// orphaned files to the quarantine folder
+fn fsck(args: &Args, quarantine: bool) -> Result<(), sftp_rkfs::RemarkableError> {
+    let rfs = try_connect_rkfs(args)?;
+    let report = rfs.fsck()?;
+    print!("{report}");
+    if quarantine {
+        let dir = std::path::Path::new(RK_QUARANTINE);
+        for finding in report
+            .findings
+            .iter()
+            .filter(|f| f.category.is_quarantinable())
+        {
+            match rfs.quarantine(finding, dir) {
+                Ok(()) => println!("quarantined {} in {RK_QUARANTINE}", finding.uid),
+                Err(e) => error!("{} not quarantined: {e}", finding.uid),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Prints the trashed items older than `older_than`, oldest first
+fn trash_list(
+    args: &Args,
+    older_than: Option<std::time::Duration>,
+) -> Result<(), sftp_rkfs::RemarkableError> {
+    let mut rfs = try_connect_rkfs(args)?;
+    let now = std::time::SystemTime::now();
+    let items = rfs
+        .trashed_items()?
+        .into_iter()
+        .filter(|item| older_than.is_none_or(|age| item.age(now) > age))
+        .collect::<Vec<_>>();
+    for item in &items {
+        let kind = if item.is_document {
+            "document"
+        } else {
+            "collection"
+        };
+        println!(
+            "{:>5}d  {kind:<10}  {}  {}",
+            item.age(now).as_secs() / 86400,
+            item.uid,
+            item.name
+        );
+    }
+    println!("{} items in the trash", items.len());
+    Ok(())
+}
+
+/// Lists the collection at `path` from a snapshot of the metadata, with the
+/// storage used by each item when `du` is set
+fn ls(args: &Args, path: &str, du: bool) -> Result<(), sftp_rkfs::RemarkableError> {
+    let rfs = shared_builder(args).connect()?;
+    let snapshot = rfs.snapshot()?;
+    let uid = snapshot
+        .uid_at(path)
+        .ok_or_else(|| FsError::InvalidPath(format!("{path} not found")))?;
+    if snapshot.is_document(&uid) {
+        return Err(FsError::NotACollection(path.to_owned()).into());
+    }
+    let sizes = if du { Some(rfs.disk_usage()?) } else { None };
+    let mut items = snapshot.list(&uid, sizes.as_ref());
+    if du {
+        items.sort_by_key(|item| std::cmp::Reverse(item.bytes));
+    }
+    for item in &items {
+        let name = if item.is_document {
+            item.name.clone()
+        } else {
+            format!("{}/", item.name)
+        };
+        match item.bytes {
+            Some(bytes) => println!(
+                "{:>10}  {:>5} docs  {name}",
+                indicatif::HumanBytes(bytes).to_string(),
+                item.documents
+            ),
+            None => println!("{name}"),
+        }
+    }
+    if let Some(sizes) = &sizes {
+        let total = items.iter().filter_map(|item| item.bytes).sum::<u64>();
+        println!(
+            "{} in {} items, {} on the tablet",
+            indicatif::HumanBytes(total),
+            items.len(),
+            indicatif::HumanBytes(sizes.values().sum())
+        );
+    }
+    Ok(())
+}
+
+/// Sets the tablet clock to the host time, reporting how far off it was
+fn set_clock(args: &Args) -> Result<(), sftp_rkfs::RemarkableError> {
+    let rfs = rkfs_builder(args).connect()?;
+    let skew = rfs.clock_skew()?;
+    rfs.set_clock(std::time::SystemTime::now())?;
+    match skew {
+        0 => println!("Tablet clock set, it was already on time"),
+        skew if skew > 0 => println!("Tablet clock set, it was {skew}s ahead"),
+        skew => println!("Tablet clock set, it was {}s behind", -skew),
+    }
+    Ok(())
+}
+
+/// Runs `command` on the tablet, its output printed as is. Returns its exit
+/// status, None when it was not run
+fn ssh(args: &Args, command: &str, yes: bool) -> Result<Option<i32>, sftp_rkfs::RemarkableError> {
+    if !yes
+        && !shell::is_read_only(command)
+        && !shell::confirm(&format!("`{command}` may change the tablet, run it?"))
+    {
+        println!("Not run");
+        return Ok(None);
+    }
+    let rfs = rkfs_builder(args).connect()?;
+    let output = rfs.run_command(command)?;
+    print!("{}", output.stdout);
+    eprint!("{}", output.stderr);
+    debug!("`{command}` exited with {}", output.status);
+    Ok(Some(output.status))
+}
+
+/// Prints the journal of `unit` on the tablet, streaming new entries with `follow`
+fn logs(
+    args: &Args,
+    unit: &str,
+    lines: usize,
+    follow: bool,
+) -> Result<i32, sftp_rkfs::RemarkableError> {
+    let rfs = rkfs_builder(args).connect()?;
+    check_trust(&args.address, &rfs)?;
+    let unit = unit.replace('\'', r"'\''");
+    let mut command = format!("journalctl --no-pager -u '{unit}' -n {lines}");
+    if follow {
+        command.push_str(" -f");
+    }
+    rfs.stream_command(&command, &mut std::io::stdout())
+}
+
+/// Serves `remote` (HOST:PORT as reached from the tablet) on localhost:`local_port`
+/// until killed
+fn forward(args: &Args, remote: &str, local_port: u16) -> Result<(), sftp_rkfs::RemarkableError> {
+    let (host, port) = remote
+        .rsplit_once(':')
+        .and_then(|(h, p)| Some((h, p.parse::<u16>().ok()?)))
+        .ok_or_else(|| FsError::InvalidPath(format!("{remote}, expected HOST:PORT")))?;
+    let rfs = try_connect_rkfs(args)?;
+    let listener = std::net::TcpListener::bind(("127.0.0.1", local_port))
+        .with_context(|| format!("listening on port {local_port}"))?;
+    println!("Forwarding 127.0.0.1:{local_port} to {remote} on the tablet, Ctrl-C to stop");
+    rfs.forward_port(&listener, host, port)
+}
+
+/// Prints the summary of `rmkmount info`
+fn print_info(args: &Args, json: bool) -> Result<(), sftp_rkfs::RemarkableError> {
+    let info = try_connect_shared(args)?.tablet_info()?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
+    let unknown = || "unknown".to_owned();
+    let model = match (&info.model, info.generation) {
+        (Some(model), Some(generation)) => format!("{model} ({generation})"),
+        (model, _) => model.clone().unwrap_or_else(unknown),
+    };
+    println!("Model:    {model}");
+    println!(
+        "Firmware: {}",
+        info.firmware.clone().unwrap_or_else(unknown)
+    );
+    println!("Build:    {}", info.build.clone().unwrap_or_else(unknown));
+    match info.battery {
+        Some(battery) => println!("Battery:  {battery}%"),
+        None => println!("Battery:  unknown"),
+    }
+    match &info.storage {
+        Some(storage) => println!(
+            "Storage:  {} used of {}, {} free ({} on {})",
+            indicatif::HumanBytes(storage.used * 1024),
+            indicatif::HumanBytes(storage.total * 1024),
+            indicatif::HumanBytes(storage.available * 1024),
+            storage.filesystem,
+            storage.mount_point
+        ),
+        None => println!("Storage:  unknown"),
+    }
+    Ok(())
+}
+
+/// Prints document changes every `interval`, until killed. A failed check is
+/// logged and retried, changes are then reported against the last good snapshot.
+fn watch(
+    args: &Args,
+    json: bool,
+    interval: std::time::Duration,
+) -> Result<(), sftp_rkfs::RemarkableError> {
+    let rfs = try_connect_rkfs(args)?;
+    let mut previous = rfs.snapshot()?;
+    info!("watching {} items", previous.len());
+    loop {
+        std::thread::sleep(interval);
+        let current = match rfs.snapshot() {
+            Ok(current) => current,
+            Err(e) => {
+                warn!("check failed: {e}");
+                continue;
+            }
+        };
+        let mut out = std::io::stdout().lock();
+        for event in current.changes_since(&previous) {
+            let line = if json {
+                serde_json::to_string(&event)?
+            } else {
+                match &event.from {
+                    Some(from) => format!("{:?} {} -> {}", event.kind, from, event.path),
+                    None => format!("{:?} {}", event.kind, event.path),
+                }
+            };
+            writeln!(out, "{line}")?;
+        }
+        out.flush()?;
+        previous = current;
+    }
+}
+
+/// Archives documents added or modified on the tablet into paperless-ngx, until
+/// interrupted. With `all`, documents already on the tablet are archived first.
+fn export_paperless(
+    args: &Args,
+    client: &PaperlessClient,
+    mapping: &TagMapping,
+    all: bool,
+    interval: std::time::Duration,
+) -> Result<(), sftp_rkfs::RemarkableError> {
+    let mut rfs = try_connect_rkfs(args)?;
+    let mut previous = if all {
+        sftp_rkfs::fs::TreeSnapshot::default()
+    } else {
+        rfs.snapshot()?
+    };
+    info!(
+        "archiving {} changes into paperless",
+        if all { "all" } else { "new" }
+    );
+    // uploads that failed, tried again at the next check
+    let mut failed = vec![];
+    loop {
+        match rfs.snapshot() {
+            Ok(current) => {
+                let events = paperless::events_to_archive(
+                    std::mem::take(&mut failed),
+                    current.changes_since(&previous),
+                );
+                for event in events {
+                    if let Err(e) = upload_to_paperless(&mut rfs, client, mapping, &event) {
+                        warn!(
+                            "{} not archived, tried again at the next check: {e}",
+                            event.path
+                        );
+                        failed.push(event);
+                    }
+                }
+                previous = current;
+            }
+            Err(e) => warn!("check failed: {e}"),
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+/// Publishes the documents of `collection` into `target` as they are added,
+/// modified or moved, until interrupted. Documents already published and up to
+/// date are left untouched, so a restart only writes what changed meanwhile.
+/// Copies of documents moved out of the collection or deleted are kept.
+fn publish(
+    args: &Args,
+    collection: &str,
+    target: &std::path::Path,
+    hook: Option<&str>,
+    interval: std::time::Duration,
+) -> Result<(), sftp_rkfs::RemarkableError> {
+    let mut rfs = try_connect_rkfs(args)?;
+    let collection = collection.trim_matches('/');
+    let ino = rfs.resolve_path(collection)?;
+    if rfs.is_document(ino) {
+        return Err(FsError::NotACollection(collection.to_owned()).into());
+    }
+    let mut previous = sftp_rkfs::fs::TreeSnapshot::default();
+    info!("publishing {collection:?} into {}", target.display());
+    loop {
+        match rfs.snapshot() {
+            Ok(current) => {
+                for event in current.changes_since(&previous) {
+                    if !matches!(
+                        event.kind,
+                        ChangeKind::Added | ChangeKind::Modified | ChangeKind::Moved
+                    ) {
+                        continue;
+                    }
+                    let Some(dir) = publish::relative_dir(collection, &event.path) else {
+                        continue;
+                    };
+                    if let Err(e) = publish_document(&mut rfs, &event, target, dir, hook) {
+                        warn!("{} not published: {e}", event.path);
+                    }
+                }
+                previous = current;
+            }
+            Err(e) => warn!("check failed: {e}"),
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+/// Writes the document of `event` into folder `dir` of `target`, then runs
+/// `hook` on it. The file is written aside and renamed, so that readers of the
+/// target folder never see it partially written.
+fn publish_document(
+    rfs: &mut sftp_rkfs::fs::RemarkableFs,
+    event: &ChangeEvent,
+    target: &std::path::Path,
+    dir: &str,
+    hook: Option<&str>,
+) -> Result<(), sftp_rkfs::RemarkableError> {
+    let (ino, _) = resolve_event(rfs, event)?;
+    let name = rfs.visible_name(ino).unwrap_or_default();
+    let has_payload = name.extension().is_some_and(|e| e == "pdf" || e == "epub");
+    let file_name = if has_payload {
+        name.to_string_lossy().into_owned()
+    } else {
+        let title = event.path.rsplit('/').next().unwrap_or_default();
+        format!("{title}.rmdoc")
+    };
+    let output = publish::output_path(target, dir, &file_name);
+    if std::fs::metadata(&output).is_ok_and(|m| m.modified().ok() >= rfs.modified(ino)) {
+        debug!("{} already published", event.path);
+        return Ok(());
+    }
+    if let Some(parent) = output.parent() {
+        std::fs::create_dir_all(parent).with_context(|| format!("creating {parent:?}"))?;
+    }
+    let mut staged = output.clone().into_os_string();
+    staged.push(".part");
+    let staged = std::path::PathBuf::from(staged);
+    if has_payload {
+        std::fs::write(&staged, read_document(rfs, ino)?)
+            .with_context(|| format!("writing {staged:?}"))?;
+    } else {
+        rfs.export_rmdoc(&event.path, &staged)?;
+    }
+    std::fs::rename(&staged, &output).with_context(|| format!("writing {output:?}"))?;
+    info!("published {} to {}", event.path, output.display());
+    if let Some(hook) = hook {
+        let status = publish::run_hook(hook, &output, &event.path, &event.uid)
+            .with_context(|| format!("running the publish hook on {output:?}"))?;
+        if !status.success() {
+            warn!("publish hook failed on {} ({status})", output.display());
+        }
+    }
+    Ok(())
+}
+
+/// Reads the whole payload of the document at `ino`
+fn read_document(
+    rfs: &sftp_rkfs::fs::RemarkableFs,
+    ino: usize,
+) -> Result<Vec<u8>, sftp_rkfs::RemarkableError> {
+    const CHUNK_SIZE: u32 = 1024 * 1024;
+    let size = rfs.size(ino).unwrap_or(0);
+    let mut data = Vec::with_capacity(size as usize);
+    while (data.len() as u64) < size {
+        let chunk = rfs.read(ino, data.len() as u64, CHUNK_SIZE)?;
+        if chunk.is_empty() {
+            break;
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// Emails the pdf or epub file of `document`, notebooks cannot be rendered yet
+fn send_document(
+    args: &Args,
+    document: &str,
+    smtp: &mail::SmtpClient,
+    from: &str,
+    to: &[String],
+) -> Result<(), sftp_rkfs::RemarkableError> {
+    let mut rfs = try_connect_rkfs(args)?;
+    let ino = rfs.resolve_path(document)?;
+    if !rfs.is_document(ino) {
+        return Err(FsError::NotADocument(document.to_owned()).into());
+    }
+    if rfs.size(ino).unwrap_or(0) == 0 {
+        return Err(
+            FsError::Unsupported(format!("{document} has no pdf or epub file to send")).into(),
+        );
+    }
+    let data = read_document(&rfs, ino)?;
+    let name = rfs.visible_name(ino).unwrap_or_default();
+    let title = name.file_stem().unwrap_or_default().to_string_lossy();
+    smtp.send(&mail::Mail {
+        from,
+        to,
+        subject: &title,
+        text: &format!("{title}, sent from the reMarkable tablet by rmkmount"),
+        file_name: &name.to_string_lossy(),
+        attachment: &data,
+    })
+    .with_context(|| format!("sending {document} through {}", smtp.server))
+}
+
+/// Inode and collection path of the document of `event`, listing its collection
+fn resolve_event<'e>(
+    rfs: &mut sftp_rkfs::fs::RemarkableFs,
+    event: &'e ChangeEvent,
+) -> Result<(usize, &'e str), sftp_rkfs::RemarkableError> {
+    let collection = event.path.rsplit_once('/').map_or("", |(c, _)| c);
+    rfs.resolve_path(collection)?;
+    let ino = rfs
+        .resolve_uid(&event.uid)
+        .ok_or_else(|| FsError::InvalidPath(event.path.clone()))?;
+    Ok((ino, collection))
+}
+
+/// Uploads the pdf of the document of `event`, other documents are skipped
+fn upload_to_paperless(
+    rfs: &mut sftp_rkfs::fs::RemarkableFs,
+    client: &PaperlessClient,
+    mapping: &TagMapping,
+    event: &ChangeEvent,
+) -> Result<(), sftp_rkfs::RemarkableError> {
+    let (ino, _) = resolve_event(rfs, event)?;
+    let name = rfs.visible_name(ino).unwrap_or_default();
+    if name.extension().is_none_or(|e| e != "pdf") {
+        info!("{} skipped: only pdf documents are archived", event.path);
+        return Ok(());
+    }
+    let title = &name.file_stem().unwrap_or_default().to_string_lossy();
+    let data = read_document(rfs, ino)?;
+    let (tags, correspondent) = mapping.apply(&rfs.tags(ino));
+    client
+        .post_document(&Upload {
+            file_name: &name.to_string_lossy(),
+            title,
+            data: &data,
+            tags,
+            correspondent,
+        })
+        .with_context(|| format!("archiving {}", event.path))?;
+    Ok(())
+}
+
+/// Mirrors every PDF and EPUB document outside the trash into `library`, one
+/// calibre book folder each. Books already up to date are left untouched.
+fn export_calibre(
+    args: &Args,
+    library: &std::path::Path,
+) -> Result<(), sftp_rkfs::RemarkableError> {
+    let mut rfs = try_connect_rkfs(args)?;
+    let events = rfs
+        .snapshot()?
+        .changes_since(&sftp_rkfs::fs::TreeSnapshot::default());
+    let (mut written, mut current) = (0, 0);
+    for event in events.iter().filter(|e| !e.path.starts_with(".Trash/")) {
+        let (ino, collection) = match resolve_event(&mut rfs, event) {
+            Ok(found) => found,
+            Err(e) => {
+                warn!("{} not exported: {e}", event.path);
+                continue;
+            }
+        };
+        let name = rfs.visible_name(ino).unwrap_or_default();
+        if !name.extension().is_some_and(|e| e == "pdf" || e == "epub") {
+            debug!("{} skipped: not a pdf or epub document", event.path);
+            continue;
+        }
+        let title = &name.file_stem().unwrap_or_default().to_string_lossy();
+        let dir = calibre::book_dir(library, title, &event.uid);
+        let book = dir.join(&name);
+        let size = rfs.size(ino).unwrap_or(0);
+        if std::fs::metadata(&book)
+            .is_ok_and(|m| m.len() == size && m.modified().ok() >= rfs.modified(ino))
+        {
+            current += 1;
+            continue;
+        }
+        let source = format!("{collection}/{}", name.to_string_lossy());
+        TransferQueue::pull_document(&mut rfs, &source, &dir, &ProgressBar::new(size))?;
+        let tags = rfs.tags(ino);
+        let metadata = calibre::BookMetadata {
+            uid: &event.uid,
+            title,
+            tags: &tags,
+            created: rfs.created(ino),
+            modified: rfs.modified(ino),
+        };
+        std::fs::write(dir.join(calibre::OPF_FILE), metadata.to_opf())?;
+        info!("exported {} to {}", event.path, dir.display());
+        written += 1;
+    }
+    println!("{written} books exported, {current} already up to date");
+    Ok(())
+}
+
+/// `$XDG_RUNTIME_DIR/rmkmount`, private to the user and usually in memory
+fn runtime_dir() -> std::path::PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("rmkmount")
+}
+
+/// Folder for cached documents : $XDG_CACHE_HOME/rmkmount or ~/.cache/rmkmount
+fn cache_dir() -> std::path::PathBuf {
+    std::env::var_os("XDG_CACHE_HOME")
+        .map(std::path::PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| std::path::PathBuf::from(h).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir)
+        .join("rmkmount")
+}
+
+/// Makes sure the cached copy of `document` matches the tablet, then hands it to
+/// the desktop viewer. Returns the local file opened.
+fn open_document(
+    args: &Args,
+    document: &str,
+) -> Result<std::path::PathBuf, sftp_rkfs::RemarkableError> {
+    let mut rfs = try_connect_rkfs(args)?;
+    let ino = rfs.resolve_path(document)?;
+    if !rfs.is_document(ino) {
+        return Err(FsError::NotADocument(document.to_owned()).into());
+    }
+    let size = rfs.size(ino).unwrap_or(0);
+    if size == 0 {
+        return Err(FsError::Unsupported(format!(
+            "{document} has no pdf or epub file, use export-rmdoc"
+        ))
+        .into());
+    }
+    let uid = rfs.unique_id(ino).unwrap_or_default();
+    let dir = DocumentCache::new(cache_dir()).open_dir(&uid);
+    let name = rfs.visible_name(ino).unwrap_or_default();
+    let target = match Packing::enabled() {
+        Some(packing) => {
+            // the cache only holds the packed copy, the viewer gets a plain
+            // one in the private runtime folder
+            let packing = packing.for_size(size);
+            let packed = dir.join(packing.file_name(&name.to_string_lossy()));
+            std::fs::create_dir_all(runtime_dir())?;
+            std::fs::set_permissions(
+                runtime_dir(),
+                std::os::unix::fs::PermissionsExt::from_mode(0o700),
+            )?;
+            let plain_dir = runtime_dir().join("open").join(&uid);
+            let target = plain_dir.join(&name);
+            let work = plain_dir.join(format!("{}.part", name.display()));
+            let current =
+                std::fs::metadata(&packed).is_ok_and(|m| m.modified().ok() >= rfs.modified(ino));
+            let _ = std::fs::remove_dir_all(&plain_dir);
+            if current {
+                debug!("{} is up to date", packed.display());
+                std::fs::create_dir_all(&plain_dir)?;
+                packing.unpack(&packed, &target, &work)?;
+            } else {
+                let _ = std::fs::remove_dir_all(&dir);
+                TransferQueue::pull_document(
+                    &mut rfs,
+                    document,
+                    &plain_dir,
+                    &ProgressBar::new(size),
+                )?;
+                std::fs::create_dir_all(&dir)?;
+                packing.pack(&target, &packed, &work)?;
+            }
+            target
+        }
+        None => {
+            let target = dir.join(&name);
+            let current = std::fs::metadata(&target)
+                .is_ok_and(|m| m.len() == size && m.modified().ok() >= rfs.modified(ino));
+            if current {
+                debug!("{} is up to date", target.display());
+            } else {
+                // drop any stale copy, including a partial download of a previous version
+                let _ = std::fs::remove_dir_all(&dir);
+                TransferQueue::pull_document(&mut rfs, document, &dir, &ProgressBar::new(size))?;
+            }
+            target
+        }
+    };
+    let viewer = if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    };
+    std::process::Command::new(viewer)
+        .arg(&target)
+        .spawn()
+        .with_context(|| format!("running {viewer}"))?;
+    Ok(target)
+}
+
+/// Writes the last `lines` log lines with connection details redacted into `output`
+fn write_debug_bundle(args: &Args, output: &str, lines: usize) -> std::io::Result<()> {
+    let log = std::fs::read_to_string(CliLogger::log_path()).unwrap_or_default();
+    let log_lines = log.lines().collect::<Vec<_>>();
+    let recent = log_lines[log_lines.len().saturating_sub(lines)..].join("\n");
+    let user = args.username.as_deref().unwrap_or_default();
+    let password = given_password(args).unwrap_or_default();
+    let host = args
+        .host
+        .as_ref()
+        .map(|h| h.to_string())
+        .unwrap_or_default();
+    let secrets = [args.address.as_str(), user, &host, &password];
+    let bundle = format!(
+        "rmkmount {} debug bundle\nos: {} {}\n--- last {} log lines ---\n{}\n",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        lines,
+        logging::redact(&recent, &secrets)
+    );
+    std::fs::write(output, bundle)
+}
+
+/// Builder using the connection of a running mount of the device when there
+/// is one, for commands that only read from the tablet
+fn shared_builder(args: &Args) -> sftp_rkfs::RemarkableFsBuilder {
+    match mounts::shared_socket(&args.address) {
+        Some(socket) => rkfs_builder(args).share_connection(socket),
+        None => rkfs_builder(args),
+    }
+}
+
+/// Connects to the tablet for one-shot commands that do not mount the filesystem
+fn try_connect_rkfs(
+    args: &Args,
+) -> Result<sftp_rkfs::fs::RemarkableFs, sftp_rkfs::RemarkableError> {
+    connect_with(args, rkfs_builder(args))
+}
+
+/// like `try_connect_rkfs`, through a running mount of the device if any
+fn try_connect_shared(
+    args: &Args,
+) -> Result<sftp_rkfs::fs::RemarkableFs, sftp_rkfs::RemarkableError> {
+    connect_with(args, shared_builder(args))
+}
+
+fn connect_with(
+    args: &Args,
+    builder: sftp_rkfs::RemarkableFsBuilder,
+) -> Result<sftp_rkfs::fs::RemarkableFs, sftp_rkfs::RemarkableError> {
+    info!("Connecting to {}", args.address);
+    let mut rfs = builder.connect()?;
+    check_trust(&args.address, &rfs)?;
+    rfs.init_root().inspect_err(document_root_hint)?;
+    check_firmware(&args.address, &rfs);
+    Ok(rfs)
+}
+
+fn connect_rkfs(args: &Args) -> sftp_rkfs::fs::RemarkableFs {
+    try_connect_rkfs(args).expect("Failed to connect to the remarkable tablet")
+}
+
+/// Runs the persisted transfer queue, with newly requested `items` appended
+fn run_transfers(args: &Args, items: Vec<TransferItem>, jobs: usize) {
+    let mut queue = TransferQueue::load();
+    for item in items {
+        queue.push(item);
+    }
+    if queue.remaining() == 0 {
+        println!("Nothing to transfer");
+        return;
+    }
+    // pulls only read, they can go through a running mount
+    let failed = if queue.pulls_only() {
+        queue.run(jobs, || try_connect_shared(args))
+    } else {
+        queue.run(jobs, || try_connect_rkfs(args))
+    };
+    if failed > 0 {
+        println!("{failed} transfer(s) failed, run `rmkmount resume` to retry");
+    }
+}
+
+/// Fills the options not given on the command line from `profile`
+fn apply_profile(
+    args: &mut Args,
+    matches: &clap::ArgMatches,
+    profile: &config::Profile,
+) -> Result<(), String> {
+    let given = |matches: &clap::ArgMatches, id: &str| {
+        matches.value_source(id) == Some(clap::parser::ValueSource::CommandLine)
+    };
+    if let (Some(address), false) = (&profile.address, given(matches, "address")) {
+        args.address = address.clone();
+    }
+    if let (Some(root), false) = (&profile.document_root, given(matches, "document_root")) {
+        args.document_root = Some(root.clone());
+    }
+    args.port = args.port.or(profile.port);
+    args.username = args.username.take().or_else(|| profile.user.clone());
+    args.identity = args.identity.take().or_else(|| profile.identity.clone());
+    let (
+        Commands::Mount {
+            mountpoint,
+            file_mode,
+            dir_mode,
+            memory_budget,
+            strict_names,
+            raw_pages,
+            write_back,
+            read_only,
+            scan_jobs,
+            volume_name,
+            ..
+        },
+        Some(("mount", matches)),
+    ) = (&mut args.command, matches.subcommand())
+    else {
+        return Ok(());
+    };
+    *mountpoint = mountpoint.take().or_else(|| profile.mountpoint.clone());
+    if let (Some(mode), false) = (&profile.file_mode, given(matches, "file_mode")) {
+        *file_mode = parse_octal_mode(mode)?;
+    }
+    if let (Some(mode), false) = (&profile.dir_mode, given(matches, "dir_mode")) {
+        *dir_mode = parse_octal_mode(mode)?;
+    }
+    if let (Some(budget), false) = (profile.memory_budget, given(matches, "memory_budget")) {
+        *memory_budget = budget;
+    }
+    if let (Some(jobs), false) = (profile.scan_jobs, given(matches, "scan_jobs")) {
+        *scan_jobs = jobs;
+    }
+    if let (Some(name), false) = (&profile.volume_name, given(matches, "volume_name")) {
+        *volume_name = name.clone();
+    }
+    // flags can only be turned on from the command line
+    *strict_names |= profile.strict_names.unwrap_or_default();
+    *raw_pages |= profile.raw_pages.unwrap_or_default();
+    *write_back |= profile.write_back.unwrap_or_default();
+    *read_only |= profile.read_only.unwrap_or_default();
+    Ok(())
+}
+
+/// Replaces --address, --username and --port with the parts of --host, when
+/// given. Values given on the command line with both styles must agree
+fn apply_host(args: &mut Args, matches: &clap::ArgMatches) -> Result<(), String> {
+    let Some(host) = args.host.clone() else {
+        return Ok(());
+    };
+    let given = |id: &str| matches.value_source(id) == Some(clap::parser::ValueSource::CommandLine);
+    if given("address") && args.address != host.address {
+        return Err(format!(
+            "--host {host} conflicts with --address {}",
+            args.address
+        ));
+    }
+    args.address = host.address.clone();
+    if let Some(user) = host.user.clone() {
+        match &args.username {
+            Some(username) if given("username") && *username != user => {
+                return Err(format!(
+                    "--host {host} conflicts with --username {username}"
+                ));
+            }
+            _ => args.username = Some(user),
+        }
+    }
+    if let Some(port) = host.port {
+        match args.port {
+            Some(given_port) if given("port") && given_port != port => {
+                return Err(format!("--host {host} conflicts with --port {given_port}"));
+            }
+            _ => args.port = Some(port),
+        }
+    }
+    Ok(())
+}
 
-    let args = Args::parse();
+fn main() {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    CliLogger::init(args.verbose, args.quiet);
+    let profile = config::Config::load()
+        .and_then(|config| Ok(config.profile(args.profile.as_deref())?.cloned()));
+    match profile {
+        Ok(Some(profile)) => {
+            if let Err(e) = apply_profile(&mut args, &matches, &profile) {
+                error!(
+                    "Invalid profile in {}: {e}",
+                    config::Config::path().display()
+                );
+                return;
+            }
+        }
+        Ok(None) => {}
+        Err(e) => {
+            error!("Unable to use {}: {e}", config::Config::path().display());
+            return;
+        }
+    }
+    if let Err(e) = apply_host(&mut args, &matches) {
+        error!("Unable to connect: {e}");
+        return;
+    }
+    if args.encrypt_cache {
+        if let Err(e) = vault::Vault::enable() {
+            error!("Unable to set up cache encryption: {e}");
+            return;
+        }
+    }
+    if let Some(codec) = args.cache_compression {
+        compress::Compression {
+            codec,
+            level: args.compression_level,
+            min_size: args.compression_min_size * 1024,
+        }
+        .enable();
+    }
     // match the requested command
     match &args.command {
         Commands::Identities {} => {
             println!("Available identities: ");
         }
-        Commands::Mount { mountpoint } => {
-            if let Some(usr) = args.username {
-                mount_rkfs(
-                    mountpoint,
-                    &args.address,
-                    args.port.unwrap_or(22),
-                    &usr,
-                    &args.password,
-                );
+        #[cfg(feature = "keyring")]
+        Commands::Login {} => login(&args),
+        Commands::Devices {
+            action: DevicesCommand::List {},
+        } => devices_list(),
+        Commands::Devices {
+            action: DevicesCommand::Forget { address },
+        } => match trust::TrustRecord::forget(address) {
+            Ok(true) => println!("{address} forgotten"),
+            Ok(false) => println!("{address} was not trusted"),
+            Err(e) => error!("Unable to forget {address}: {e}"),
+        },
+        Commands::Devices {
+            action: DevicesCommand::Trust {},
+        } => match rkfs_builder(&args).connect() {
+            Ok(rfs) => match trust::verify(&args.address, &rfs, true) {
+                Ok(record) => println!(
+                    "{} trusted with host key {}",
+                    args.address, record.fingerprint
+                ),
+                Err(e) => error!("Unable to trust {}: {e}", args.address),
+            },
+            Err(e) => error!("Unable to connect to {}: {e}", args.address),
+        },
+        Commands::Mount {
+            mountpoint,
+            file_mode,
+            dir_mode,
+            memory_budget,
+            strict_names,
+            collisions,
+            sort,
+            ghost_grace,
+            tree_refresh,
+            refresh_interval,
+            reconnect_attempts,
+            show_ghosts,
+            raw_pages,
+            coalesce_reads,
+            disk_cache,
+            write_back,
+            read_only,
+            scan_jobs,
+            scan_batch_size,
+            bulk_load,
+            strict_schema,
+            listing_channels,
+            background_jobs,
+            background_bandwidth,
+            busy_requests,
+            write_rate,
+            write_burst,
+            write_stop,
+            views,
+            devices,
+            force,
+            remount,
+            volume_name,
+            bookmark,
+        } => {
+            let Some(mountpoint) = mountpoint else {
+                error!("Unable to mount: no --mountpoint, nor one in the profile");
+                return;
+            };
+            let mountpoint = match mounts::check_mountpoint(
+                std::path::Path::new(mountpoint),
+                *force,
+                *remount,
+            ) {
+                Ok(canonical) => canonical.to_string_lossy().into_owned(),
+                Err(e) => {
+                    error!("Unable to mount: {e}");
+                    return;
+                }
+            };
+            let script = match views.as_ref().map(std::fs::read_to_string).transpose() {
+                Ok(script) => script,
+                Err(e) => {
+                    error!("Unable to read the views script: {e}");
+                    return;
+                }
+            };
+            if script.is_some() && cfg!(not(feature = "scripting")) {
+                warn!("views script ignored, rmkmount was built without the scripting feature");
+            }
+            let permissions = sftp_rkfs::fs::PermissionPolicy {
+                file_mode: *file_mode,
+                dir_mode: *dir_mode,
+                ..Default::default()
+            };
+            let builder = || {
+                let builder = rkfs_builder(&args)
+                    .permissions(permissions)
+                    .detail_budget(memory_budget * 1024 * 1024)
+                    .name_policy(if *strict_names {
+                        NamePolicy::Strict
+                    } else {
+                        NamePolicy::Lossy
+                    })
+                    .collision_policy(*collisions)
+                    .sort_policy(*sort)
+                    .ghost_grace(std::time::Duration::from_secs(*ghost_grace))
+                    .tree_refresh(std::time::Duration::from_secs(*tree_refresh))
+                    .refresh_interval(std::time::Duration::from_secs(*refresh_interval))
+                    .reconnect_attempts(*reconnect_attempts)
+                    .show_ghosts(*show_ghosts)
+                    .raw_pages(*raw_pages)
+                    .coalesce_reads(*coalesce_reads)
+                    .read_only(*read_only)
+                    .write_policy(if *write_back {
+                        WritePolicy::WriteBack
+                    } else {
+                        WritePolicy::WriteThrough
+                    })
+                    .scan_jobs(*scan_jobs)
+                    .scan_batch_size(*scan_batch_size)
+                    .bulk_load(*bulk_load)
+                    .strict_schema(*strict_schema)
+                    .listing_channels(*listing_channels)
+                    .job_budget(sftp_rkfs::fs::JobBudget {
+                        max_jobs: *background_jobs,
+                        bandwidth: background_bandwidth.map(|kib| kib * 1024),
+                        busy_requests: *busy_requests,
+                    })
+                    .write_limit(sftp_rkfs::fs::WriteLimit {
+                        per_minute: *write_rate,
+                        burst: *write_burst,
+                        stop_after: *write_stop,
+                    })
+                    .volume_name(volume_name)
+                    .spool_dir(runtime_dir());
+                let builder = match disk_cache {
+                    Some(mib) => builder.disk_cache(cache_dir(), mib * 1024 * 1024),
+                    None => builder,
+                };
+                #[cfg(feature = "scripting")]
+                let builder = match &script {
+                    Some(script) => builder.views_script(script),
+                    None => builder,
+                };
+                builder
+            };
+            let _bookmark = bookmark
+                .then(|| {
+                    mounts::Bookmark::add(std::path::Path::new(&mountpoint), volume_name)
+                        .map_err(|e| warn!("mount point not bookmarked: {e}"))
+                        .ok()
+                })
+                .flatten();
+            if devices.is_empty() {
+                mount_rkfs(builder(), &args.address, &mountpoint, *force);
+            } else {
+                mount_devices(builder, devices, &mountpoint, *force);
             }
         }
         Commands::Umount {} => {
             println!("Umounting");
         }
+        Commands::ExportRmdoc { document, output } => {
+            let output = output.clone().unwrap_or_else(|| {
+                let name = document.rsplit('/').next().unwrap_or(document);
+                format!("{name}.rmdoc")
+            });
+            let mut rfs = connect_rkfs(&args);
+            match rfs.export_rmdoc(document, std::path::Path::new(&output)) {
+                Ok(()) => println!("Exported {document} to {output}"),
+                Err(e) => error!("Export of {document} failed: {e}"),
+            }
+        }
+        Commands::ImportRmdoc { file, into } => {
+            let mut rfs = connect_rkfs(&args);
+            match rfs.import_rmdoc(std::path::Path::new(file), into.as_deref()) {
+                Ok(uid) => {
+                    println!("Imported {file} as {uid}");
+                    println!("Restart xochitl on the tablet to see the document");
+                }
+                Err(e) => error!("Import of {file} failed: {e}"),
+            }
+        }
+        Commands::Pull {
+            documents,
+            to,
+            jobs: _,
+            pages: Some(range),
+            annotated,
+        } => {
+            let mut rfs =
+                try_connect_shared(&args).expect("Failed to connect to the remarkable tablet");
+            for document in documents {
+                let exported = excerpt::export_pages(
+                    &mut rfs,
+                    document,
+                    *range,
+                    *annotated,
+                    std::path::Path::new(to),
+                );
+                match exported {
+                    Ok(target) => info!("Exported {target:?}"),
+                    Err(e) => error!("Exporting pages of {document} failed: {e}"),
+                }
+            }
+        }
+        Commands::Pull {
+            documents,
+            to,
+            jobs,
+            ..
+        } => {
+            let items = documents
+                .iter()
+                .map(|d| TransferItem::new(TransferKind::Pull, d, Some(to)))
+                .collect();
+            run_transfers(&args, items, *jobs);
+        }
+        Commands::Push { files, into, jobs } => {
+            let mut items = vec![];
+            let (dirs, files): (Vec<_>, Vec<_>) =
+                files.iter().partition(|f| std::path::Path::new(f).is_dir());
+            if !dirs.is_empty() {
+                let mut rfs = connect_rkfs(&args);
+                for dir in dirs {
+                    match map_directory_push(&mut rfs, std::path::Path::new(dir), into.as_deref()) {
+                        Ok(mut dir_items) => items.append(&mut dir_items),
+                        Err(e) => error!("Mapping {dir} to collections failed: {e}"),
+                    }
+                }
+            }
+            items.extend(
+                files
+                    .iter()
+                    .map(|f| TransferItem::new(TransferKind::Push, f, into.as_deref())),
+            );
+            run_transfers(&args, items, *jobs);
+        }
+        Commands::DebugBundle { output, lines } => {
+            let output = output.clone().unwrap_or_else(|| {
+                let secs = std::time::SystemTime::now()
+                    .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0);
+                format!("rmkmount-debug-{secs}.txt")
+            });
+            match write_debug_bundle(&args, &output, *lines) {
+                Ok(()) => {
+                    println!("Debug bundle written to {output}, please review it before sharing")
+                }
+                Err(e) => error!("Unable to write debug bundle {output}: {e}"),
+            }
+        }
+        Commands::Resume { jobs } => {
+            run_transfers(&args, vec![], *jobs);
+        }
+        Commands::Cache {
+            action:
+                CacheCommand::Purge {
+                    document,
+                    all: _,
+                    older_than,
+                },
+        } => {
+            // the argument group makes --all the remaining choice
+            let filter = match (document, older_than) {
+                (Some(uid), _) => PurgeFilter::Document(uid.clone()),
+                (None, Some(age)) => PurgeFilter::OlderThan(*age),
+                (None, None) => PurgeFilter::All,
+            };
+            let report = DocumentCache::new(cache_dir()).purge(&filter);
+            println!(
+                "removed {} documents ({}), {} kept ({})",
+                report.removed,
+                indicatif::HumanBytes(report.freed),
+                report.kept,
+                indicatif::HumanBytes(report.remaining)
+            );
+        }
+        Commands::Trash {
+            action: TrashCommand::List { older_than },
+        } => {
+            if let Err(e) = trash_list(&args, *older_than) {
+                error!("Unable to list the trash: {e}");
+            }
+        }
+        Commands::Ssh { yes, command } => match ssh(&args, &command.join(" "), *yes) {
+            Ok(Some(status)) if status != 0 => std::process::exit(status),
+            Ok(_) => {}
+            Err(e) => error!("Unable to run the command: {e}"),
+        },
+        Commands::Logs {
+            follow,
+            lines,
+            unit,
+        } => match logs(&args, unit, *lines, *follow) {
+            Ok(0) => {}
+            Ok(status) => std::process::exit(status),
+            Err(e) => error!("Unable to show the logs of {unit}: {e}"),
+        },
+        Commands::Discover { timeout } => {
+            let devices = discover::discover(std::time::Duration::from_secs(*timeout));
+            if devices.is_empty() {
+                println!("No tablet found");
+            }
+            for device in devices {
+                println!(
+                    "{:<16} {:<5} {}",
+                    device.address,
+                    device.source,
+                    device.ssh.as_deref().unwrap_or("ssh closed")
+                );
+            }
+        }
+        Commands::Info { json } => {
+            if let Err(e) = print_info(&args, *json) {
+                error!("Reading the tablet information failed: {e}");
+            }
+        }
+        Commands::Publish {
+            to,
+            collection,
+            hook,
+            interval,
+        } => {
+            let interval = std::time::Duration::from_secs(*interval);
+            if let Err(e) = publish(&args, collection, to, hook.as_deref(), interval) {
+                error!("Unable to publish {collection:?}: {e}");
+            }
+        }
+        Commands::Ls { path, du } => {
+            if let Err(e) = ls(&args, path, *du) {
+                error!("Unable to list {path}: {e}");
+            }
+        }
+        Commands::SetClock {} => {
+            if let Err(e) = set_clock(&args) {
+                error!("Unable to set the tablet clock: {e}");
+            }
+        }
+        Commands::Fsck { quarantine } => {
+            if let Err(e) = fsck(&args, *quarantine) {
+                error!("Unable to check the tablet: {e}");
+            }
+        }
+        Commands::Forward {
+            web,
+            remote,
+            local_port,
+        } => {
+            let remote = if *web {
+                WEB_UI_ADDRESS
+            } else {
+                remote.as_deref().unwrap_or_default()
+            };
+            if let Err(e) = forward(&args, remote, *local_port) {
+                error!("Unable to forward {remote}: {e}");
+            }
+        }
+        Commands::Watch { json, interval } => {
+            if let Err(e) = watch(&args, *json, std::time::Duration::from_secs(*interval)) {
+                error!("Unable to watch the tablet: {e}");
+            }
+        }
+        Commands::Export {
+            calibre: Some(library),
+            ..
+        } => {
+            if let Err(e) = export_calibre(&args, library) {
+                error!("Unable to export to {}: {e}", library.display());
+            }
+        }
+        Commands::Export {
+            paperless,
+            token,
+            tags,
+            correspondents,
+            all,
+            interval,
+            ..
+        } => {
+            let mapping = TagMapping::parse_pairs(tags).and_then(|tags| {
+                Ok(TagMapping {
+                    tags,
+                    correspondents: TagMapping::parse_pairs(correspondents)?,
+                })
+            });
+            let client = PaperlessClient::new(
+                paperless.as_deref().unwrap_or_default(),
+                token.as_deref().unwrap_or_default(),
+            )
+            .map_err(|e| e.to_string());
+            match mapping.and_then(|m| Ok((m, client?))) {
+                Ok((mapping, client)) => {
+                    let interval = std::time::Duration::from_secs(*interval);
+                    if let Err(e) = export_paperless(&args, &client, &mapping, *all, interval) {
+                        error!("Unable to export to paperless: {e}");
+                    }
+                }
+                Err(e) => error!("Invalid export settings: {e}"),
+            }
+        }
+        Commands::Send {
+            document,
+            to,
+            from,
+            smtp,
+            smtp_user,
+            smtp_security,
+        } => {
+            let smtp = mail::SmtpClient {
+                server: smtp.clone(),
+                credentials: smtp_user.clone().map(|user| {
+                    let password = std::env::var("RMKMOUNT_SMTP_PASSWORD").unwrap_or_default();
+                    (user, password)
+                }),
+                security: *smtp_security,
+            };
+            match send_document(&args, document, &smtp, from, to) {
+                Ok(()) => println!("{document} sent to {}", to.join(", ")),
+                Err(e) => error!("Unable to send {document}: {e}"),
+            }
+        }
+        Commands::Open { document } => match open_document(&args, document) {
+            Ok(path) => info!("opened {}", path.display()),
+            Err(e) => error!("Unable to open {document}: {e}"),
+        },
     }
 }
@@ -0,0 +1,326 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Why a mount point cannot be used as is
+#[derive(Debug, Error)]
+pub enum MountConflict {
+    #[error("{0} is already a {1} mount (use --remount to replace it)")]
+    AlreadyMounted(PathBuf, String),
+    #[error("{0} is already a {1} mount, not a FUSE one rmkmount could replace")]
+    ForeignMount(PathBuf, String),
+    #[error("{address} is already mounted on {} by rmkmount process {pid} (use --force to mount it again)", .mountpoint.display())]
+    DeviceMounted {
+        address: String,
+        mountpoint: PathBuf,
+        pid: u32,
+    },
+    #[error("{0} is not empty (use --force to mount over its content)")]
+    NotEmpty(PathBuf),
+    #[error("{0} is not a directory")]
+    NotADirectory(PathBuf),
+    #[error("unable to unmount {0}: {1}")]
+    Unmount(PathBuf, String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Record of a running mount of a device, so that a second instance can tell
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct MountRecord {
+    pid: u32,
+    mountpoint: PathBuf,
+    /// control socket sharing the connection of the mount, if any
+    #[serde(default)]
+    socket: Option<PathBuf>,
+}
+
+/// Held while a device is mounted, removes its mount record when dropped
+pub struct MountLock {
+    path: PathBuf,
+    socket: PathBuf,
+}
+
+impl MountLock {
+    /// where the mount shares its connection with other rmkmount commands
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+}
+
+impl Drop for MountLock {
+    fn drop(&mut self) {
+        // a record rewritten by another mount belongs to it
+        let ours = std::fs::read_to_string(&self.path)
+            .ok()
+            .and_then(|s| serde_json::from_str::<MountRecord>(&s).ok())
+            .is_some_and(|r| r.pid == std::process::id());
+        if !ours {
+            return;
+        }
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("mount record {} left behind: {e}", self.path.display());
+        }
+    }
+}
+
+/// Checks that `mountpoint` can receive a mount, returns its canonical path.
+/// With `remount`, a FUSE file system already mounted there is unmounted first.
+/// With `force`, a non empty mount point is accepted.
+pub fn check_mountpoint(
+    mountpoint: &Path,
+    force: bool,
+    remount: bool,
+) -> Result<PathBuf, MountConflict> {
+    let mounts = std::fs::read_to_string("/proc/self/mounts").unwrap_or_default();
+    let canonical = mountpoint
+        .canonicalize()
+        .unwrap_or_else(|_| mountpoint.to_path_buf());
+    if let Some(fstype) = mount_type(&mounts, &canonical) {
+        if !fstype.starts_with("fuse") {
+            return Err(MountConflict::ForeignMount(canonical, fstype));
+        }
+        if !remount {
+            return Err(MountConflict::AlreadyMounted(canonical, fstype));
+        }
+        unmount(&canonical)?;
+    }
+    if !canonical.is_dir() {
+        return Err(MountConflict::NotADirectory(canonical));
+    }
+    if !force && canonical.read_dir()?.next().is_some() {
+        return Err(MountConflict::NotEmpty(canonical));
+    }
+    Ok(canonical)
+}
+
+/// Records that the device at `address` is mounted on `mountpoint`, unless a
+/// running rmkmount already mounts it. With `force`, both mounts are kept : the
+/// running one keeps its record and socket, this one gets its own.
+pub fn lock_device(
+    address: &str,
+    mountpoint: &Path,
+    force: bool,
+) -> Result<MountLock, MountConflict> {
+    let mut path = record_path(address, None);
+    if let Some(running) = running(&path) {
+        if !force {
+            return Err(MountConflict::DeviceMounted {
+                address: address.to_owned(),
+                mountpoint: running.mountpoint,
+                pid: running.pid,
+            });
+        }
+        warn!(
+            "{address} also mounted on {} by process {}",
+            running.mountpoint.display(),
+            running.pid
+        );
+        path = record_path(address, Some(std::process::id()));
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let socket = path.with_extension("sock");
+    let record = MountRecord {
+        pid: std::process::id(),
+        mountpoint: mountpoint.to_path_buf(),
+        socket: Some(socket.clone()),
+    };
+    std::fs::write(
+        &path,
+        serde_json::to_string_pretty(&record).map_err(std::io::Error::other)?,
+    )?;
+    Ok(MountLock { path, socket })
+}
+
+/// Control socket of the running mount of the device at `address`, if any
+pub fn shared_socket(address: &str) -> Option<PathBuf> {
+    running(&record_path(address, None))?
+        .socket
+        .filter(|socket| socket.exists())
+}
+
+/// the mount record at `path`, if its process still runs
+fn running(path: &Path) -> Option<MountRecord> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|s| serde_json::from_str::<MountRecord>(&s).ok())
+        .filter(|r| Path::new(&format!("/proc/{}", r.pid)).exists())
+}
+
+/// A GTK bookmark of the mount point, removed when dropped
+pub struct Bookmark {
+    file: PathBuf,
+    line: String,
+}
+
+impl Bookmark {
+    /// Adds `mountpoint` labelled `label` to the GTK bookmarks, shown in the
+    /// sidebar of GNOME Files
+    pub fn add(mountpoint: &Path, label: &str) -> std::io::Result<Self> {
+        let file = bookmarks_file();
+        let line = format!("{} {label}", file_uri(mountpoint));
+        let content = std::fs::read_to_string(&file).unwrap_or_default();
+        if !content.lines().any(|l| l == line) {
+            if let Some(dir) = file.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            let mut content = content;
+            if !content.is_empty() && !content.ends_with('\n') {
+                content.push('\n');
+            }
+            content.push_str(&line);
+            content.push('\n');
+            std::fs::write(&file, content)?;
+        }
+        Ok(Self { file, line })
+    }
+}
+
+impl Drop for Bookmark {
+    fn drop(&mut self) {
+        let Ok(content) = std::fs::read_to_string(&self.file) else {
+            return;
+        };
+        let kept = content
+            .lines()
+            .filter(|l| *l != self.line)
+            .map(|l| format!("{l}\n"))
+            .collect::<String>();
+        if let Err(e) = std::fs::write(&self.file, kept) {
+            warn!("bookmark {} left behind: {e}", self.line);
+        }
+    }
+}
+
+/// `$XDG_CONFIG_HOME/gtk-3.0/bookmarks`, also read by GTK 4
+fn bookmarks_file() -> PathBuf {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+        .unwrap_or_else(std::env::temp_dir)
+        .join("gtk-3.0")
+        .join("bookmarks")
+}
+
+/// `file://` URI of the absolute `path`
+fn file_uri(path: &Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    let mut uri = String::from("file://");
+    for &b in path.as_os_str().as_bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                uri.push(b as char)
+            }
+            _ => uri.push_str(&format!("%{b:02X}")),
+        }
+    }
+    uri
+}
+
+/// `<state>/mounts/<address>.json`, `<address>-<pid>.json` for a mount forced
+/// by process `pid` over a running one
+fn record_path(address: &str, forced: Option<u32>) -> PathBuf {
+    let name = sftp_rkfs::names::file_safe(address);
+    let name = match forced {
+        Some(pid) => format!("{name}-{pid}.json"),
+        None => format!("{name}.json"),
+    };
+    crate::state_dir().join("mounts").join(name)
+}
+
+/// Unmounts the FUSE file system at `mountpoint`, with fusermount3 or fusermount
+fn unmount(mountpoint: &Path) -> Result<(), MountConflict> {
+    let mut last = String::from("fusermount not found");
+    for program in ["fusermount3", "fusermount"] {
+        match std::process::Command::new(program)
+            .arg("-u")
+            .arg(mountpoint)
+            .output()
+        {
+            Ok(out) if out.status.success() => {
+                info!("unmounted {}", mountpoint.display());
+                return Ok(());
+            }
+            Ok(out) => last = String::from_utf8_lossy(&out.stderr).trim().to_owned(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => last = e.to_string(),
+        }
+    }
+    Err(MountConflict::Unmount(mountpoint.to_path_buf(), last))
+}
+
+/// File system type mounted at `path` according to the `/proc/mounts` lines
+/// `mounts`, the last mount winning
+fn mount_type(mounts: &str, path: &Path) -> Option<String> {
+    mounts.lines().rev().find_map(|line| {
+        let mut fields = line.split(' ');
+        let (_, target, fstype) = (fields.next()?, fields.next()?, fields.next()?);
+        (Path::new(&unescape(target)) == path).then(|| fstype.to_owned())
+    })
+}
+
+/// decodes the octal escapes (`\040` for a space) of `/proc/mounts` fields
+fn unescape(field: &str) -> String {
+    let mut out = Vec::with_capacity(field.len());
+    let bytes = field.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let code = bytes
+            .get(i + 1..i + 4)
+            .and_then(|d| std::str::from_utf8(d).ok())
+            .and_then(|d| u8::from_str_radix(d, 8).ok());
+        match code {
+            Some(b) if bytes[i] == b'\\' => {
+                out.push(b);
+                i += 4;
+            }
+            _ => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mount_type() {
+        let mounts = "proc /proc proc rw 0 0\n\
+                      Remarkable /home/me/tablet\\040notes fuse rw,nosuid 0 0\n\
+                      /dev/sda1 /mnt ext4 rw 0 0\n\
+                      sshfs#me@h: /mnt fuse.sshfs rw 0 0\n";
+        assert_eq!(
+            mount_type(mounts, Path::new("/home/me/tablet notes")).as_deref(),
+            Some("fuse")
+        );
+        assert_eq!(
+            mount_type(mounts, Path::new("/mnt")).as_deref(),
+            Some("fuse.sshfs")
+        );
+        assert_eq!(mount_type(mounts, Path::new("/home/me")), None);
+        assert_eq!(
+            file_uri(Path::new("/home/me/tablet notes")),
+            "file:///home/me/tablet%20notes"
+        );
+    }
+
+    #[test]
+    fn test_record_path() {
+        let record = record_path("fe80::1%3", None);
+        let forced = record_path("fe80::1%3", Some(4242));
+        assert_eq!(record.file_name().unwrap(), "fe80__1_3.json");
+        assert_eq!(forced.file_name().unwrap(), "fe80__1_3-4242.json");
+        assert_eq!(
+            forced.with_extension("sock").file_name().unwrap(),
+            "fe80__1_3-4242.sock"
+        );
+        assert_eq!(record.parent(), forced.parent());
+    }
+}
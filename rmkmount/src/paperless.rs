@@ -0,0 +1,271 @@
+use log::{debug, info};
+use sftp_rkfs::fs::{ChangeEvent, ChangeKind};
+use sftp_rkfs::rkids;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Uploads documents to a paperless-ngx instance through its REST api, over
+/// http or https
+pub struct PaperlessClient {
+    agent: ureq::Agent,
+    /// base url of the instance, without trailing slash
+    url: String,
+    token: String,
+}
+
+/// A document to post, with the paperless ids of its tags and correspondent
+pub struct Upload<'a> {
+    pub file_name: &'a str,
+    pub title: &'a str,
+    pub data: &'a [u8],
+    pub tags: Vec<u32>,
+    pub correspondent: Option<u32>,
+}
+
+/// reMarkable tag names mapped to paperless tag and correspondent ids
+#[derive(Debug, Default)]
+pub struct TagMapping {
+    pub tags: HashMap<String, u32>,
+    pub correspondents: HashMap<String, u32>,
+}
+
+impl TagMapping {
+    /// parses `NAME=ID` pairs
+    pub fn parse_pairs(pairs: &[String]) -> Result<HashMap<String, u32>, String> {
+        pairs
+            .iter()
+            .map(|pair| {
+                pair.rsplit_once('=')
+                    .and_then(|(name, id)| Some((name.to_owned(), id.parse().ok()?)))
+                    .ok_or(format!("invalid mapping {pair}, expected NAME=ID"))
+            })
+            .collect()
+    }
+
+    /// paperless tag ids and correspondent of a document tagged `tags` on the
+    /// tablet. Unmapped tags are ignored, the first mapped correspondent wins.
+    pub fn apply(&self, tags: &[String]) -> (Vec<u32>, Option<u32>) {
+        let mut ids = tags
+            .iter()
+            .filter_map(|t| self.tags.get(t).copied())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.dedup();
+        let correspondent = tags
+            .iter()
+            .find_map(|t| self.correspondents.get(t).copied());
+        (ids, correspondent)
+    }
+}
+
+impl PaperlessClient {
+    const UPLOAD_PATH: &'static str = "/api/documents/post_document/";
+
+    /// `url` is the base url of the instance, e.g. https://host:8000
+    pub fn new(url: &str, token: &str) -> std::io::Result<Self> {
+        let invalid = |reason: &str| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{url}: {reason}"))
+        };
+        let rest = url
+            .strip_prefix("http://")
+            .or_else(|| url.strip_prefix("https://"))
+            .ok_or_else(|| invalid("only http:// and https:// urls are supported"))?;
+        if rest.starts_with('/') || rest.is_empty() {
+            return Err(invalid("missing host"));
+        }
+        let tls = ureq::native_tls::TlsConnector::new().map_err(std::io::Error::other)?;
+        Ok(Self {
+            agent: ureq::AgentBuilder::new()
+                .tls_connector(Arc::new(tls))
+                .build(),
+            url: url.trim_end_matches('/').to_owned(),
+            token: token.to_owned(),
+        })
+    }
+
+    fn upload_url(&self) -> String {
+        format!("{}{}", self.url, Self::UPLOAD_PATH)
+    }
+
+    /// Posts `upload` for consumption, returns the id of the paperless task
+    pub fn post_document(&self, upload: &Upload) -> std::io::Result<String> {
+        // random, so that no document contains it
+        let boundary = format!("rmkmount-{}", rkids::new_uid());
+        let body = multipart_body(&boundary, upload);
+        debug!(
+            "posting {} ({} bytes) to {}",
+            upload.file_name,
+            body.len(),
+            self.url
+        );
+        let response = self
+            .agent
+            .post(&self.upload_url())
+            .set("Authorization", &format!("Token {}", self.token))
+            .set(
+                "Content-Type",
+                &format!("multipart/form-data; boundary={boundary}"),
+            )
+            .send_bytes(&body);
+        let task = match response {
+            Ok(response) => response.into_string()?,
+            Err(ureq::Error::Status(status, response)) => {
+                return Err(std::io::Error::other(format!(
+                    "paperless answered {status}: {}",
+                    response.into_string().unwrap_or_default().trim()
+                )))
+            }
+            Err(e) => return Err(std::io::Error::other(e)),
+        };
+        let task = task.trim().trim_matches('"').to_owned();
+        info!("{} queued for consumption as task {task}", upload.file_name);
+        Ok(task)
+    }
+}
+
+/// The events to archive at a check : the uploads that failed before, then
+/// the documents added or modified since. A failed upload is replaced by a
+/// later change of its document, follows its moves, and is dropped once the
+/// document is trashed or removed.
+pub fn events_to_archive(failed: Vec<ChangeEvent>, events: Vec<ChangeEvent>) -> Vec<ChangeEvent> {
+    let mut archived = vec![];
+    for mut retry in failed {
+        let mut dropped = false;
+        for event in events.iter().filter(|e| e.uid == retry.uid) {
+            match event.kind {
+                ChangeKind::Moved => retry.path.clone_from(&event.path),
+                _ => dropped = true,
+            }
+        }
+        if !dropped {
+            archived.push(retry);
+        }
+    }
+    // trashed documents are only reported as added by --all
+    archived.extend(events.into_iter().filter(|e| {
+        matches!(e.kind, ChangeKind::Added | ChangeKind::Modified) && !e.path.starts_with(".Trash/")
+    }));
+    archived
+}
+
+/// multipart/form-data fields of a post_document request
+fn multipart_body(boundary: &str, upload: &Upload) -> Vec<u8> {
+    let mut fields = vec![("title", upload.title.to_owned())];
+    fields.extend(upload.tags.iter().map(|t| ("tags", t.to_string())));
+    fields.extend(
+        upload
+            .correspondent
+            .map(|c| ("correspondent", c.to_string())),
+    );
+    let mut body = vec![];
+    for (name, value) in fields {
+        body.extend_from_slice(
+            format!(
+                "--{boundary}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"
+            )
+            .as_bytes(),
+        );
+    }
+    let file_name = upload.file_name.replace(['"', '\r', '\n'], "_");
+    body.extend_from_slice(
+        format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"document\"; \
+             filename=\"{file_name}\"\r\nContent-Type: application/octet-stream\r\n\r\n"
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(upload.data);
+    body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multipart_body() {
+        let upload = Upload {
+            file_name: "Spec.pdf",
+            title: "Spec",
+            data: b"%PDF",
+            tags: vec![3],
+            correspondent: Some(7),
+        };
+        let body = String::from_utf8(multipart_body("b", &upload)).unwrap();
+        assert_eq!(
+            body,
+            "--b\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nSpec\r\n\
+             --b\r\nContent-Disposition: form-data; name=\"tags\"\r\n\r\n3\r\n\
+             --b\r\nContent-Disposition: form-data; name=\"correspondent\"\r\n\r\n7\r\n\
+             --b\r\nContent-Disposition: form-data; name=\"document\"; filename=\"Spec.pdf\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n%PDF\r\n--b--\r\n"
+        );
+    }
+
+    #[test]
+    fn test_mapping_and_urls() {
+        let mapping = TagMapping {
+            tags: TagMapping::parse_pairs(&["work=3".to_string(), "todo=1".to_string()]).unwrap(),
+            correspondents: TagMapping::parse_pairs(&["acme=7".to_string()]).unwrap(),
+        };
+        let tags = ["todo", "acme", "work", "other"].map(String::from);
+        assert_eq!(mapping.apply(&tags), (vec![1, 3], Some(7)));
+        assert!(TagMapping::parse_pairs(&["work".to_string()]).is_err());
+
+        let client = PaperlessClient::new("http://nas:8000/paperless/", "t").unwrap();
+        assert_eq!(
+            client.upload_url(),
+            "http://nas:8000/paperless/api/documents/post_document/"
+        );
+        assert_eq!(
+            PaperlessClient::new("https://nas", "t")
+                .unwrap()
+                .upload_url(),
+            "https://nas/api/documents/post_document/"
+        );
+        assert!(PaperlessClient::new("ftp://nas", "t").is_err());
+        assert!(PaperlessClient::new("http:///paperless", "t").is_err());
+    }
+
+    fn event(kind: ChangeKind, uid: &str, path: &str) -> ChangeEvent {
+        ChangeEvent {
+            kind,
+            uid: uid.to_owned(),
+            path: path.to_owned(),
+            from: None,
+            last_modified: 0,
+        }
+    }
+
+    #[test]
+    fn test_events_to_archive() {
+        let failed = vec![
+            event(ChangeKind::Added, "a", "Spec.pdf"),
+            event(ChangeKind::Modified, "b", "Work/Plan.pdf"),
+            event(ChangeKind::Added, "c", "Old.pdf"),
+            event(ChangeKind::Added, "d", "Todo.pdf"),
+        ];
+        let events = vec![
+            event(ChangeKind::Moved, "b", "Done/Plan.pdf"),
+            event(ChangeKind::Trashed, "c", "Old.pdf"),
+            event(ChangeKind::Modified, "d", "Todo.pdf"),
+            event(ChangeKind::Added, "e", "New.pdf"),
+            event(ChangeKind::Added, "f", ".Trash/Gone.pdf"),
+        ];
+        let archived = events_to_archive(failed, events);
+        let archived: Vec<_> = archived
+            .iter()
+            .map(|e| (e.uid.as_str(), e.path.as_str()))
+            .collect();
+        assert_eq!(
+            archived,
+            [
+                ("a", "Spec.pdf"),
+                ("b", "Done/Plan.pdf"),
+                ("d", "Todo.pdf"),
+                ("e", "New.pdf")
+            ]
+        );
+    }
+}
@@ -0,0 +1,28 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// runs `program` with `input` on its stdin, returns its stdout. The input is
+/// written from another thread, so that a full stdout pipe cannot block it.
+pub fn run(program: &str, args: &[&str], input: Option<&[u8]>) -> std::io::Result<Vec<u8>> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| std::io::Error::new(e.kind(), format!("running {program}: {e}")))?;
+    let out = std::thread::scope(|scope| {
+        if let Some(mut stdin) = child.stdin.take() {
+            // a failed write shows up as the program failing
+            scope.spawn(move || stdin.write_all(input.unwrap_or_default()));
+        }
+        child.wait_with_output()
+    })?;
+    if !out.status.success() {
+        return Err(std::io::Error::other(format!(
+            "{program} failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    Ok(out.stdout)
+}
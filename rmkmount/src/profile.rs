@@ -0,0 +1,61 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// What is remembered about a tablet between runs, one file per address
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct DeviceProfile {
+    /// firmware version seen on the last connection
+    pub firmware: Option<String>,
+}
+
+impl DeviceProfile {
+    const PROFILE_DIR: &'static str = "devices";
+
+    fn path(address: &str) -> PathBuf {
+        let name = address
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        crate::state_dir()
+            .join(Self::PROFILE_DIR)
+            .join(format!("{name}.json"))
+    }
+
+    /// Loads the profile of the tablet at `address`, empty if never saved
+    pub fn load(address: &str) -> Self {
+        crate::vault::read_to_string(&Self::path(address))
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, address: &str) -> std::io::Result<()> {
+        let path = Self::path(address);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let json = serde_json::to_string_pretty(self).map_err(std::io::Error::other)?;
+        crate::vault::write(&path, json.as_bytes())
+    }
+
+    pub fn save_or_warn(&self, address: &str) {
+        if let Err(e) = self.save(address) {
+            warn!("unable to persist profile of {address}: {e}");
+        }
+    }
+
+    /// Records `firmware`, returns the previously recorded version when it differs
+    pub fn update_firmware(&mut self, firmware: &str) -> Option<String> {
+        match self.firmware.replace(firmware.to_owned()) {
+            Some(previous) if previous != firmware => Some(previous),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+
+/// Folder of the document at visible `path` relative to the published
+/// `collection` ("" for its direct children), None when the document is not
+/// in the collection. The whole library is published for an empty collection,
+/// the trash excepted.
+pub fn relative_dir<'p>(collection: &str, path: &'p str) -> Option<&'p str> {
+    let inside = if collection.is_empty() {
+        Some(path).filter(|p| !p.starts_with(".Trash/"))
+    } else {
+        path.strip_prefix(collection)?.strip_prefix('/')
+    }?;
+    Some(inside.rsplit_once('/').map_or("", |(dir, _)| dir))
+}
+
+/// `<target>/<dir>/<file_name>`, with characters that cannot be in local file
+/// names replaced
+pub fn output_path(target: &Path, dir: &str, file_name: &str) -> PathBuf {
+    let mut path = target.to_path_buf();
+    for component in dir.split('/').filter(|c| !c.is_empty()) {
+        path.push(sanitize(component));
+    }
+    path.join(sanitize(file_name))
+}
+
+fn sanitize(name: &str) -> String {
+    match name {
+        "." | ".." => name.replace('.', "_"),
+        _ => name
+            .chars()
+            .map(|c| if c == '/' || c.is_control() { '_' } else { c })
+            .collect(),
+    }
+}
+
+/// Runs the publish `hook` through `sh -c`, with the published file, the
+/// document visible path and its uid in RMKMOUNT_FILE, RMKMOUNT_DOCUMENT and
+/// RMKMOUNT_UID
+pub fn run_hook(hook: &str, file: &Path, document: &str, uid: &str) -> std::io::Result<ExitStatus> {
+    Command::new("sh")
+        .arg("-c")
+        .arg(hook)
+        .env("RMKMOUNT_FILE", file)
+        .env("RMKMOUNT_DOCUMENT", document)
+        .env("RMKMOUNT_UID", uid)
+        .status()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_path() {
+        assert_eq!(relative_dir("Class", "Class/Math/Week 1"), Some("Math"));
+        assert_eq!(relative_dir("Class", "Class/Week 1"), Some(""));
+        assert_eq!(relative_dir("Class", "Classic/Week 1"), None);
+        assert_eq!(relative_dir("", "Class/Week 1"), Some("Class"));
+        assert_eq!(relative_dir("", ".Trash/Week 1"), None);
+        assert_eq!(
+            output_path(Path::new("site/notes"), "Math/..", "Week\n1.pdf"),
+            PathBuf::from("site/notes/Math/__/Week_1.pdf")
+        );
+    }
+}
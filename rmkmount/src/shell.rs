@@ -0,0 +1,162 @@
+use std::io::{BufRead, Write};
+
+/// Programs that only read the tablet state, run without confirmation. Programs
+/// running others (env, xargs, nice...) are left out
+const READ_ONLY_PROGRAMS: [&str; 26] = [
+    "cat",
+    "date",
+    "df",
+    "dmesg",
+    "du",
+    "echo",
+    "file",
+    "find",
+    "free",
+    "grep",
+    "head",
+    "hostname",
+    "id",
+    "journalctl",
+    "ls",
+    "md5sum",
+    "ps",
+    "pwd",
+    "sha256sum",
+    "sort",
+    "stat",
+    "tail",
+    "uname",
+    "uptime",
+    "wc",
+    "whoami",
+];
+
+/// systemctl subcommands that only query units
+const SYSTEMCTL_QUERIES: [&str; 5] = ["status", "is-active", "is-enabled", "list-units", "show"];
+
+/// Options turning a read only program into one changing something : find
+/// actions and output files, sort output files and compression programs,
+/// kernel log clearing, magic file compilation, journal cleanup. Long options
+/// match by prefix, short ones anywhere in a cluster (`dmesg -rc`)
+const MUTATING_OPTIONS: [(&str, &[&str]); 5] = [
+    ("find", &["-delete", "-exec", "-ok", "-fprint", "-fls"]),
+    ("sort", &["-o", "--output", "--compress-program"]),
+    (
+        "dmesg",
+        &[
+            "-c",
+            "-C",
+            "-n",
+            "-D",
+            "-E",
+            "--clear",
+            "--read-clear",
+            "--console",
+        ],
+    ),
+    ("file", &["-C", "--compile"]),
+    ("journalctl", &["--vacuum", "--rotate", "--flush", "--sync"]),
+];
+
+/// Programs setting the tablet state when given any argument : the clock, the
+/// host name
+const SETTERS: [&str; 2] = ["date", "hostname"];
+
+/// Does the shell `command` only read the tablet state ? Every program of its
+/// pipelines and lists must be known to be read only; redirections, command
+/// and process substitutions are assumed to change something.
+pub fn is_read_only(command: &str) -> bool {
+    if command.contains(['>', '`']) || command.contains("$(") || command.contains("<(") {
+        return false;
+    }
+    command.split(['|', ';', '&', '\n']).all(|segment| {
+        let mut words = segment.split_whitespace();
+        let Some(program) = words.next() else {
+            // between the two characters of `&&` or `||`
+            return true;
+        };
+        let args = words.collect::<Vec<_>>();
+        let program = program.rsplit('/').next().unwrap_or(program);
+        if SETTERS.contains(&program) && !args.is_empty() {
+            return false;
+        }
+        if MUTATING_OPTIONS
+            .iter()
+            .filter(|(name, _)| *name == program)
+            .flat_map(|(_, options)| options.iter())
+            .any(|option| args.iter().any(|arg| has_option(arg, option)))
+        {
+            return false;
+        }
+        match program {
+            "systemctl" => args
+                .first()
+                .is_some_and(|query| SYSTEMCTL_QUERIES.contains(query)),
+            program => READ_ONLY_PROGRAMS.contains(&program),
+        }
+    })
+}
+
+/// is `option` given by `arg` ? Short options may be clustered with others or
+/// have their value attached (`sort -uofile`)
+fn has_option(arg: &str, option: &str) -> bool {
+    match option.strip_prefix('-') {
+        Some(letter) if letter.len() == 1 => arg
+            .strip_prefix('-')
+            .is_some_and(|cluster| !cluster.starts_with('-') && cluster.contains(letter)),
+        _ => arg.starts_with(option),
+    }
+}
+
+/// Asks `question` on the terminal, true when answered y or yes
+pub fn confirm(question: &str) -> bool {
+    print!("{question} [y/N] ");
+    if std::io::stdout().flush().is_err() {
+        return false;
+    }
+    let mut answer = String::new();
+    match std::io::stdin().lock().read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+        Err(_) => false,
+    }
+}
+
+/// Reads a password from the terminal without echoing it, as ssh does
+pub fn read_password(prompt: &str) -> std::io::Result<String> {
+    rpassword::prompt_password(prompt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_read_only() {
+        assert!(is_read_only("df -h"));
+        assert!(is_read_only("ls -l /home/root | wc -l && uptime"));
+        assert!(is_read_only("/bin/cat /etc/version"));
+        assert!(is_read_only("systemctl status xochitl"));
+        assert!(!is_read_only("systemctl restart xochitl"));
+        assert!(!is_read_only("rm -rf /home/root/x"));
+        assert!(!is_read_only("ls; reboot"));
+        assert!(!is_read_only("echo 1 > /sys/power/state"));
+        assert!(!is_read_only("find . -name '*.tmp' -delete"));
+        assert!(!is_read_only("cat $(rm x)"));
+        assert!(!is_read_only("cat <(rm -rf x)"));
+        assert!(!is_read_only("env rm -rf /home/root"));
+        assert!(is_read_only("date") && is_read_only("hostname"));
+        assert!(!is_read_only("date -s '2020-01-01 00:00'"));
+        assert!(!is_read_only("hostname tablet"));
+        assert!(!is_read_only("sort -o /etc/shadow list"));
+        assert!(!is_read_only("sort -uo/etc/shadow list"));
+        assert!(!is_read_only("sort --compress-program=reboot -S 1 list"));
+        assert!(!is_read_only("dmesg -C"));
+        assert!(!is_read_only("dmesg -rc"));
+        assert!(is_read_only("dmesg -T | tail"));
+        assert!(!is_read_only("find / -fprint /home/root/x"));
+        assert!(!is_read_only("find / -fprintf x %p"));
+        assert!(!is_read_only("find / -fls x"));
+        assert!(!is_read_only("file -C -m magic"));
+        assert!(is_read_only("grep -c x /etc/version"));
+    }
+}
@@ -0,0 +1,443 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sftp_rkfs::fs::RemarkableFs;
+use sftp_rkfs::{rkids, ErrorContext, FsError, RemarkableError};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum TransferKind {
+    Pull,
+    Push,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum TransferState {
+    Pending,
+    Running,
+    Done,
+    Failed(String),
+}
+
+/// A single pull (tablet document -> local folder) or push (local file -> tablet collection)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferItem {
+    pub kind: TransferKind,
+    pub source: String,
+    pub destination: Option<String>,
+    pub state: TransferState,
+    /// uid of the pushed document, chosen before the first attempt so that a
+    /// retry resumes the same document instead of uploading another one
+    #[serde(default)]
+    pub uid: Option<String>,
+}
+
+impl TransferItem {
+    pub fn new(kind: TransferKind, source: &str, destination: Option<&str>) -> Self {
+        Self {
+            kind,
+            source: source.to_owned(),
+            destination: destination.map(str::to_owned),
+            state: TransferState::Pending,
+            uid: None,
+        }
+    }
+
+    fn same_transfer(&self, other: &Self) -> bool {
+        self.kind == other.kind
+            && self.source == other.source
+            && self.destination == other.destination
+    }
+}
+
+/// Maps a local folder tree onto tablet collections for a push: every sub-folder
+/// becomes a collection under `into` (created when missing, reused by name
+/// otherwise), the mapping is reported and one push item per PDF/EPUB is returned.
+pub fn map_directory_push(
+    rfs: &mut RemarkableFs,
+    dir: &Path,
+    into: Option<&str>,
+) -> Result<Vec<TransferItem>, RemarkableError> {
+    let root_name = dir
+        .canonicalize()?
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let root_collection = format!("{}/{root_name}", into.unwrap_or_default());
+    let mut items = vec![];
+    let mut pending_dirs = vec![(dir.to_path_buf(), root_collection)];
+    while let Some((local, collection)) = pending_dirs.pop() {
+        let existed = rfs.resolve_path(&collection).is_ok();
+        let uid = rfs.ensure_collection(&collection)?;
+        println!(
+            "{} -> {collection} ({uid}, {})",
+            local.display(),
+            if existed { "existing" } else { "created" }
+        );
+        let mut entries = std::fs::read_dir(&local)?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .collect::<Vec<_>>();
+        entries.sort();
+        for entry in entries {
+            let name = entry
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            if entry.is_dir() {
+                pending_dirs.push((entry, format!("{collection}/{name}")));
+            } else if matches!(
+                entry.extension().and_then(|e| e.to_str()),
+                Some("pdf" | "epub" | "PDF" | "EPUB")
+            ) {
+                items.push(TransferItem::new(
+                    TransferKind::Push,
+                    &entry.to_string_lossy(),
+                    Some(&collection),
+                ));
+            } else {
+                warn!("skipping {entry:?}: only pdf and epub files can be pushed");
+            }
+        }
+    }
+    Ok(items)
+}
+
+/// Transfer queue persisted to disk after each state change, so that an
+/// interrupted pull/push can be resumed where it stopped
+pub struct TransferQueue {
+    path: PathBuf,
+    items: Vec<TransferItem>,
+}
+
+impl TransferQueue {
+    /// the tablet ssh server does not cope well with many concurrent sessions
+    pub const MAX_JOBS: usize = 4;
+    const PULL_CHUNK_SIZE: u32 = 256 * 1024;
+    const QUEUE_FILE: &'static str = "transfers.json";
+
+    /// queue file location, in the rmkmount state folder
+    fn default_path() -> PathBuf {
+        crate::state_dir().join(Self::QUEUE_FILE)
+    }
+
+    /// Loads the persisted queue, items left running by an interrupted run are pending again
+    pub fn load() -> Self {
+        Self::load_from(Self::default_path())
+    }
+
+    fn load_from(path: PathBuf) -> Self {
+        let mut items: Vec<TransferItem> = crate::vault::read_to_string(&path)
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default();
+        for item in items.iter_mut() {
+            if item.state == TransferState::Running {
+                item.state = TransferState::Pending;
+            }
+        }
+        Self { path, items }
+    }
+
+    /// Number of items still to be transferred (pending or failed)
+    pub fn remaining(&self) -> usize {
+        self.items
+            .iter()
+            .filter(|i| i.state != TransferState::Done)
+            .count()
+    }
+
+    /// are the items still to be transferred all pulls ?
+    pub fn pulls_only(&self) -> bool {
+        self.items
+            .iter()
+            .filter(|i| i.state != TransferState::Done)
+            .all(|i| i.kind == TransferKind::Pull)
+    }
+
+    /// Adds an item to the queue unless the same transfer is already queued
+    pub fn push(&mut self, item: TransferItem) {
+        if let Some(existing) = self.items.iter_mut().find(|i| i.same_transfer(&item)) {
+            existing.state = TransferState::Pending;
+        } else {
+            self.items.push(item);
+        }
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let json = serde_json::to_string_pretty(&self.items).map_err(std::io::Error::other)?;
+        crate::vault::write(&self.path, json.as_bytes())
+    }
+
+    fn save_or_warn(&self) {
+        if let Err(e) = self.save() {
+            warn!("unable to persist transfer queue {:?}: {e}", self.path);
+        }
+    }
+
+    /// Runs every pending or failed item with `jobs` workers, each one using its own
+    /// connection from `connect`. Returns the number of items that failed.
+    pub fn run<F>(mut self, jobs: usize, connect: F) -> usize
+    where
+        F: Fn() -> Result<RemarkableFs, RemarkableError> + Sync,
+    {
+        for item in self.items.iter_mut() {
+            if let TransferState::Failed(_) = item.state {
+                item.state = TransferState::Pending;
+            }
+        }
+        let jobs = if jobs > Self::MAX_JOBS {
+            warn!("limiting transfers to {} parallel jobs", Self::MAX_JOBS);
+            Self::MAX_JOBS
+        } else {
+            jobs.max(1)
+        };
+        self.save_or_warn();
+
+        let queue = Mutex::new(self);
+        let bars = MultiProgress::new();
+        std::thread::scope(|s| {
+            for _ in 0..jobs {
+                s.spawn(|| Self::worker(&queue, &bars, &connect));
+            }
+        });
+
+        let mut queue = queue.into_inner().unwrap_or_else(|e| e.into_inner());
+        let failed = queue
+            .items
+            .iter()
+            .filter(|i| matches!(i.state, TransferState::Failed(_)))
+            .count();
+        queue.items.retain(|i| i.state != TransferState::Done);
+        if queue.items.is_empty() {
+            let _ = std::fs::remove_file(&queue.path);
+        } else {
+            queue.save_or_warn();
+        }
+        failed
+    }
+
+    /// takes the next pending item and marks it as running. Pushes get their
+    /// document uid, saved before anything is uploaded.
+    fn next_pending(queue: &Mutex<Self>) -> Option<(usize, TransferItem)> {
+        let mut queue = queue.lock().ok()?;
+        let idx = queue
+            .items
+            .iter()
+            .position(|i| i.state == TransferState::Pending)?;
+        let item = &mut queue.items[idx];
+        item.state = TransferState::Running;
+        if item.kind == TransferKind::Push && item.uid.is_none() {
+            item.uid = Some(rkids::new_uid());
+        }
+        queue.save_or_warn();
+        Some((idx, queue.items[idx].clone()))
+    }
+
+    fn worker<F>(queue: &Mutex<Self>, bars: &MultiProgress, connect: &F)
+    where
+        F: Fn() -> Result<RemarkableFs, RemarkableError>,
+    {
+        let mut rfs = match connect() {
+            Ok(rfs) => rfs,
+            Err(e) => {
+                error!("transfer worker could not connect: {e}");
+                return;
+            }
+        };
+        while let Some((idx, item)) = Self::next_pending(queue) {
+            let bar = bars.add(ProgressBar::new(0));
+            bar.set_style(
+                ProgressStyle::with_template("{msg} [{bar:30}] {bytes}/{total_bytes} {eta}")
+                    .unwrap_or_else(|_| ProgressStyle::default_bar()),
+            );
+            bar.set_message(item.source.clone());
+            let res = match item.kind {
+                TransferKind::Pull => Self::pull_document(
+                    &mut rfs,
+                    &item.source,
+                    Path::new(item.destination.as_deref().unwrap_or(".")),
+                    &bar,
+                ),
+                TransferKind::Push => Self::push_file(
+                    &mut rfs,
+                    Path::new(&item.source),
+                    item.destination.as_deref(),
+                    item.uid.as_deref().unwrap_or_default(),
+                    &bar,
+                ),
+            };
+            bar.finish();
+            let state = match res {
+                Ok(()) => {
+                    info!("transfer of {} done", item.source);
+                    TransferState::Done
+                }
+                Err(e) => {
+                    error!("transfer of {} failed: {e}", item.source);
+                    TransferState::Failed(e.to_string())
+                }
+            };
+            if let Ok(mut queue) = queue.lock() {
+                queue.items[idx].state = state;
+                queue.save_or_warn();
+            }
+        }
+    }
+
+    /// Downloads a document payload into `destination`, resuming from a previous
+    /// `.part` file if any. Documents without payload (notebooks) are exported as .rmdoc.
+    pub fn pull_document(
+        rfs: &mut RemarkableFs,
+        source: &str,
+        destination: &Path,
+        bar: &ProgressBar,
+    ) -> Result<(), RemarkableError> {
+        let ino = rfs.resolve_path(source)?;
+        if !rfs.is_document(ino) {
+            return Err(FsError::NotADocument(source.to_owned()).into());
+        }
+        std::fs::create_dir_all(destination)?;
+        let target = destination.join(rfs.visible_name(ino).unwrap_or_default());
+        let size = rfs.size(ino).unwrap_or(0);
+        if size == 0 {
+            let mut bundle = target.into_os_string();
+            bundle.push(".rmdoc");
+            return rfs.export_rmdoc(source, Path::new(&bundle));
+        }
+
+        let mut part = target.clone().into_os_string();
+        part.push(".part");
+        let mut offset = std::fs::metadata(&part).map(|m| m.len()).unwrap_or(0);
+        if offset > size {
+            // stale part of an older version of the document
+            warn!("{part:?} is larger than {source}, downloading it again");
+            std::fs::remove_file(&part).with_context(|| format!("removing {part:?}"))?;
+            offset = 0;
+        }
+        if offset > 0 {
+            info!("resuming {source} at {offset} bytes");
+        }
+        let mut out = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&part)
+            .with_context(|| format!("opening {part:?}"))?;
+        bar.set_length(size);
+        bar.set_position(offset);
+        while offset < size {
+            let buf = rfs.read(ino, offset, Self::PULL_CHUNK_SIZE)?;
+            if buf.is_empty() {
+                break;
+            }
+            out.write_all(&buf)
+                .with_context(|| format!("writing {part:?}"))?;
+            offset += buf.len() as u64;
+            bar.set_position(offset);
+        }
+        if offset != size {
+            // the part is kept, a retry resumes it
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("{source} ended at {offset} of {size} bytes"),
+            ))
+            .with_context(|| format!("downloading {source}"));
+        }
+        std::fs::rename(&part, &target).with_context(|| format!("renaming {part:?}"))?;
+        Ok(())
+    }
+
+    /// Uploads a local file into the collection at visible path `collection`
+    /// as document `uid`
+    fn push_file(
+        rfs: &mut RemarkableFs,
+        source: &Path,
+        collection: Option<&str>,
+        uid: &str,
+        bar: &ProgressBar,
+    ) -> Result<(), RemarkableError> {
+        bar.set_length(std::fs::metadata(source)?.len());
+        rfs.push_document(source, collection, uid, &mut |sent| bar.set_position(sent))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue_path(name: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("rmkmount-transfer-test-{}", std::process::id()))
+            .join(name)
+    }
+
+    #[test]
+    fn test_queue_persistence() {
+        let path = queue_path("persistence.json");
+        let mut queue = TransferQueue::load_from(path.clone());
+        queue.push(TransferItem::new(TransferKind::Pull, "/Spec", Some(".")));
+        queue.push(TransferItem::new(TransferKind::Push, "a.pdf", None));
+        queue.push(TransferItem::new(TransferKind::Push, "b.pdf", None));
+        queue.items[0].state = TransferState::Running;
+        queue.items[1].state = TransferState::Done;
+        queue.items[2].state = TransferState::Failed("timeout".to_owned());
+        queue.items[2].uid = Some(rkids::new_uid());
+        queue.save().unwrap();
+
+        // running items were interrupted, the others keep their state and uid
+        let mut loaded = TransferQueue::load_from(path.clone());
+        let states: Vec<_> = loaded.items.iter().map(|i| i.state.clone()).collect();
+        assert_eq!(
+            states,
+            [
+                TransferState::Pending,
+                TransferState::Done,
+                TransferState::Failed("timeout".to_owned())
+            ]
+        );
+        assert_eq!(loaded.items[2].uid, queue.items[2].uid);
+        assert_eq!(loaded.remaining(), 2);
+        assert!(!loaded.pulls_only());
+
+        // queuing the same transfer again retries it under the same uid
+        loaded.push(TransferItem::new(TransferKind::Push, "b.pdf", None));
+        assert_eq!(loaded.items.len(), 3);
+        assert_eq!(loaded.items[2].state, TransferState::Pending);
+        assert_eq!(loaded.items[2].uid, queue.items[2].uid);
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn test_next_pending() {
+        let path = queue_path("next.json");
+        let mut queue = TransferQueue::load_from(path.clone());
+        queue.push(TransferItem::new(TransferKind::Pull, "/Spec", Some(".")));
+        queue.push(TransferItem::new(TransferKind::Push, "a.pdf", None));
+        let queue = Mutex::new(queue);
+
+        let (idx, pull) = TransferQueue::next_pending(&queue).unwrap();
+        assert_eq!(
+            (idx, pull.state, pull.uid),
+            (0, TransferState::Running, None)
+        );
+        let (idx, push) = TransferQueue::next_pending(&queue).unwrap();
+        assert_eq!((idx, push.state), (1, TransferState::Running));
+        let uid = push.uid.unwrap();
+        assert!(rkids::is_uid(&uid));
+        assert!(TransferQueue::next_pending(&queue).is_none());
+
+        // the uid is saved before the push starts, and kept by a restart
+        let loaded = TransferQueue::load_from(path.clone());
+        assert_eq!(loaded.items[1].state, TransferState::Pending);
+        assert_eq!(loaded.items[1].uid.as_deref(), Some(uid.as_str()));
+        let queue = Mutex::new(loaded);
+        TransferQueue::next_pending(&queue).unwrap();
+        let (_, push) = TransferQueue::next_pending(&queue).unwrap();
+        assert_eq!(push.uid.as_deref(), Some(uid.as_str()));
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -0,0 +1,223 @@
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sftp_rkfs::auth::AuthProvider;
+use sftp_rkfs::fs::RemarkableFs;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+/// Why a tablet is not trusted
+#[derive(Debug, Error)]
+pub enum TrustError {
+    #[error("host key of {address} changed from {trusted} to {presented}: it may be another device, or someone in between (once checked, run `rmkmount --address {address} devices trust`)")]
+    HostKeyChanged {
+        address: String,
+        trusted: String,
+        presented: String,
+    },
+    #[error("{0} presented no host key")]
+    NoHostKey(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// What was seen of a tablet when it was first trusted, one file per address
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TrustRecord {
+    pub address: String,
+    /// SHA256 fingerprint of the host key
+    pub fingerprint: String,
+    pub device_id: Option<String>,
+    pub model: String,
+    /// seconds since epoch of the first connection
+    pub first_seen: u64,
+}
+
+/// How the host key presented compares with the trust record
+#[derive(Debug, PartialEq)]
+enum Check {
+    /// never connected to this address
+    Unknown,
+    Trusted,
+    Changed,
+}
+
+impl TrustRecord {
+    const TRUST_DIR: &'static str = "devices";
+
+    fn path(address: &str) -> PathBuf {
+        let name = sftp_rkfs::names::file_safe(address);
+        crate::config_dir()
+            .join(Self::TRUST_DIR)
+            .join(format!("{name}.json"))
+    }
+
+    pub fn load(address: &str) -> Option<Self> {
+        let json = std::fs::read_to_string(Self::path(address)).ok()?;
+        serde_json::from_str(&json)
+            .inspect_err(|e| warn!("trust record of {address} unreadable: {e}"))
+            .ok()
+    }
+
+    /// All the trust records, by address
+    pub fn list() -> Vec<Self> {
+        let dir = crate::config_dir().join(Self::TRUST_DIR);
+        let mut records = std::fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| std::fs::read_to_string(entry.path()).ok())
+            .filter_map(|json| serde_json::from_str::<Self>(&json).ok())
+            .collect::<Vec<_>>();
+        records.sort_by(|a, b| a.address.cmp(&b.address));
+        records
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        let path = Self::path(&self.address);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let json = serde_json::to_string_pretty(self).map_err(std::io::Error::other)?;
+        std::fs::write(&path, json)
+    }
+
+    /// Removes the record of `address`, returns whether there was one
+    pub fn forget(address: &str) -> std::io::Result<bool> {
+        match std::fs::remove_file(Self::path(address)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Time since the first connection
+    pub fn age(&self, now: SystemTime) -> Duration {
+        now.duration_since(SystemTime::UNIX_EPOCH + Duration::from_secs(self.first_seen))
+            .unwrap_or_default()
+    }
+}
+
+/// The password of the command line, or the one `rmkmount login` saved in the
+/// keyring, or else asked on the terminal. Tablets missing from known_hosts are
+/// trusted when rmkmount already trusts their key, otherwise asked about on the
+/// terminal too.
+pub struct PromptingAuth {
+    /// tablet address of the command line, which may be an ssh config alias
+    pub address: String,
+    pub password: Option<String>,
+}
+
+impl AuthProvider for PromptingAuth {
+    fn password(&mut self, host: &str, username: &str) -> Option<String> {
+        #[cfg(feature = "keyring")]
+        if self.password.is_none() {
+            self.password = crate::keyring::password(&self.address)
+                .inspect_err(|e| warn!("unable to read the password from the keyring: {e}"))
+                .ok()
+                .flatten();
+        }
+        if self.password.is_none() && std::io::stdin().is_terminal() {
+            // asked once, reconnections reuse it
+            self.password = crate::shell::read_password(&format!("{username}@{host}'s password: "))
+                .inspect_err(|e| error!("unable to read the password: {e}"))
+                .ok();
+        }
+        self.password.clone()
+    }
+
+    fn accept_host_key(&mut self, host: &str, fingerprint: &str) -> bool {
+        if TrustRecord::load(&self.address).is_some_and(|r| r.fingerprint == fingerprint) {
+            info!("{host} is trusted by rmkmount, adding its key to known_hosts");
+            return true;
+        }
+        if !std::io::stdin().is_terminal() {
+            error!("host key {fingerprint} of {host} is unknown: connect once from a terminal to trust it, or pin it with --host-key");
+            return false;
+        }
+        crate::shell::confirm(&format!(
+            "The authenticity of {host} can't be established, its host key fingerprint is {fingerprint}.\nTrust it and add it to ~/.ssh/known_hosts?"
+        ))
+    }
+}
+
+fn check(record: Option<&TrustRecord>, fingerprint: &str) -> Check {
+    match record {
+        None => Check::Unknown,
+        Some(record) if record.fingerprint == fingerprint => Check::Trusted,
+        Some(_) => Check::Changed,
+    }
+}
+
+/// Checks the host key of the tablet at `address` against its trust record.
+/// The first connection records it; a changed key is refused unless `retrust`
+/// is set, which records the new key instead.
+pub fn verify(address: &str, rfs: &RemarkableFs, retrust: bool) -> Result<TrustRecord, TrustError> {
+    let fingerprint = rfs
+        .host_key_fingerprint()
+        .ok_or_else(|| TrustError::NoHostKey(address.to_owned()))?;
+    let record = TrustRecord::load(address);
+    let record = match (check(record.as_ref(), &fingerprint), record) {
+        (Check::Trusted, Some(record)) => return Ok(record),
+        (Check::Changed, Some(record)) if !retrust => {
+            return Err(TrustError::HostKeyChanged {
+                address: address.to_owned(),
+                trusted: record.fingerprint,
+                presented: fingerprint,
+            })
+        }
+        (_, record) => record,
+    };
+    let (model, device_id) = match rfs.device_info() {
+        Ok(info) => (info.model, info.device_id),
+        Err(e) => {
+            warn!("unable to identify the tablet at {address}: {e}");
+            ("unknown".to_owned(), None)
+        }
+    };
+    // the same tablet with a new key keeps its history
+    let first_seen = record
+        .filter(|r| r.device_id.is_some() && r.device_id == device_id)
+        .map(|r| r.first_seen)
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        });
+    let record = TrustRecord {
+        address: address.to_owned(),
+        fingerprint,
+        device_id,
+        model,
+        first_seen,
+    };
+    record.save()?;
+    info!(
+        "{address} ({}) trusted with host key {}",
+        record.model, record.fingerprint
+    );
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let record = TrustRecord {
+            address: "10.11.99.1".to_owned(),
+            fingerprint: "SHA256:abc".to_owned(),
+            device_id: Some("RM110-313-12345".to_owned()),
+            model: "reMarkable 2.0".to_owned(),
+            first_seen: 1_700_000_000,
+        };
+        assert_eq!(check(None, "SHA256:abc"), Check::Unknown);
+        assert_eq!(check(Some(&record), "SHA256:abc"), Check::Trusted);
+        assert_eq!(check(Some(&record), "SHA256:xyz"), Check::Changed);
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_086_400);
+        assert_eq!(record.age(now).as_secs(), 86_400);
+    }
+}
@@ -0,0 +1,160 @@
+use age::secrecy::ExposeSecret;
+use age::x25519::{Identity, Recipient};
+use log::{debug, info};
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// At-rest encryption of the cache and state files in the `age` format, the
+/// key being an age identity kept in the OS keyring. Encrypted files are
+/// recognised by their age header, so they are read whether or not encryption
+/// is still enabled.
+pub struct Vault {
+    identity: Identity,
+    recipient: Recipient,
+}
+
+/// set once at startup by `--encrypt-cache`
+static VAULT: OnceLock<Vault> = OnceLock::new();
+
+const AGE_HEADER: &[u8] = b"age-encryption.org/v1\n";
+const KEYRING_ACCOUNT: &str = "cache-identity";
+
+impl Vault {
+    /// Encrypts the files written from now on, creating the key on first use
+    pub fn enable() -> std::io::Result<&'static Vault> {
+        if let Some(vault) = VAULT.get() {
+            return Ok(vault);
+        }
+        let vault = Self::load()?;
+        Ok(VAULT.get_or_init(|| vault))
+    }
+
+    /// the vault when encryption is enabled
+    pub fn enabled() -> Option<&'static Vault> {
+        VAULT.get()
+    }
+
+    /// the identity from the keyring, a new one stored there when missing
+    fn load() -> std::io::Result<Self> {
+        let identity = match crate::keyring::lookup(KEYRING_ACCOUNT)? {
+            Some(identity) => identity.parse::<Identity>().map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("cache key in the keyring: {e}"),
+                )
+            })?,
+            None => {
+                let identity = Identity::generate();
+                crate::keyring::store(
+                    KEYRING_ACCOUNT,
+                    "rmkmount cache key",
+                    identity.to_string().expose_secret(),
+                )?;
+                info!("new cache encryption key stored in the keyring");
+                identity
+            }
+        };
+        Ok(Self::new(identity))
+    }
+
+    fn new(identity: Identity) -> Self {
+        Self {
+            recipient: identity.to_public(),
+            identity,
+        }
+    }
+
+    pub fn encrypt(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        age::encrypt(&self.recipient, data).map_err(std::io::Error::other)
+    }
+
+    /// encrypts the file `plain` into `encrypted`
+    pub fn encrypt_file(&self, plain: &Path, encrypted: &Path) -> std::io::Result<()> {
+        let encryptor = age::Encryptor::with_recipients(std::iter::once(
+            &self.recipient as &dyn age::Recipient,
+        ))
+        .map_err(std::io::Error::other)?;
+        let mut writer = encryptor.wrap_output(File::create(encrypted)?)?;
+        std::io::copy(&mut File::open(plain)?, &mut writer)?;
+        writer.finish()?.flush()
+    }
+
+    /// decrypts the file at `path`
+    pub fn decrypt_file(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        age::decrypt(&self.identity, &std::fs::read(path)?).map_err(std::io::Error::other)
+    }
+
+    /// decrypts the file `encrypted` into `plain`
+    pub fn decrypt_to(&self, encrypted: &Path, plain: &Path) -> std::io::Result<()> {
+        let decryptor = age::Decryptor::new_buffered(BufReader::new(File::open(encrypted)?))
+            .map_err(std::io::Error::other)?;
+        let mut reader = decryptor
+            .decrypt(std::iter::once(&self.identity as &dyn age::Identity))
+            .map_err(std::io::Error::other)?;
+        std::io::copy(&mut reader, &mut File::create(plain)?)?;
+        Ok(())
+    }
+}
+
+/// was `data` written by age ?
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(AGE_HEADER)
+}
+
+/// Writes `data` to `path`, encrypted when the vault is enabled
+pub fn write(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    match Vault::enabled() {
+        Some(vault) => std::fs::write(path, vault.encrypt(data)?),
+        None => std::fs::write(path, data),
+    }
+}
+
+/// Reads `path`, decrypting it when it was written encrypted
+pub fn read(path: &Path) -> std::io::Result<Vec<u8>> {
+    let data = std::fs::read(path)?;
+    if !is_encrypted(&data) {
+        return Ok(data);
+    }
+    debug!("decrypting {}", path.display());
+    let vault = match Vault::enabled() {
+        Some(vault) => vault,
+        None => Vault::enable()?,
+    };
+    vault.decrypt_file(path)
+}
+
+pub fn read_to_string(path: &Path) -> std::io::Result<String> {
+    String::from_utf8(read(path)?).map_err(std::io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vault() {
+        let vault = Vault::new(Identity::generate());
+        let encrypted = vault.encrypt(b"{\"firmware\": null}").unwrap();
+        assert!(is_encrypted(&encrypted));
+        assert!(!is_encrypted(b"{\"firmware\": null}"));
+
+        let dir = std::env::temp_dir().join(format!("rmkmount-vault-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (state, plain, sealed) = (dir.join("state"), dir.join("a.pdf"), dir.join("a.age"));
+        std::fs::write(&state, &encrypted).unwrap();
+        assert_eq!(vault.decrypt_file(&state).unwrap(), b"{\"firmware\": null}");
+        let data = b"%PDF-1.7 ".repeat(10_000);
+        std::fs::write(&plain, &data).unwrap();
+        vault.encrypt_file(&plain, &sealed).unwrap();
+        std::fs::remove_file(&plain).unwrap();
+        vault.decrypt_to(&sealed, &plain).unwrap();
+        assert_eq!(std::fs::read(&plain).unwrap(), data);
+        // another key cannot read them
+        assert!(Vault::new(Identity::generate())
+            .decrypt_file(&state)
+            .is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,157 @@
+/// A question asked by the tablet during keyboard-interactive authentication
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthPrompt {
+    pub text: String,
+    /// may the answer be shown while typed ? (false for secrets)
+    pub echo: bool,
+}
+
+use std::path::PathBuf;
+
+/// Supplies credentials while authenticating to the tablet. Implement it for
+/// flows a fixed password does not cover : prompting the user, answering
+/// keyboard-interactive challenges, per host secrets...
+pub trait AuthProvider: Send {
+    /// password of `username` on `host`, None to skip password authentication
+    fn password(&mut self, host: &str, username: &str) -> Option<String>;
+
+    /// private key file of `username` on `host` and its passphrase, tried before
+    /// passwords. None (the default) skips public key authentication
+    fn identity(&mut self, host: &str, username: &str) -> Option<(PathBuf, Option<String>)> {
+        let _ = (host, username);
+        None
+    }
+
+    /// is the host key of SHA256 `fingerprint` presented by `host` trusted ? Only
+    /// asked for hosts missing from ~/.ssh/known_hosts, an accepted key is added
+    /// there. Refused by default, as ssh does without a terminal
+    fn accept_host_key(&mut self, host: &str, fingerprint: &str) -> bool {
+        let _ = (host, fingerprint);
+        false
+    }
+
+    /// answers to a keyboard-interactive challenge, one per prompt. By default
+    /// every prompt asking for a secret gets the password
+    fn respond(
+        &mut self,
+        host: &str,
+        username: &str,
+        instructions: &str,
+        prompts: &[AuthPrompt],
+    ) -> Vec<String> {
+        let _ = instructions;
+        let password = self.password(host, username).unwrap_or_default();
+        prompts
+            .iter()
+            .map(|p| {
+                if p.echo {
+                    String::new()
+                } else {
+                    password.clone()
+                }
+            })
+            .collect()
+    }
+}
+
+/// The same password for every host, the builder default
+#[derive(Clone)]
+pub struct PasswordAuth(pub String);
+
+impl AuthProvider for PasswordAuth {
+    fn password(&mut self, _host: &str, _username: &str) -> Option<String> {
+        Some(self.0.clone())
+    }
+}
+
+impl std::fmt::Debug for PasswordAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PasswordAuth(..)")
+    }
+}
+
+/// A private key file, falling back to the credentials of another provider
+/// when the tablet refuses the key
+pub struct IdentityAuth {
+    pub identity_file: PathBuf,
+    pub passphrase: Option<String>,
+    pub fallback: Box<dyn AuthProvider>,
+}
+
+impl AuthProvider for IdentityAuth {
+    fn password(&mut self, host: &str, username: &str) -> Option<String> {
+        self.fallback.password(host, username)
+    }
+
+    fn identity(&mut self, _host: &str, _username: &str) -> Option<(PathBuf, Option<String>)> {
+        Some((self.identity_file.clone(), self.passphrase.clone()))
+    }
+
+    fn accept_host_key(&mut self, host: &str, fingerprint: &str) -> bool {
+        self.fallback.accept_host_key(host, fingerprint)
+    }
+
+    fn respond(
+        &mut self,
+        host: &str,
+        username: &str,
+        instructions: &str,
+        prompts: &[AuthPrompt],
+    ) -> Vec<String> {
+        self.fallback.respond(host, username, instructions, prompts)
+    }
+}
+
+impl std::fmt::Debug for IdentityAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IdentityAuth")
+            .field("identity_file", &self.identity_file)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Adapts an AuthProvider to the ssh2 keyboard-interactive callback
+pub(crate) struct InteractivePrompter<'a> {
+    pub host: &'a str,
+    pub provider: &'a mut dyn AuthProvider,
+}
+
+impl ssh2::KeyboardInteractivePrompt for InteractivePrompter<'_> {
+    fn prompt<'p>(
+        &mut self,
+        username: &str,
+        instructions: &str,
+        prompts: &[ssh2::Prompt<'p>],
+    ) -> Vec<String> {
+        let prompts = prompts
+            .iter()
+            .map(|p| AuthPrompt {
+                text: p.text.to_string(),
+                echo: p.echo,
+            })
+            .collect::<Vec<_>>();
+        self.provider
+            .respond(self.host, username, instructions, &prompts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_answers_secret_prompts() {
+        let prompts = [
+            AuthPrompt {
+                text: "Password: ".to_string(),
+                echo: false,
+            },
+            AuthPrompt {
+                text: "Banner ack".to_string(),
+                echo: true,
+            },
+        ];
+        let answers = PasswordAuth("s3cret".to_string()).respond("rm", "root", "", &prompts);
+        assert_eq!(answers, vec!["s3cret".to_string(), String::new()]);
+    }
+}
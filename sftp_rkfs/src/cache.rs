@@ -0,0 +1,326 @@
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Payload bytes of documents kept on disk across mounts, in blocks of
+/// `BLOCK_SIZE` under `<dir>/<uid>`. The blocks of a payload are dropped once
+/// its remote modification time or size changes, the least recently used ones
+/// when the cache outgrows its capacity.
+pub struct DiskCache {
+    dir: PathBuf,
+    capacity: u64,
+    index: Mutex<Index>,
+}
+
+/// Block files in the cache, with their size and last use
+#[derive(Debug, Default)]
+struct Index {
+    blocks: HashMap<PathBuf, (u64, u64)>,
+    total: u64,
+    clock: u64,
+}
+
+impl Index {
+    fn touch(&mut self, path: &Path, size: u64) {
+        self.clock += 1;
+        let previous = self
+            .blocks
+            .insert(path.to_path_buf(), (size, self.clock))
+            .map_or(0, |(size, _)| size);
+        self.total = self.total + size - previous;
+    }
+
+    fn forget(&mut self, path: &Path) {
+        if let Some((size, _)) = self.blocks.remove(path) {
+            self.total -= size;
+        }
+    }
+
+    /// least recently used blocks to drop to fit in `capacity`
+    fn overflow(&self, capacity: u64) -> Vec<PathBuf> {
+        if self.total <= capacity {
+            return vec![];
+        }
+        let mut blocks = self.blocks.iter().collect::<Vec<_>>();
+        blocks.sort_unstable_by_key(|(_, (_, used))| *used);
+        let mut excess = self.total - capacity;
+        blocks
+            .into_iter()
+            .take_while(|(_, (size, _))| {
+                let take = excess > 0;
+                excess = excess.saturating_sub(*size);
+                take
+            })
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+}
+
+impl DiskCache {
+    /// bytes fetched and stored at once
+    pub const BLOCK_SIZE: u64 = 1024 * 1024;
+    /// remote modification time and size of the cached payload, per payload
+    const STAMP_SUFFIX: &'static str = ".stamp";
+
+    /// Opens the cache in `dir`, created if needed, holding at most `capacity`
+    /// bytes. Blocks already there are ordered by the modification time of
+    /// their files
+    pub fn open(dir: impl AsRef<Path>, capacity: u64) -> std::io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        let mut found = vec![];
+        for doc in std::fs::read_dir(&dir)?.flatten() {
+            let Ok(files) = std::fs::read_dir(doc.path()) else {
+                continue;
+            };
+            for file in files.flatten() {
+                let path = file.path();
+                let Ok(meta) = file.metadata() else {
+                    continue;
+                };
+                if is_block(&path) {
+                    found.push((meta.modified().unwrap_or(UNIX_EPOCH), path, meta.len()));
+                }
+            }
+        }
+        found.sort();
+        let mut index = Index::default();
+        for (_, path, size) in found {
+            index.touch(&path, size);
+        }
+        info!(
+            "disk cache {dir:?} holds {} blocks, {} bytes",
+            index.blocks.len(),
+            index.total
+        );
+        let cache = Self {
+            dir,
+            capacity,
+            index: Mutex::new(index),
+        };
+        cache.evict();
+        Ok(cache)
+    }
+
+    /// bytes held by the cache
+    pub fn size(&self) -> u64 {
+        self.lock().total
+    }
+
+    /// Reads `len` bytes at `offset` of the payload `name` (its extension) of
+    /// document `uid`, last modified at `modified` and `size` bytes long. Missing
+    /// blocks are fetched with `fetch`, consecutive ones in a single call.
+    /// Failures of the cache itself are only logged, the payload being fetched
+    /// then
+    #[allow(clippy::too_many_arguments)]
+    pub fn read<E>(
+        &self,
+        uid: &str,
+        name: &str,
+        modified: SystemTime,
+        size: u64,
+        offset: u64,
+        len: u64,
+        mut fetch: impl FnMut(u64, u64) -> Result<Vec<u8>, E>,
+    ) -> Result<Vec<u8>, E> {
+        let end = offset.saturating_add(len).min(size);
+        if offset >= end {
+            return Ok(vec![]);
+        }
+        let doc = self.dir.join(uid);
+        if let Err(e) = self.validate(&doc, name, modified, size) {
+            warn!("disk cache bypassed for {uid}: {e}");
+            return fetch(offset, end - offset);
+        }
+        let first = offset / Self::BLOCK_SIZE;
+        let last = (end - 1) / Self::BLOCK_SIZE;
+        let mut blocks = (first..=last)
+            .map(|block| self.load(&doc, name, block, block_len(block, size)))
+            .collect::<Vec<_>>();
+        let mut idx = 0;
+        while idx < blocks.len() {
+            if blocks[idx].is_some() {
+                idx += 1;
+                continue;
+            }
+            let run_end = (idx..blocks.len())
+                .find(|&i| blocks[i].is_some())
+                .unwrap_or(blocks.len());
+            let start = (first + idx as u64) * Self::BLOCK_SIZE;
+            let stop = ((first + run_end as u64) * Self::BLOCK_SIZE).min(size);
+            let data = fetch(start, stop - start)?;
+            if data.len() as u64 != stop - start {
+                // the payload changed meanwhile, nothing is cached from it
+                debug!("{uid} read {} bytes for {}", data.len(), stop - start);
+                return fetch(offset, end - offset);
+            }
+            for (i, chunk) in data.chunks(Self::BLOCK_SIZE as usize).enumerate() {
+                let block = first + (idx + i) as u64;
+                self.store(&doc, name, block, chunk);
+                blocks[idx + i] = Some(chunk.to_vec());
+            }
+            idx = run_end;
+        }
+        self.evict();
+        let from = (offset - first * Self::BLOCK_SIZE) as usize;
+        Ok(blocks.into_iter().flatten().flatten().collect::<Vec<_>>()
+            [from..from + (end - offset) as usize]
+            .to_vec())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Index> {
+        self.index.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// drops the blocks of payload `name` in `doc` when they were fetched from
+    /// another version of it
+    fn validate(
+        &self,
+        doc: &Path,
+        name: &str,
+        modified: SystemTime,
+        size: u64,
+    ) -> std::io::Result<()> {
+        let stamp_path = doc.join(format!("{name}{}", Self::STAMP_SUFFIX));
+        let stamp = stamp(modified, size);
+        if std::fs::read_to_string(&stamp_path).is_ok_and(|s| s == stamp) {
+            return Ok(());
+        }
+        std::fs::create_dir_all(doc)?;
+        let prefix = format!("{name}-");
+        let mut index = self.lock();
+        for file in std::fs::read_dir(doc)?.flatten() {
+            let path = file.path();
+            if is_block(&path) && file.file_name().to_string_lossy().starts_with(&prefix) {
+                std::fs::remove_file(&path)?;
+                index.forget(&path);
+            }
+        }
+        debug!("disk cache of {doc:?} {name} set to {stamp}");
+        std::fs::write(stamp_path, stamp)
+    }
+
+    /// content of a cached block, when complete
+    fn load(&self, doc: &Path, name: &str, block: u64, len: u64) -> Option<Vec<u8>> {
+        let path = block_path(doc, name, block);
+        let data = std::fs::read(&path)
+            .ok()
+            .filter(|d| d.len() as u64 == len)?;
+        if let Err(e) = std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|f| f.set_modified(SystemTime::now()))
+        {
+            debug!("unable to mark {path:?} as used: {e}");
+        }
+        self.lock().touch(&path, len);
+        Some(data)
+    }
+
+    fn store(&self, doc: &Path, name: &str, block: u64, data: &[u8]) {
+        let path = block_path(doc, name, block);
+        // written aside then renamed, a block file is always complete
+        let mut part = path.clone().into_os_string();
+        part.push(".part");
+        match std::fs::write(&part, data).and_then(|_| std::fs::rename(&part, &path)) {
+            Ok(()) => self.lock().touch(&path, data.len() as u64),
+            Err(e) => warn!("unable to cache {path:?}: {e}"),
+        }
+    }
+
+    /// removes the least recently used blocks beyond the capacity
+    fn evict(&self) {
+        let mut index = self.lock();
+        let dropped = index.overflow(self.capacity);
+        for path in &dropped {
+            if let Err(e) = std::fs::remove_file(path) {
+                debug!("unable to remove {path:?}: {e}");
+            }
+            index.forget(path);
+        }
+        if !dropped.is_empty() {
+            debug!("disk cache evicted {} blocks", dropped.len());
+        }
+    }
+}
+
+fn block_path(doc: &Path, name: &str, block: u64) -> PathBuf {
+    doc.join(format!("{name}-{block}"))
+}
+
+/// is `path` a block file (`<name>-<number>`) ?
+fn is_block(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .and_then(|n| n.rsplit_once('-'))
+        .is_some_and(|(_, block)| !block.is_empty() && block.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// length of `block` of a payload of `size` bytes
+fn block_len(block: u64, size: u64) -> u64 {
+    size.saturating_sub(block * DiskCache::BLOCK_SIZE)
+        .min(DiskCache::BLOCK_SIZE)
+}
+
+fn stamp(modified: SystemTime, size: u64) -> String {
+    let modified = modified
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    format!("{modified} {size}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_disk_cache() {
+        let dir = std::env::temp_dir().join(format!("rkfs-disk-cache-{}", std::process::id()));
+        let block = DiskCache::BLOCK_SIZE;
+        let payload = (0..block * 3 + 10).map(|i| i as u8).collect::<Vec<_>>();
+        let size = payload.len() as u64;
+        let fetched = Cell::new(0);
+        let fetch = |start: u64, len: u64| {
+            fetched.set(fetched.get() + len);
+            Ok::<_, ()>(payload[start as usize..(start + len) as usize].to_vec())
+        };
+        let cache = DiskCache::open(&dir, 2 * block).unwrap();
+        let t0 = UNIX_EPOCH;
+        let read =
+            |offset: u64, len: u64, at| cache.read("doc", "pdf", at, size, offset, len, fetch);
+        assert_eq!(
+            read(block - 5, 10, t0),
+            Ok(payload[block as usize - 5..][..10].to_vec())
+        );
+        assert_eq!(fetched.get(), 2 * block);
+        // served from disk
+        assert_eq!(
+            read(block, 100, t0),
+            Ok(payload[block as usize..][..100].to_vec())
+        );
+        assert_eq!(fetched.get(), 2 * block);
+        // the last block is short, reads stop at the end of the payload
+        assert_eq!(
+            read(size - 4, 100, t0),
+            Ok(payload[size as usize - 4..].to_vec())
+        );
+        assert_eq!(fetched.get(), 2 * block + 10);
+        // over the capacity: block 0, the least recently used, went
+        assert_eq!(cache.size(), block + 10);
+        assert!(!dir.join("doc/pdf-0").exists());
+        // another version of the payload drops its blocks
+        let t1 = UNIX_EPOCH + std::time::Duration::from_secs(60);
+        assert_eq!(read(block, 1, t1), Ok(vec![payload[block as usize]]));
+        assert_eq!(fetched.get(), 3 * block + 10);
+        assert_eq!(cache.size(), block);
+        drop(cache);
+        assert_eq!(DiskCache::open(&dir, 2 * block).unwrap().size(), block);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(is_block(Path::new("/c/doc/epub-12")));
+        assert!(!is_block(Path::new("/c/doc/epub.stamp")));
+        assert!(!is_block(Path::new("/c/doc/epub-1.part")));
+    }
+}
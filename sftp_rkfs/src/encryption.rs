@@ -0,0 +1,67 @@
+use std::path::Path;
+
+/// How a metadata or content file is stored, told from its bytes. Those files
+/// are json text; newer devices may keep some of them encrypted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protection {
+    Plain,
+    /// encrypted with `scheme`, "unknown" for binary data without a known magic
+    Encrypted {
+        scheme: &'static str,
+    },
+}
+
+/// magics of the encryption formats recognised, with their scheme name
+const MAGICS: [(&[u8], &str); 4] = [
+    (b"age-encryption.org/", "age"),
+    (b"-----BEGIN PGP MESSAGE", "openpgp"),
+    (b"Salted__", "openssl"),
+    (b"LUKS\xba\xbe", "luks"),
+];
+
+impl Protection {
+    pub fn of(data: &[u8]) -> Self {
+        if let Some((_, scheme)) = MAGICS.iter().find(|(magic, _)| data.starts_with(magic)) {
+            return Self::Encrypted { scheme };
+        }
+        if data.contains(&0) || std::str::from_utf8(data).is_err() {
+            return Self::Encrypted { scheme: "unknown" };
+        }
+        Self::Plain
+    }
+}
+
+/// Decrypts tablet files stored encrypted. None is installed by default, such
+/// items are then shown as `<uid>.encrypted` placeholders.
+pub trait DecryptionProvider: Send {
+    /// name reported in logs and on placeholders
+    fn name(&self) -> &str;
+    /// plain content of the remote file `path` holding `data`, encrypted with
+    /// `scheme`. None when the provider does not handle it
+    fn decrypt(&self, path: &Path, scheme: &str, data: &[u8]) -> Option<Result<Vec<u8>, String>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protection() {
+        assert_eq!(
+            Protection::of(br#"{"visibleName": "Notes"}"#),
+            Protection::Plain
+        );
+        assert_eq!(
+            Protection::of(b"age-encryption.org/v1\n-> X25519 abc"),
+            Protection::Encrypted { scheme: "age" }
+        );
+        assert_eq!(
+            Protection::of(&[0x8c, 0x0d, 0x04, 0x09]),
+            Protection::Encrypted { scheme: "unknown" }
+        );
+        assert_eq!(
+            Protection::of(b"{\"a\":\0}"),
+            Protection::Encrypted { scheme: "unknown" }
+        );
+    }
+}
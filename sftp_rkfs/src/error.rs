@@ -0,0 +1,315 @@
+use std::fmt::Display;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Failures talking to the tablet: ssh session, sftp and remote commands
+#[derive(Debug, Error)]
+pub enum TransportError {
+    #[error("cannot connect to {0}: {1}")]
+    Connect(String, #[source] std::io::Error),
+    #[error(transparent)]
+    Ssh2(#[from] ssh2::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("{0} presented no host key")]
+    NoHostKey(String),
+    #[error("host key of {host} is {presented}, not the pinned {expected}")]
+    HostKeyMismatch {
+        host: String,
+        expected: String,
+        presented: String,
+    },
+    #[error("host key of {host} ({fingerprint}) is not the one in known_hosts: it may be another device, or someone in between (once checked, remove the former key with `ssh-keygen -R '{host}'`)")]
+    HostKeyChanged { host: String, fingerprint: String },
+    #[error("host key {fingerprint} of {host} is not trusted")]
+    HostKeyRejected { host: String, fingerprint: String },
+    #[error("mount sharing its connection: {0}")]
+    Shared(String),
+}
+
+/// Tablet files that do not have the expected content
+#[derive(Debug, Error)]
+pub enum SchemaError {
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("invalid document: {0}")]
+    Invalid(String),
+    #[error("stored encrypted ({0}), no decryption provider handles it")]
+    Encrypted(String),
+    #[error("decryption by {provider} failed: {reason}")]
+    Decryption { provider: String, reason: String },
+}
+
+/// Filesystem level failures: unknown nodes, wrong node kinds, bad paths
+#[derive(Debug, Error)]
+pub enum FsError {
+    #[error("Duplicated node")]
+    NodeDuplicated,
+    #[error("Node not found {0}")]
+    NodeNotFound(usize),
+    #[error("Node io error {0}")]
+    NodeIoError(libc::c_int),
+    #[error("{0} is not a document")]
+    NotADocument(String),
+    #[error("{0} is not a collection")]
+    NotACollection(String),
+    #[error("unsupported file {0}")]
+    Unsupported(String),
+    #[error("invalid path: {0}")]
+    InvalidPath(String),
+    #[error("document root {0:?} {1}")]
+    DocumentRoot(PathBuf, RootProblem),
+}
+
+/// What is wrong with the document root on the tablet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum RootProblem {
+    #[error("does not exist")]
+    Missing,
+    #[error("is not a folder")]
+    NotAFolder,
+    #[error("cannot be read")]
+    Unreadable,
+}
+
+/// Failures producing files out of documents (bundles, exports...)
+#[derive(Debug, Error)]
+pub enum RenderError {
+    #[error(transparent)]
+    Archive(#[from] zip::result::ZipError),
+}
+
+#[derive(Debug, Error)]
+pub enum RemarkableError {
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error(transparent)]
+    Schema(#[from] SchemaError),
+    #[error(transparent)]
+    Fs(#[from] FsError),
+    #[error(transparent)]
+    Render(#[from] RenderError),
+    /// `source` annotated with what was being done (path, uid, operation)
+    #[error("{context}: {source}")]
+    Context {
+        context: String,
+        source: Box<RemarkableError>,
+    },
+}
+
+/// Builder settings that cannot work, reported by `RemarkableFsBuilder::try_build`
+/// before anything is attempted, or the failure to connect with valid ones
+#[derive(Debug, Error)]
+pub enum BuildError {
+    #[error("mountpoint not provided")]
+    MissingMountpoint,
+    #[error("document root {0:?} is not an absolute path")]
+    RelativeDocumentRoot(PathBuf),
+    #[error("empty host")]
+    EmptyHost,
+    #[error("empty user")]
+    EmptyUser,
+    #[error("identity file {0:?} not found")]
+    MissingIdentity(PathBuf),
+    #[error("invalid url: {0}")]
+    InvalidUrl(&'static str),
+    #[error(transparent)]
+    Connect(#[from] RemarkableError),
+}
+
+impl From<BuildError> for RemarkableError {
+    fn from(e: BuildError) -> Self {
+        match e {
+            BuildError::Connect(e) => e,
+            e => FsError::InvalidPath(e.to_string()).into(),
+        }
+    }
+}
+
+impl From<ssh2::Error> for RemarkableError {
+    fn from(e: ssh2::Error) -> Self {
+        TransportError::from(e).into()
+    }
+}
+
+impl From<std::io::Error> for RemarkableError {
+    fn from(e: std::io::Error) -> Self {
+        TransportError::from(e).into()
+    }
+}
+
+impl From<serde_json::Error> for RemarkableError {
+    fn from(e: serde_json::Error) -> Self {
+        SchemaError::from(e).into()
+    }
+}
+
+impl From<zip::result::ZipError> for RemarkableError {
+    fn from(e: zip::result::ZipError) -> Self {
+        RenderError::from(e).into()
+    }
+}
+
+impl RemarkableError {
+    /// wraps the error with what was being done when it happened
+    pub fn context(self, context: impl Display) -> Self {
+        Self::Context {
+            context: context.to_string(),
+            source: Box::new(self),
+        }
+    }
+
+    /// the error without its context layers
+    pub fn root(&self) -> &Self {
+        match self {
+            Self::Context { source, .. } => source.root(),
+            e => e,
+        }
+    }
+
+    /// was the connection to the tablet lost (reset, closed, tablet rebooted) ?
+    /// Such failures are worth a reconnection, unlike errors reported by the tablet
+    pub fn is_connection_lost(&self) -> bool {
+        // LIBSSH2_ERROR_SOCKET_SEND, _TIMEOUT, _SOCKET_DISCONNECT,
+        // _CHANNEL_CLOSED, _SOCKET_TIMEOUT, _SOCKET_RECV
+        const SESSION_LOST: [libc::c_int; 6] = [-7, -9, -13, -26, -30, -43];
+        // LIBSSH2_FX_NO_CONNECTION, LIBSSH2_FX_CONNECTION_LOST
+        const SFTP_LOST: [libc::c_int; 2] = [6, 7];
+        match self.root() {
+            Self::Transport(TransportError::Ssh2(e)) => match e.code() {
+                ssh2::ErrorCode::Session(code) => SESSION_LOST.contains(&code),
+                ssh2::ErrorCode::SFTP(code) => SFTP_LOST.contains(&code),
+            },
+            Self::Transport(TransportError::Io(e)) => matches!(
+                e.kind(),
+                std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::NotConnected
+                    | std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::UnexpectedEof
+            ),
+            _ => false,
+        }
+    }
+
+    /// is a tablet file broken for good (unparsable, unreadable by the ssh
+    /// user) ? Trying again will not help, unlike with transport failures
+    pub fn is_corrupt(&self) -> bool {
+        // LIBSSH2_FX_PERMISSION_DENIED
+        const SFTP_DENIED: libc::c_int = 3;
+        match self.root() {
+            Self::Schema(_) => true,
+            Self::Transport(TransportError::Ssh2(e)) => {
+                e.code() == ssh2::ErrorCode::SFTP(SFTP_DENIED)
+            }
+            _ => false,
+        }
+    }
+
+    /// is the tablet file missing ?
+    pub fn is_not_found(&self) -> bool {
+        // LIBSSH2_FX_NO_SUCH_FILE
+        const SFTP_NO_SUCH_FILE: libc::c_int = 2;
+        match self.root() {
+            Self::Transport(TransportError::Ssh2(e)) => {
+                e.code() == ssh2::ErrorCode::SFTP(SFTP_NO_SUCH_FILE)
+            }
+            Self::Transport(TransportError::Io(e)) => e.kind() == std::io::ErrorKind::NotFound,
+            _ => false,
+        }
+    }
+
+    /// is a tablet file stored encrypted, without a way to decrypt it ?
+    pub fn is_encrypted(&self) -> bool {
+        matches!(
+            self.root(),
+            Self::Schema(SchemaError::Encrypted(_) | SchemaError::Decryption { .. })
+        )
+    }
+
+    /// the filesystem error, if this is one
+    pub fn fs_error(&self) -> Option<&FsError> {
+        match self.root() {
+            Self::Fs(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Attaches context to any result whose error converts into a RemarkableError
+pub trait ErrorContext<T> {
+    fn context(self, context: impl Display) -> Result<T, RemarkableError>;
+    fn with_context<C: Display>(self, context: impl FnOnce() -> C) -> Result<T, RemarkableError>;
+}
+
+impl<T, E: Into<RemarkableError>> ErrorContext<T> for Result<T, E> {
+    fn context(self, context: impl Display) -> Result<T, RemarkableError> {
+        self.map_err(|e| e.into().context(context))
+    }
+
+    fn with_context<C: Display>(self, context: impl FnOnce() -> C) -> Result<T, RemarkableError> {
+        self.map_err(|e| e.into().context(context()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_chain() {
+        let res: Result<(), FsError> = Err(FsError::NodeNotFound(12));
+        let err = res
+            .context("reading 'Work/Spec.pdf'")
+            .with_context(|| "exporting Work")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "exporting Work: reading 'Work/Spec.pdf': Node not found 12"
+        );
+        assert!(matches!(err.fs_error(), Some(FsError::NodeNotFound(12))));
+        assert!(!err.is_connection_lost());
+    }
+
+    #[test]
+    fn test_connection_lost() {
+        let reset = RemarkableError::from(ssh2::Error::new(
+            ssh2::ErrorCode::Session(-43),
+            "Failure reading from socket",
+        ))
+        .context("opening \"doc.pdf\"");
+        assert!(reset.is_connection_lost());
+        let missing =
+            RemarkableError::from(ssh2::Error::new(ssh2::ErrorCode::SFTP(2), "no such file"));
+        assert!(!missing.is_connection_lost());
+        let eof = RemarkableError::from(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+        assert!(eof.is_connection_lost());
+        assert!(!eof.is_corrupt());
+        // a timed out session is in an unknown state, a new one is needed
+        let timeout = RemarkableError::from(ssh2::Error::new(
+            ssh2::ErrorCode::Session(-9),
+            "Timed out waiting on socket",
+        ));
+        assert!(timeout.is_connection_lost());
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let truncated = serde_json::from_str::<serde_json::Value>("{\"pages\": [")
+            .map_err(RemarkableError::from)
+            .context("parsing content of abc")
+            .unwrap_err();
+        assert!(truncated.is_corrupt());
+        let denied = RemarkableError::from(ssh2::Error::new(
+            ssh2::ErrorCode::SFTP(3),
+            "permission denied",
+        ));
+        assert!(denied.is_corrupt());
+        let missing =
+            RemarkableError::from(ssh2::Error::new(ssh2::ErrorCode::SFTP(2), "no such file"));
+        assert!(!missing.is_corrupt());
+        assert!(missing.context("reading abc.content").is_not_found());
+        assert!(!denied.is_not_found());
+    }
+}
@@ -1,14 +1,94 @@
 use super::RemarkableFsBuilder;
+use crate::cache::DiskCache;
+use crate::encryption::DecryptionProvider;
+use crate::layout::{StorageLayout, XochitlLayout};
+use crate::names::{self, CollisionPolicy, NamePolicy};
 use crate::nodes::{FuserChild, Node};
-use crate::sshutils::{SshFileStat, SshWrapper};
-use crate::RemarkableError;
-use log::{debug, error, info, warn};
+use crate::share::ShareServer;
+use crate::sshutils::{Prefetch, SshFileStat, SshWrapper};
+use crate::trace::TraceScope;
+use crate::{ErrorContext, FsError, RemarkableError};
+use log::{debug, error, info, trace, warn};
 use std::borrow::{Borrow, BorrowMut};
 use std::ops::Deref;
-use std::path::PathBuf;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
 use std::usize;
-use std::{cell::Ref, cell::RefCell, collections::HashMap};
+use std::{cell::RefCell, collections::HashMap};
+
+mod access;
+mod changes;
+mod clock;
+mod coalesce;
+mod collisions;
+mod control;
+mod device;
+mod encrypted;
+mod features;
+mod flush;
+mod forward;
+mod fsck;
+mod ghosts;
+mod handles;
+mod health;
+mod incoming;
+mod interrupt;
+mod jobs;
+mod moves;
+mod multi;
+mod pages;
+mod paths;
+mod progress;
+mod quarantine;
+mod readonly;
+mod recovery;
+mod refresh;
+mod rendering;
+mod root;
+mod scan;
+mod schema;
+#[cfg(feature = "scripting")]
+mod script;
+mod sorting;
+mod trash;
+mod tree;
+mod usage;
+mod version;
+mod views;
+mod volume;
+mod writelimit;
+use access::ReadCache;
+use collisions::Naming;
+use flush::DeferredErrors;
+use ghosts::Ghost;
+use handles::HandleTable;
+use health::LastError;
+use incoming::PendingUpload;
+use jobs::Scheduler;
+use progress::Transfer;
+use quarantine::Quarantined;
+use readonly::WriteRefusals;
+use refresh::Refresher;
+use scan::ScanState;
+use schema::{SchemaFile, SchemaReport};
+use tree::TreeIndex;
+use views::{VirtualDir, VirtualFile};
+use writelimit::MutationBudget;
+
+pub use crate::nodes::RenderedSize;
+pub use changes::{ChangeEvent, ChangeKind, TreeSnapshot};
+pub use device::{DeviceInfo, StorageInfo, TabletInfo};
+pub use flush::WritePolicy;
+pub use fsck::{FsckCategory, FsckFinding, FsckReport};
+pub use jobs::{JobBudget, JobKind};
+pub use multi::MultiDeviceFs;
+pub use paths::PathResolver;
+pub use scan::ScanReport;
+pub use sorting::SortPolicy;
+pub use trash::TrashedItem;
+pub use usage::ListedItem;
+pub use views::date_from_ms;
+pub use writelimit::WriteLimit;
 
 impl From<&Node> for fuser::FileAttr {
     fn from(node: &Node) -> Self {
@@ -33,16 +113,207 @@ impl From<&Node> for fuser::FileAttr {
     }
 }
 
+/// Synthetic ownership and modes presented by the mount, independent from the
+/// remote stat (xochitl files are root owned 644 which confuses local users)
+#[derive(Debug, Clone, Copy)]
+pub struct PermissionPolicy {
+    pub file_mode: u16,
+    pub dir_mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Default for PermissionPolicy {
+    /// read-only documents and browsable collections owned by the mounting user
+    fn default() -> Self {
+        // SAFETY: getuid/getgid have no preconditions and cannot fail
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        Self {
+            file_mode: 0o444,
+            dir_mode: 0o555,
+            uid,
+            gid,
+        }
+    }
+}
+
+impl PermissionPolicy {
+    /// overrides ownership and mode of `attr` according to the policy
+    fn apply(&self, attr: &mut fuser::FileAttr) {
+        attr.perm = match attr.kind {
+            fuser::FileType::Directory => self.dir_mode,
+            _ => self.file_mode,
+        };
+        attr.uid = self.uid;
+        attr.gid = self.gid;
+    }
+}
+
 pub struct RemarkableFs {
     session: SshWrapper,
     document_root: PathBuf,
     mount_point: PathBuf,
     nodes: Vec<RefCell<Node>>,
     uid_map: HashMap<String, usize>,
+    virtual_dirs: HashMap<usize, VirtualDir>,
+    virtual_files: HashMap<usize, VirtualFile>,
+    /// metadata files listed for each collection, loaded into nodes on demand
+    listings: HashMap<usize, DirListing>,
+    /// FileAttr computed for each node, valid while the node generation is unchanged
+    attr_cache: RefCell<HashMap<usize, (u64, fuser::FileAttr)>>,
+    getattr_count: u64,
+    started: SystemTime,
+    last_error: Option<LastError>,
+    /// items whose files are corrupt or encrypted, by inode of their marker
+    quarantined: HashMap<usize, Quarantined>,
+    /// items missing from the last listing of their collection, by uid
+    ghosts: HashMap<String, Ghost>,
+    ghost_grace: Duration,
+    show_ghosts: bool,
+    /// payload reads of open documents, reported by `.progress` files
+    transfers: HashMap<usize, Transfer>,
+    /// file handles of open documents, with their remote files
+    handles: RefCell<HandleTable>,
+    /// payload fetched ahead of the reads of open documents
+    read_caches: RefCell<HashMap<usize, ReadCache>>,
+    /// payload blocks kept on disk across mounts, if enabled
+    disk_cache: Option<DiskCache>,
+    /// read errors reported again when the file is closed
+    deferred_errors: DeferredErrors,
+    write_policy: WritePolicy,
+    read_only: bool,
+    write_refusals: WriteRefusals,
+    /// changes sent to the tablet, limited in rate
+    mutations: MutationBudget,
+    /// umask of the processes that created files and collections, by inode
+    umasks: HashMap<usize, u16>,
+    /// memory allowed for parsed document contents before the coldest are evicted
+    detail_budget: usize,
+    detail_bytes: usize,
+    detail_clock: u64,
+    permissions: PermissionPolicy,
+    name_policy: NamePolicy,
+    collision_policy: CollisionPolicy,
+    sort_policy: SortPolicy,
+    /// are `.rm` page files exposed in `<name>.pages` folders ?
+    raw_pages: bool,
+    /// label of the mount in file managers
+    volume_name: String,
+    /// program using the library, reported by `/.version`
+    client_version: Option<String>,
+    device_versions: RefCell<Option<version::DeviceVersions>>,
+    /// batches fetched at once by the scan at mount, 0 for no scan
+    scan_jobs: usize,
+    /// listing entries fetched by each scan command
+    scan_batch_size: usize,
+    /// channels fetching the entries of a collection being listed at once, 0
+    /// for entry by entry
+    listing_channels: usize,
+    /// remote files fetched ahead by the scan, used instead of a round trip
+    prefetched: RefCell<Prefetch>,
+    /// collections of the whole library, listings are served from
+    tree_index: RefCell<Option<TreeIndex>>,
+    /// age of the tree index from which it is fetched again
+    tree_refresh: Duration,
+    /// whole library fetched in one command when the scan starts
+    bulk_load: bool,
+    /// library scan going on between requests
+    scan: Option<ScanState>,
+    last_scan: Option<ScanReport>,
+    /// period of the polling of the tablet for changes, if polling
+    refresh_interval: Option<Duration>,
+    refresher: Option<Refresher>,
+    /// the last reconnection failed, the session is reconnected before the
+    /// next remote operation
+    disconnected: bool,
+    /// end of the last remote operation, to check the session after a pause
+    last_remote_op: Option<Instant>,
+    /// unknown json fields found, when checking the schema
+    schema: RefCell<Option<SchemaReport>>,
+    /// pdf and epub files being copied into collections, by ino
+    pending_uploads: HashMap<usize, PendingUpload>,
+    /// where the private folder of the spool files is created
+    spool_parent: PathBuf,
+    /// private folder of the spool files, while there are pending uploads
+    spool_dir: Option<PathBuf>,
+    /// folders of `/.views`, defined by a views script
+    #[cfg(feature = "scripting")]
+    scripted_views: Vec<script::ScriptedView>,
+    layout: Box<dyn StorageLayout>,
+    decryption: Option<Box<dyn DecryptionProvider>>,
+    coalesce_reads: bool,
+    read_coalescer: Option<coalesce::ReadCoalescer>,
+    /// background jobs, waiting while requests are served
+    jobs: Scheduler,
+    /// control socket sharing the connection with other processes, if any
+    share_server: Option<ShareServer>,
+}
+
+/// Metadata files of a collection as of its last listing. Entries are only
+/// turned into nodes when readdir (or a lookup) reaches them, so that large
+/// collections answer their first entries quickly.
+#[derive(Debug, Default)]
+struct DirListing {
+    /// readdir position of every uid ever listed in the collection. Positions are
+    /// never reused, so offsets handed to the kernel stay valid across refreshes
+    positions: HashMap<String, usize>,
+    /// (position, metadata file) of the last listing, ordered by position
+    entries: Vec<(usize, String)>,
+    /// number of leading `entries` loaded into nodes since the last listing
+    loaded: usize,
+    /// children in the order of the sort policy, once handed out sorted
+    sorted: Option<Vec<FuserChild>>,
+}
+
+impl DirListing {
+    /// positions below this one are kept for the virtual views of the root node
+    const FIRST_POSITION: usize = 16;
+    /// positions taken by each uid : the document, its paginated pdf and its
+    /// raw pages folder, if any
+    const POSITIONS_PER_ENTRY: usize = 3;
+
+    /// replaces the entries with the metadata files `files`
+    fn refresh(&mut self, files: Vec<String>) {
+        let mut entries = files
+            .into_iter()
+            .map(|file| {
+                let uid = Path::new(&file)
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .unwrap_or_default()
+                    .to_owned();
+                let next = Self::FIRST_POSITION + Self::POSITIONS_PER_ENTRY * self.positions.len();
+                (*self.positions.entry(uid).or_insert(next), file)
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|(position, _)| *position);
+        self.entries = entries;
+        self.loaded = 0;
+        self.sorted = None;
+    }
+
+    /// takes the next position for `key`, an item that is not listed
+    fn reserve_position(&mut self, key: &str) -> usize {
+        let next = Self::FIRST_POSITION + Self::POSITIONS_PER_ENTRY * self.positions.len();
+        *self.positions.entry(key.to_owned()).or_insert(next)
+    }
+
+    /// index of the first entry with a position at or after `position`
+    fn first_entry_from(&self, position: usize) -> usize {
+        self.entries
+            .partition_point(|(p, _)| p + Self::POSITIONS_PER_ENTRY - 1 < position)
+    }
 }
 
 /// private funcs and consts
 impl RemarkableFs {
+    /// only one getattr out of GETATTR_LOG_SAMPLING is logged
+    const GETATTR_LOG_SAMPLING: u64 = 100;
+    const XATTR_REMOTE_PERM: &'static str = "user.remarkable.remote_perm";
+    /// holds REMARKABLE_RELEASE_VERSION, the firmware version shown in settings
+    const FIRMWARE_CONF: &'static str = "/usr/share/remarkable/update.conf";
+    pub const DEFAULT_DETAIL_BUDGET: usize = 64 * 1024 * 1024;
+
     /// Main assuption : all metadata files are under remarkable root folder
     /// So stripping the filename gives the uid
     /// At this point, an attempt to load node's metadata will be performed
@@ -57,110 +328,520 @@ impl RemarkableFs {
             let node = self.get_node(node_id).unwrap();
             if node.borrow().needs_updating(filestat) {
                 info!("refreshing metadata for node {node_id} : {filestat:?}");
-                let strmetadata = self.session.read_as_string(filestat.get_path())?;
+                let strmetadata = self.read_remote(filestat.get_path())?;
+                self.check_schema(SchemaFile::Metadata, &uid, &strmetadata);
                 let _res = node
                     .borrow_mut()
-                    .update_metadata(filestat, parent_ino, &strmetadata)?;
+                    .update_metadata(filestat, parent_ino, &strmetadata)
+                    .with_context(|| format!("parsing metadata of {uid}"))?;
+                self.add_paginated_node(node_id);
             } else {
                 debug!("unchanged node {node_id}")
             }
-            Ok(node)
+            Ok(&self.nodes[node_id])
         } else {
             let nodeid = self.nodes.len();
             debug!("adding node with metadata {nodeid} : {filestat:?}");
-            let strmetadata = self.session.read_as_string(filestat.get_path())?;
-            let mut node = Node::from_metadata(nodeid, parent_ino, filestat, &strmetadata)?;
+            let strmetadata = self.read_remote(filestat.get_path())?;
+            self.check_schema(SchemaFile::Metadata, &uid, &strmetadata);
+            let mut node = Node::from_metadata(nodeid, parent_ino, filestat, &strmetadata)
+                .with_context(|| format!("parsing metadata of {uid}"))?;
             if node.borrow().is_document() {
-                let content_path = node.borrow().get_content_path(&self.document_root);
-                //PathBuf::new();
-                //                content_path.push(&self.document_root);
-                //                content_path.push(node.borrow().get_unique());
-                //                content_path.set_extension(Self::CONTENT_EXTENSION);
+                let content_path = self
+                    .layout
+                    .content_path(&self.document_root, node.get_unique());
                 info!("adding content for node {nodeid} : {content_path:?}");
-                let _res = self.session.read_as_string(&content_path)?;
-                node.borrow_mut().update_content(&_res)?;
-                if let Some(target) = node.borrow().get_target_file_path(&self.document_root) {
+                match self.read_remote(&content_path) {
+                    Ok(content) => {
+                        self.check_schema(SchemaFile::Content, &uid, &content);
+                        node.borrow_mut()
+                            .update_content(&content)
+                            .with_context(|| format!("parsing content of {uid}"))?;
+                    }
+                    Err(e) if e.is_not_found() => warn!("{uid} has no content file"),
+                    Err(e) => return Err(e),
+                }
+                if !node.has_file_type() {
+                    self.infer_payload(&mut node);
+                }
+                if let Some(target) = self.payload_path(&node) {
                     debug!("stat content for size {target:?}");
                     // stat file for size
-                    let mut fstat = self.session.stat(target.to_str().unwrap_or(""))?;
+                    let mut fstat = self.stat_remote(&names::remote_str(&target))?;
                     node.borrow_mut().update_target_fstat(&mut fstat);
                 }
             }
             self.uid_map.insert(uid, nodeid);
             self.nodes.push(RefCell::new(node));
+            self.track_details(nodeid);
+            self.add_paginated_node(nodeid);
             Ok(&self.nodes[nodeid])
         }
     }
 
+    /// Accounts for the parsed content of `ino` just loaded, evicting the least
+    /// recently used contents when the budget is exceeded
+    fn track_details(&mut self, ino: usize) {
+        self.detail_clock += 1;
+        let size = {
+            let mut node = self.nodes[ino].borrow_mut();
+            node.touch(self.detail_clock);
+            node.details_size()
+        };
+        self.detail_bytes += size;
+        if self.detail_bytes > self.detail_budget {
+            self.evict_cold_details(ino);
+        }
+    }
+
+    /// Drops parsed contents, coldest first, until 3/4 of the budget is used. Open
+    /// nodes and `keep` are left alone; evicted nodes keep name, parent and type.
+    fn evict_cold_details(&mut self, keep: usize) {
+        let target = self.detail_budget / 4 * 3;
+        let cold = self
+            .nodes
+            .iter()
+            .map(|n| n.borrow())
+            .filter(|n| n.has_details() && n.handles() == 0 && n.get_ino() != keep)
+            .map(|n| (n.last_used(), n.get_ino(), n.details_size()))
+            .collect::<Vec<_>>();
+        let victims = lru_victims(cold, self.detail_bytes, target);
+        for &ino in &victims {
+            let freed = self.nodes[ino].borrow_mut().evict_details();
+            self.detail_bytes = self.detail_bytes.saturating_sub(freed);
+        }
+        info!(
+            "evicted contents of {} nodes, {} bytes kept",
+            victims.len(),
+            self.detail_bytes
+        );
+    }
+
+    /// Makes sure the parsed content of document `ino` is in memory, reloading it
+    /// from the tablet if it was evicted
+    fn ensure_details(&mut self, ino: usize) -> Result<(), RemarkableError> {
+        if self.virtual_files.contains_key(&ino) {
+            return Ok(());
+        }
+        let uid = {
+            let node = self
+                .get_node(ino)
+                .ok_or(FsError::NodeNotFound(ino))?
+                .borrow();
+            if !node.has_content_file() {
+                return Ok(());
+            }
+            if node.has_details() {
+                drop(node);
+                self.detail_clock += 1;
+                self.nodes[ino].borrow_mut().touch(self.detail_clock);
+                return Ok(());
+            }
+            node.get_unique().to_owned()
+        };
+        let content_path = self.layout.content_path(&self.document_root, &uid);
+        debug!("reloading content for node {ino} : {content_path:?}");
+        let content = self.session.read_as_string(&content_path)?;
+        self.check_schema(SchemaFile::Content, &uid, &content);
+        self.nodes[ino]
+            .borrow_mut()
+            .update_content(&content)
+            .with_context(|| format!("parsing content of {uid}"))?;
+        self.track_details(ino);
+        Ok(())
+    }
+
+    /// xochitl keeps the paginated rendering of an epub as `<uid>.pdf`: expose it as
+    /// an extra read-only `<name>.paginated.pdf` document next to the epub
+    fn add_paginated_node(&mut self, doc_ino: usize) {
+        let (uid, name, parent) = {
+            let doc = self.nodes[doc_ino].borrow();
+            if !doc.is_epub() {
+                return;
+            }
+            (
+                doc.get_unique().to_owned(),
+                doc.get_basename().unwrap_or_default().to_owned(),
+                doc.get_parent(),
+            )
+        };
+        let key = format!("{uid}{}", Node::PAGINATED_SUFFIX);
+        if let Some(&ino) = self.uid_map.get(&key) {
+            self.nodes[ino].borrow_mut().set_parent(parent);
+            return;
+        }
+        let pdf = self.layout.payload_path(&self.document_root, &uid, "pdf");
+        match self.stat_remote(&names::remote_str(&pdf)) {
+            Ok(fstat) => {
+                let ino = self.nodes.len();
+                debug!("adding paginated pdf {ino} for epub {doc_ino}");
+                self.nodes.push(RefCell::new(Node::new_alternate_payload(
+                    ino,
+                    parent,
+                    &format!("{name}{}", Node::PAGINATED_SUFFIX),
+                    "pdf",
+                    fstat,
+                )));
+                self.uid_map.insert(key, ino);
+            }
+            Err(_) => debug!("epub {uid} has no paginated pdf yet"),
+        }
+    }
+
+    /// Types a document without file type from the payload present next to its
+    /// metadata, for documents lacking a content file (older firmware, partial
+    /// sync). Notebooks have no payload and stay untyped
+    fn infer_payload(&self, node: &mut Node) {
+        if !node.is_document() {
+            return;
+        }
+        for extension in ["pdf", "epub"] {
+            let payload =
+                self.layout
+                    .payload_path(&self.document_root, node.get_unique(), extension);
+            if self.stat_remote(&names::remote_str(&payload)).is_ok() {
+                info!("{} typed {extension} from its payload", node.get_unique());
+                node.infer_payload(extension);
+                return;
+            }
+        }
+    }
+
+    /// remote path of the payload (pdf, epub...) of `node`, if it has one
+    fn payload_path(&self, node: &Node) -> Option<PathBuf> {
+        if node.get_extension() == Some(Node::RAW_PAGE_EXTENSION) {
+            // raw pages are stat'ed in their page folder, under their own name
+            return Some(node.get_path().clone());
+        }
+        node.get_extension().map(|ext| {
+            self.layout
+                .payload_path(&self.document_root, node.get_unique(), ext)
+        })
+    }
+
     /// Looks up parent node children for a specific file name
     fn lookup_node(
         &self,
         parent_ino: usize,
         name: &str,
     ) -> Result<Option<&RefCell<Node>>, RemarkableError> {
+        let root_view = (parent_ino == Node::ROOT_NODE_INO)
+            .then(|| self.root_views().into_iter().find(|v| v.3 == name))
+            .flatten();
         if parent_ino == Node::ROOT_NODE_INO && name == Node::TRASH_NODE_PATH {
             Ok(Some(&self.nodes[Node::TRASH_NODE_INO]))
+        } else if let Some(view) = root_view {
+            Ok(Some(&self.nodes[view.ino()]))
         } else if let Some(root_node) = self.get_node(parent_ino) {
-            // get all child nodes
-            let children = self.get_nodes(&root_node.borrow().get_children_ino());
-            let found = children
-                .into_iter()
-                .flatten() //.filter(|n| n.is_some())
-                //.map(|n| n.unwrap())
-                .find(|&n| n.borrow().get_visible_name().as_os_str() == name);
+            // listed names first, they differ from node names on collisions
+            let listed = root_node.borrow().get_child_named(name);
+            let found = listed.and_then(|ino| self.get_node(ino)).or_else(|| {
+                self.get_nodes(&root_node.borrow().get_children_ino())
+                    .into_iter()
+                    .flatten()
+                    .find(|&n| n.borrow().get_visible_name().as_os_str() == name)
+            });
+            // trashed since their former collection was listed, their metadata
+            // already says so but the trash listing does not hold them yet
+            let found = match found {
+                None if parent_ino == Node::TRASH_NODE_INO => self.nodes.iter().find(|n| {
+                    let n = RefCell::borrow(n);
+                    n.is_trashed() && n.get_visible_name().as_os_str() == name
+                }),
+                found => found,
+            };
             debug!("{name} in {parent_ino} gives empty?={}", found.is_none());
             Ok(found)
         } else {
             warn!("node {name} not found in inode={parent_ino}");
-            Err(RemarkableError::NodeNotFound(parent_ino))
+            Err(FsError::NodeNotFound(parent_ino).into())
         }
     }
 
-    /// get all children of nodeid node and create them with metadata if needed
+    /// Looks up `name` in `parent_ino`, loading the collection entries not handed
+    /// out by readdir yet when it is not among the known children
+    fn lookup_child(
+        &mut self,
+        parent_ino: usize,
+        name: &str,
+    ) -> Result<Option<usize>, RemarkableError> {
+        if let Some(node) = self.lookup_node(parent_ino, name)? {
+            return Ok(Some(node.borrow().get_ino()));
+        }
+        self.load_listing(parent_ino)?;
+        if let Some(node) = self.lookup_node(parent_ino, name)? {
+            return Ok(Some(node.borrow().get_ino()));
+        }
+        Ok(self.lookup_progress(parent_ino, name))
+    }
+
+    /// Re-lists the metadata files of collection `node_ino` as they are on the
+    /// tablet now, nodes are loaded later on
+    fn refresh_listing(&mut self, node_ino: usize) -> Result<(), RemarkableError> {
+        self.refresh_listings(&[node_ino])
+    }
+
+    /// Re-lists the metadata files of the collections `inos` as they are on the
+    /// tablet now, after a change made by the mount
+    fn refresh_listings(&mut self, inos: &[usize]) -> Result<(), RemarkableError> {
+        self.forget_tree_index();
+        self.list_collections(inos)
+    }
+
+    /// Lists the metadata files of the collections `inos` from the tree index,
+    /// fetched in a single remote command when older than the refresh interval
+    fn list_collections(&mut self, inos: &[usize]) -> Result<(), RemarkableError> {
+        let parents = inos
+            .iter()
+            .map(|&ino| self.listed_uid(ino))
+            .collect::<Result<Vec<_>, _>>()?;
+        let listings = self.indexed_children(&parents)?;
+        for (&ino, files) in inos.iter().zip(listings) {
+            self.set_listing(ino, files);
+        }
+        Ok(())
+    }
+
+    fn set_listing(&mut self, node_ino: usize, files: Vec<String>) {
+        debug!("collection {node_ino} lists {} entries", files.len());
+        let previous = self
+            .listings
+            .get(&node_ino)
+            .map(|l| l.entries.clone())
+            .unwrap_or_default();
+        self.reconcile_ghosts(node_ino, &previous, &files);
+        self.listings.entry(node_ino).or_default().refresh(files);
+        let mut pending = self.pending_children(node_ino);
+        pending.extend(self.ghost_children(node_ino));
+        if let Some(node) = self.get_node(node_ino) {
+            node.borrow_mut().set_children(&mut vec![]);
+            for child in pending.drain(..) {
+                node.borrow_mut().upsert_child(child);
+            }
+        }
+    }
+
+    /// Loads every entry of `node_ino` not loaded since its last listing
+    fn load_listing(&mut self, node_ino: usize) -> Result<(), RemarkableError> {
+        if let Some(&view) = self.virtual_dirs.get(&node_ino) {
+            let mut view_nodes = self.view_children(node_ino, view);
+            if let Some(viewnode) = self.get_node(node_ino) {
+                viewnode.borrow_mut().set_children(&mut view_nodes);
+            }
+            return Ok(());
+        }
+        if !self.listings.contains_key(&node_ino) {
+            self.list_collections(&[node_ino])?;
+        }
+        let (loaded, count) = self
+            .listings
+            .get(&node_ino)
+            .map(|l| (l.loaded, l.entries.len()))
+            .unwrap_or_default();
+        self.prefetch_entries(node_ino, loaded..count);
+        for idx in loaded..count {
+            self.load_listing_entry(node_ino, idx);
+        }
+        Ok(())
+    }
+
+    /// Loads the listing entry `idx` of collection `node_ino` into nodes and returns
+    /// the resulting children : the node itself and its paginated pdf, if any
+    fn load_listing_entry(&mut self, node_ino: usize, idx: usize) -> Vec<FuserChild> {
+        let Some((position, file)) = self
+            .listings
+            .get(&node_ino)
+            .and_then(|l| l.entries.get(idx))
+            .cloned()
+        else {
+            return vec![];
+        };
+        let mut children = vec![];
+        match self.stat_remote(&file).and_then(|mut fstat| {
+            self.add_or_update_node_from_metadata(node_ino, &mut fstat)
+                .map(|n| n.borrow().get_ino())
+        }) {
+            Ok(ino)
+                if self.name_policy == NamePolicy::Strict
+                    && !self.nodes[ino].borrow().has_clean_name() =>
+            {
+                warn!("entry {file} of {node_ino} left out : invalid name");
+            }
+            Ok(ino) => 'listed: {
+                let uid = self.nodes[ino].borrow().get_unique().to_owned();
+                self.release_quarantine(&uid);
+                let name = self.nodes[ino].borrow().get_visible_name();
+                let name = match self.claim_name(ino, &name.to_string_lossy(), &|n| {
+                    self.get_node(node_ino)?.borrow().get_child_named(n)
+                }) {
+                    Naming::As(name) => PathBuf::from(name),
+                    Naming::Replacing(holding) => {
+                        if let Some(dir) = self.get_node(node_ino) {
+                            dir.borrow_mut().remove_child(holding);
+                        }
+                        name
+                    }
+                    Naming::Hidden => break 'listed,
+                };
+                let node = self.nodes[ino].borrow();
+                children.push(FuserChild::new(
+                    ino,
+                    position,
+                    node.get_kind_for_fuser(),
+                    name,
+                ));
+                let key = format!("{}{}", node.get_unique(), Node::PAGINATED_SUFFIX);
+                if let Some(&paginated) = self.uid_map.get(&key) {
+                    children.push(FuserChild::new(
+                        paginated,
+                        position + 1,
+                        fuser::FileType::RegularFile,
+                        self.nodes[paginated].borrow().get_visible_name(),
+                    ));
+                }
+                drop(node);
+                if let Some(pages) = self.add_pages_node(ino) {
+                    children.push(FuserChild::new(
+                        pages,
+                        position + 2,
+                        fuser::FileType::Directory,
+                        self.nodes[pages].borrow().get_visible_name(),
+                    ));
+                }
+            }
+            Err(e) => children.extend(self.quarantine_entry(node_ino, position, &file, &e)),
+        }
+        if let Some(listing) = self.listings.get_mut(&node_ino) {
+            listing.loaded = listing.loaded.max(idx + 1);
+        }
+        if let Some(dir) = self.get_node(node_ino) {
+            for child in &children {
+                dir.borrow_mut().upsert_child(child.clone());
+            }
+        }
+        children
+    }
+
+    /// Hands the children of `node_ino` from readdir position `offset` on to `add`,
+    /// until it returns true (reply full). Collections are only re-listed at offset 0
+    /// and their entries are loaded as they are handed out, so that a large
+    /// collection is never materialized at once.
     fn node_readdir(
         &mut self,
         node_ino: usize,
-        ioffset: usize,
-    ) -> Result<Ref<[FuserChild]>, RemarkableError> {
-        if ioffset == 0 {
-            let mut read_children = self.get_metadata_files_by_parent(node_ino)?;
-            let mut children = Node::root_children(node_ino);
-            // add root children and fuse with `children` when relevant
-            children.append(&mut read_children);
-            // check if nodes are known in nodes hashmap
-            let mut readdir_nodes = children
-                .iter_mut()
-                .enumerate()
-                .filter_map(|(o, f)| {
-                    if let Ok(node) = self.add_or_update_node_from_metadata(node_ino, f) {
-                        Some(FuserChild::new(
-                            node.borrow().get_ino(),
-                            o,
-                            node.borrow().get_kind_for_fuser(), //.clone(),
-                            node.borrow().get_visible_name(),
-                        ))
-                    } else {
-                        warn!("node index {o}:{f:?} was not Ok");
-                        None
-                    }
-                })
-                .collect::<Vec<_>>();
-            debug!("readdir got {} entries", readdir_nodes.len());
-            // update child list
-            if let Some(rootnode) = self.get_node(node_ino) {
-                rootnode.borrow_mut().set_children(&mut readdir_nodes);
+        offset: usize,
+        add: &mut dyn FnMut(&FuserChild) -> bool,
+    ) -> Result<(), RemarkableError> {
+        if self.get_node(node_ino).is_none() {
+            return Err(FsError::NodeNotFound(node_ino).into());
+        }
+        if self.virtual_dirs.contains_key(&node_ino) {
+            if offset == 0 {
+                self.load_listing(node_ino)?;
+            }
+            let children = self.nodes[node_ino].borrow().get_children(offset);
+            for child in &children {
+                if add(child) {
+                    break;
+                }
             }
-            //            Ok(readdir_nodes.clone())
+            return Ok(());
         }
 
-        if let Some(root_node) = self.get_node(node_ino) {
-            let ret = Ref::map(root_node.borrow(), |r| r.get_children(ioffset));
-            Ok(ret)
-        } else {
-            Err(RemarkableError::NodeNotFound(node_ino))
+        if offset == 0 || !self.listings.contains_key(&node_ino) {
+            self.list_collections(&[node_ino])?;
+        }
+        if node_ino == Node::ROOT_NODE_INO {
+            for view in self.root_views().iter().filter(|v| v.1 >= offset) {
+                if add(view) {
+                    return Ok(());
+                }
+            }
+        }
+        if self.sort_policy != SortPolicy::Tablet {
+            return self.readdir_sorted(node_ino, offset, add);
+        }
+        let (first, count) = self
+            .listings
+            .get(&node_ino)
+            .map(|l| (l.first_entry_from(offset), l.entries.len()))
+            .unwrap_or_default();
+        self.prefetch_entries(node_ino, first..count);
+        let mut ghosts = self.ghost_children(node_ino);
+        ghosts.retain(|g| g.1 >= offset);
+        let mut ghosts = ghosts.into_iter().peekable();
+        for idx in first..count {
+            // ghosts keep their position among the entries
+            let next = self.listings[&node_ino].entries[idx].0;
+            while let Some(ghost) = ghosts.next_if(|g| g.1 < next) {
+                if add(&ghost) {
+                    return Ok(());
+                }
+            }
+            let children = self.load_listing_entry(node_ino, idx);
+            for child in children.iter().filter(|c| c.1 >= offset) {
+                if add(child) {
+                    return Ok(());
+                }
+            }
+        }
+        for ghost in ghosts {
+            if add(&ghost) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets the FileAttr of node `node`, from cache unless the node changed since
+    fn node_attr(&self, node: &RefCell<Node>) -> fuser::FileAttr {
+        let node = node.borrow();
+        let mut cache = self.attr_cache.borrow_mut();
+        match cache.get(&node.get_ino()) {
+            Some((generation, attr)) if *generation == node.generation() => *attr,
+            _ => {
+                let mut attr: fuser::FileAttr = node.deref().into();
+                self.permissions.apply(&mut attr);
+                if self
+                    .virtual_files
+                    .get(&node.get_ino())
+                    .is_some_and(|f| f.is_writable())
+                    || self.is_pending_upload(node.get_ino())
+                {
+                    attr.perm |= 0o200;
+                }
+                if let Some(umask) = self.umasks.get(&node.get_ino()) {
+                    attr.perm &= !umask;
+                }
+                cache.insert(node.get_ino(), (node.generation(), attr));
+                attr
+            }
         }
     }
 
+    /// Extended attributes (name, value) exposed for node `ino`
+    fn node_xattrs(&self, ino: usize) -> Vec<(String, Vec<u8>)> {
+        let mut xattrs = vec![];
+        if let Some(node) = self.get_node(ino) {
+            let node = node.borrow();
+            xattrs.push(Self::remote_perm_xattr(node.get_perm()));
+        }
+        xattrs.extend(self.trash_xattrs(ino));
+        xattrs.extend(self.quarantine_xattrs(ino));
+        xattrs.extend(self.ghost_xattrs(ino));
+        xattrs.extend(self.collection_xattrs(ino));
+        xattrs
+    }
+
+    /// `user.remarkable.remote_perm` attribute : the mode of the remote files in
+    /// octal, as the presented one follows the permission policy
+    fn remote_perm_xattr(perm: u16) -> (String, Vec<u8>) {
+        (
+            Self::XATTR_REMOTE_PERM.to_string(),
+            format!("{perm:04o}").into_bytes(),
+        )
+    }
+
     // TODO : replace Option by Result
     /// Gets RefCell to a node whose inode identifier is `ino`
     fn get_node(&self, ino: usize) -> Option<&RefCell<Node>> {
@@ -192,38 +873,533 @@ impl RemarkableFs {
     fn node_read_ofs_size(
         &self,
         node_ino: usize,
+        fh: Option<u64>,
         offset: u64,
         size: u32,
+        cancelled: &dyn Fn() -> bool,
     ) -> Result<Vec<u8>, RemarkableError> {
+        if let Some(&file) = self.virtual_files.get(&node_ino) {
+            let content = self.virtual_file_content(file);
+            let start = (offset as usize).min(content.len());
+            let end = start.saturating_add(size as usize).min(content.len());
+            return Ok(content[start..end].to_vec());
+        }
+        if self.is_pending_upload(node_ino) {
+            return self.read_upload(node_ino, offset, size);
+        }
         if let Some(node) = self.get_node(node_ino) {
-            if let Some(fpath) = node.borrow().get_target_file_path(&self.document_root) {
-                let sz = node.borrow().get_size() - offset;
+            if let Some(fpath) = self.payload_path(&node.borrow()) {
+                let sz = node.borrow().get_payload_size().saturating_sub(offset);
                 let readsz = std::cmp::min(sz, size as u64);
 
                 debug!(
                     "read request for {node_ino} : ofs={offset} reqsz = {size}, gotsz ={readsz} on {fpath:?}"
                 );
 
-                let mut buf = vec![0; readsz as usize];
-
-                match self.session.read_as_bytes(&fpath, offset, readsz, &mut buf) {
-                    Ok(_) => Ok(buf),
-                    Err(e) => Err(e),
-                }
+                let payload_size = node.borrow().get_payload_size();
+                let fetch = |start, len| {
+                    let mut buf = vec![0; len as usize];
+                    self.read_payload(node_ino, fh, &fpath, start, &mut buf, cancelled)?;
+                    Ok(buf)
+                };
+                self.cached_read(
+                    node_ino,
+                    offset,
+                    readsz,
+                    payload_size,
+                    |start, len| match &self.disk_cache {
+                        Some(disk) => {
+                            let node = node.borrow();
+                            let name = node.get_extension().unwrap_or_default();
+                            let modified = node.get_mtime();
+                            disk.read(
+                                node.get_unique(),
+                                name,
+                                modified,
+                                payload_size,
+                                start,
+                                len,
+                                fetch,
+                            )
+                        }
+                        None => fetch(start, len),
+                    },
+                )
+                .inspect(|_| self.read_ahead(node_ino, &fpath, offset + readsz, payload_size))
             } else {
-                Err(RemarkableError::NodeNotFound(node_ino))
+                Err(FsError::NodeNotFound(node_ino).into())
             }
         } else {
-            Err(RemarkableError::NodeNotFound(node_ino))
+            Err(FsError::NodeNotFound(node_ino).into())
         }
     }
 
     /// get fuse options
     fn options(&self) -> Vec<fuser::MountOption> {
-        vec![
-            fuser::MountOption::RO,
-            fuser::MountOption::FSName("Remarkable".to_string()),
-        ]
+        // unless asked, not mounted read-only so that control files can be
+        // written, unsupported writes are refused by the filesystem itself
+        let mut options = vec![fuser::MountOption::FSName("Remarkable".to_string())];
+        if self.read_only {
+            options.push(fuser::MountOption::RO);
+        }
+        options.extend(self.volume_options());
+        options.extend(self.feature_options());
+        options
+    }
+}
+
+/// fuse operations without fuser reply types, shared by the fuser trait
+/// implementations of RemarkableFs and MultiDeviceFs
+impl RemarkableFs {
+    pub(crate) fn op_getattr(&mut self, ino: usize) -> Result<fuser::FileAttr, libc::c_int> {
+        self.advance_scan();
+        self.apply_refresh();
+        self.getattr_count += 1;
+        if let Some(node) = self.get_node(ino) {
+            let fileattr = self.node_attr(node);
+            if self
+                .getattr_count
+                .is_multiple_of(Self::GETATTR_LOG_SAMPLING)
+            {
+                trace!("node {ino} : {fileattr:?} ({} getattr)", self.getattr_count);
+            }
+            Ok(fileattr)
+        } else {
+            error!("node {ino} not found");
+            Err(libc::ENOENT)
+        }
+    }
+
+    pub(crate) fn op_lookup(
+        &mut self,
+        parent: usize,
+        name: &std::ffi::OsStr,
+    ) -> Result<fuser::FileAttr, libc::c_int> {
+        self.jobs.note_request();
+        self.advance_scan();
+        self.apply_refresh();
+        let Some(nodestr) = names::from_os(name) else {
+            // presented names are always UTF-8, this one cannot exist
+            debug!("lookup of non UTF-8 name {name:?} in {parent}");
+            return Err(libc::ENOENT);
+        };
+        match self.with_reconnect("lookup", |fs| fs.lookup_child(parent, nodestr)) {
+            Ok(res) => {
+                if let Some(node) = res.and_then(|ino| self.get_node(ino)) {
+                    let fileattr = self.node_attr(node);
+                    debug!("found node {nodestr}: {fileattr:?}");
+                    Ok(fileattr)
+                } else {
+                    // not found
+                    error!("node {nodestr} not found in parent {parent}");
+                    Err(libc::ENOENT)
+                }
+            }
+            Err(e) => {
+                error!("got error {e}");
+                self.record_error("lookup", &e);
+                // root node does not exist or general error (ssh channel?)
+                Err(libc::ENOSYS)
+            }
+        }
+    }
+
+    pub(crate) fn op_readdir(
+        &mut self,
+        ino: usize,
+        offset: usize,
+        add: &mut dyn FnMut(&FuserChild) -> bool,
+    ) -> Result<(), libc::c_int> {
+        self.jobs.note_request();
+        self.advance_scan();
+        self.apply_refresh();
+        self.with_reconnect("readdir", |fs| fs.node_readdir(ino, offset, &mut *add))
+            .map_err(|e| {
+                error!("got error {e}");
+                self.record_error("readdir", &e);
+                libc::ENOENT
+            })
+    }
+
+    /// opens node `ino`, returns the file handle and fuse open flags
+    pub(crate) fn op_open(&mut self, ino: usize) -> Result<(u64, u32), libc::c_int> {
+        self.jobs.note_request();
+        if let Err(e) = self.with_reconnect("open", |fs| fs.ensure_details(ino)) {
+            warn!("could not reload content of {ino} : {e}");
+            self.deferred_errors.record(ino, libc::EIO);
+        }
+        // generated content has no known size : bypass the page cache
+        let flags = if self.virtual_files.contains_key(&ino) {
+            fuser::consts::FOPEN_DIRECT_IO
+        } else {
+            0
+        };
+        if let Some(node) = self.get_node(ino) {
+            match node.borrow_mut().open() {
+                Ok(v) => {
+                    let fh = self.handles.borrow_mut().insert(ino);
+                    debug!("open request for {ino} = {v}, handle {fh}");
+                    Ok((fh, flags))
+                }
+                Err(e) => {
+                    if let Some(FsError::NodeIoError(v)) = e.fs_error() {
+                        error!("open failed for {ino} with io error {v}");
+                        Err(*v)
+                    } else {
+                        error!("open failed for {ino} with io error {e}");
+                        Err(libc::EBADFD)
+                    }
+                }
+            }
+        } else {
+            error!("open failed : {ino} not found");
+            Err(libc::EBADFD)
+        }
+    }
+
+    /// reads for the process `pid`, abandoned if it gets interrupted meanwhile
+    pub(crate) fn op_read(
+        &mut self,
+        ino: usize,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        pid: u32,
+    ) -> Result<Vec<u8>, libc::c_int> {
+        self.jobs.note_request();
+        if size > 0 || offset < 0 {
+            let cancelled = || interrupt::requester_interrupted(pid);
+            let data = self
+                .with_reconnect("read", |fs| {
+                    fs.node_read_ofs_size(ino, Some(fh), offset as u64, size, &cancelled)
+                })
+                .map_err(|e| {
+                    let errno = if let Some(FsError::NodeIoError(libc::EINTR)) = e.fs_error() {
+                        // not a failure of the tablet, kept out of the health report
+                        info!("read of {ino} abandoned, process {pid} was interrupted");
+                        return libc::EINTR;
+                    } else if let Some(FsError::NodeIoError(v)) = e.fs_error() {
+                        error!("read failed for {ino} : {v}");
+                        *v
+                    } else {
+                        error!("read failed for {ino} : {e}");
+                        libc::EBADFD
+                    };
+                    self.record_error("read", &e);
+                    errno
+                })
+                .inspect_err(|&errno| self.deferred_errors.record(ino, errno))?;
+            if !self.virtual_files.contains_key(&ino) {
+                self.record_progress(ino, offset as u64, data.len());
+            }
+            Ok(data)
+        } else {
+            error!("read failed for {ino} : invalid size {size}");
+            Err(libc::EINVAL)
+        }
+    }
+
+    /// creates `name` in collection `parent` to upload it as a document, returns
+    /// its attributes and file handle. Its mode is masked with `umask`.
+    pub(crate) fn op_create(
+        &mut self,
+        parent: usize,
+        name: &std::ffi::OsStr,
+        umask: u32,
+    ) -> Result<(fuser::FileAttr, u64), libc::c_int> {
+        self.check_writable("create", parent)?;
+        self.check_mutation("create", parent)?;
+        let Some(nodestr) = names::from_os(name) else {
+            debug!("create of non UTF-8 name {name:?} in {parent}");
+            return Err(libc::EINVAL);
+        };
+        let ino = self
+            .with_reconnect("create", |fs| fs.create_upload(parent, nodestr))
+            .map_err(|e| match e.fs_error() {
+                Some(FsError::Unsupported(_)) => {
+                    debug!("create refused : {e}");
+                    libc::EPERM
+                }
+                Some(FsError::NotACollection(_)) => {
+                    debug!("create refused : {e}");
+                    self.refuse_write("create", parent)
+                }
+                Some(FsError::NodeDuplicated) => libc::EEXIST,
+                _ => {
+                    error!("create of {nodestr} in {parent} failed : {e}");
+                    self.record_error("create", &e);
+                    libc::EIO
+                }
+            })?;
+        self.record_umask(ino, umask);
+        let (fh, _) = self.op_open(ino)?;
+        Ok((self.op_getattr(ino)?, fh))
+    }
+
+    /// creates the collection `name` in collection `parent`, returns its
+    /// attributes. Its mode is masked with `umask`.
+    pub(crate) fn op_mkdir(
+        &mut self,
+        parent: usize,
+        name: &std::ffi::OsStr,
+        umask: u32,
+    ) -> Result<fuser::FileAttr, libc::c_int> {
+        self.check_writable("mkdir", parent)?;
+        self.check_mutation("mkdir", parent)?;
+        let Some(nodestr) = names::from_os(name) else {
+            debug!("mkdir of non UTF-8 name {name:?} in {parent}");
+            return Err(libc::EINVAL);
+        };
+        let ino = self
+            .with_reconnect("mkdir", |fs| fs.make_collection(parent, nodestr))
+            .map_err(|e| match e.fs_error() {
+                Some(FsError::InvalidPath(_)) => libc::EINVAL,
+                Some(FsError::NotACollection(_)) => {
+                    debug!("mkdir refused : {e}");
+                    self.refuse_write("mkdir", parent)
+                }
+                Some(FsError::NodeDuplicated) => libc::EEXIST,
+                _ => {
+                    error!("mkdir of {nodestr} in {parent} failed : {e}");
+                    self.record_error("mkdir", &e);
+                    libc::EIO
+                }
+            })?;
+        self.record_umask(ino, umask);
+        self.op_getattr(ino)
+    }
+
+    /// renames `name` of `parent` to `new_name` in `new_parent`. Existing targets
+    /// are never replaced, and exchanges are not supported.
+    pub(crate) fn op_rename(
+        &mut self,
+        parent: usize,
+        name: &std::ffi::OsStr,
+        new_parent: usize,
+        new_name: &std::ffi::OsStr,
+        flags: u32,
+    ) -> Result<(), libc::c_int> {
+        self.check_writable("rename", parent)?;
+        self.check_mutation("rename", parent)?;
+        let (Some(name), Some(new_name)) = (names::from_os(name), names::from_os(new_name)) else {
+            debug!("rename of non UTF-8 names {name:?} -> {new_name:?}");
+            return Err(libc::EINVAL);
+        };
+        if flags & libc::RENAME_EXCHANGE != 0 || !names::is_clean(new_name) {
+            return Err(libc::EINVAL);
+        }
+        self.with_reconnect("rename", |fs| {
+            fs.move_item(parent, name, new_parent, new_name)
+        })
+        .map_err(|e| match e.fs_error() {
+            Some(FsError::NodeIoError(v)) => {
+                debug!("rename of {name} refused : {e}");
+                *v
+            }
+            Some(FsError::NotACollection(_)) => libc::ENOTDIR,
+            Some(FsError::NodeDuplicated) => libc::EEXIST,
+            _ => {
+                error!("rename of {name} to {new_name} failed : {e}");
+                self.record_error("rename", &e);
+                libc::EIO
+            }
+        })
+    }
+
+    /// moves document `name` of `parent` to the trash
+    pub(crate) fn op_unlink(
+        &mut self,
+        parent: usize,
+        name: &std::ffi::OsStr,
+    ) -> Result<(), libc::c_int> {
+        self.check_writable("unlink", parent)?;
+        self.check_mutation("unlink", parent)?;
+        let Some(name) = names::from_os(name) else {
+            debug!("unlink of non UTF-8 name {name:?} in {parent}");
+            return Err(libc::ENOENT);
+        };
+        self.with_reconnect("unlink", |fs| fs.trash_document(parent, name))
+            .map_err(|e| match e.fs_error() {
+                Some(FsError::NodeIoError(v)) => {
+                    debug!("unlink of {name} refused : {e}");
+                    *v
+                }
+                _ => {
+                    error!("unlink of {name} in {parent} failed : {e}");
+                    self.record_error("unlink", &e);
+                    libc::EIO
+                }
+            })
+    }
+
+    /// writes to control files and pending uploads, returns the number of bytes
+    /// consumed
+    pub(crate) fn op_write(
+        &mut self,
+        ino: usize,
+        offset: i64,
+        data: &[u8],
+    ) -> Result<u32, libc::c_int> {
+        if self.is_pending_upload(ino) {
+            return match self.write_upload(ino, offset.max(0) as u64, data) {
+                Ok(()) => Ok(data.len() as u32),
+                Err(e) => {
+                    error!("write to {ino} failed : {e}");
+                    match e.fs_error() {
+                        Some(FsError::NodeIoError(v)) => Err(*v),
+                        _ => {
+                            self.record_error("write", &e);
+                            Err(libc::EIO)
+                        }
+                    }
+                }
+            };
+        }
+        self.check_writable("write", ino)?;
+        let Some(&file) = self.virtual_files.get(&ino).filter(|f| f.is_writable()) else {
+            return Err(self.refuse_write("write", ino));
+        };
+        match self.control_write(file, data) {
+            Ok(()) => Ok(data.len() as u32),
+            Err(e) => {
+                error!("control write to {ino} failed : {e}");
+                let errno = match e.fs_error() {
+                    Some(FsError::NodeNotFound(_)) => libc::ENOENT,
+                    _ => libc::EIO,
+                };
+                self.record_error("write", &e);
+                Err(errno)
+            }
+        }
+    }
+
+    /// only truncation of control files is accepted, as done by shell redirections,
+    /// and resizing of pending uploads
+    pub(crate) fn op_setattr(
+        &mut self,
+        ino: usize,
+        size: Option<u64>,
+    ) -> Result<fuser::FileAttr, libc::c_int> {
+        self.check_writable("setattr", ino)?;
+        if self.is_pending_upload(ino) {
+            if let Some(size) = size {
+                self.truncate_upload(ino, size).map_err(|e| {
+                    error!("resizing {ino} failed : {e}");
+                    match e.fs_error() {
+                        Some(FsError::NodeIoError(v)) => *v,
+                        _ => libc::EIO,
+                    }
+                })?;
+            }
+            return self.op_getattr(ino);
+        }
+        let writable = self
+            .virtual_files
+            .get(&ino)
+            .is_some_and(|f| f.is_writable());
+        if !writable || size.is_some_and(|s| s > 0) {
+            return Err(self.refuse_write("setattr", ino));
+        }
+        self.op_getattr(ino)
+    }
+
+    pub(crate) fn op_release(&mut self, ino: usize, fh: u64) -> Result<(), libc::c_int> {
+        self.handles.borrow_mut().remove(fh);
+        if let Some(node) = self.get_node(ino) {
+            let closed = node.borrow_mut().close();
+            match closed {
+                Ok(v) => {
+                    debug!("release request for {ino} = {v}");
+                    if v == 0 {
+                        self.collect_read_failures();
+                        self.write_back(ino);
+                        self.end_progress(ino);
+                        self.release_upload(ino);
+                        self.deferred_errors.take(ino);
+                    }
+                    Ok(())
+                }
+                Err(e) => {
+                    if let Some(FsError::NodeIoError(v)) = e.fs_error() {
+                        error!("release failed for {ino} with io error {v}");
+                        Err(*v)
+                    } else {
+                        error!("open failed for {ino} with io error {e}");
+                        Err(libc::EBADFD)
+                    }
+                }
+            }
+        } else {
+            error!("open failed : {ino} not found");
+            Err(libc::EBADFD)
+        }
+    }
+}
+
+/// Nodes whose parsed contents are evicted, least recently used first, to bring
+/// `used` bytes down to `target`. `cold` holds (last use, inode, size) of the
+/// nodes that may be evicted.
+fn lru_victims(mut cold: Vec<(u64, usize, usize)>, mut used: usize, target: usize) -> Vec<usize> {
+    cold.sort_unstable();
+    let mut victims = vec![];
+    for (_, ino, size) in cold {
+        if used <= target {
+            break;
+        }
+        used = used.saturating_sub(size);
+        victims.push(ino);
+    }
+    victims
+}
+
+/// REMARKABLE_RELEASE_VERSION value of the firmware update configuration
+fn release_version(conf: &str) -> Option<&str> {
+    conf.lines()
+        .find_map(|l| l.trim().strip_prefix("REMARKABLE_RELEASE_VERSION="))
+        .map(|v| v.trim().trim_matches('"'))
+        .filter(|v| !v.is_empty())
+}
+
+/// Reply to getxattr and listxattr : the size of the data when asked with a
+/// zero size, the data otherwise
+#[derive(Debug, PartialEq, Eq)]
+enum XattrReply {
+    Size(u32),
+    Data(Vec<u8>),
+}
+
+impl XattrReply {
+    /// `data` for a buffer of `size` bytes, ERANGE when it does not fit
+    fn new(data: Vec<u8>, size: u32) -> Result<Self, libc::c_int> {
+        if size == 0 {
+            Ok(Self::Size(data.len() as u32))
+        } else if data.len() <= size as usize {
+            Ok(Self::Data(data))
+        } else {
+            Err(libc::ERANGE)
+        }
+    }
+
+    /// getxattr reply with `value` (None when the attribute does not exist)
+    fn value(value: Option<Vec<u8>>, size: u32) -> Result<Self, libc::c_int> {
+        Self::new(value.ok_or(libc::ENODATA)?, size)
+    }
+
+    /// listxattr reply with the nul terminated names of `xattrs`
+    fn names(xattrs: Vec<(String, Vec<u8>)>, size: u32) -> Result<Self, libc::c_int> {
+        let names = xattrs
+            .into_iter()
+            .flat_map(|(n, _)| n.into_bytes().into_iter().chain(std::iter::once(0)))
+            .collect::<Vec<u8>>();
+        Self::new(names, size)
+    }
+
+    fn send(result: Result<Self, libc::c_int>, reply: fuser::ReplyXattr) {
+        match result {
+            Ok(Self::Size(size)) => reply.size(size),
+            Ok(Self::Data(data)) => reply.data(&data),
+            Err(errno) => reply.error(errno),
+        }
     }
 }
 
@@ -232,124 +1408,117 @@ impl fuser::Filesystem for RemarkableFs {
     /// initialize remarkable filesystem
     fn init(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         _config: &mut fuser::KernelConfig,
     ) -> Result<(), libc::c_int> {
-        if self.init_root().is_err() {
-            error!("Error while initializing fs root");
+        let _trace = TraceScope::enter("init", req.unique());
+        if let Err(e) = self.init_root() {
+            error!("Error while initializing fs root : {e}");
             Err(libc::ENOSYS)
         } else {
             info!("Initialization done");
+            self.initial_scan();
             Ok(())
         }
     }
 
     /*
-    fn opendir(&mut self, _req: &fuser::Request, _ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
+    fn opendir(&mut self, req: &fuser::Request, _ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
+        let _trace = TraceScope::enter("opendir", req.unique());
         info!("opendir request {:?}", _req);
         //reply.opened(_ino, 0);
     }*/
 
-    fn getattr(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyAttr) {
+    fn getattr(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyAttr) {
+        let _trace = TraceScope::enter("getattr", req.unique());
         //info!("getattr request {:?}", _req);
-        if let Some(node) = self.get_node(ino as usize) {
-            let fileattr: fuser::FileAttr = node.borrow().deref().into();
-            info!("node {ino} : {fileattr:?}");
-            reply.attr(&Duration::new(0, 0), &fileattr);
-        } else {
-            error!("node {ino} not found");
-            reply.error(libc::ENOENT)
+        match self.op_getattr(ino as usize) {
+            Ok(fileattr) => reply.attr(&Duration::new(0, 0), &fileattr),
+            Err(errno) => reply.error(errno),
         }
     }
 
     fn lookup(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         parent: u64,
         name: &std::ffi::OsStr,
         reply: fuser::ReplyEntry,
     ) {
+        let _trace = TraceScope::enter("lookup", req.unique());
         //info!("lookup request {:?}", _req);
-        if let Some(nodestr) = name.to_str() {
-            match self.lookup_node(parent as usize, nodestr) {
-                Ok(res) => {
-                    if let Some(node) = res {
-                        let fileattr: fuser::FileAttr = node.borrow().deref().into();
-                        info!("found node {nodestr}: {fileattr:?}");
-                        reply.entry(&Duration::new(0, 0), &fileattr, 0);
-                    } else {
-                        // not found
-                        error!("node {nodestr} not found in parent {parent}");
-                        reply.error(libc::ENOENT)
-                    }
-                }
-                Err(e) => {
-                    error!("got error {e:?}");
-                    // root node does not exist or general error (ssh channel?)
-                    reply.error(libc::ENOSYS);
-                }
-            };
-        } else {
-            error!("provided name could not be converted to string");
-            reply.error(libc::EINVAL);
+        match self.op_lookup(parent as usize, name) {
+            Ok(fileattr) => reply.entry(&Duration::new(0, 0), &fileattr, 0),
+            Err(errno) => reply.error(errno),
         }
     }
 
     fn readdir(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         ino: u64,
         _fh: u64,
         offset: i64,
         mut reply: fuser::ReplyDirectory,
     ) {
+        let _trace = TraceScope::enter("readdir", req.unique());
         //info!("readdir request {:?}", _req);
-        match self.node_readdir(ino as usize, offset as usize) {
-            Ok(res) => {
-                let _ = res.iter().try_for_each(|v| {
-                    let (s_ino, s_offs, s_knd, s_nm) = (v.0, v.1, v.2, &v.3);
-                    info!("adding {s_ino} {s_offs} {s_knd:?} {:?}", s_nm);
-                    if reply.add(s_ino as u64, s_offs as i64 + 1, s_knd, s_nm.as_os_str()) {
-                        Err(())
-                    } else {
-                        Ok(())
-                    }
-                });
+        let res = self.op_readdir(ino as usize, offset as usize, &mut |v| {
+            let (s_ino, s_offs, s_knd, s_nm) = (v.0, v.1, v.2, &v.3);
+            debug!("adding {s_ino} {s_offs} {s_knd:?} {:?}", s_nm);
+            reply.add(s_ino as u64, s_offs as i64 + 1, s_knd, s_nm.as_os_str())
+        });
+        match res {
+            Ok(()) => {
                 debug!("READDIR reply {reply:?}");
                 reply.ok();
             }
-            Err(e) => {
-                error!("got error {e:?}");
-                reply.error(libc::ENOENT);
-            }
+            Err(errno) => reply.error(errno),
         };
     }
 
-    fn open(&mut self, _req: &fuser::Request, _ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
-        if let Some(node) = self.get_node(_ino as usize) {
-            match node.borrow_mut().open() {
-                Ok(v) => {
-                    reply.opened(v, 0);
-                    debug!("open request for {_ino} = {v}");
-                }
-                Err(RemarkableError::NodeIoError(v)) => {
-                    reply.error(v);
-                    error!("open failed for {_ino} with io error {v}");
-                }
-                Err(e) => {
-                    reply.error(libc::EBADFD);
-                    error!("open failed for {_ino} with io error {e}");
-                }
-            }
-        } else {
-            error!("open failed : {_ino} not found");
-            reply.error(libc::EBADFD);
+    fn getxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        name: &std::ffi::OsStr,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        let _trace = TraceScope::enter("getxattr", req.unique());
+        let value = self
+            .node_xattrs(ino as usize)
+            .into_iter()
+            .find(|(n, _)| name == n.as_str())
+            .map(|(_, v)| v);
+        XattrReply::send(XattrReply::value(value, size), reply);
+    }
+
+    fn listxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        let _trace = TraceScope::enter("listxattr", req.unique());
+        XattrReply::send(
+            XattrReply::names(self.node_xattrs(ino as usize), size),
+            reply,
+        );
+    }
+
+    fn open(&mut self, req: &fuser::Request, _ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
+        let _trace = TraceScope::enter("open", req.unique());
+        match self.op_open(_ino as usize) {
+            Ok((fh, flags)) => reply.opened(fh, flags),
+            Err(errno) => reply.error(errno),
         }
     }
 
     fn read(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         ino: u64,
         fh: u64,
         offset: i64,
@@ -358,55 +1527,192 @@ impl fuser::Filesystem for RemarkableFs {
         lock_owner: Option<u64>,
         reply: fuser::ReplyData,
     ) {
+        let _trace = TraceScope::enter("read", req.unique());
         debug!("read request for {ino} : {offset} {size} {fh} {flags} {lock_owner:?}");
-        if size > 0 || offset < 0 {
-            match self.node_read_ofs_size(ino as usize, offset as u64, size) {
-                Ok(buffer) => {
-                    reply.data(&buffer);
-                }
-                Err(RemarkableError::NodeIoError(e)) => {
-                    reply.error(e);
-                    error!("read failed for {ino} : {e}");
-                }
-                Err(e) => {
-                    reply.error(libc::EBADFD);
-                    error!("read failed for {ino} : {e:?}");
-                }
-            }
-        } else {
-            error!("read failed for {ino} : invalid size {size}");
-            reply.error(libc::EINVAL);
+        let Some(reply) = self.coalesce_read(ino as usize, offset, size, reply) else {
+            return;
+        };
+        match self.op_read(ino as usize, fh, offset, size, req.pid()) {
+            Ok(buffer) => reply.data(&buffer),
+            Err(errno) => reply.error(errno),
         }
     }
 
     fn release(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         _ino: u64,
-        _fh: u64,
+        fh: u64,
         _flags: i32,
         _lock_owner: Option<u64>,
         _flush: bool,
         reply: fuser::ReplyEmpty,
     ) {
-        if let Some(node) = self.get_node(_ino as usize) {
-            match node.borrow_mut().close() {
-                Ok(v) => {
-                    reply.ok();
-                    debug!("release request for {_ino} = {v}");
-                }
-                Err(RemarkableError::NodeIoError(v)) => {
-                    reply.error(v);
-                    error!("release failed for {_ino} with io error {v}");
-                }
-                Err(e) => {
-                    reply.error(libc::EBADFD);
-                    error!("open failed for {_ino} with io error {e}");
-                }
-            }
-        } else {
-            error!("open failed : {_ino} not found");
-            reply.error(libc::EBADFD);
+        let _trace = TraceScope::enter("release", req.unique());
+        match self.op_release(_ino as usize, fh) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn create(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        _mode: u32,
+        umask: u32,
+        _flags: i32,
+        reply: fuser::ReplyCreate,
+    ) {
+        let _trace = TraceScope::enter("create", req.unique());
+        match self.op_create(parent as usize, name, umask) {
+            Ok((fileattr, fh)) => reply.created(&Duration::new(0, 0), &fileattr, 0, fh, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        _mode: u32,
+        umask: u32,
+        reply: fuser::ReplyEntry,
+    ) {
+        let _trace = TraceScope::enter("mkdir", req.unique());
+        match self.op_mkdir(parent as usize, name, umask) {
+            Ok(fileattr) => reply.entry(&Duration::new(0, 0), &fileattr, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        newparent: u64,
+        newname: &std::ffi::OsStr,
+        flags: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let _trace = TraceScope::enter("rename", req.unique());
+        match self.op_rename(parent as usize, name, newparent as usize, newname, flags) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn unlink(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let _trace = TraceScope::enter("unlink", req.unique());
+        match self.op_unlink(parent as usize, name) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn mknod(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        _name: &std::ffi::OsStr,
+        _mode: u32,
+        _umask: u32,
+        _rdev: u32,
+        reply: fuser::ReplyEntry,
+    ) {
+        let _trace = TraceScope::enter("mknod", req.unique());
+        reply.error(self.refuse_write("mknod", parent as usize));
+    }
+
+    fn symlink(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        _link_name: &std::ffi::OsStr,
+        _target: &Path,
+        reply: fuser::ReplyEntry,
+    ) {
+        let _trace = TraceScope::enter("symlink", req.unique());
+        reply.error(self.refuse_write("symlink", parent as usize));
+    }
+
+    fn link(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        _newparent: u64,
+        _newname: &std::ffi::OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        let _trace = TraceScope::enter("link", req.unique());
+        reply.error(self.refuse_write("link", ino as usize));
+    }
+
+    fn flush(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let _trace = TraceScope::enter("flush", req.unique());
+        match self.op_flush(ino as usize) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<fuser::TimeOrNow>,
+        _mtime: Option<fuser::TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: fuser::ReplyAttr,
+    ) {
+        let _trace = TraceScope::enter("setattr", req.unique());
+        match self.op_setattr(ino as usize, size) {
+            Ok(fileattr) => reply.attr(&Duration::new(0, 0), &fileattr),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn write(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyWrite,
+    ) {
+        let _trace = TraceScope::enter("write", req.unique());
+        match self.op_write(ino as usize, offset, data) {
+            Ok(written) => reply.written(written),
+            Err(errno) => reply.error(errno),
         }
     }
 }
@@ -422,11 +1728,69 @@ impl RemarkableFs {
             mount_point,
             nodes: vec![],
             uid_map: HashMap::new(),
+            virtual_dirs: HashMap::new(),
+            virtual_files: HashMap::new(),
+            listings: HashMap::new(),
+            attr_cache: RefCell::new(HashMap::new()),
+            getattr_count: 0,
+            started: SystemTime::now(),
+            last_error: None,
+            quarantined: HashMap::new(),
+            ghosts: HashMap::new(),
+            ghost_grace: Self::DEFAULT_GHOST_GRACE,
+            show_ghosts: false,
+            transfers: HashMap::new(),
+            handles: RefCell::new(HandleTable::default()),
+            read_caches: RefCell::new(HashMap::new()),
+            disk_cache: None,
+            deferred_errors: DeferredErrors::default(),
+            write_policy: WritePolicy::default(),
+            read_only: false,
+            write_refusals: WriteRefusals::default(),
+            mutations: MutationBudget::new(WriteLimit::default()),
+            umasks: HashMap::new(),
+            detail_budget: Self::DEFAULT_DETAIL_BUDGET,
+            detail_bytes: 0,
+            detail_clock: 0,
+            permissions: PermissionPolicy::default(),
+            name_policy: NamePolicy::default(),
+            collision_policy: CollisionPolicy::default(),
+            sort_policy: SortPolicy::default(),
+            raw_pages: false,
+            volume_name: Self::DEFAULT_VOLUME_NAME.to_owned(),
+            client_version: None,
+            device_versions: RefCell::new(None),
+            scan_jobs: 0,
+            scan_batch_size: Self::DEFAULT_SCAN_BATCH_SIZE,
+            listing_channels: Self::DEFAULT_LISTING_CHANNELS,
+            prefetched: RefCell::new(Prefetch::default()),
+            tree_index: RefCell::new(None),
+            refresh_interval: None,
+            refresher: None,
+            disconnected: false,
+            last_remote_op: None,
+            schema: RefCell::new(None),
+            tree_refresh: Self::DEFAULT_TREE_REFRESH,
+            bulk_load: false,
+            scan: None,
+            last_scan: None,
+            pending_uploads: HashMap::new(),
+            spool_parent: std::env::temp_dir(),
+            spool_dir: None,
+            #[cfg(feature = "scripting")]
+            scripted_views: vec![],
+            layout: Box::new(XochitlLayout),
+            decryption: None,
+            coalesce_reads: false,
+            read_coalescer: None,
+            jobs: Scheduler::new(JobBudget::default()),
+            share_server: None,
         }
     }
 
     /// initialize basic root nodes (Invalid node(0), Root(ROOT_NODE_UID) and Trash)
     pub fn init_root(&mut self) -> Result<(), RemarkableError> {
+        self.check_document_root()?;
         // push invalid node at ino = 0
         self.nodes.push(RefCell::new(Node::new(
             Node::INVALID_NODE_INO,
@@ -451,6 +1815,8 @@ impl RemarkableFs {
         self.nodes.push(trash_node);
         self.uid_map
             .insert(Node::TRASH_NODE_UID.to_string(), Node::TRASH_NODE_INO);
+        // add virtual views (by-date...)
+        self.init_views();
         // TODO stat root
         // let root_metadata = self.get_metadata_files_by_parent("")?;
         //
@@ -463,35 +1829,347 @@ impl RemarkableFs {
         &self,
         parent_ino: usize,
     ) -> Result<Vec<SshFileStat>, RemarkableError> {
-        if let Some(n_id) = self.get_node_unique_id(parent_ino) {
-            if let Some(path) = self.document_root.to_str() {
-                let grepcmd = format!(r#"grep -l \"parent\":\ \"{n_id}\" {path}*.metadata"#);
-                debug!("{grepcmd}");
-                let cmd_res = self.session.execute_cmd(&grepcmd)?;
-                let file_list = cmd_res
-                    .split('\n')
-                    //            .map(|s| format!("{s}.metadata"))
-                    .filter(|s| !s.is_empty())
-                    .collect::<Vec<_>>();
-                Ok(self.session.stat_files(&file_list)?)
-            } else {
-                Err(RemarkableError::RkError("invalid document root".into()))
+        let files = self.list_metadata_files_by_parent(parent_ino)?;
+        let file_list = files.iter().map(String::as_str).collect::<Vec<_>>();
+        self.session.stat_files(&file_list)
+    }
+
+    /// Paths of the metadata files of all children of a specific parent node
+    fn list_metadata_files_by_parent(
+        &self,
+        parent_ino: usize,
+    ) -> Result<Vec<String>, RemarkableError> {
+        let parent = self.listed_uid(parent_ino)?;
+        Ok(self
+            .indexed_children(&[parent])?
+            .into_iter()
+            .next()
+            .unwrap_or_default())
+    }
+
+    /// uid the metadata files of the children of `parent_ino` give as parent
+    fn listed_uid(&self, parent_ino: usize) -> Result<String, RemarkableError> {
+        // trashed items have "trash" as parent, not the uid of the trash node
+        match parent_ino {
+            Node::TRASH_NODE_INO => Ok(Node::TRASH_PARENT_UID.to_owned()),
+            _ => self
+                .get_node_unique_id(parent_ino)
+                .ok_or_else(|| FsError::NodeNotFound(parent_ino).into()),
+        }
+    }
+
+    /// Sets ownership and modes presented by the mount
+    pub fn set_permission_policy(&mut self, permissions: PermissionPolicy) {
+        self.permissions = permissions;
+        self.attr_cache.borrow_mut().clear();
+    }
+
+    /// Resolves a slash separated visible path (e.g. "Work/Spec.pdf") to an inode,
+    /// listing each traversed collection on the way
+    pub fn resolve_path(&mut self, path: &str) -> Result<usize, RemarkableError> {
+        let mut ino = Node::ROOT_NODE_INO;
+        for name in path.split('/').filter(|c| !c.is_empty()) {
+            self.node_readdir(ino, 0, &mut |_| false)?;
+            match self.lookup_node(ino, name)? {
+                Some(node) => ino = node.borrow().get_ino(),
+                None => {
+                    warn!("{name} not found while resolving {path}");
+                    return Err(FsError::NodeNotFound(ino).into());
+                }
             }
-        } else {
-            Err(RemarkableError::NodeNotFound(parent_ino))
         }
+        Ok(ino)
+    }
+
+    /// Gets the remarkable unique id of the node at inode `ino`
+    pub fn unique_id(&self, ino: usize) -> Option<String> {
+        self.get_node_unique_id(ino)
+    }
+
+    /// Is the node at inode `ino` a document (as opposed to a collection) ?
+    pub fn is_document(&self, ino: usize) -> bool {
+        self.get_node(ino)
+            .map(|n| n.borrow().is_document())
+            .unwrap_or(false)
+    }
+
+    /// Gets the visible name (with extension) of the node at inode `ino`
+    pub fn visible_name(&self, ino: usize) -> Option<PathBuf> {
+        self.get_node(ino).map(|n| n.borrow().get_visible_name())
+    }
+
+    /// Gets the size of the node at inode `ino`
+    pub fn size(&self, ino: usize) -> Option<u64> {
+        self.get_node(ino).map(|n| n.borrow().get_size())
+    }
+
+    /// Gets the page count of the document at inode `ino`, reloading its content if needed
+    pub fn page_count(&mut self, ino: usize) -> Option<u16> {
+        self.ensure_details(ino).ok()?;
+        self.get_node(ino).and_then(|n| n.borrow().get_page_count())
+    }
+
+    /// Gets the tags of the document at inode `ino`, reloading its content if needed
+    pub fn tags(&mut self, ino: usize) -> Vec<String> {
+        if self.ensure_details(ino).is_err() {
+            return vec![];
+        }
+        self.get_node(ino)
+            .map(|n| n.borrow().get_tags())
+            .unwrap_or_default()
+    }
+
+    /// Gets the inode of the item with unique id `uid`, once its collection has
+    /// been listed (e.g. by resolving the collection path)
+    pub fn resolve_uid(&self, uid: &str) -> Option<usize> {
+        self.uid_map.get(uid).copied()
+    }
+
+    /// Sets how names from the tablet that are not valid file names are presented
+    pub fn set_name_policy(&mut self, policy: NamePolicy) {
+        self.name_policy = policy;
+    }
+
+    /// Shows or hides the `<name>.pages` folders holding the raw `.rm` page files
+    pub fn set_raw_pages(&mut self, enabled: bool) {
+        self.raw_pages = enabled;
+    }
+
+    /// Sets the label file managers show for the mount
+    pub fn set_volume_name(&mut self, name: &str) {
+        self.volume_name = name.to_owned();
+    }
+
+    /// Defines the folders of `/.views` from a views script, see the `scripting`
+    /// feature. Must be called before `init_root`.
+    #[cfg(feature = "scripting")]
+    pub fn set_views_script(&mut self, script: &str) -> Result<(), RemarkableError> {
+        self.scripted_views = script::ScriptedView::parse_script(script)?;
+        info!("{} scripted views defined", self.scripted_views.len());
+        Ok(())
+    }
+
+    /// Sets the memory allowed for parsed document contents, the least recently
+    /// used contents are dropped above it and reloaded on demand
+    pub fn set_detail_budget(&mut self, bytes: usize) {
+        self.detail_budget = bytes;
+    }
+
+    /// Gets the last modification time of the node at inode `ino`
+    pub fn modified(&self, ino: usize) -> Option<SystemTime> {
+        self.get_node(ino).map(|n| n.borrow().get_mtime())
+    }
+
+    /// Gets the creation time of the node at inode `ino`, as recorded by the tablet
+    pub fn created(&self, ino: usize) -> Option<SystemTime> {
+        let ms = self.get_node(ino)?.borrow().get_created_ms()?;
+        SystemTime::UNIX_EPOCH.checked_add(Duration::from_millis(ms))
+    }
+
+    /// Reads at most `size` bytes of the document at inode `ino` from `offset`
+    pub fn read(&self, ino: usize, offset: u64, size: u32) -> Result<Vec<u8>, RemarkableError> {
+        self.node_read_ofs_size(ino, None, offset, size, &|| false)
+    }
+
+    /// Firmware version of the tablet (e.g. "3.11.2.5")
+    pub fn firmware_version(&self) -> Result<String, RemarkableError> {
+        let conf = self
+            .session
+            .execute_cmd(&format!("cat {}", Self::FIRMWARE_CONF))?;
+        release_version(&conf)
+            .map(str::to_owned)
+            .ok_or_else(|| FsError::Unsupported(Self::FIRMWARE_CONF.to_string()).into())
+    }
+
+    /// Runs the shell `command` on the tablet as the logged in user
+    pub fn run_command(&self, command: &str) -> Result<crate::CommandOutput, RemarkableError> {
+        self.session.run_command(command)
+    }
+
+    /// Runs the shell `command` on the tablet, its output copied into `out`
+    /// while it runs. Returns its exit status
+    pub fn stream_command(
+        &self,
+        command: &str,
+        out: &mut dyn std::io::Write,
+    ) -> Result<i32, RemarkableError> {
+        self.session.stream_command(command, out)
+    }
+
+    /// Sets how documents are laid out under the document root
+    pub fn set_layout(&mut self, layout: Box<dyn StorageLayout>) {
+        self.layout = layout;
+    }
+
+    /// document layout, shared with helper modules
+    pub(crate) fn layout(&self) -> &dyn StorageLayout {
+        self.layout.as_ref()
+    }
+
+    /// ssh session to the tablet, shared with helper modules
+    pub(crate) fn session(&self) -> &SshWrapper {
+        &self.session
+    }
+
+    /// Serves the connection to other processes on the unix socket `path` for
+    /// as long as the filesystem lives, see `RemarkableFsBuilder::share_connection`
+    pub fn serve_connection(&mut self, path: &Path) -> Result<(), RemarkableError> {
+        let server = ShareServer::spawn(
+            path,
+            self.session.runner(),
+            self.session.host_key_fingerprint(),
+        )
+        .with_context(|| format!("sharing the connection on {path:?}"))?;
+        self.share_server = Some(server);
+        Ok(())
     }
 
     /// RemarkableFs is consumed by mount
-    pub fn mount(self) -> Result<(), std::io::Error> {
+    pub fn mount(mut self) -> Result<(), std::io::Error> {
+        if self.mount_point.as_os_str().is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Mountpoint not provided",
+            ));
+        }
         let mountpoint = &self.mount_point.clone();
         let options = &self.options().clone();
-        fuser::mount2(self, mountpoint, options)
+        let Some(poller) = self.start_refresher() else {
+            return fuser::mount2(self, mountpoint, options);
+        };
+        let mut session = fuser::Session::new(self, mountpoint, options)?;
+        poller.spawn(session.notifier());
+        session.run()
     }
 
     #[cfg(test)]
     /// For tests purposes of node_readir from library main lib.rs
-    pub fn pub_readdir(&mut self, ino: usize) -> Result<&[FuserChild], RemarkableError> {
-        self.node_readdir(ino, 0)
+    pub fn pub_readdir(&mut self, ino: usize) -> Result<Vec<FuserChild>, RemarkableError> {
+        let mut children = vec![];
+        self.node_readdir(ino, 0, &mut |c| {
+            children.push(c.clone());
+            false
+        })?;
+        Ok(children)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        lru_victims, release_version, DirListing, PermissionPolicy, RemarkableFs, XattrReply,
+    };
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_release_version() {
+        let conf = "[General]\nREMARKABLE_RELEASE_VERSION=3.11.2.5\nGROUP=Prod\n";
+        assert_eq!(release_version(conf), Some("3.11.2.5"));
+        assert_eq!(release_version("[General]\n"), None);
+    }
+
+    #[test]
+    fn test_listing_positions_survive_refresh() {
+        let mut listing = DirListing::default();
+        listing.refresh(vec!["/r/a.metadata".into(), "/r/b.metadata".into()]);
+        let first = DirListing::FIRST_POSITION;
+        let step = DirListing::POSITIONS_PER_ENTRY;
+        assert_eq!(listing.entries[1], (first + step, "/r/b.metadata".into()));
+
+        // a removed, c added : b keeps its position, c goes after it
+        listing.refresh(vec!["/r/c.metadata".into(), "/r/b.metadata".into()]);
+        let positions = listing.entries.iter().map(|e| e.0).collect::<Vec<_>>();
+        assert_eq!(positions, vec![first + step, first + 2 * step]);
+        assert_eq!(listing.first_entry_from(first + 2 * step - 1), 0);
+        assert_eq!(listing.first_entry_from(first + 2 * step), 1);
+        assert_eq!(listing.first_entry_from(first + 3 * step), 2);
+    }
+
+    #[test]
+    fn test_detail_budget_eviction() {
+        // budget of 1000 bytes, evicting down to 750 : (last use, inode, size)
+        let cold = vec![(7, 2, 200), (3, 5, 100), (9, 4, 300), (1, 8, 150)];
+        assert_eq!(lru_victims(cold.clone(), 1100, 750), vec![8, 5, 2]);
+        assert_eq!(lru_victims(cold.clone(), 800, 750), vec![8]);
+        assert!(lru_victims(cold.clone(), 750, 750).is_empty());
+        // open and just loaded nodes are not candidates : evicting all the
+        // others may not be enough
+        assert_eq!(lru_victims(cold, 5000, 750), vec![8, 5, 2, 4]);
+        assert!(lru_victims(vec![], 5000, 750).is_empty());
+    }
+
+    fn attr(kind: fuser::FileType, perm: u16) -> fuser::FileAttr {
+        fuser::FileAttr {
+            ino: 2,
+            size: 0,
+            blocks: 0,
+            atime: SystemTime::UNIX_EPOCH,
+            mtime: SystemTime::UNIX_EPOCH,
+            ctime: SystemTime::UNIX_EPOCH,
+            crtime: SystemTime::UNIX_EPOCH,
+            kind,
+            perm,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        }
+    }
+
+    #[test]
+    fn test_permission_policy() {
+        let default = PermissionPolicy::default();
+        // SAFETY: getuid/getgid have no preconditions and cannot fail
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        assert_eq!((default.file_mode, default.dir_mode), (0o444, 0o555));
+        assert_eq!((default.uid, default.gid), (uid, gid));
+
+        let policy = PermissionPolicy {
+            file_mode: 0o640,
+            dir_mode: 0o750,
+            uid: 1000,
+            gid: 100,
+        };
+        let mut file = attr(fuser::FileType::RegularFile, 0o644);
+        policy.apply(&mut file);
+        assert_eq!((file.perm, file.uid, file.gid), (0o640, 1000, 100));
+        let mut dir = attr(fuser::FileType::Directory, 0o755);
+        policy.apply(&mut dir);
+        assert_eq!((dir.perm, dir.uid, dir.gid), (0o750, 1000, 100));
+    }
+
+    #[test]
+    fn test_remote_perm_xattr() {
+        let xattr = RemarkableFs::remote_perm_xattr(0o644);
+        assert_eq!(
+            xattr,
+            ("user.remarkable.remote_perm".to_owned(), b"0644".to_vec())
+        );
+
+        // getxattr : size query, value, too small a buffer, missing attribute
+        let value = Some(xattr.1.clone());
+        assert_eq!(XattrReply::value(value.clone(), 0), Ok(XattrReply::Size(4)));
+        assert_eq!(
+            XattrReply::value(value.clone(), 64),
+            Ok(XattrReply::Data(b"0644".to_vec()))
+        );
+        assert_eq!(XattrReply::value(value, 3), Err(libc::ERANGE));
+        assert_eq!(XattrReply::value(None, 64), Err(libc::ENODATA));
+
+        // listxattr : nul terminated names
+        let names = b"user.remarkable.remote_perm\0user.remarkable.ghost\0".to_vec();
+        let xattrs = vec![xattr, ("user.remarkable.ghost".to_owned(), vec![])];
+        assert_eq!(
+            XattrReply::names(xattrs.clone(), 0),
+            Ok(XattrReply::Size(names.len() as u32))
+        );
+        assert_eq!(
+            XattrReply::names(xattrs.clone(), 64),
+            Ok(XattrReply::Data(names))
+        );
+        assert_eq!(XattrReply::names(xattrs, 10), Err(libc::ERANGE));
+        assert_eq!(XattrReply::names(vec![], 0), Ok(XattrReply::Size(0)));
     }
 }
@@ -0,0 +1,360 @@
+use super::RemarkableFs;
+use crate::cache::DiskCache;
+use crate::RemarkableError;
+use log::debug;
+use std::collections::VecDeque;
+use std::fmt;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+
+/// How a document is being read, from its latest reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum AccessPattern {
+    /// too few reads to tell
+    #[default]
+    Unknown,
+    /// each read starts where the previous one ended (viewers loading a file)
+    Sequential,
+    /// reads jump around (page thumbnails, indexers)
+    Random,
+}
+
+impl fmt::Display for AccessPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AccessPattern::Unknown => "unknown",
+            AccessPattern::Sequential => "sequential",
+            AccessPattern::Random => "random",
+        })
+    }
+}
+
+/// Classifies the reads of one file over a window of the latest ones
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct AccessTracker {
+    /// where the next read starts when sequential
+    next: Option<u64>,
+    /// one bit per read of the window, set for sequential ones
+    history: u16,
+    reads: u32,
+}
+
+impl AccessTracker {
+    /// reads considered, older ones are forgotten
+    const WINDOW: u32 = 8;
+    /// reads needed before a pattern is given
+    const MIN_READS: u32 = 3;
+    /// forward gap still sequential, the kernel skips what it already cached
+    const MAX_GAP: u64 = 128 * 1024;
+
+    pub(crate) fn record(&mut self, offset: u64, len: usize) {
+        let sequential = self
+            .next
+            .is_some_and(|next| offset >= next && offset - next <= Self::MAX_GAP);
+        let mask = (1u16 << Self::WINDOW) - 1;
+        self.history = ((self.history << 1) | u16::from(sequential)) & mask;
+        self.reads = self.reads.saturating_add(1);
+        self.next = Some(offset + len as u64);
+    }
+
+    pub(crate) fn reads(&self) -> u32 {
+        self.reads
+    }
+
+    /// sequential when 3/4 of the window was, the first read never counting
+    pub(crate) fn pattern(&self) -> AccessPattern {
+        let window = self.reads.min(Self::WINDOW);
+        if window < Self::MIN_READS {
+            return AccessPattern::Unknown;
+        }
+        // the first read of a file has nothing to follow
+        let judged = if self.reads <= Self::WINDOW {
+            window - 1
+        } else {
+            window
+        };
+        if self.history.count_ones() * 4 >= judged * 3 {
+            AccessPattern::Sequential
+        } else {
+            AccessPattern::Random
+        }
+    }
+}
+
+/// Payload bytes fetched beyond what reads asked for, by file
+#[derive(Debug, Default)]
+pub(crate) struct ReadCache {
+    /// (offset, data) windows, most recent last
+    windows: VecDeque<(u64, Vec<u8>)>,
+    /// fetch running in the background past the windows, if any
+    ahead: Option<Readahead>,
+    pub(crate) hits: u32,
+}
+
+/// Payload fetched in the background while a file is read sequentially
+#[derive(Debug)]
+struct Readahead {
+    start: u64,
+    len: u64,
+    arrival: Receiver<Result<Vec<u8>, RemarkableError>>,
+}
+
+impl ReadCache {
+    /// bytes fetched at once while a file is read sequentially
+    const READAHEAD: u64 = 1024 * 1024;
+    /// blocks fetched around random reads
+    const BLOCK: u64 = 64 * 1024;
+    /// blocks kept for a file read randomly
+    const BLOCKS: usize = 16;
+    /// bytes fetched in the background once the reads come this close to the
+    /// end of the windows
+    const AHEAD: u64 = 512 * 1024;
+
+    /// `len` bytes at `offset`, when a window holds them all
+    fn get(&mut self, offset: u64, len: u64) -> Option<Vec<u8>> {
+        let (start, data) = self
+            .windows
+            .iter()
+            .find(|(start, data)| offset >= *start && offset + len <= start + data.len() as u64)?;
+        let from = (offset - start) as usize;
+        let found = data[from..from + len as usize].to_vec();
+        self.hits += 1;
+        Some(found)
+    }
+
+    /// where the windows end, the next readahead starting there
+    fn end(&self) -> u64 {
+        self.windows
+            .iter()
+            .map(|(start, data)| start + data.len() as u64)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Adds the readahead to the windows once it arrived, waiting for it when
+    /// it holds `offset` rather than fetching the same bytes again
+    fn collect_readahead(&mut self, offset: u64) {
+        let Some(ahead) = &self.ahead else {
+            return;
+        };
+        let arrived = if (ahead.start..ahead.start + ahead.len).contains(&offset) {
+            ahead.arrival.recv().ok()
+        } else {
+            match ahead.arrival.try_recv() {
+                Ok(arrived) => Some(arrived),
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => None,
+            }
+        };
+        let Some(ahead) = self.ahead.take() else {
+            return;
+        };
+        match arrived {
+            Some(Ok(data)) => match self.windows.back_mut() {
+                // merged with the window being read, so that reads across both
+                // are served, the bytes before `offset` being read already
+                Some((start, window)) if *start + window.len() as u64 == ahead.start => {
+                    let read = offset.saturating_sub(*start).min(window.len() as u64);
+                    window.drain(..read as usize);
+                    *start += read;
+                    window.extend(data);
+                }
+                _ => {
+                    while self.windows.len() > 1 {
+                        self.windows.pop_front();
+                    }
+                    self.windows.push_back((ahead.start, data));
+                }
+            },
+            Some(Err(e)) => debug!("readahead at {} failed : {e}", ahead.start),
+            None => debug!("readahead at {} lost", ahead.start),
+        }
+    }
+
+    fn insert(&mut self, pattern: AccessPattern, offset: u64, data: Vec<u8>) {
+        match pattern {
+            AccessPattern::Sequential => self.windows.clear(),
+            _ => {
+                if self.windows.len() >= Self::BLOCKS {
+                    self.windows.pop_front();
+                }
+            }
+        }
+        self.windows.push_back((offset, data));
+    }
+}
+
+/// (offset, length) fetched for a read of `len` bytes at `offset` of a file of
+/// `size` bytes : far ahead when sequential, whole blocks when random, the
+/// read alone until the pattern is known
+fn fetch_window(pattern: AccessPattern, offset: u64, len: u64, size: u64) -> (u64, u64) {
+    let (start, end) = match pattern {
+        AccessPattern::Unknown => (offset, offset + len),
+        AccessPattern::Sequential => (offset, offset + len.max(ReadCache::READAHEAD)),
+        AccessPattern::Random => (
+            offset / ReadCache::BLOCK * ReadCache::BLOCK,
+            (offset + len).div_ceil(ReadCache::BLOCK) * ReadCache::BLOCK,
+        ),
+    };
+    let end = end.min(size).max(offset + len);
+    (start, end - start)
+}
+
+impl RemarkableFs {
+    /// access pattern of the open document `ino`
+    pub(crate) fn access_pattern(&self, ino: usize) -> AccessPattern {
+        self.transfers
+            .get(&ino)
+            .map(|t| t.access.pattern())
+            .unwrap_or_default()
+    }
+
+    /// Reads `len` bytes at `offset` of the payload of `ino` (`size` bytes)
+    /// through its read cache, `read` fetching from the tablet. What is
+    /// fetched around the read depends on the access pattern of the document.
+    pub(crate) fn cached_read(
+        &self,
+        ino: usize,
+        offset: u64,
+        len: u64,
+        size: u64,
+        read: impl FnOnce(u64, u64) -> Result<Vec<u8>, RemarkableError>,
+    ) -> Result<Vec<u8>, RemarkableError> {
+        if len == 0 {
+            return Ok(vec![]);
+        }
+        let mut caches = self.read_caches.borrow_mut();
+        let cache = caches.entry(ino).or_default();
+        cache.collect_readahead(offset);
+        if let Some(data) = cache.get(offset, len) {
+            return Ok(data);
+        }
+        let pattern = self.access_pattern(ino);
+        let (start, fetch) = fetch_window(pattern, offset, len, size);
+        let data = read(start, fetch)?;
+        if pattern == AccessPattern::Unknown {
+            return Ok(data);
+        }
+        debug!("{ino} read {fetch} bytes at {start} for {len} at {offset}, {pattern}");
+        let from = (offset - start) as usize;
+        let wanted = data[from..from + len as usize].to_vec();
+        cache.insert(pattern, start, data);
+        Ok(wanted)
+    }
+
+    /// Fetches in the background the payload at `path` of `ino`, `size` bytes
+    /// long, that follows its windows when the sequential reads of the file
+    /// reached `end` and come close to where the windows end
+    pub(crate) fn read_ahead(&self, ino: usize, path: &Path, end: u64, size: u64) {
+        // the runner of a shared connection cannot read
+        if self.access_pattern(ino) != AccessPattern::Sequential || self.session.is_shared() {
+            return;
+        }
+        let mut caches = self.read_caches.borrow_mut();
+        let Some(cache) = caches.get_mut(&ino).filter(|c| c.ahead.is_none()) else {
+            return;
+        };
+        let start = cache.end();
+        if start >= size || start < end || start - end > ReadCache::AHEAD {
+            return;
+        }
+        let len = ReadCache::AHEAD.min(size - start);
+        let (sender, arrival) = mpsc::channel();
+        let (runner, path) = (self.session.runner(), path.to_path_buf());
+        std::thread::spawn(move || {
+            // the file may be closed meanwhile
+            let _ = sender.send(runner.read_range(&path, start, len));
+        });
+        debug!("{ino} reading {len} bytes ahead at {start}");
+        cache.ahead = Some(Readahead {
+            start,
+            len,
+            arrival,
+        });
+    }
+
+    /// Keeps the payload blocks read in `cache`, on disk, on top of the read
+    /// caches kept in memory while documents are open
+    pub fn set_disk_cache(&mut self, cache: DiskCache) {
+        self.disk_cache = Some(cache);
+    }
+
+    /// reads of `ino` served by its read cache
+    pub(crate) fn cache_hits(&self, ino: usize) -> u32 {
+        self.read_caches
+            .borrow()
+            .get(&ino)
+            .map_or(0, |cache| cache.hits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access_pattern() {
+        let mut tracker = AccessTracker::default();
+        for i in 0..6 {
+            tracker.record(i * 4096, 4096);
+        }
+        assert_eq!(tracker.pattern(), AccessPattern::Sequential);
+        let mut tracker = AccessTracker::default();
+        for offset in [900_000, 12_000, 4_000_000, 4096, 2_000_000, 700] {
+            tracker.record(offset, 4096);
+        }
+        assert_eq!(tracker.pattern(), AccessPattern::Random);
+        assert_eq!(AccessTracker::default().pattern(), AccessPattern::Unknown);
+    }
+
+    #[test]
+    fn test_fetch_window() {
+        let mib = 1024 * 1024;
+        assert_eq!(
+            fetch_window(AccessPattern::Unknown, 100, 10, 10 * mib),
+            (100, 10)
+        );
+        assert_eq!(
+            fetch_window(AccessPattern::Sequential, 4096, 4096, 10 * mib),
+            (4096, mib)
+        );
+        assert_eq!(
+            fetch_window(AccessPattern::Sequential, 4096, 4096, 20_000),
+            (4096, 20_000 - 4096)
+        );
+        assert_eq!(
+            fetch_window(AccessPattern::Random, 70_000, 4096, 10 * mib),
+            (65_536, 65_536)
+        );
+        let mut cache = ReadCache::default();
+        cache.insert(AccessPattern::Random, 65_536, vec![7; 65_536]);
+        assert_eq!(cache.get(70_000, 4), Some(vec![7; 4]));
+        assert_eq!(cache.get(130_000, 4096), None);
+        assert_eq!(cache.hits, 1);
+    }
+
+    #[test]
+    fn test_readahead() {
+        let mut cache = ReadCache::default();
+        cache.insert(AccessPattern::Sequential, 0, vec![1; 1000]);
+        let (sender, arrival) = mpsc::channel();
+        cache.ahead = Some(Readahead {
+            start: cache.end(),
+            len: 500,
+            arrival,
+        });
+        // not arrived, not needed yet
+        cache.collect_readahead(600);
+        assert!(cache.ahead.is_some());
+        sender.send(Ok(vec![2; 500])).unwrap();
+        cache.collect_readahead(800);
+        assert!(cache.ahead.is_none());
+        assert_eq!(cache.end(), 1500);
+        // a read across both windows is served
+        assert_eq!(
+            cache.get(990, 20),
+            Some([vec![1; 10], vec![2; 10]].concat())
+        );
+        assert_eq!(cache.get(700, 10), None);
+    }
+}
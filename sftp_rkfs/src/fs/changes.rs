@@ -0,0 +1,280 @@
+use super::paths::PathResolver;
+use super::RemarkableFs;
+use crate::names;
+use crate::nodes::Node;
+use crate::{ErrorContext, RemarkableError};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// What happened to a document between two snapshots
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Moved,
+    Trashed,
+    Removed,
+}
+
+/// A document change, as streamed by `rmkmount watch`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChangeEvent {
+    pub kind: ChangeKind,
+    pub uid: String,
+    /// visible path after the change, before it for trashed and removed documents
+    pub path: String,
+    /// visible path before a move
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    /// last modification reported by the tablet, in ms since the epoch
+    pub last_modified: u64,
+}
+
+/// metadata fields the snapshot compares
+#[serde_as]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct ItemState {
+    #[serde(default)]
+    deleted: bool,
+    #[serde_as(as = "DisplayFromStr")]
+    last_modified: u64,
+    pub(super) parent: String,
+    #[serde(rename = "type")]
+    type_: String,
+    pub(super) visible_name: String,
+}
+
+impl ItemState {
+    pub(super) fn is_document(&self) -> bool {
+        self.type_ == "DocumentType"
+    }
+}
+
+/// Name, parent and modification time of every item on the tablet at one point
+/// in time. Two snapshots are compared to detect changes.
+#[derive(Debug, Default)]
+pub struct TreeSnapshot {
+    pub(super) items: HashMap<String, ItemState>,
+    /// visible paths of the items, as the mount presents them
+    paths: PathResolver,
+}
+
+impl RemarkableFs {
+    /// Takes a snapshot of all the metadata files, in a single remote command
+    pub fn snapshot(&self) -> Result<TreeSnapshot, RemarkableError> {
+        let glob = self.layout.metadata_glob(&self.document_root);
+        let files = self.session.read_files(&glob).with_context(|| {
+            format!(
+                "listing metadata of {}",
+                names::remote_str(&self.document_root)
+            )
+        })?;
+        let mut snapshot = TreeSnapshot::parse(&files);
+        let contents = self.layout.content_glob(&self.document_root);
+        match self
+            .session
+            .execute_cmd(&format!(r#"grep -o -H '"fileType": *"[^"]*"' {contents}"#))
+        {
+            Ok(out) => snapshot.set_file_types(&out),
+            Err(e) => debug!("document types unknown, paths without extension : {e}"),
+        }
+        snapshot.paths.set_collision_policy(self.collision_policy);
+        Ok(snapshot)
+    }
+}
+
+impl TreeSnapshot {
+    pub(super) fn parse(files: &[(PathBuf, String)]) -> Self {
+        let mut items = HashMap::new();
+        let mut paths = PathResolver::default();
+        for (path, content) in files {
+            let Some(uid) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            match serde_json::from_str::<ItemState>(content) {
+                Ok(state) if !state.deleted => {
+                    let modified = Some(state.last_modified);
+                    paths.insert(uid, &state.parent, &state.visible_name, None, modified);
+                    items.insert(uid.to_owned(), state);
+                }
+                Ok(_) => debug!("{uid} is deleted"),
+                Err(e) => warn!("metadata of {uid} left out of snapshot : {e}"),
+            }
+        }
+        Self { items, paths }
+    }
+
+    /// Gives the documents the extension of their payload, from `grep -o -H`
+    /// lines of their content files : `<content file>:"fileType": "<type>"`
+    fn set_file_types(&mut self, out: &str) {
+        for line in out.lines() {
+            let Some((file, matched)) = line.rsplit_once(":\"fileType\"") else {
+                continue;
+            };
+            let uid = Path::new(file).file_stem().and_then(|s| s.to_str());
+            // notebooks are presented without extension
+            let extension = matched
+                .split('"')
+                .nth(1)
+                .filter(|t| matches!(*t, "pdf" | "epub"));
+            let (Some(uid), Some(extension)) = (uid, extension) else {
+                continue;
+            };
+            if let Some(item) = self.items.get(uid).filter(|i| i.is_document()) {
+                let modified = Some(item.last_modified);
+                self.paths.insert(
+                    uid,
+                    &item.parent,
+                    &item.visible_name,
+                    Some(extension),
+                    modified,
+                );
+            }
+        }
+    }
+
+    /// visible paths of the items, as the mount presents them
+    pub fn paths(&self) -> &PathResolver {
+        &self.paths
+    }
+
+    /// Number of items (documents and collections) in the snapshot
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// visible path of `uid`, built from the names of its ancestors
+    pub(super) fn path_of(&self, uid: &str) -> String {
+        self.paths.path_of(uid)
+    }
+
+    /// Document changes from `previous` to this snapshot
+    pub fn changes_since(&self, previous: &TreeSnapshot) -> Vec<ChangeEvent> {
+        let mut events = vec![];
+        for (uid, item) in self.items.iter().filter(|(_, i)| i.is_document()) {
+            let event = |kind, path, from| ChangeEvent {
+                kind,
+                uid: uid.clone(),
+                path,
+                from,
+                last_modified: item.last_modified,
+            };
+            let Some(before) = previous.items.get(uid) else {
+                events.push(event(ChangeKind::Added, self.path_of(uid), None));
+                continue;
+            };
+            if item.parent != before.parent && item.parent == Node::TRASH_PARENT_UID {
+                events.push(event(ChangeKind::Trashed, previous.path_of(uid), None));
+                continue;
+            }
+            let (path, from) = (self.path_of(uid), previous.path_of(uid));
+            if path != from {
+                events.push(event(ChangeKind::Moved, path.clone(), Some(from)));
+            }
+            if item.last_modified != before.last_modified {
+                events.push(event(ChangeKind::Modified, path, None));
+            }
+        }
+        for (uid, before) in previous.items.iter().filter(|(_, i)| i.is_document()) {
+            if !self.items.contains_key(uid) {
+                events.push(ChangeEvent {
+                    kind: ChangeKind::Removed,
+                    uid: uid.clone(),
+                    path: previous.path_of(uid),
+                    from: None,
+                    last_modified: before.last_modified,
+                });
+            }
+        }
+        events.sort_by(|a, b| (a.last_modified, &a.uid).cmp(&(b.last_modified, &b.uid)));
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(name: &str, parent: &str, kind: &str, modified: u64) -> String {
+        format!(
+            r#"{{"lastModified": "{modified}", "parent": "{parent}", "type": "{kind}", "visibleName": "{name}"}}"#
+        )
+    }
+
+    fn listing(files: &[(&str, String)]) -> Vec<(PathBuf, String)> {
+        files
+            .iter()
+            .map(|(uid, content)| {
+                (
+                    PathBuf::from(format!("/root/{uid}.metadata")),
+                    content.clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_changes_since() {
+        let before = TreeSnapshot::parse(&listing(&[
+            ("w", metadata("Work", "", "CollectionType", 1)),
+            ("a", metadata("Spec", "w", "DocumentType", 10)),
+            ("b", metadata("Draft", "", "DocumentType", 10)),
+            ("c", metadata("Old", "", "DocumentType", 10)),
+        ]));
+        let after = TreeSnapshot::parse(&listing(&[
+            ("w", metadata("Work", "", "CollectionType", 1)),
+            ("a", metadata("Spec", "", "DocumentType", 20)),
+            ("b", metadata("Draft", "trash", "DocumentType", 30)),
+            ("d", metadata("New", "w", "DocumentType", 40)),
+        ]));
+        assert_eq!(after.len(), 4);
+        let summary = after
+            .changes_since(&before)
+            .into_iter()
+            .map(|e| (e.kind, e.path, e.from))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (ChangeKind::Removed, "Old".to_string(), None),
+                (
+                    ChangeKind::Moved,
+                    "Spec".to_string(),
+                    Some("Work/Spec".to_string())
+                ),
+                (ChangeKind::Modified, "Spec".to_string(), None),
+                (ChangeKind::Trashed, "Draft".to_string(), None),
+                (ChangeKind::Added, "Work/New".to_string(), None),
+            ]
+        );
+        assert!(before.changes_since(&before).is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_file_types() {
+        let mut snapshot = TreeSnapshot::parse(&listing(&[
+            ("a", metadata("Spec", "", "DocumentType", 1)),
+            ("b", metadata("Spec", "", "DocumentType", 2)),
+            ("c", metadata("Notes", "", "DocumentType", 3)),
+        ]));
+        assert_eq!(snapshot.path_of("b"), "Spec (b)");
+        snapshot.set_file_types(
+            "/root/a.content:\"fileType\": \"pdf\"\n\
+             /root/b.content:\"fileType\":\"epub\"\n\
+             /root/c.content:\"fileType\": \"notebook\"\n",
+        );
+        assert_eq!(snapshot.path_of("a"), "Spec.pdf");
+        assert_eq!(snapshot.path_of("b"), "Spec.epub");
+        assert_eq!(snapshot.path_of("c"), "Notes");
+        assert_eq!(snapshot.uid_at("Spec.epub").as_deref(), Some("b"));
+    }
+}
@@ -0,0 +1,81 @@
+use super::RemarkableFs;
+use crate::{FsError, RemarkableError};
+use log::{debug, info, warn};
+use std::time::{Duration, SystemTime};
+
+impl RemarkableFs {
+    /// Tablet clock minus host clock in seconds, positive when the tablet is
+    /// ahead. Measured over a remote command, so only good to a second or so.
+    pub fn clock_skew(&self) -> Result<i64, RemarkableError> {
+        let host = unix_seconds(SystemTime::now());
+        let out = self.session.execute_cmd("date +%s")?;
+        let tablet = out
+            .trim()
+            .parse::<i64>()
+            .map_err(|_| FsError::Unsupported(format!("tablet date {:?}", out.trim())))?;
+        Ok(tablet - host)
+    }
+
+    /// Sets the tablet clock to `time`, and its hardware clock when it has one
+    /// so that the time survives a reboot
+    pub fn set_clock(&self, time: SystemTime) -> Result<(), RemarkableError> {
+        let out = self
+            .session
+            .execute_cmd(&set_clock_cmd(unix_seconds(time)))?;
+        if !out.lines().any(|l| l == "date set") {
+            return Err(
+                RemarkableError::from(std::io::Error::other(out.trim().to_owned()))
+                    .context("setting the tablet clock"),
+            );
+        }
+        if !out.lines().any(|l| l == "hwclock set") {
+            warn!("tablet hardware clock not set, the time may be lost on reboot");
+        }
+        Ok(())
+    }
+
+    /// Sets the tablet clock to the host time when they are more than
+    /// `max_skew` apart, returns the skew that was corrected
+    pub fn sync_clock(&self, max_skew: Duration) -> Result<Option<i64>, RemarkableError> {
+        let skew = self.clock_skew()?;
+        if skew.unsigned_abs() <= max_skew.as_secs() {
+            debug!("tablet clock within {skew}s of the host");
+            return Ok(None);
+        }
+        self.set_clock(SystemTime::now())?;
+        info!("tablet clock was {skew}s off, set to the host time");
+        Ok(Some(skew))
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> i64 {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+/// sets the system clock to `seconds` since epoch, then the hardware clock,
+/// printing a line for each clock set. Errors of `date` are kept in the output
+fn set_clock_cmd(seconds: i64) -> String {
+    format!(
+        "date -u -s @{seconds} 2>&1 > /dev/null && echo 'date set' && \
+         hwclock -w -u > /dev/null 2>&1 && echo 'hwclock set'"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unix_seconds() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(unix_seconds(time), 1_700_000_000);
+        assert_eq!(
+            unix_seconds(SystemTime::UNIX_EPOCH - Duration::from_secs(5)),
+            -5
+        );
+        assert!(set_clock_cmd(1_700_000_000).starts_with("date -u -s @1700000000 "));
+    }
+}
@@ -0,0 +1,279 @@
+use super::RemarkableFs;
+use crate::sshutils::CommandRunner;
+use log::{debug, error, warn};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+/// A read of a document payload waiting for its data
+struct PendingRead {
+    ino: usize,
+    path: PathBuf,
+    offset: u64,
+    len: u64,
+    reply: fuser::ReplyData,
+}
+
+/// Reads answered by a thread of their own. The reads the kernel issues
+/// together (its readahead next to the reads of the application) are merged
+/// when adjacent or overlapping, one sftp request serving them all.
+pub(crate) struct ReadCoalescer {
+    sender: mpsc::Sender<PendingRead>,
+    /// (ino, errno) of the failed reads, reported by the next close
+    failures: Arc<Mutex<Vec<(usize, libc::c_int)>>>,
+    /// set when a read failed with the connection, a new session is needed
+    broken: Arc<AtomicBool>,
+}
+
+/// Reads served by one request: `[start, end)` of the payload of `ino`, and
+/// the indexes of the reads it answers
+#[derive(Debug, PartialEq)]
+struct MergedRead {
+    ino: usize,
+    start: u64,
+    end: u64,
+    reads: Vec<usize>,
+}
+
+impl ReadCoalescer {
+    /// how long reads arriving after the first are waited for
+    const WINDOW: Duration = Duration::from_millis(2);
+    /// reads waited for at most
+    const MAX_BATCH: usize = 32;
+    /// largest request reads are merged into
+    const MAX_MERGED: u64 = 1024 * 1024;
+
+    fn start(runner: CommandRunner) -> std::io::Result<Self> {
+        let (sender, receiver) = mpsc::channel::<PendingRead>();
+        let failures = Arc::new(Mutex::new(vec![]));
+        let broken = Arc::new(AtomicBool::new(false));
+        let (thread_failures, thread_broken) = (Arc::clone(&failures), Arc::clone(&broken));
+        std::thread::Builder::new()
+            .name("read-coalescer".to_owned())
+            .spawn(move || {
+                while let Ok(first) = receiver.recv() {
+                    let mut batch = vec![first];
+                    while batch.len() < Self::MAX_BATCH {
+                        match receiver.recv_timeout(Self::WINDOW) {
+                            Ok(read) => batch.push(read),
+                            Err(_) => break,
+                        }
+                    }
+                    for (ino, errno, lost) in serve(&runner, batch) {
+                        thread_broken.fetch_or(lost, Ordering::Relaxed);
+                        if let Ok(mut failures) = thread_failures.lock() {
+                            failures.push((ino, errno));
+                        }
+                    }
+                }
+            })?;
+        Ok(Self {
+            sender,
+            failures,
+            broken,
+        })
+    }
+}
+
+/// Answers the reads of `batch`, merged. Returns the (ino, errno, connection
+/// lost) of the failures
+fn serve(runner: &CommandRunner, batch: Vec<PendingRead>) -> Vec<(usize, libc::c_int, bool)> {
+    let ranges = batch
+        .iter()
+        .map(|r| (r.ino, r.offset, r.len))
+        .collect::<Vec<_>>();
+    let merged = merge(&ranges, ReadCoalescer::MAX_MERGED);
+    if merged.len() < batch.len() {
+        debug!("{} reads served by {} requests", batch.len(), merged.len());
+    }
+    let mut replies = batch.into_iter().map(Some).collect::<Vec<_>>();
+    let mut failures = vec![];
+    for request in merged {
+        let Some(path) = replies[request.reads[0]].as_ref().map(|r| r.path.clone()) else {
+            continue;
+        };
+        let fetched = runner.read_range(&path, request.start, request.end - request.start);
+        if let Err(e) = &fetched {
+            error!("read failed for {} : {e}", request.ino);
+            failures.push((request.ino, libc::EIO, e.is_connection_lost()));
+        }
+        for read in request.reads.iter().filter_map(|&i| replies[i].take()) {
+            match &fetched {
+                Ok(data) => {
+                    let from = ((read.offset - request.start) as usize).min(data.len());
+                    let to = (from + read.len as usize).min(data.len());
+                    read.reply.data(&data[from..to]);
+                }
+                Err(_) => read.reply.error(libc::EIO),
+            }
+        }
+    }
+    failures
+}
+
+/// Groups the reads `(ino, offset, len)` of the same file that are adjacent or
+/// overlapping, within `max_len` bytes per group
+fn merge(reads: &[(usize, u64, u64)], max_len: u64) -> Vec<MergedRead> {
+    let mut order = (0..reads.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| (reads[i].0, reads[i].1));
+    let mut merged: Vec<MergedRead> = vec![];
+    for i in order {
+        let (ino, offset, len) = reads[i];
+        match merged.last_mut() {
+            Some(last)
+                if last.ino == ino
+                    && offset <= last.end
+                    && last.end.max(offset + len) - last.start <= max_len =>
+            {
+                last.end = last.end.max(offset + len);
+                last.reads.push(i);
+            }
+            _ => merged.push(MergedRead {
+                ino,
+                start: offset,
+                end: offset + len,
+                reads: vec![i],
+            }),
+        }
+    }
+    merged
+}
+
+impl RemarkableFs {
+    /// Merges the reads of document payloads arriving together into fewer
+    /// requests, answered from another thread. They skip the read cache.
+    pub fn set_coalesce_reads(&mut self, enabled: bool) {
+        self.coalesce_reads = enabled;
+    }
+
+    /// Hands the read of `size` bytes at `offset` of `ino` to the coalescing
+    /// thread, which replies. The reply is given back for the reads it does
+    /// not handle: generated files, uploads, or coalescing off.
+    pub(crate) fn coalesce_read(
+        &mut self,
+        ino: usize,
+        offset: i64,
+        size: u32,
+        reply: fuser::ReplyData,
+    ) -> Option<fuser::ReplyData> {
+        if !self.coalesce_reads
+            || offset < 0
+            || self.virtual_files.contains_key(&ino)
+            || self.is_pending_upload(ino)
+        {
+            return Some(reply);
+        }
+        let payload = self.get_node(ino).and_then(|node| {
+            let node = node.borrow();
+            Some((self.payload_path(&node)?, node.get_payload_size()))
+        });
+        let Some((path, payload_size)) = payload else {
+            return Some(reply);
+        };
+        let offset = offset as u64;
+        let len = payload_size.saturating_sub(offset).min(u64::from(size));
+        if len == 0 {
+            return Some(reply);
+        }
+        if self
+            .read_coalescer
+            .as_ref()
+            .is_some_and(|c| c.broken.load(Ordering::Relaxed))
+        {
+            warn!("connection of the coalesced reads lost, reconnecting");
+            if !self.session.is_alive() {
+                if let Err(e) = self.session.reconnect() {
+                    error!("reconnection failed : {e}");
+                    self.disconnected = true;
+                    return Some(reply);
+                }
+                self.reconnected();
+            }
+            self.read_coalescer = None;
+        }
+        if self.read_coalescer.is_none() {
+            match ReadCoalescer::start(self.session.runner()) {
+                Ok(coalescer) => self.read_coalescer = Some(coalescer),
+                Err(e) => {
+                    error!("unable to start the read coalescing thread : {e}");
+                    return Some(reply);
+                }
+            }
+        }
+        self.jobs.note_request();
+        self.record_progress(ino, offset, len as usize);
+        let coalescer = self.read_coalescer.as_ref()?;
+        let read = PendingRead {
+            ino,
+            path,
+            offset,
+            len,
+            reply,
+        };
+        match coalescer.sender.send(read) {
+            Ok(()) => None,
+            Err(mpsc::SendError(read)) => {
+                self.read_coalescer = None;
+                Some(read.reply)
+            }
+        }
+    }
+
+    /// keeps the failures of coalesced reads for the next close of their file
+    pub(crate) fn collect_read_failures(&mut self) {
+        let Some(coalescer) = &self.read_coalescer else {
+            return;
+        };
+        let failures = match coalescer.failures.lock() {
+            Ok(mut failures) => std::mem::take(&mut *failures),
+            Err(_) => return,
+        };
+        for (ino, errno) in failures {
+            self.deferred_errors.record(ino, errno);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let kib = 1024;
+        // readahead next to the application read, then an overlapping one
+        let reads = [
+            (7, 128 * kib, 128 * kib),
+            (7, 0, 128 * kib),
+            (9, 0, 4 * kib),
+            (7, 64 * kib, 16 * kib),
+            (7, 512 * kib, 4 * kib),
+        ];
+        assert_eq!(
+            merge(&reads, 1024 * kib),
+            vec![
+                MergedRead {
+                    ino: 7,
+                    start: 0,
+                    end: 256 * kib,
+                    reads: vec![1, 3, 0],
+                },
+                MergedRead {
+                    ino: 7,
+                    start: 512 * kib,
+                    end: 516 * kib,
+                    reads: vec![4],
+                },
+                MergedRead {
+                    ino: 9,
+                    start: 0,
+                    end: 4 * kib,
+                    reads: vec![2],
+                },
+            ]
+        );
+        // not beyond the largest request
+        assert_eq!(merge(&reads[..2], 128 * kib).len(), 2);
+    }
+}
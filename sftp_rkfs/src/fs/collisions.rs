@@ -0,0 +1,94 @@
+use super::paths::collision_name;
+use super::RemarkableFs;
+use crate::names::CollisionPolicy;
+use crate::nodes::FuserChild;
+use log::{debug, error};
+use std::path::PathBuf;
+
+/// How an entry is listed in its folder
+#[derive(Debug, PartialEq)]
+pub(crate) enum Naming {
+    As(String),
+    /// under its own name, in place of the entry `ino` holding it so far
+    Replacing(usize),
+    Hidden,
+}
+
+impl RemarkableFs {
+    /// Sets what to do with entries of a folder sharing a name
+    pub fn set_collision_policy(&mut self, policy: CollisionPolicy) {
+        self.collision_policy = policy;
+    }
+
+    /// how node `ino` named `name` is listed in a folder, `holder` giving the
+    /// entry already listed under a name, if any
+    pub(crate) fn claim_name(
+        &self,
+        ino: usize,
+        name: &str,
+        holder: &dyn Fn(&str) -> Option<usize>,
+    ) -> Naming {
+        let Some(holding) = holder(name).filter(|&h| h != ino) else {
+            return Naming::As(name.to_owned());
+        };
+        let (uid, modified, has_extension) = match self.get_node(ino) {
+            Some(node) => {
+                let node = node.borrow();
+                (
+                    node.get_unique().to_owned(),
+                    node.get_last_modified_ms(),
+                    node.get_extension().is_some(),
+                )
+            }
+            None => return Naming::As(name.to_owned()),
+        };
+        let naming = match self.collision_policy {
+            policy @ (CollisionPolicy::SuffixUid | CollisionPolicy::SuffixDate) => Naming::As(
+                collision_name(policy, name, &uid, modified, has_extension, &|n| {
+                    holder(n).is_some()
+                }),
+            ),
+            CollisionPolicy::KeepNewest => {
+                let held = self
+                    .get_node(holding)
+                    .and_then(|n| n.borrow().get_last_modified_ms());
+                if modified > held {
+                    Naming::Replacing(holding)
+                } else {
+                    Naming::Hidden
+                }
+            }
+            CollisionPolicy::Error => {
+                error!("{name} is used by both {holding} and {ino}, {ino} left out");
+                Naming::Hidden
+            }
+        };
+        debug!("name collision of {ino} with {holding} on {name} : {naming:?}");
+        naming
+    }
+
+    /// applies the collision policy to a whole listing, as generated by views
+    pub(crate) fn resolve_collisions(&self, children: Vec<FuserChild>) -> Vec<FuserChild> {
+        let mut listed: Vec<FuserChild> = vec![];
+        for child in children {
+            let name = child.3.to_string_lossy().into_owned();
+            let naming = self.claim_name(child.ino(), &name, &|n| {
+                listed.iter().find(|c| c.3 == n).map(|c| c.ino())
+            });
+            match naming {
+                Naming::As(name) => listed.push(FuserChild::new(
+                    child.0,
+                    child.1,
+                    child.2,
+                    PathBuf::from(name),
+                )),
+                Naming::Replacing(holding) => {
+                    listed.retain(|c| c.ino() != holding);
+                    listed.push(child);
+                }
+                Naming::Hidden => {}
+            }
+        }
+        listed
+    }
+}
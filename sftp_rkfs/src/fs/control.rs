@@ -0,0 +1,92 @@
+use super::views::VirtualFile;
+use super::RemarkableFs;
+use crate::nodes::Node;
+use crate::{ErrorContext, FsError, RemarkableError};
+use log::{debug, info};
+
+impl RemarkableFs {
+    /// Acts on `data` written to the control file `file`. For `/.control/refresh`
+    /// each line is a visible path to refresh, an empty write refreshes everything.
+    /// `/.control/jobs` takes `pause` or `resume`.
+    pub(crate) fn control_write(
+        &mut self,
+        file: VirtualFile,
+        data: &[u8],
+    ) -> Result<(), RemarkableError> {
+        let text = String::from_utf8_lossy(data);
+        match file {
+            VirtualFile::Refresh => {}
+            VirtualFile::Jobs => return self.jobs_control(&text),
+            _ => return Err(FsError::Unsupported(format!("writing to {file:?}")).into()),
+        }
+        let mut paths = text
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .peekable();
+        if paths.peek().is_none() {
+            return self.refresh_subtree(Node::ROOT_NODE_INO);
+        }
+        for path in paths {
+            let ino = self
+                .resolve_path(path)
+                .with_context(|| format!("refreshing {path}"))?;
+            self.refresh_subtree(ino)?;
+        }
+        Ok(())
+    }
+
+    /// Lists again the collection `ino` (or the collection holding document `ino`)
+    /// and all its listed sub collections, in a single remote command, so that
+    /// changes made on the tablet show up without waiting for the kernel
+    pub(crate) fn refresh_subtree(&mut self, ino: usize) -> Result<(), RemarkableError> {
+        let dir = if self.is_document(ino) {
+            self.get_node(ino)
+                .map(|n| n.borrow().get_parent())
+                .ok_or(FsError::NodeNotFound(ino))?
+        } else {
+            ino
+        };
+        let mut stale = self
+            .listings
+            .keys()
+            .copied()
+            .filter(|&l| l != dir && self.is_within(l, dir))
+            .collect::<Vec<_>>();
+        stale.push(dir);
+        debug!("refreshing listings {stale:?}");
+        self.attr_cache.borrow_mut().clear();
+        if let Err(e) = self.refresh_listings(&stale) {
+            // a sub collection may be gone, forget listings and start over from dir
+            debug!("batched refresh failed ({e}), forgetting listings");
+            for listing in &stale {
+                self.listings.remove(listing);
+            }
+        }
+        self.load_listing(dir)?;
+        info!(
+            "refreshed collection {dir} and {} listings",
+            stale.len() - 1
+        );
+        Ok(())
+    }
+
+    /// is node `ino` `ancestor` or one of its descendants ?
+    fn is_within(&self, ino: usize, ancestor: usize) -> bool {
+        let mut current = ino;
+        // bounded by the node count, in case of a parent cycle
+        for _ in 0..self.nodes.len() {
+            if current == ancestor {
+                return true;
+            }
+            if current == Node::ROOT_NODE_INO {
+                return false;
+            }
+            match self.get_node(current).map(|n| n.borrow().get_parent()) {
+                Some(parent) if parent != current => current = parent,
+                _ => return false,
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,182 @@
+use super::{release_version, RemarkableFs};
+use crate::{ErrorContext, FsError, RemarkableError};
+use serde::Serialize;
+
+/// What tells a tablet apart from another one answering at the same address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// model name, such as "reMarkable 2.0"
+    pub model: String,
+    /// serial number, when the kernel exposes it
+    pub device_id: Option<String>,
+}
+
+/// Summary of the tablet printed by `rmkmount info`, fields the tablet does not
+/// expose being None
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TabletInfo {
+    /// model of the device tree, such as "reMarkable 2.0"
+    pub model: Option<String>,
+    /// short name of the model: rM1, rM2, Paper Pro or Paper Pro Move
+    pub generation: Option<&'static str>,
+    /// firmware version (e.g. "3.11.2.5")
+    pub firmware: Option<String>,
+    /// build stamp of `/etc/version`
+    pub build: Option<String>,
+    /// battery charge in percent
+    pub battery: Option<u8>,
+    /// partition holding the documents
+    pub storage: Option<StorageInfo>,
+}
+
+/// A partition as reported by `df`, sizes in KiB
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StorageInfo {
+    pub filesystem: String,
+    pub mount_point: String,
+    pub total: u64,
+    pub used: u64,
+    pub available: u64,
+}
+
+impl RemarkableFs {
+    const MODEL_FILE: &'static str = "/proc/device-tree/model";
+    pub(super) const BUILD_FILE: &'static str = "/etc/version";
+    const MACHINE_FILE: &'static str = "/sys/devices/soc0/machine";
+    const SERIAL_FILE: &'static str = "/sys/devices/soc0/serial_number";
+
+    /// SHA256 fingerprint of the host key the tablet presented, as printed by
+    /// `ssh-keygen -l`
+    pub fn host_key_fingerprint(&self) -> Option<String> {
+        self.session.host_key_fingerprint()
+    }
+
+    /// Model and serial number of the tablet
+    pub fn device_info(&self) -> Result<DeviceInfo, RemarkableError> {
+        let out = self.session.execute_cmd(&format!(
+            "printf '%s\\n%s\\n' \"$(cat {} 2>/dev/null)\" \"$(cat {} 2>/dev/null)\"",
+            Self::MACHINE_FILE,
+            Self::SERIAL_FILE
+        ))?;
+        parse_device_info(&out)
+            .ok_or_else(|| FsError::Unsupported(Self::MACHINE_FILE.to_string()).into())
+    }
+
+    /// Model, firmware, battery level and storage of the tablet, read by a
+    /// single remote command
+    pub fn tablet_info(&self) -> Result<TabletInfo, RemarkableError> {
+        // one line each: build, model, battery, df, then the firmware configuration
+        let command = format!(
+            "printf '%s\\n' \"$(cat {build} 2>/dev/null)\" \
+             \"$(tr -d '\\000' < {model} 2>/dev/null)\" \
+             \"$(for s in /sys/class/power_supply/*; do \
+             [ \"$(cat $s/type 2>/dev/null)\" = Battery ] && cat $s/capacity && break; done)\" \
+             \"$(df -kP '{root}' 2>/dev/null | tail -n 1)\"; cat {conf} 2>/dev/null",
+            build = Self::BUILD_FILE,
+            model = Self::MODEL_FILE,
+            root = self.document_root.to_string_lossy(),
+            conf = Self::FIRMWARE_CONF,
+        );
+        let out = self
+            .session
+            .execute_cmd(&command)
+            .context("reading the tablet information")?;
+        Ok(parse_tablet_info(&out))
+    }
+}
+
+/// short name of the device tree `model`
+fn generation(model: &str) -> Option<&'static str> {
+    match model {
+        m if m.contains("reMarkable 1") => Some("rM1"),
+        m if m.contains("reMarkable 2") => Some("rM2"),
+        m if m.contains("Chiappa") || m.contains("Paper Pro Move") => Some("Paper Pro Move"),
+        m if m.contains("Ferrari") || m.contains("Paper Pro") => Some("Paper Pro"),
+        _ => None,
+    }
+}
+
+/// `df -kP` line of a partition
+fn parse_df(line: &str) -> Option<StorageInfo> {
+    let fields = line.split_whitespace().collect::<Vec<_>>();
+    let [filesystem, total, used, available, _, mount_point] = fields[..] else {
+        return None;
+    };
+    Some(StorageInfo {
+        filesystem: filesystem.to_owned(),
+        mount_point: mount_point.to_owned(),
+        total: total.parse().ok()?,
+        used: used.parse().ok()?,
+        available: available.parse().ok()?,
+    })
+}
+
+/// output of the `tablet_info` command
+fn parse_tablet_info(out: &str) -> TabletInfo {
+    let mut lines = out.lines().map(str::trim);
+    let mut next = || lines.next().filter(|l| !l.is_empty()).map(str::to_owned);
+    let (build, model, battery, df) = (next(), next(), next(), next());
+    let conf = out.lines().skip(4).collect::<Vec<_>>().join("\n");
+    TabletInfo {
+        generation: model.as_deref().and_then(generation),
+        model,
+        firmware: release_version(&conf).map(str::to_owned),
+        build,
+        battery: battery.and_then(|b| b.parse().ok()),
+        storage: df.as_deref().and_then(parse_df),
+    }
+}
+
+/// model line then serial number line, the model being required
+fn parse_device_info(out: &str) -> Option<DeviceInfo> {
+    let mut lines = out.lines().map(str::trim);
+    let model = lines.next().filter(|m| !m.is_empty())?.to_owned();
+    let device_id = lines.next().filter(|id| !id.is_empty()).map(str::to_owned);
+    Some(DeviceInfo { model, device_id })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_device_info() {
+        assert_eq!(
+            parse_device_info("reMarkable 2.0\nRM110-313-12345\n"),
+            Some(DeviceInfo {
+                model: "reMarkable 2.0".to_owned(),
+                device_id: Some("RM110-313-12345".to_owned()),
+            })
+        );
+        assert_eq!(
+            parse_device_info("reMarkable 1.0\n\n").and_then(|d| d.device_id),
+            None
+        );
+        assert_eq!(parse_device_info("\n\n"), None);
+    }
+
+    #[test]
+    fn test_parse_tablet_info() {
+        let out = "20231010164506\nreMarkable 2.0\n87\n\
+            /dev/root 7104208 2315224 4404056 34% /home\n\
+            [General]\nREMARKABLE_RELEASE_VERSION=3.8.2.1965\n";
+        let info = parse_tablet_info(out);
+        assert_eq!(info.generation, Some("rM2"));
+        assert_eq!(info.firmware.as_deref(), Some("3.8.2.1965"));
+        assert_eq!(info.build.as_deref(), Some("20231010164506"));
+        assert_eq!(info.battery, Some(87));
+        assert_eq!(
+            info.storage,
+            Some(StorageInfo {
+                filesystem: "/dev/root".to_owned(),
+                mount_point: "/home".to_owned(),
+                total: 7104208,
+                used: 2315224,
+                available: 4404056,
+            })
+        );
+        let info = parse_tablet_info("\nreMarkable Ferrari\n\n\n");
+        assert_eq!(info.generation, Some("Paper Pro"));
+        assert_eq!((info.build, info.battery, info.storage), (None, None, None));
+    }
+}
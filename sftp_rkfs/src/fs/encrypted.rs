@@ -0,0 +1,44 @@
+use super::RemarkableFs;
+use crate::encryption::{DecryptionProvider, Protection};
+use crate::{RemarkableError, SchemaError};
+use log::debug;
+use std::path::Path;
+
+impl RemarkableFs {
+    /// Sets how metadata and content files stored encrypted are decrypted
+    pub fn set_decryption_provider(&mut self, provider: Box<dyn DecryptionProvider>) {
+        self.decryption = Some(provider);
+    }
+
+    /// Text of the metadata or content file `path` holding `data`, decrypted
+    /// when stored encrypted. Fails with a schema error when it cannot be, the
+    /// item then gets a placeholder instead of failing its whole collection.
+    pub(crate) fn decode_remote(
+        &self,
+        path: &Path,
+        data: Vec<u8>,
+    ) -> Result<String, RemarkableError> {
+        let scheme = match Protection::of(&data) {
+            Protection::Plain => {
+                return String::from_utf8(data)
+                    .map_err(|e| SchemaError::Invalid(e.to_string()).into())
+            }
+            Protection::Encrypted { scheme } => scheme,
+        };
+        let Some(provider) = &self.decryption else {
+            return Err(SchemaError::Encrypted(scheme.to_owned()).into());
+        };
+        let failed = |reason: String| SchemaError::Decryption {
+            provider: provider.name().to_owned(),
+            reason,
+        };
+        match provider.decrypt(path, scheme, &data) {
+            Some(Ok(plain)) => {
+                debug!("{path:?} decrypted by {}", provider.name());
+                String::from_utf8(plain).map_err(|e| failed(e.to_string()).into())
+            }
+            Some(Err(reason)) => Err(failed(reason).into()),
+            None => Err(SchemaError::Encrypted(scheme.to_owned()).into()),
+        }
+    }
+}
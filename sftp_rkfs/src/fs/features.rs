@@ -0,0 +1,52 @@
+use super::{RemarkableFs, WritePolicy};
+
+impl RemarkableFs {
+    /// prefix of the mount options describing the configuration
+    const FEATURE_PREFIX: &'static str = "x-rmkmount-";
+
+    /// (feature, setting) pairs of the mount, e.g. ("cache", "disk")
+    pub(crate) fn features(&self) -> Vec<(&'static str, String)> {
+        let writes = match (self.read_only, self.write_policy) {
+            (true, _) => "off",
+            (false, WritePolicy::WriteThrough) => "through",
+            (false, WritePolicy::WriteBack) => "back",
+        };
+        let refresh = self
+            .refresh_interval
+            .map_or("off".to_owned(), |i| format!("{}s", i.as_secs()));
+        vec![
+            ("transport", "ssh".to_owned()),
+            (
+                "cache",
+                setting(self.disk_cache.is_some(), "disk", "memory"),
+            ),
+            ("writes", writes.to_owned()),
+            ("reads", setting(self.coalesce_reads, "coalesced", "direct")),
+            ("refresh", refresh),
+            ("scan", self.scan_jobs.to_string()),
+            (
+                "decryption",
+                setting(self.decryption.is_some(), "on", "off"),
+            ),
+            ("pages", setting(self.raw_pages, "raw", "hidden")),
+            ("ghosts", setting(self.show_ghosts, "shown", "hidden")),
+        ]
+    }
+
+    /// `x-rmkmount-<feature>=<setting>` mount options, so that the mount table
+    /// tells how the mount is configured without asking the process.
+    /// fusermount records `x-` options in the user mount table shown by `mount`
+    /// and `findmnt`, the kernel one (`/proc/mounts`) does not carry them.
+    pub(crate) fn feature_options(&self) -> Vec<fuser::MountOption> {
+        self.features()
+            .into_iter()
+            .map(|(feature, setting)| {
+                fuser::MountOption::CUSTOM(format!("{}{feature}={setting}", Self::FEATURE_PREFIX))
+            })
+            .collect()
+    }
+}
+
+fn setting(enabled: bool, on: &str, off: &str) -> String {
+    if enabled { on } else { off }.to_owned()
+}
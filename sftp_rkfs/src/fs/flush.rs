@@ -0,0 +1,96 @@
+use super::RemarkableFs;
+use log::{debug, error};
+use std::collections::HashMap;
+
+/// When files copied into the mount are uploaded to the tablet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WritePolicy {
+    /// at each close of the file, an upload failure being returned by close()
+    #[default]
+    WriteThrough,
+    /// once its last handle is released : close() returns at once, upload
+    /// failures are only logged and shown in `/.health`
+    WriteBack,
+}
+
+/// Read errors of open files not seen by their reader, returned by the next
+/// close() of the file then forgotten
+#[derive(Debug, Default)]
+pub(crate) struct DeferredErrors {
+    errors: HashMap<usize, libc::c_int>,
+}
+
+impl DeferredErrors {
+    /// keeps the first error of `ino`, interrupted reads are no failure
+    pub(crate) fn record(&mut self, ino: usize, errno: libc::c_int) {
+        if errno != libc::EINTR {
+            self.errors.entry(ino).or_insert(errno);
+        }
+    }
+
+    pub(crate) fn take(&mut self, ino: usize) -> Option<libc::c_int> {
+        self.errors.remove(&ino)
+    }
+}
+
+impl RemarkableFs {
+    /// Sets when files copied into the mount are uploaded
+    pub fn set_write_policy(&mut self, policy: WritePolicy) {
+        self.write_policy = policy;
+    }
+
+    /// Runs on each close() of `ino` : drops what was fetched ahead for its
+    /// reads, uploads it when written through, then reports the first read
+    /// error since the previous close
+    pub(crate) fn op_flush(&mut self, ino: usize) -> Result<(), libc::c_int> {
+        self.read_caches.borrow_mut().remove(&ino);
+        self.collect_read_failures();
+        if self.write_policy == WritePolicy::WriteThrough {
+            // a retried upload resumes the same document, see flush_upload
+            self.with_reconnect("flush", |fs| fs.flush_upload(ino))
+                .map_err(|e| {
+                    error!("upload of {ino} failed : {e}");
+                    self.record_error("flush", &e);
+                    libc::EIO
+                })?;
+        }
+        match self.deferred_errors.take(ino) {
+            Some(errno) => {
+                debug!("close of {ino} reports read error {errno}");
+                Err(errno)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Uploads `ino` when written back, as its last handle is released
+    pub(crate) fn write_back(&mut self, ino: usize) {
+        if self.write_policy != WritePolicy::WriteBack {
+            return;
+        }
+        if let Err(e) = self.with_reconnect("release", |fs| fs.flush_upload(ino)) {
+            error!("upload of {ino} failed : {e}");
+            self.record_error("release", &e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_after_error() {
+        let mut errors = DeferredErrors::default();
+        errors.record(5, libc::EIO);
+        errors.record(5, libc::EBADFD);
+        errors.record(6, libc::EINTR);
+        // the first error is reported by the next close only
+        assert_eq!(errors.take(5), Some(libc::EIO));
+        assert_eq!(errors.take(5), None);
+        assert_eq!(errors.take(6), None);
+        // an error after a close is reported by the following one
+        errors.record(5, libc::ENOENT);
+        assert_eq!(errors.take(5), Some(libc::ENOENT));
+    }
+}
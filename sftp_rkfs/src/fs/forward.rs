@@ -0,0 +1,126 @@
+use super::RemarkableFs;
+use crate::RemarkableError;
+use log::{debug, info, warn};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+/// One forwarded connection : a local client and its channel on the tablet
+struct Tunnel {
+    client: TcpStream,
+    channel: ssh2::Channel,
+    /// read from the client, not yet accepted by the channel
+    upstream: Vec<u8>,
+    /// read from the channel, not yet accepted by the client
+    downstream: Vec<u8>,
+}
+
+impl Tunnel {
+    /// moves available data both ways without blocking. Returns whether anything
+    /// moved, or None once either side is closed
+    fn pump(&mut self, buf: &mut [u8]) -> std::io::Result<Option<bool>> {
+        let mut active = false;
+        if self.upstream.is_empty() {
+            match self.client.read(buf) {
+                Ok(0) => return Ok(None),
+                Ok(n) => self.upstream.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !self.upstream.is_empty() {
+            match self.channel.write(&self.upstream) {
+                Ok(n) => {
+                    self.upstream.drain(..n);
+                    active = true;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if self.downstream.is_empty() {
+            match self.channel.read(buf) {
+                Ok(0) if self.channel.eof() => return Ok(None),
+                Ok(n) => self.downstream.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !self.downstream.is_empty() {
+            match self.client.write(&self.downstream) {
+                Ok(n) => {
+                    self.downstream.drain(..n);
+                    active = true;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Some(active))
+    }
+}
+
+impl RemarkableFs {
+    const FORWARD_BUFFER_SIZE: usize = 32 * 1024;
+    /// pause of the forwarding loop when no data moved
+    const FORWARD_IDLE: Duration = Duration::from_millis(5);
+
+    /// Forwards connections accepted on `listener` to `host:port` as reached from
+    /// the tablet, over the ssh session. Runs until the listener fails; the
+    /// session is left non-blocking, so the filesystem is not usable afterwards.
+    pub fn forward_port(
+        &self,
+        listener: &TcpListener,
+        host: &str,
+        port: u16,
+    ) -> Result<(), RemarkableError> {
+        listener.set_nonblocking(true)?;
+        self.session.set_blocking(false);
+        let mut tunnels: Vec<Tunnel> = vec![];
+        let mut buf = vec![0; Self::FORWARD_BUFFER_SIZE];
+        loop {
+            let mut idle = true;
+            match listener.accept() {
+                Ok((client, peer)) => {
+                    idle = false;
+                    // opening is a single exchange, simpler done blocking
+                    self.session.set_blocking(true);
+                    let channel = self.session.open_tunnel(host, port, peer);
+                    self.session.set_blocking(false);
+                    match channel.and_then(|c| Ok((c, client.set_nonblocking(true)?))) {
+                        Ok((channel, ())) => {
+                            info!("forwarding {peer} to {host}:{port}");
+                            tunnels.push(Tunnel {
+                                client,
+                                channel,
+                                upstream: vec![],
+                                downstream: vec![],
+                            });
+                        }
+                        Err(e) => warn!("connection from {peer} not forwarded: {e}"),
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e.into()),
+            }
+            tunnels.retain_mut(|tunnel| match tunnel.pump(&mut buf) {
+                Ok(Some(active)) => {
+                    idle &= !active;
+                    true
+                }
+                Ok(None) => {
+                    debug!("forwarded connection closed");
+                    let _ = tunnel.channel.close();
+                    false
+                }
+                Err(e) => {
+                    warn!("forwarded connection dropped: {e}");
+                    false
+                }
+            });
+            if idle {
+                std::thread::sleep(Self::FORWARD_IDLE);
+            }
+        }
+    }
+}
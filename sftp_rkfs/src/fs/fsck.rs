@@ -0,0 +1,358 @@
+use super::RemarkableFs;
+use crate::nodes::Node;
+use crate::{ErrorContext, RemarkableError};
+use log::{info, warn};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Kinds of inconsistencies found in the xochitl storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FsckCategory {
+    /// `.metadata` or `.content` that is not valid JSON
+    MalformedJson,
+    /// document whose `.content` or payload (pdf, epub) is missing
+    MissingPayload,
+    /// item whose parent collection does not exist
+    DanglingParent,
+    /// uids differing only in case or formatting, that clash on most hosts
+    DuplicateUuid,
+    /// document file left without its `.metadata`
+    OrphanPayload,
+    /// page, thumbnail... folder left without its `.metadata`
+    OrphanPagesDir,
+}
+
+impl FsckCategory {
+    /// can the files of the finding be moved away without losing a document ?
+    pub fn is_quarantinable(self) -> bool {
+        matches!(self, Self::OrphanPayload | Self::OrphanPagesDir)
+    }
+}
+
+/// One inconsistency and the remote files involved
+#[derive(Debug, Clone, PartialEq)]
+pub struct FsckFinding {
+    pub category: FsckCategory,
+    pub uid: String,
+    pub detail: String,
+    pub files: Vec<PathBuf>,
+}
+
+/// Result of a consistency check of the document root
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    /// number of items (documents and collections) with a metadata file
+    pub checked: usize,
+    pub findings: Vec<FsckFinding>,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    /// findings grouped by category
+    pub fn by_category(&self) -> BTreeMap<FsckCategory, Vec<&FsckFinding>> {
+        let mut categories = BTreeMap::<_, Vec<_>>::new();
+        for finding in &self.findings {
+            categories
+                .entry(finding.category)
+                .or_default()
+                .push(finding);
+        }
+        categories
+    }
+}
+
+impl fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} items checked, {} problems",
+            self.checked,
+            self.findings.len()
+        )?;
+        for (category, findings) in self.by_category() {
+            writeln!(f, "{category:?} ({})", findings.len())?;
+            for finding in findings {
+                writeln!(f, "  {} : {}", finding.uid, finding.detail)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// files of document folders and payloads besides `.metadata`, including the
+/// journal and staged metadata of an interrupted push
+const DOCUMENT_FILES: [&str; 7] = [
+    "content",
+    "pdf",
+    "epub",
+    "pagedata",
+    "local",
+    "push-journal",
+    "metadata.part",
+];
+
+impl RemarkableFs {
+    /// Checks the document root for inconsistencies, reading all metadata and
+    /// content files in a single remote command. Assumes the flat xochitl layout.
+    pub fn fsck(&self) -> Result<FsckReport, RemarkableError> {
+        let entries = self
+            .session
+            .readdir(&self.document_root)?
+            .into_iter()
+            .filter_map(|f| {
+                let name = f.get_path().file_name()?.to_str()?.to_owned();
+                Some((name, f.is_dir()))
+            })
+            .collect::<Vec<_>>();
+        let globs = [
+            self.layout.metadata_glob(&self.document_root),
+            self.layout.content_glob(&self.document_root),
+        ];
+        let mut sets = self
+            .session
+            .read_file_sets(&[&globs[0], &globs[1]])?
+            .into_iter();
+        let (metadata, contents) = (
+            sets.next().unwrap_or_default(),
+            sets.next().unwrap_or_default(),
+        );
+        let findings = check(&self.document_root, &entries, &metadata, &contents);
+        info!(
+            "checked {} items, {} problems",
+            metadata.len(),
+            findings.len()
+        );
+        Ok(FsckReport {
+            checked: metadata.len(),
+            findings,
+        })
+    }
+
+    /// Moves the files of a quarantinable finding into the remote folder `dir`,
+    /// created if needed
+    pub fn quarantine(&self, finding: &FsckFinding, dir: &Path) -> Result<(), RemarkableError> {
+        if !finding.category.is_quarantinable() {
+            warn!("{:?} findings are not quarantined", finding.category);
+            return Ok(());
+        }
+        self.session.mkdir(dir)?;
+        for file in &finding.files {
+            let Some(name) = file.file_name() else {
+                continue;
+            };
+            self.session
+                .rename(file, &dir.join(name))
+                .with_context(|| format!("quarantining {}", finding.uid))?;
+        }
+        Ok(())
+    }
+}
+
+/// uid and extension of a document root entry, None when not named after a uid
+fn split_entry(name: &str) -> Option<(&str, &str)> {
+    let (uid, ext) = name.split_once('.').unwrap_or((name, ""));
+    uuid::Uuid::parse_str(uid).ok().map(|_| (uid, ext))
+}
+
+fn check(
+    root: &Path,
+    entries: &[(String, bool)],
+    metadata: &[(PathBuf, String)],
+    contents: &[(PathBuf, String)],
+) -> Vec<FsckFinding> {
+    let mut findings = vec![];
+    let mut found = |category, uid: &str, detail: String, files: Vec<PathBuf>| {
+        findings.push(FsckFinding {
+            category,
+            uid: uid.to_owned(),
+            detail,
+            files,
+        })
+    };
+    let stem = |path: &Path| {
+        path.file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or_default()
+            .to_owned()
+    };
+    let mut items = HashMap::new();
+    for (path, text) in metadata {
+        match serde_json::from_str::<serde_json::Value>(text) {
+            Ok(value) => {
+                items.insert(stem(path), value);
+            }
+            Err(e) => found(
+                FsckCategory::MalformedJson,
+                &stem(path),
+                format!("metadata: {e}"),
+                vec![path.clone()],
+            ),
+        }
+    }
+    let mut file_types = HashMap::new();
+    for (path, text) in contents {
+        match serde_json::from_str::<serde_json::Value>(text) {
+            Ok(value) => {
+                let file_type = value["fileType"].as_str().unwrap_or_default().to_owned();
+                file_types.insert(stem(path), file_type);
+            }
+            Err(e) => found(
+                FsckCategory::MalformedJson,
+                &stem(path),
+                format!("content: {e}"),
+                vec![path.clone()],
+            ),
+        }
+    }
+    let files = entries
+        .iter()
+        .filter(|(_, dir)| !dir)
+        .map(|(name, _)| name.as_str())
+        .collect::<HashSet<_>>();
+
+    let mut uids = items.iter().collect::<Vec<_>>();
+    uids.sort_by_key(|(uid, _)| uid.as_str());
+    let mut by_uuid = HashMap::<_, Vec<&str>>::new();
+    for (uid, item) in uids {
+        if let Ok(uuid) = uuid::Uuid::parse_str(uid) {
+            by_uuid.entry(uuid).or_default().push(uid);
+        }
+        let parent = item["parent"].as_str().unwrap_or_default();
+        if parent != Node::ROOT_NODE_UID
+            && parent != Node::TRASH_PARENT_UID
+            && !items.contains_key(parent)
+        {
+            found(
+                FsckCategory::DanglingParent,
+                uid,
+                format!("parent {parent} does not exist"),
+                vec![],
+            );
+        }
+        if item["type"] != "DocumentType" {
+            continue;
+        }
+        if !files.contains(format!("{uid}.content").as_str()) {
+            found(
+                FsckCategory::MissingPayload,
+                uid,
+                "no .content file".to_string(),
+                vec![],
+            );
+        } else if let Some(ext @ ("pdf" | "epub")) = file_types.get(uid).map(String::as_str) {
+            if !files.contains(format!("{uid}.{ext}").as_str()) {
+                found(
+                    FsckCategory::MissingPayload,
+                    uid,
+                    format!("no .{ext} file"),
+                    vec![],
+                );
+            }
+        }
+    }
+    let mut duplicates = by_uuid
+        .into_values()
+        .filter(|uids| uids.len() > 1)
+        .collect::<Vec<_>>();
+    duplicates.sort();
+    for uids in duplicates {
+        found(
+            FsckCategory::DuplicateUuid,
+            uids[0],
+            format!("same uuid as {}", uids[1..].join(", ")),
+            vec![],
+        );
+    }
+
+    let mut orphans = BTreeMap::<(&str, bool), Vec<PathBuf>>::new();
+    for (name, dir) in entries {
+        let Some((uid, ext)) = split_entry(name) else {
+            continue;
+        };
+        if items.contains_key(uid) || ext == "metadata" {
+            continue;
+        }
+        if *dir || DOCUMENT_FILES.contains(&ext) {
+            orphans
+                .entry((uid, *dir))
+                .or_default()
+                .push(root.join(name));
+        }
+    }
+    for ((uid, dir), paths) in orphans {
+        let names = paths
+            .iter()
+            .filter_map(|p| p.file_name())
+            .map(|n| n.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let category = if dir {
+            FsckCategory::OrphanPagesDir
+        } else {
+            FsckCategory::OrphanPayload
+        };
+        found(category, uid, format!("no metadata for {names}"), paths);
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOC: &str = "0b5d7d31-6f7a-4c55-9a4e-6b6e9f6c1a01";
+    const ORPHAN: &str = "1c6e8e42-7a8b-4d66-8b5f-7c7fa07d2b12";
+    const BROKEN: &str = "2d7f9f53-8b9c-4e77-9c60-8d80b18e3c23";
+
+    fn metadata(uid: &str, text: &str) -> (PathBuf, String) {
+        (
+            PathBuf::from(format!("/r/{uid}.metadata")),
+            text.to_string(),
+        )
+    }
+
+    #[test]
+    fn test_check() {
+        let entries = [
+            (format!("{DOC}.metadata"), false),
+            (format!("{DOC}.content"), false),
+            (DOC.to_string(), true),
+            (format!("{ORPHAN}.pdf"), false),
+            (format!("{ORPHAN}.content"), false),
+            (format!("{ORPHAN}.thumbnails"), true),
+            (format!("{BROKEN}.metadata"), false),
+            ("notes.txt".to_string(), false),
+        ];
+        let metadata = [
+            metadata(
+                DOC,
+                r#"{"type": "DocumentType", "parent": "missing", "visibleName": "Spec"}"#,
+            ),
+            metadata(BROKEN, r#"{"type": "#),
+        ];
+        let contents = [(
+            PathBuf::from(format!("/r/{DOC}.content")),
+            r#"{"fileType": "pdf"}"#.to_string(),
+        )];
+        let findings = check(Path::new("/r"), &entries, &metadata, &contents);
+        let summary = findings
+            .iter()
+            .map(|f| (f.category, f.uid.as_str(), f.files.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (FsckCategory::MalformedJson, BROKEN, 1),
+                (FsckCategory::DanglingParent, DOC, 0),
+                (FsckCategory::MissingPayload, DOC, 0),
+                (FsckCategory::OrphanPayload, ORPHAN, 2),
+                (FsckCategory::OrphanPagesDir, ORPHAN, 1),
+            ]
+        );
+        assert!(FsckCategory::OrphanPagesDir.is_quarantinable());
+    }
+}
@@ -0,0 +1,166 @@
+use super::RemarkableFs;
+use crate::nodes::FuserChild;
+use log::{debug, info};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// An item that went missing from the listing of its collection. Its node is
+/// kept for the grace period, so that an item hidden by a transient scan
+/// failure comes back unchanged, and it can be shown as `<name>.ghost`
+#[derive(Debug, Clone)]
+pub(crate) struct Ghost {
+    ino: usize,
+    parent: usize,
+    /// readdir position of the item when it was listed
+    position: usize,
+    since: Instant,
+}
+
+impl RemarkableFs {
+    /// seconds left before a ghost is forgotten
+    const XATTR_GHOST: &'static str = "user.remarkable.ghost";
+    const GHOST_SUFFIX: &'static str = ".ghost";
+    pub const DEFAULT_GHOST_GRACE: Duration = Duration::from_secs(600);
+
+    /// Sets how long items missing from a listing are kept before they are
+    /// forgotten
+    pub fn set_ghost_grace(&mut self, grace: Duration) {
+        self.ghost_grace = grace;
+    }
+
+    /// Lists items missing from their collection as `<name>.ghost` during the
+    /// grace period
+    pub fn set_show_ghosts(&mut self, enabled: bool) {
+        self.show_ghosts = enabled;
+    }
+
+    /// Reconciles the ghosts with a new listing `files` of collection `parent`,
+    /// which had the entries `previous` : items listed again are ghosts no
+    /// more, items missing from it become ghosts, expired ghosts are forgotten
+    pub(crate) fn reconcile_ghosts(
+        &mut self,
+        parent: usize,
+        previous: &[(usize, String)],
+        files: &[String],
+    ) {
+        let listed = files.iter().map(|f| entry_uid(f)).collect::<HashSet<_>>();
+        self.ghosts.retain(|uid, ghost| {
+            let back = listed.contains(uid.as_str());
+            if back {
+                info!(
+                    "{uid} listed again after {}s",
+                    ghost.since.elapsed().as_secs()
+                );
+            }
+            !back
+        });
+        for (uid, position) in vanished(previous, &listed) {
+            let Some(&ino) = self.uid_map.get(&uid) else {
+                continue;
+            };
+            // moved to a collection listed since, not missing
+            if self.nodes[ino].borrow().get_parent() != parent {
+                continue;
+            }
+            debug!("{uid} missing from collection {parent}, kept as a ghost");
+            self.ghosts.entry(uid).or_insert(Ghost {
+                ino,
+                parent,
+                position,
+                since: Instant::now(),
+            });
+        }
+        self.expire_ghosts();
+    }
+
+    /// forgets the ghosts older than the grace period
+    fn expire_ghosts(&mut self) {
+        let grace = self.ghost_grace;
+        let expired = self
+            .ghosts
+            .iter()
+            .filter(|(_, g)| g.since.elapsed() >= grace)
+            .map(|(uid, g)| (uid.clone(), g.ino, g.parent))
+            .collect::<Vec<_>>();
+        for (uid, ino, parent) in expired {
+            info!("{uid} still missing after the grace period, removed");
+            self.ghosts.remove(&uid);
+            if let Some(dir) = self.get_node(parent) {
+                dir.borrow_mut().remove_child(ino);
+            }
+            self.read_caches.borrow_mut().remove(&ino);
+        }
+    }
+
+    /// children standing for the ghosts of collection `parent`, if shown
+    pub(crate) fn ghost_children(&self, parent: usize) -> Vec<FuserChild> {
+        if !self.show_ghosts {
+            return vec![];
+        }
+        let mut children = self
+            .ghosts
+            .values()
+            .filter(|g| g.parent == parent)
+            .map(|g| {
+                let node = self.nodes[g.ino].borrow();
+                let mut name = node.get_visible_name().into_os_string();
+                name.push(Self::GHOST_SUFFIX);
+                FuserChild::new(
+                    g.ino,
+                    g.position,
+                    node.get_kind_for_fuser(),
+                    PathBuf::from(name),
+                )
+            })
+            .collect::<Vec<_>>();
+        children.sort_by_key(|c| c.1);
+        children
+    }
+
+    /// time left before ghost `ino` is forgotten
+    pub(crate) fn ghost_xattrs(&self, ino: usize) -> Option<(String, Vec<u8>)> {
+        let ghost = self.ghosts.values().find(|g| g.ino == ino)?;
+        let left = self.ghost_grace.saturating_sub(ghost.since.elapsed());
+        Some((
+            Self::XATTR_GHOST.to_string(),
+            left.as_secs().to_string().into_bytes(),
+        ))
+    }
+}
+
+/// uid of the metadata file `file`
+fn entry_uid(file: &str) -> &str {
+    Path::new(file)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or_default()
+}
+
+/// (uid, position) of the `previous` entries that are not `listed` anymore
+fn vanished(previous: &[(usize, String)], listed: &HashSet<&str>) -> Vec<(String, usize)> {
+    previous
+        .iter()
+        .map(|(position, file)| (entry_uid(file), *position))
+        .filter(|(uid, _)| !uid.is_empty() && !listed.contains(uid))
+        .map(|(uid, position)| (uid.to_owned(), position))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vanished() {
+        let previous = vec![
+            (16, "/x/a1.metadata".to_owned()),
+            (19, "/x/b2.metadata".to_owned()),
+            (22, "/x/c3.metadata".to_owned()),
+        ];
+        let listed = ["a1", "c3", "d4"].into_iter().collect::<HashSet<_>>();
+        assert_eq!(vanished(&previous, &listed), vec![("b2".to_owned(), 19)]);
+        assert_eq!(vanished(&previous, &HashSet::new()).len(), 3);
+        assert_eq!(entry_uid("/x/a1.metadata"), "a1");
+    }
+}
@@ -0,0 +1,111 @@
+use super::RemarkableFs;
+use crate::sshutils::RemoteFile;
+use crate::RemarkableError;
+use log::debug;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// File handles returned by `open`. Each keeps the remote payload open from
+/// its first read to its release, so that the reads of a handle do not open
+/// and seek the remote file each time.
+#[derive(Default)]
+pub(crate) struct HandleTable {
+    last: u64,
+    handles: HashMap<u64, Handle>,
+}
+
+struct Handle {
+    ino: usize,
+    /// opened on the first read of the handle
+    file: Option<RemoteFile>,
+}
+
+impl HandleTable {
+    /// allocates a handle on node `ino`
+    pub(crate) fn insert(&mut self, ino: usize) -> u64 {
+        self.last += 1;
+        self.handles.insert(self.last, Handle { ino, file: None });
+        self.last
+    }
+
+    /// releases handle `fh`, closing its remote file. Returns its node
+    pub(crate) fn remove(&mut self, fh: u64) -> Option<usize> {
+        self.handles.remove(&fh).map(|h| h.ino)
+    }
+
+    /// closes the remote files of all handles, reopened by their next read.
+    /// For a new session, the files belonging to the former one
+    pub(crate) fn close_files(&mut self) {
+        for handle in self.handles.values_mut() {
+            handle.file = None;
+        }
+    }
+
+    /// remote file of handle `fh`, if it is a handle on node `ino`
+    fn file(&mut self, fh: u64, ino: usize) -> Option<&mut Option<RemoteFile>> {
+        let handle = self.handles.get_mut(&fh).filter(|h| h.ino == ino)?;
+        Some(&mut handle.file)
+    }
+}
+
+impl RemarkableFs {
+    /// Reads `buf.len()` bytes at `offset` of `path`, the payload of node `ino`,
+    /// with the remote file of handle `fh`, opened on its first read. Without
+    /// a handle, or when the file cannot be kept open, the remote file is
+    /// opened for this read only
+    pub(crate) fn read_payload(
+        &self,
+        ino: usize,
+        fh: Option<u64>,
+        path: &Path,
+        offset: u64,
+        buf: &mut [u8],
+        cancelled: &dyn Fn() -> bool,
+    ) -> Result<(), RemarkableError> {
+        let mut handles = self.handles.borrow_mut();
+        if let Some(slot) = fh.and_then(|fh| handles.file(fh, ino)) {
+            // the payload changed since the handle was opened
+            if slot.as_ref().is_some_and(|f| f.path() != path) {
+                *slot = None;
+            }
+            if slot.is_none() {
+                match self.session.open_file(path) {
+                    Ok(file) => *slot = Some(file),
+                    Err(e) => debug!("{path:?} read without keeping it open : {e}"),
+                }
+            }
+            if let Some(file) = slot {
+                let read = file.read_at(offset, buf, cancelled);
+                if read.is_err() {
+                    // reopened by the next read
+                    *slot = None;
+                }
+                return read;
+            }
+        }
+        drop(handles);
+        let len = buf.len() as u64;
+        self.session
+            .read_as_bytes(path, offset, len, buf, cancelled)
+            .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handle_table() {
+        let mut table = HandleTable::default();
+        let (a, b) = (table.insert(7), table.insert(7));
+        assert_ne!(a, b);
+        assert!(table.file(a, 7).is_some_and(|f| f.is_none()));
+        // a handle only reads the node it was opened on
+        assert!(table.file(a, 8).is_none());
+        assert_eq!(table.remove(a), Some(7));
+        assert_eq!(table.remove(a), None);
+        assert!(table.file(b, 7).is_some());
+        assert_ne!(table.insert(9), a);
+    }
+}
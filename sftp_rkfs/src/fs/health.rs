@@ -0,0 +1,90 @@
+use super::RemarkableFs;
+use crate::names;
+use crate::RemarkableError;
+use std::time::SystemTime;
+
+/// Last failure of a filesystem operation, reported by `/.health`
+#[derive(Debug, Clone)]
+pub(crate) struct LastError {
+    at: SystemTime,
+    operation: &'static str,
+    message: String,
+}
+
+impl RemarkableFs {
+    /// remembers `e`, returned by `operation`, for the health report
+    pub(crate) fn record_error(&mut self, operation: &'static str, e: &RemarkableError) {
+        self.record_failure(operation, e.to_string());
+    }
+
+    /// remembers the failure of `operation`, described by `message`, for the
+    /// health report
+    pub(crate) fn record_failure(&mut self, operation: &'static str, message: String) {
+        self.last_error = Some(LastError {
+            at: SystemTime::now(),
+            operation,
+            message,
+        });
+    }
+
+    /// `/.health` content. The tablet is probed over ssh on each read so the
+    /// status reflects whether the mount is usable, not only mounted.
+    pub(crate) fn health_report(&self) -> String {
+        let probe = self
+            .session
+            .stat(&names::remote_str(&self.document_root))
+            .map(|_| ())
+            .map_err(|e| e.to_string());
+        let uptime = self.started.elapsed().map(|d| d.as_secs()).unwrap_or(0);
+        format_report(&probe, uptime, self.last_error.as_ref())
+    }
+}
+
+fn format_report(
+    probe: &Result<(), String>,
+    uptime: u64,
+    last_error: Option<&LastError>,
+) -> String {
+    let mut report = match probe {
+        Ok(()) => "status: ok\nssh: alive\n".to_string(),
+        Err(e) => format!("status: degraded\nssh: down ({e})\n"),
+    };
+    report.push_str(&format!("uptime: {uptime}s\n"));
+    match last_error {
+        Some(last) => {
+            let at = last
+                .at
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            report.push_str(&format!(
+                "last_error: {at} {}: {}\n",
+                last.operation, last.message
+            ));
+        }
+        None => report.push_str("last_error: none\n"),
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_report() {
+        assert_eq!(
+            format_report(&Ok(()), 12, None),
+            "status: ok\nssh: alive\nuptime: 12s\nlast_error: none\n"
+        );
+        let last = LastError {
+            at: SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1700000000),
+            operation: "read",
+            message: "Node not found 42".to_string(),
+        };
+        assert_eq!(
+            format_report(&Err("timeout".to_string()), 0, Some(&last)),
+            "status: degraded\nssh: down (timeout)\nuptime: 0s\nlast_error: 1700000000 read: Node not found 42\n"
+        );
+    }
+}
@@ -0,0 +1,347 @@
+use super::RemarkableFs;
+use crate::nodes::{FuserChild, Node};
+use crate::sshutils::SshFileStat;
+use crate::{names, rkids, ErrorContext, FsError, RemarkableError};
+use log::{debug, info, warn};
+use std::cell::RefCell;
+use std::fs::File;
+use std::os::unix::fs::{DirBuilderExt, FileExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+/// A PDF or EPUB file copied into a collection. It is spooled to a local file
+/// while written and uploaded as a new document when flushed.
+#[derive(Debug)]
+pub(crate) struct PendingUpload {
+    spool: PathBuf,
+    file: File,
+    /// collection receiving the document
+    parent: usize,
+    /// readdir position reserved in the collection
+    position: usize,
+    /// visible name of the document, without extension
+    name: String,
+    extension: &'static str,
+    size: u64,
+    /// written since created or truncated, and not uploaded yet
+    dirty: bool,
+    /// uid of the document, chosen when created so that an upload retried
+    /// after a reconnection resumes it instead of uploading another copy
+    uid: String,
+    uploaded: bool,
+}
+
+impl RemarkableFs {
+    /// Sets where the private folder of the files being copied into the mount
+    /// is created
+    pub fn set_spool_dir(&mut self, dir: PathBuf) {
+        self.spool_parent = dir;
+    }
+
+    /// Private folder of the spool files, created afresh (mode 0700) with the
+    /// first pending upload, so that no other user can open or replace them
+    fn spool_dir(&mut self) -> Result<PathBuf, RemarkableError> {
+        if let Some(dir) = &self.spool_dir {
+            return Ok(dir.clone());
+        }
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.spool_parent)
+            .with_context(|| format!("creating {:?}", self.spool_parent))?;
+        let dir = self
+            .spool_parent
+            .join(format!("spool-{}", rkids::new_uid()));
+        std::fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&dir)
+            .with_context(|| format!("creating spool folder {dir:?}"))?;
+        self.spool_dir = Some(dir.clone());
+        Ok(dir)
+    }
+
+    /// Creates `name` in collection `parent` as a pending upload, returns its ino.
+    /// Only pdf and epub files can be created, in collections of the library.
+    pub(crate) fn create_upload(
+        &mut self,
+        parent: usize,
+        name: &str,
+    ) -> Result<usize, RemarkableError> {
+        let (stem, extension) = upload_name(name)
+            .ok_or_else(|| FsError::Unsupported(format!("{name}: only pdf and epub files")))?;
+        self.check_new_child(parent, name)?;
+        let ino = self.nodes.len();
+        let spool = self.spool_dir()?.join(format!("{ino}.{extension}"));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&spool)
+            .with_context(|| format!("creating spool file {spool:?}"))?;
+        let key = spool_key(ino);
+        let position = self
+            .listings
+            .entry(parent)
+            .or_default()
+            .reserve_position(&key);
+        let node = Node::new_alternate_payload(
+            ino,
+            parent,
+            stem,
+            extension,
+            SshFileStat::build_pending_file(&key, 0),
+        );
+        let child = FuserChild::new(
+            ino,
+            position,
+            fuser::FileType::RegularFile,
+            node.get_visible_name(),
+        );
+        self.nodes.push(RefCell::new(node));
+        if let Some(dir) = self.get_node(parent) {
+            dir.borrow_mut().upsert_child(child);
+        }
+        debug!("spooling {name} of {parent} to {spool:?}");
+        self.pending_uploads.insert(
+            ino,
+            PendingUpload {
+                spool,
+                file,
+                parent,
+                position,
+                name: stem.to_owned(),
+                extension,
+                size: 0,
+                dirty: false,
+                uid: rkids::new_uid(),
+                uploaded: false,
+            },
+        );
+        Ok(ino)
+    }
+
+    /// Creates the collection `name` in collection `parent` on the tablet,
+    /// returns its ino
+    pub(crate) fn make_collection(
+        &mut self,
+        parent: usize,
+        name: &str,
+    ) -> Result<usize, RemarkableError> {
+        if !names::is_clean(name) {
+            return Err(FsError::InvalidPath(name.to_owned()).into());
+        }
+        self.check_new_child(parent, name)?;
+        let parent_uid = self
+            .get_node_unique_id(parent)
+            .ok_or(FsError::NodeNotFound(parent))?;
+        let uid = self.create_collection(&parent_uid, name)?;
+        self.refresh_listing(parent)?;
+        self.lookup_child(parent, name)?
+            .ok_or_else(|| FsError::Unsupported(format!("collection {uid} not listed")).into())
+    }
+
+    /// can `name` be created in `parent` ? Only collections of the library
+    /// (not the trash, nor views) receive new items, under unused names
+    fn check_new_child(&mut self, parent: usize, name: &str) -> Result<(), RemarkableError> {
+        if parent == Node::TRASH_NODE_INO
+            || self.is_document(parent)
+            || self.virtual_dirs.contains_key(&parent)
+            || self.virtual_files.contains_key(&parent)
+        {
+            return Err(FsError::NotACollection(format!("{parent}")).into());
+        }
+        if self.lookup_child(parent, name)?.is_some() {
+            return Err(RemarkableError::from(FsError::NodeDuplicated).context(name.to_owned()));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_pending_upload(&self, ino: usize) -> bool {
+        self.pending_uploads.contains_key(&ino)
+    }
+
+    /// writes `data` at `offset` of the spool file of pending upload `ino`
+    pub(crate) fn write_upload(
+        &mut self,
+        ino: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), RemarkableError> {
+        let upload = self
+            .pending_uploads
+            .get_mut(&ino)
+            .ok_or(FsError::NodeNotFound(ino))?;
+        if upload.uploaded {
+            return Err(FsError::NodeIoError(libc::EROFS).into());
+        }
+        upload
+            .file
+            .write_all_at(data, offset)
+            .with_context(|| format!("writing {:?}", upload.spool))?;
+        upload.size = upload.size.max(offset + data.len() as u64);
+        upload.dirty = true;
+        let size = upload.size;
+        self.set_upload_size(ino, size);
+        Ok(())
+    }
+
+    /// truncates or extends the spool file of pending upload `ino` to `size`
+    pub(crate) fn truncate_upload(&mut self, ino: usize, size: u64) -> Result<(), RemarkableError> {
+        let upload = self
+            .pending_uploads
+            .get_mut(&ino)
+            .ok_or(FsError::NodeNotFound(ino))?;
+        if upload.uploaded {
+            return Err(FsError::NodeIoError(libc::EROFS).into());
+        }
+        upload
+            .file
+            .set_len(size)
+            .with_context(|| format!("truncating {:?}", upload.spool))?;
+        upload.size = size;
+        upload.dirty = true;
+        self.set_upload_size(ino, size);
+        Ok(())
+    }
+
+    /// reads back what was written to pending upload `ino`
+    pub(crate) fn read_upload(
+        &self,
+        ino: usize,
+        offset: u64,
+        size: u32,
+    ) -> Result<Vec<u8>, RemarkableError> {
+        let upload = self
+            .pending_uploads
+            .get(&ino)
+            .ok_or(FsError::NodeNotFound(ino))?;
+        let len = upload.size.saturating_sub(offset).min(size as u64);
+        let mut buf = vec![0; len as usize];
+        upload
+            .file
+            .read_exact_at(&mut buf, offset)
+            .with_context(|| format!("reading {:?}", upload.spool))?;
+        Ok(buf)
+    }
+
+    /// Uploads pending upload `ino` as a new document of its collection, if it
+    /// was written to since. The collection is then listed again, so that the
+    /// document replaces the pending file. Running it again after a failure
+    /// resumes the same document.
+    pub(crate) fn flush_upload(&mut self, ino: usize) -> Result<(), RemarkableError> {
+        let Some(upload) = self
+            .pending_uploads
+            .get(&ino)
+            .filter(|u| u.dirty && u.size > 0)
+        else {
+            return Ok(());
+        };
+        let (spool, parent, name, extension, uid) = (
+            upload.spool.clone(),
+            upload.parent,
+            upload.name.clone(),
+            upload.extension,
+            upload.uid.clone(),
+        );
+        let parent_uid = self
+            .get_node_unique_id(parent)
+            .ok_or(FsError::NodeNotFound(parent))?;
+        self.push_file(&spool, &parent_uid, &name, extension, &uid, &mut |_| {})
+            .with_context(|| format!("uploading {name}.{extension}"))?;
+        info!("{name}.{extension} uploaded as {uid}");
+        if let Some(upload) = self.pending_uploads.get_mut(&ino) {
+            upload.dirty = false;
+            upload.uploaded = true;
+        }
+        if let Some(dir) = self.get_node(parent) {
+            dir.borrow_mut().remove_child(ino);
+        }
+        if let Err(e) = self.refresh_listing(parent) {
+            warn!("collection {parent} not listed again after upload : {e}");
+        }
+        Ok(())
+    }
+
+    /// Drops pending upload `ino` once its last handle is released. A file
+    /// never uploaded disappears from its collection.
+    pub(crate) fn release_upload(&mut self, ino: usize) {
+        let Some(upload) = self.pending_uploads.remove(&ino) else {
+            return;
+        };
+        if !upload.uploaded {
+            if upload.dirty {
+                warn!(
+                    "{}.{} discarded, it was never flushed",
+                    upload.name, upload.extension
+                );
+            }
+            if let Some(dir) = self.get_node(upload.parent) {
+                dir.borrow_mut().remove_child(ino);
+            }
+        }
+        drop(upload.file);
+        if let Err(e) = std::fs::remove_file(&upload.spool) {
+            warn!("spool file {:?} left behind : {e}", upload.spool);
+        }
+        if self.pending_uploads.is_empty() {
+            if let Some(dir) = self.spool_dir.take() {
+                let _ = std::fs::remove_dir(dir);
+            }
+        }
+    }
+
+    /// children of collection `parent` that are still being written
+    pub(crate) fn pending_children(&self, parent: usize) -> Vec<FuserChild> {
+        self.pending_uploads
+            .iter()
+            .filter(|(_, u)| u.parent == parent && !u.uploaded)
+            .map(|(&ino, u)| {
+                FuserChild::new(
+                    ino,
+                    u.position,
+                    fuser::FileType::RegularFile,
+                    self.nodes[ino].borrow().get_visible_name(),
+                )
+            })
+            .collect()
+    }
+
+    fn set_upload_size(&mut self, ino: usize, size: u64) {
+        let key = spool_key(ino);
+        if let Some(node) = self.get_node(ino) {
+            node.borrow_mut()
+                .update_target_fstat(&mut SshFileStat::build_pending_file(&key, size));
+        }
+    }
+}
+
+/// unique id of the node of pending upload `ino`, never a tablet uid
+fn spool_key(ino: usize) -> String {
+    format!(".upload-{ino}")
+}
+
+/// (visible name, payload extension) of a file created as `name`, None when
+/// it cannot be uploaded
+fn upload_name(name: &str) -> Option<(&str, &'static str)> {
+    let path = Path::new(name);
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    let extension = RemarkableFs::UPLOAD_EXTENSIONS
+        .into_iter()
+        .find(|e| *e == extension)?;
+    let stem = path.file_stem()?.to_str()?;
+    Some((stem, extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::upload_name;
+
+    #[test]
+    fn test_upload_name() {
+        assert_eq!(upload_name("Paper.pdf"), Some(("Paper", "pdf")));
+        assert_eq!(upload_name("My book.v2.EPUB"), Some(("My book.v2", "epub")));
+        assert_eq!(upload_name("notes.txt"), None);
+        assert_eq!(upload_name("pdf"), None);
+        assert_eq!(upload_name(".pdf"), None);
+    }
+}
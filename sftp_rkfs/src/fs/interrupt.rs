@@ -0,0 +1,62 @@
+/// signals killing a process that does not handle them : SIGHUP, SIGINT,
+/// SIGQUIT, SIGKILL and SIGTERM. A fatal signal is also turned into a pending
+/// SIGKILL by the kernel
+const FATAL_SIGNALS: [u32; 5] = [
+    libc::SIGHUP as u32,
+    libc::SIGINT as u32,
+    libc::SIGQUIT as u32,
+    libc::SIGKILL as u32,
+    libc::SIGTERM as u32,
+];
+
+/// Has the process `pid` that requested an operation been interrupted, or is it
+/// gone ? pid 0 stands for requests made by the kernel, never interrupted.
+///
+/// fuser answers FUSE_INTERRUPT itself, so a Ctrl-C on a process waiting for a
+/// read never reaches the filesystem : reads poll their requester instead.
+pub(crate) fn requester_interrupted(pid: u32) -> bool {
+    if pid == 0 {
+        return false;
+    }
+    match std::fs::read_to_string(format!("/proc/{pid}/status")) {
+        Ok(status) => has_fatal_signal(pending_signals(&status)),
+        Err(e) => e.kind() == std::io::ErrorKind::NotFound,
+    }
+}
+
+/// union of the thread and process pending signal masks of a /proc status file
+fn pending_signals(status: &str) -> u64 {
+    status
+        .lines()
+        .filter_map(|l| {
+            l.strip_prefix("SigPnd:")
+                .or_else(|| l.strip_prefix("ShdPnd:"))
+        })
+        .filter_map(|mask| u64::from_str_radix(mask.trim(), 16).ok())
+        .fold(0, |all, mask| all | mask)
+}
+
+fn has_fatal_signal(pending: u64) -> bool {
+    FATAL_SIGNALS
+        .iter()
+        .any(|&signal| pending & (1 << (signal - 1)) != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending_signals() {
+        let status = "Name:\tcp\nSigQ:\t1/63229\nSigPnd:\t0000000000000000\n\
+                      ShdPnd:\t0000000000000002\nSigBlk:\t0000000000000000\n";
+        let pending = pending_signals(status);
+        assert_eq!(pending, 2);
+        // SIGINT is signal 2, bit 1
+        assert!(has_fatal_signal(pending));
+        // SIGCHLD (17) does not stop the read
+        assert!(!has_fatal_signal(1 << 16));
+        assert!(!requester_interrupted(0));
+        assert!(!requester_interrupted(std::process::id()));
+    }
+}
@@ -0,0 +1,350 @@
+use super::RemarkableFs;
+use crate::{FsError, RemarkableError};
+use log::{debug, error, info};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Background work run by the scheduler, counted by kind in `/.control/jobs`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum JobKind {
+    /// entry batches fetched by the library scan
+    Scan,
+}
+
+/// Limits shared by all background jobs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JobBudget {
+    /// jobs running at once
+    pub max_jobs: usize,
+    /// bytes per second fetched by jobs, None for no limit
+    pub bandwidth: Option<u64>,
+    /// filesystem requests per second above which jobs wait, 0 never waiting
+    pub busy_requests: u32,
+}
+
+impl Default for JobBudget {
+    fn default() -> Self {
+        Self {
+            max_jobs: 4,
+            bandwidth: None,
+            busy_requests: 200,
+        }
+    }
+}
+
+/// Work of a job, given its context to account for the bytes it transfers
+type Work = Box<dyn FnOnce(&JobContext) + Send>;
+
+/// Runs background jobs on threads of their own, within a `JobBudget`. Jobs
+/// wait while paused by `/.control/jobs` and while the filesystem is busy
+/// serving requests, interactive use coming first.
+#[derive(Clone)]
+pub(crate) struct Scheduler {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    budget: JobBudget,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<(JobKind, Work)>,
+    running: usize,
+    workers: usize,
+    paused: bool,
+    traffic: Traffic,
+    bucket: Bucket,
+    transferred: u64,
+    done: BTreeMap<JobKind, u64>,
+}
+
+/// Handed to running jobs
+pub(crate) struct JobContext<'a> {
+    shared: &'a Shared,
+}
+
+impl JobContext<'_> {
+    /// Accounts for `bytes` fetched by the job, waiting as long as the bandwidth
+    /// budget requires
+    pub(crate) fn charge(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.shared.lock();
+            state.transferred += bytes;
+            match self.shared.budget.bandwidth {
+                Some(rate) => state.bucket.take(bytes, rate, Instant::now()),
+                None => Duration::ZERO,
+            }
+        };
+        if !wait.is_zero() {
+            debug!("job waits {}ms for the bandwidth budget", wait.as_millis());
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+/// Requests served per second, over the current and the previous second
+#[derive(Debug)]
+struct Traffic {
+    second: Instant,
+    count: u32,
+    previous: u32,
+}
+
+impl Default for Traffic {
+    fn default() -> Self {
+        Self {
+            second: Instant::now(),
+            count: 0,
+            previous: 0,
+        }
+    }
+}
+
+impl Traffic {
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.second);
+        if elapsed >= Duration::from_secs(1) {
+            self.previous = if elapsed < Duration::from_secs(2) {
+                self.count
+            } else {
+                0
+            };
+            self.second = now;
+            self.count = 0;
+        }
+    }
+
+    fn record(&mut self, now: Instant) {
+        self.roll(now);
+        self.count = self.count.saturating_add(1);
+    }
+
+    /// more than `threshold` requests in the last second ?
+    fn is_busy(&mut self, threshold: u32, now: Instant) -> bool {
+        self.roll(now);
+        threshold > 0 && self.count.max(self.previous) > threshold
+    }
+}
+
+/// Token bucket of the bandwidth budget, allowing a burst of one second
+#[derive(Debug, Default)]
+struct Bucket {
+    allowance: f64,
+    refilled: Option<Instant>,
+}
+
+impl Bucket {
+    /// takes `bytes` at `rate` bytes per second, returns how long to wait for them
+    fn take(&mut self, bytes: u64, rate: u64, now: Instant) -> Duration {
+        let rate = rate.max(1) as f64;
+        let elapsed = match self.refilled {
+            Some(refilled) => now.saturating_duration_since(refilled).as_secs_f64(),
+            None => 1.0,
+        };
+        self.refilled = Some(now);
+        self.allowance = (self.allowance + elapsed * rate).min(rate) - bytes as f64;
+        if self.allowance < 0.0 {
+            Duration::from_secs_f64(-self.allowance / rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Scheduler {
+    /// how often waiting jobs check whether the filesystem is still busy
+    const POLL: Duration = Duration::from_millis(100);
+
+    pub(crate) fn new(budget: JobBudget) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                budget: JobBudget {
+                    max_jobs: budget.max_jobs.max(1),
+                    ..budget
+                },
+                state: Mutex::new(State::default()),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Queues `work`, run as soon as the budget allows
+    pub(crate) fn submit(&self, kind: JobKind, work: impl FnOnce(&JobContext) + Send + 'static) {
+        let mut state = self.shared.lock();
+        state.queue.push_back((kind, Box::new(work)));
+        if state.workers < self.shared.budget.max_jobs
+            && state.workers < state.running + state.queue.len()
+        {
+            let shared = Arc::clone(&self.shared);
+            let spawned = std::thread::Builder::new()
+                .name("rkfs-job".to_owned())
+                .spawn(move || work_loop(&shared));
+            match spawned {
+                Ok(_) => state.workers += 1,
+                Err(e) => error!("unable to start a job thread : {e}"),
+            }
+        }
+        self.shared.changed.notify_one();
+    }
+
+    /// Records a filesystem request served, jobs waiting while there are many
+    pub(crate) fn note_request(&self) {
+        self.shared.lock().traffic.record(Instant::now());
+    }
+
+    pub(crate) fn set_paused(&self, paused: bool) {
+        self.shared.lock().paused = paused;
+        self.shared.changed.notify_all();
+    }
+
+    /// content of `/.control/jobs`
+    pub(crate) fn report(&self) -> String {
+        let budget = self.shared.budget;
+        let mut state = self.shared.lock();
+        let status = if state.paused {
+            "paused"
+        } else if state.traffic.is_busy(budget.busy_requests, Instant::now()) {
+            "waiting, filesystem busy"
+        } else {
+            "running"
+        };
+        let bandwidth = match budget.bandwidth {
+            Some(rate) => format!("{} KiB/s", rate / 1024),
+            None => "unlimited".to_owned(),
+        };
+        let mut report = format!(
+            "state: {status}\nrunning: {}\nqueued: {}\nmax jobs: {}\nbandwidth: {bandwidth}\ntransferred: {} bytes\n",
+            state.running,
+            state.queue.len(),
+            budget.max_jobs,
+            state.transferred,
+        );
+        for (kind, done) in &state.done {
+            report.push_str(&format!("done {kind:?}: {done}\n").to_lowercase());
+        }
+        report
+    }
+}
+
+/// Runs queued jobs while the budget allows, the thread ends once the queue is empty
+fn work_loop(shared: &Shared) {
+    let mut state = shared.lock();
+    loop {
+        let busy = state
+            .traffic
+            .is_busy(shared.budget.busy_requests, Instant::now());
+        if state.paused || busy {
+            state = shared
+                .changed
+                .wait_timeout(state, Scheduler::POLL)
+                .map(|(state, _)| state)
+                .unwrap_or_else(|e| e.into_inner().0);
+            continue;
+        }
+        let Some((kind, work)) = state.queue.pop_front() else {
+            state.workers -= 1;
+            return;
+        };
+        state.running += 1;
+        drop(state);
+        let context = JobContext { shared };
+        let run = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| work(&context)));
+        if run.is_err() {
+            error!("{kind:?} job panicked");
+        }
+        state = shared.lock();
+        state.running -= 1;
+        *state.done.entry(kind).or_default() += 1;
+    }
+}
+
+impl RemarkableFs {
+    /// Sets the limits of the background jobs, before any is started
+    pub fn set_job_budget(&mut self, budget: JobBudget) {
+        self.jobs = Scheduler::new(budget);
+    }
+
+    /// Acts on a command written to `/.control/jobs`: `pause` or `resume`
+    pub(crate) fn jobs_control(&mut self, command: &str) -> Result<(), RemarkableError> {
+        match command.trim() {
+            "pause" => self.jobs.set_paused(true),
+            "resume" => self.jobs.set_paused(false),
+            other => {
+                return Err(FsError::Unsupported(format!(
+                    "job command {other:?}, expected pause or resume"
+                ))
+                .into())
+            }
+        }
+        info!("background jobs {}d", command.trim());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traffic() {
+        let start = Instant::now();
+        let mut traffic = Traffic {
+            second: start,
+            ..Default::default()
+        };
+        for i in 0..30 {
+            traffic.record(start + Duration::from_millis(i * 10));
+        }
+        assert!(traffic.is_busy(20, start + Duration::from_millis(500)));
+        assert!(!traffic.is_busy(0, start + Duration::from_millis(500)));
+        // still busy over the next second, then quiet
+        assert!(traffic.is_busy(20, start + Duration::from_millis(1500)));
+        assert!(!traffic.is_busy(20, start + Duration::from_millis(3500)));
+    }
+
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket::default();
+        // a second worth of bytes is allowed at once
+        assert_eq!(bucket.take(1000, 1000, start), Duration::ZERO);
+        assert_eq!(bucket.take(500, 1000, start), Duration::from_millis(500));
+        let later = start + Duration::from_secs(1);
+        assert_eq!(bucket.take(500, 1000, later), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let scheduler = Scheduler::new(JobBudget {
+            max_jobs: 2,
+            ..Default::default()
+        });
+        scheduler.set_paused(true);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        for i in 0..5 {
+            let sender = sender.clone();
+            scheduler.submit(JobKind::Scan, move |context| {
+                context.charge(10);
+                sender.send(i).unwrap();
+            });
+        }
+        assert!(receiver.recv_timeout(Duration::from_millis(300)).is_err());
+        assert!(scheduler
+            .report()
+            .starts_with("state: paused\nrunning: 0\nqueued: 5\n"));
+        scheduler.set_paused(false);
+        let mut done = (0..5).map(|_| receiver.recv().unwrap()).collect::<Vec<_>>();
+        done.sort();
+        assert_eq!(done, [0, 1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,223 @@
+use super::RemarkableFs;
+use crate::nodes::Node;
+use crate::{ErrorContext, FsError, RemarkableError, SchemaError};
+use log::{info, warn};
+use std::time::SystemTime;
+
+impl RemarkableFs {
+    /// Renames `name` of collection `parent` to `new_name` in collection
+    /// `new_parent`. The `visibleName` and `parent` of its metadata file are
+    /// rewritten, nothing else moves on the tablet. Moving an item out of the
+    /// trash restores it.
+    pub(crate) fn move_item(
+        &mut self,
+        parent: usize,
+        name: &str,
+        new_parent: usize,
+        new_name: &str,
+    ) -> Result<(), RemarkableError> {
+        let ino = self
+            .lookup_child(parent, name)?
+            .ok_or(FsError::NodeIoError(libc::ENOENT))?;
+        let uid = self
+            .get_node(ino)
+            .ok_or(FsError::NodeNotFound(ino))?
+            .borrow()
+            .get_unique()
+            .to_owned();
+        if self.uid_map.get(&uid) != Some(&ino) {
+            // views, pages, paginated pdfs and pending uploads
+            return Err(FsError::NodeIoError(libc::EROFS).into());
+        }
+        let parent_uid = self.collection_uid(new_parent)?;
+        if !self.is_document(ino) && self.is_ancestor(ino, new_parent) {
+            return Err(FsError::NodeIoError(libc::EINVAL).into());
+        }
+        match self.lookup_child(new_parent, new_name)? {
+            Some(existing) if existing == ino => {}
+            Some(_) => {
+                return Err(
+                    RemarkableError::from(FsError::NodeDuplicated).context(new_name.to_owned())
+                )
+            }
+            None => {}
+        }
+        let visible_name = {
+            let node = self.nodes[ino].borrow();
+            match node.get_extension() {
+                Some(ext) => new_name
+                    .strip_suffix(&format!(".{ext}"))
+                    .unwrap_or(new_name)
+                    .to_owned(),
+                None => new_name.to_owned(),
+            }
+        };
+        self.rewrite_metadata(&uid, &parent_uid, &visible_name)
+            .with_context(|| format!("renaming {name} to {new_name}"))?;
+        let former_parent = self.nodes[ino].borrow().get_parent();
+        if parent == Node::TRASH_NODE_INO && new_parent != parent {
+            info!("{uid} restored from the trash to {new_parent} as {visible_name}");
+        } else {
+            info!("{uid} moved to {new_parent} as {visible_name}");
+        }
+        self.nodes[ino]
+            .borrow_mut()
+            .relocate(new_parent, &parent_uid, &visible_name);
+        for dir in [parent, former_parent] {
+            if let Some(dir) = self.get_node(dir) {
+                dir.borrow_mut().remove_child(ino);
+            }
+        }
+        let mut listed = vec![parent, new_parent, former_parent];
+        listed.sort_unstable();
+        listed.dedup();
+        if let Err(e) = self.refresh_listings(&listed) {
+            warn!("collections {listed:?} not listed again after a move : {e}");
+        }
+        Ok(())
+    }
+
+    /// Moves document `name` of collection `parent` to the trash, as xochitl
+    /// does : only the `parent` of its metadata becomes "trash". Documents
+    /// already in the trash are kept, they are only deleted from the tablet.
+    pub(crate) fn trash_document(
+        &mut self,
+        parent: usize,
+        name: &str,
+    ) -> Result<(), RemarkableError> {
+        let ino = self
+            .lookup_child(parent, name)?
+            .ok_or(FsError::NodeIoError(libc::ENOENT))?;
+        if !self.is_document(ino) {
+            return Err(FsError::NodeIoError(libc::EISDIR).into());
+        }
+        let (uid, visible_name, trashed) = {
+            let node = self.nodes[ino].borrow();
+            (
+                node.get_unique().to_owned(),
+                node.get_basename().unwrap_or_default().to_owned(),
+                node.is_trashed(),
+            )
+        };
+        if self.uid_map.get(&uid) != Some(&ino) {
+            return Err(FsError::NodeIoError(libc::EROFS).into());
+        }
+        if trashed {
+            return Err(FsError::NodeIoError(libc::EPERM).into());
+        }
+        self.rewrite_metadata(&uid, Node::TRASH_PARENT_UID, &visible_name)
+            .with_context(|| format!("moving {name} to the trash"))?;
+        info!("{uid} moved to the trash");
+        self.nodes[ino].borrow_mut().relocate(
+            Node::TRASH_NODE_INO,
+            Node::TRASH_PARENT_UID,
+            &visible_name,
+        );
+        if let Some(dir) = self.get_node(parent) {
+            dir.borrow_mut().remove_child(ino);
+        }
+        let listed = [parent, Node::TRASH_NODE_INO];
+        if let Err(e) = self.refresh_listings(&listed) {
+            warn!("collections {listed:?} not listed again after trashing : {e}");
+        }
+        Ok(())
+    }
+
+    /// uid written as `parent` of the items of collection `ino`
+    fn collection_uid(&self, ino: usize) -> Result<String, RemarkableError> {
+        match ino {
+            Node::ROOT_NODE_INO => Ok(Node::ROOT_NODE_UID.to_owned()),
+            Node::TRASH_NODE_INO => Ok(Node::TRASH_PARENT_UID.to_owned()),
+            _ => {
+                let uid = self.unique_id(ino).ok_or(FsError::NodeNotFound(ino))?;
+                if self.is_document(ino) || self.uid_map.get(&uid) != Some(&ino) {
+                    return Err(FsError::NotACollection(uid).into());
+                }
+                Ok(uid)
+            }
+        }
+    }
+
+    /// is `ancestor` `ino` itself or one of the collections holding it ?
+    fn is_ancestor(&self, ancestor: usize, ino: usize) -> bool {
+        let mut current = ino;
+        // parents chain is bounded by the node count, in case of a cycle
+        for _ in 0..self.nodes.len() {
+            if current == ancestor {
+                return true;
+            }
+            match self.get_node(current) {
+                Some(node) if current != Node::ROOT_NODE_INO => {
+                    current = node.borrow().get_parent()
+                }
+                _ => return false,
+            }
+        }
+        false
+    }
+
+    /// Rewrites the metadata file of `uid` with a new parent and visible name,
+    /// keeping the fields rmkmount does not know about
+    pub(crate) fn rewrite_metadata(
+        &self,
+        uid: &str,
+        parent_uid: &str,
+        visible_name: &str,
+    ) -> Result<(), RemarkableError> {
+        let path = self.layout.metadata_path(&self.document_root, uid);
+        let metadata = self.session.read_as_string(&path)?;
+        let rewritten = relocated_metadata(&metadata, parent_uid, visible_name, SystemTime::now())
+            .with_context(|| format!("parsing metadata of {uid}"))?;
+        let staged = self.staged_metadata_path(uid);
+        self.session.write_all(&staged, rewritten.as_bytes())?;
+        self.session.replace_file(&staged, &path)
+    }
+}
+
+/// `metadata` moved to `parent_uid` as `visible_name`, stamped as modified at
+/// `now` for the sync. Indented like xochitl writes it, which the listings rely on.
+fn relocated_metadata(
+    metadata: &str,
+    parent_uid: &str,
+    visible_name: &str,
+    now: SystemTime,
+) -> Result<String, RemarkableError> {
+    let mut value = serde_json::from_str::<serde_json::Value>(metadata)?;
+    let fields = value
+        .as_object_mut()
+        .ok_or_else(|| SchemaError::Invalid("metadata is not an object".to_owned()))?;
+    let now_ms = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    fields.insert("parent".to_owned(), parent_uid.into());
+    fields.insert("visibleName".to_owned(), visible_name.into());
+    fields.insert("lastModified".to_owned(), now_ms.to_string().into());
+    fields.insert("metadatamodified".to_owned(), true.into());
+    Ok(serde_json::to_string_pretty(&value)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_relocated_metadata() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let out = relocated_metadata(
+            r#"{"parent": "a", "visibleName": "Old", "pinned": true, "tags": ["x"]}"#,
+            "b",
+            "New",
+            now,
+        )
+        .unwrap();
+        assert!(out.contains(r#""parent": "b""#));
+        let value = serde_json::from_str::<serde_json::Value>(&out).unwrap();
+        assert_eq!(value["visibleName"], "New");
+        assert_eq!(value["lastModified"], "1700000000123");
+        assert_eq!(value["pinned"], true);
+        assert_eq!(value["tags"][0], "x");
+        assert!(relocated_metadata("[]", "b", "New", now).is_err());
+    }
+}
@@ -0,0 +1,517 @@
+use super::{RemarkableFs, XattrReply};
+use crate::names;
+use crate::nodes::Node;
+use crate::trace::TraceScope;
+use crate::{FsError, RemarkableError};
+use log::{debug, error, info};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// One tablet mounted as `/<name>` of a MultiDeviceFs
+struct Device {
+    name: String,
+    fs: RemarkableFs,
+}
+
+/// Several tablets mounted under one root as `/<device-name>/...`. Each device
+/// keeps its own connection, nodes and caches; inode numbers are made unique
+/// across devices by interleaving the device index.
+pub struct MultiDeviceFs {
+    mount_point: PathBuf,
+    devices: Vec<Device>,
+}
+
+impl MultiDeviceFs {
+    pub const MAX_DEVICES: usize = 64;
+
+    pub fn new(mount_point: PathBuf) -> Self {
+        Self {
+            mount_point,
+            devices: vec![],
+        }
+    }
+
+    /// Adds a connected device, shown as the `/<name>` folder
+    pub fn add_device(&mut self, name: &str, fs: RemarkableFs) -> Result<(), RemarkableError> {
+        if !names::is_clean(name) {
+            return Err(FsError::InvalidPath(format!("device name {name:?}")).into());
+        }
+        if self.devices.iter().any(|d| d.name == name) {
+            return Err(
+                RemarkableError::from(FsError::NodeDuplicated).context(format!("device {name}"))
+            );
+        }
+        if self.devices.len() == Self::MAX_DEVICES {
+            return Err(
+                FsError::InvalidPath(format!("more than {} devices", Self::MAX_DEVICES)).into(),
+            );
+        }
+        self.devices.push(Device {
+            name: name.to_owned(),
+            fs,
+        });
+        Ok(())
+    }
+
+    /// MultiDeviceFs is consumed by mount
+    pub fn mount(self) -> Result<(), std::io::Error> {
+        let Some(first) = self.devices.first() else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "No device to mount",
+            ));
+        };
+        let options = first.fs.options();
+        let mountpoint = self.mount_point.clone();
+        fuser::mount2(self, mountpoint, &options)
+    }
+
+    /// global inode of inode `ino` of device `dev`
+    fn global_ino(dev: usize, ino: usize) -> u64 {
+        (ino * Self::MAX_DEVICES + dev) as u64
+    }
+
+    /// (device, device inode) of a global inode, None for the common root
+    /// EROFS for the write `operation` on `ino`, logged by its device
+    fn refuse_write(&mut self, operation: &str, ino: u64) -> libc::c_int {
+        match self.split_ino(ino) {
+            Some((fs, _, local)) => fs.refuse_write(operation, local),
+            None => libc::EROFS,
+        }
+    }
+
+    fn split_ino(&mut self, ino: u64) -> Option<(&mut RemarkableFs, usize, usize)> {
+        let ino = ino as usize;
+        if ino < Self::MAX_DEVICES {
+            return None;
+        }
+        let dev = ino % Self::MAX_DEVICES;
+        self.devices
+            .get_mut(dev)
+            .map(|d| (&mut d.fs, dev, ino / Self::MAX_DEVICES))
+    }
+
+    /// attributes of the common root : those of the first device root
+    fn root_attr(&mut self) -> Result<fuser::FileAttr, libc::c_int> {
+        let device = self.devices.first_mut().ok_or(libc::ENOENT)?;
+        let mut attr = device.fs.op_getattr(Node::ROOT_NODE_INO)?;
+        attr.ino = fuser::FUSE_ROOT_ID;
+        Ok(attr)
+    }
+
+    /// attributes of device `dev` root folder
+    fn device_attr(&mut self, dev: usize) -> Result<fuser::FileAttr, libc::c_int> {
+        let device = self.devices.get_mut(dev).ok_or(libc::ENOENT)?;
+        let mut attr = device.fs.op_getattr(Node::ROOT_NODE_INO)?;
+        attr.ino = Self::global_ino(dev, Node::ROOT_NODE_INO);
+        Ok(attr)
+    }
+}
+
+impl fuser::Filesystem for MultiDeviceFs {
+    /// devices are initialized independently, a failing one is left empty
+    fn init(
+        &mut self,
+        req: &fuser::Request<'_>,
+        _config: &mut fuser::KernelConfig,
+    ) -> Result<(), libc::c_int> {
+        let _trace = TraceScope::enter("init", req.unique());
+        for device in self.devices.iter_mut() {
+            match device.fs.init_root() {
+                Ok(()) => {
+                    info!("device {} initialized", device.name);
+                    device.fs.initial_scan();
+                }
+                Err(e) => error!("device {} could not be initialized : {e}", device.name),
+            }
+        }
+        Ok(())
+    }
+
+    fn getattr(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyAttr) {
+        let _trace = TraceScope::enter("getattr", req.unique());
+        let res = match self.split_ino(ino) {
+            None if ino == fuser::FUSE_ROOT_ID => self.root_attr(),
+            None => Err(libc::ENOENT),
+            Some((fs, dev, local)) => fs.op_getattr(local).map(|mut attr| {
+                attr.ino = Self::global_ino(dev, local);
+                attr
+            }),
+        };
+        match res {
+            Ok(attr) => reply.attr(&Duration::new(0, 0), &attr),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn lookup(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        let _trace = TraceScope::enter("lookup", req.unique());
+        let res = match self.split_ino(parent) {
+            None => match self.devices.iter().position(|d| name == d.name.as_str()) {
+                Some(dev) => self.device_attr(dev),
+                None => {
+                    debug!("no device {name:?}");
+                    Err(libc::ENOENT)
+                }
+            },
+            Some((fs, dev, local)) => fs.op_lookup(local, name).map(|mut attr| {
+                attr.ino = Self::global_ino(dev, attr.ino as usize);
+                attr
+            }),
+        };
+        match res {
+            Ok(attr) => reply.entry(&Duration::new(0, 0), &attr, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: fuser::ReplyDirectory,
+    ) {
+        let _trace = TraceScope::enter("readdir", req.unique());
+        let res = match self.split_ino(ino) {
+            None => {
+                for (dev, device) in self.devices.iter().enumerate().skip(offset as usize) {
+                    let ino = Self::global_ino(dev, Node::ROOT_NODE_INO);
+                    if reply.add(
+                        ino,
+                        dev as i64 + 1,
+                        fuser::FileType::Directory,
+                        &device.name,
+                    ) {
+                        break;
+                    }
+                }
+                Ok(())
+            }
+            Some((fs, dev, local)) => fs.op_readdir(local, offset as usize, &mut |v| {
+                let ino = Self::global_ino(dev, v.0);
+                reply.add(ino, v.1 as i64 + 1, v.2, v.3.as_os_str())
+            }),
+        };
+        match res {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn getxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        name: &std::ffi::OsStr,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        let _trace = TraceScope::enter("getxattr", req.unique());
+        let value = self.split_ino(ino).and_then(|(fs, _, local)| {
+            fs.node_xattrs(local)
+                .into_iter()
+                .find(|(n, _)| name == n.as_str())
+                .map(|(_, v)| v)
+        });
+        XattrReply::send(XattrReply::value(value, size), reply);
+    }
+
+    fn listxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        let _trace = TraceScope::enter("listxattr", req.unique());
+        let xattrs = self
+            .split_ino(ino)
+            .map(|(fs, _, local)| fs.node_xattrs(local))
+            .unwrap_or_default();
+        XattrReply::send(XattrReply::names(xattrs, size), reply);
+    }
+
+    fn open(&mut self, req: &fuser::Request, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
+        let _trace = TraceScope::enter("open", req.unique());
+        match self.split_ino(ino) {
+            Some((fs, _, local)) => match fs.op_open(local) {
+                Ok((fh, flags)) => reply.opened(fh, flags),
+                Err(errno) => reply.error(errno),
+            },
+            None => reply.error(libc::EISDIR),
+        }
+    }
+
+    fn read(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyData,
+    ) {
+        let _trace = TraceScope::enter("read", req.unique());
+        match self.split_ino(ino) {
+            Some((fs, _, local)) => {
+                let Some(reply) = fs.coalesce_read(local, offset, size, reply) else {
+                    return;
+                };
+                match fs.op_read(local, fh, offset, size, req.pid()) {
+                    Ok(buffer) => reply.data(&buffer),
+                    Err(errno) => reply.error(errno),
+                }
+            }
+            None => reply.error(libc::EISDIR),
+        }
+    }
+
+    fn release(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let _trace = TraceScope::enter("release", req.unique());
+        match self.split_ino(ino) {
+            Some((fs, _, local)) => match fs.op_release(local, fh) {
+                Ok(()) => reply.ok(),
+                Err(errno) => reply.error(errno),
+            },
+            None => reply.ok(),
+        }
+    }
+
+    fn create(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        _mode: u32,
+        umask: u32,
+        _flags: i32,
+        reply: fuser::ReplyCreate,
+    ) {
+        let _trace = TraceScope::enter("create", req.unique());
+        let res = match self.split_ino(parent) {
+            Some((fs, dev, local)) => fs
+                .op_create(local, name, umask)
+                .map(|(attr, fh)| (attr, fh, dev)),
+            None => Err(libc::EROFS),
+        };
+        match res {
+            Ok((mut attr, fh, dev)) => {
+                attr.ino = Self::global_ino(dev, attr.ino as usize);
+                reply.created(&Duration::new(0, 0), &attr, 0, fh, 0)
+            }
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        _mode: u32,
+        umask: u32,
+        reply: fuser::ReplyEntry,
+    ) {
+        let _trace = TraceScope::enter("mkdir", req.unique());
+        let res = match self.split_ino(parent) {
+            Some((fs, dev, local)) => fs.op_mkdir(local, name, umask).map(|mut attr| {
+                attr.ino = Self::global_ino(dev, attr.ino as usize);
+                attr
+            }),
+            None => Err(libc::EROFS),
+        };
+        match res {
+            Ok(attr) => reply.entry(&Duration::new(0, 0), &attr, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// items only move within a device
+    fn rename(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        newparent: u64,
+        newname: &std::ffi::OsStr,
+        flags: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let _trace = TraceScope::enter("rename", req.unique());
+        let target = self
+            .split_ino(newparent)
+            .map(|(_, dev, local)| (dev, local));
+        let res = match (self.split_ino(parent), target) {
+            (Some((fs, dev, local)), Some((new_dev, new_local))) if dev == new_dev => {
+                fs.op_rename(local, name, new_local, newname, flags)
+            }
+            (Some(_), Some(_)) => Err(libc::EXDEV),
+            _ => Err(libc::EROFS),
+        };
+        match res {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn unlink(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let _trace = TraceScope::enter("unlink", req.unique());
+        let res = match self.split_ino(parent) {
+            Some((fs, _, local)) => fs.op_unlink(local, name),
+            None => Err(libc::EROFS),
+        };
+        match res {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn mknod(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        _name: &std::ffi::OsStr,
+        _mode: u32,
+        _umask: u32,
+        _rdev: u32,
+        reply: fuser::ReplyEntry,
+    ) {
+        let _trace = TraceScope::enter("mknod", req.unique());
+        reply.error(self.refuse_write("mknod", parent));
+    }
+
+    fn symlink(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        _link_name: &std::ffi::OsStr,
+        _target: &std::path::Path,
+        reply: fuser::ReplyEntry,
+    ) {
+        let _trace = TraceScope::enter("symlink", req.unique());
+        reply.error(self.refuse_write("symlink", parent));
+    }
+
+    fn link(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        _newparent: u64,
+        _newname: &std::ffi::OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        let _trace = TraceScope::enter("link", req.unique());
+        reply.error(self.refuse_write("link", ino));
+    }
+
+    fn flush(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let _trace = TraceScope::enter("flush", req.unique());
+        match self.split_ino(ino) {
+            Some((fs, _, local)) => match fs.op_flush(local) {
+                Ok(()) => reply.ok(),
+                Err(errno) => reply.error(errno),
+            },
+            None => reply.ok(),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<fuser::TimeOrNow>,
+        _mtime: Option<fuser::TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: fuser::ReplyAttr,
+    ) {
+        let _trace = TraceScope::enter("setattr", req.unique());
+        let res = match self.split_ino(ino) {
+            Some((fs, dev, local)) => fs.op_setattr(local, size).map(|mut attr| {
+                attr.ino = Self::global_ino(dev, local);
+                attr
+            }),
+            None => Err(libc::EROFS),
+        };
+        match res {
+            Ok(attr) => reply.attr(&Duration::new(0, 0), &attr),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn write(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyWrite,
+    ) {
+        let _trace = TraceScope::enter("write", req.unique());
+        match self.split_ino(ino) {
+            Some((fs, _, local)) => match fs.op_write(local, offset, data) {
+                Ok(written) => reply.written(written),
+                Err(errno) => reply.error(errno),
+            },
+            None => reply.error(libc::EISDIR),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MultiDeviceFs;
+
+    #[test]
+    fn test_global_ino_round_trip() {
+        let root = MultiDeviceFs::global_ino(0, 1);
+        assert!(root as usize >= MultiDeviceFs::MAX_DEVICES);
+        let ino = MultiDeviceFs::global_ino(5, 1234) as usize;
+        assert_eq!(ino % MultiDeviceFs::MAX_DEVICES, 5);
+        assert_eq!(ino / MultiDeviceFs::MAX_DEVICES, 1234);
+    }
+}
@@ -0,0 +1,153 @@
+use super::views::VirtualDir;
+use super::RemarkableFs;
+use crate::nodes::{FuserChild, Node};
+use crate::sshutils::SshFileStat;
+use crate::{ErrorContext, RemarkableError};
+use log::{debug, warn};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ops::RangeInclusive;
+
+impl RemarkableFs {
+    /// adds (or re-parents) the `<name>.pages` folder of document `doc_ino` when
+    /// raw pages are exposed, returns its inode
+    pub(crate) fn add_pages_node(&mut self, doc_ino: usize) -> Option<usize> {
+        if !self.raw_pages {
+            return None;
+        }
+        let (uid, name, parent) = {
+            let doc = self.nodes[doc_ino].borrow();
+            if !doc.has_content_file() {
+                return None;
+            }
+            (
+                doc.get_unique().to_owned(),
+                doc.get_basename().unwrap_or_default().to_owned(),
+                doc.get_parent(),
+            )
+        };
+        let key = format!("{uid}{}", Node::PAGES_SUFFIX);
+        if let Some(&ino) = self.uid_map.get(&key) {
+            self.nodes[ino].borrow_mut().set_parent(parent);
+            return Some(ino);
+        }
+        let ino = self.nodes.len();
+        debug!("adding pages folder {ino} for document {doc_ino}");
+        self.nodes.push(RefCell::new(Node::new_virtual_dir(
+            ino,
+            parent,
+            &format!("{name}{}", Node::PAGES_SUFFIX),
+        )));
+        self.virtual_dirs.insert(ino, VirtualDir::Pages(doc_ino));
+        self.uid_map.insert(key, ino);
+        Some(ino)
+    }
+
+    /// `<index>-<page uid>.rm` files of document `doc_ino`, in page order. Pages
+    /// never written on have no `.rm` file and are left out.
+    pub(crate) fn raw_page_children(&mut self, doc_ino: usize) -> Vec<FuserChild> {
+        if let Err(e) = self.ensure_details(doc_ino) {
+            warn!("could not load pages of {doc_ino} : {e}");
+            return vec![];
+        }
+        let (uid, page_ids) = {
+            let doc = self.nodes[doc_ino].borrow();
+            (doc.get_unique().to_owned(), doc.get_page_ids())
+        };
+        let Some(&dir_ino) = self.uid_map.get(&format!("{uid}{}", Node::PAGES_SUFFIX)) else {
+            return vec![];
+        };
+        let pages_dir = self.layout.pages_dir(&self.document_root, &uid);
+        let mut files = match self.session.readdir(&pages_dir) {
+            Ok(files) => files
+                .into_iter()
+                .filter_map(|f| {
+                    let name = f.get_path().file_name()?.to_str()?.to_owned();
+                    Some((name, f))
+                })
+                .collect::<HashMap<_, _>>(),
+            Err(e) => {
+                debug!("no page folder for {uid} : {e}");
+                return vec![];
+            }
+        };
+        let mut children = vec![];
+        for (index, page) in page_ids.iter().enumerate() {
+            let Some(fstat) = files.remove(&format!("{page}.{}", Node::RAW_PAGE_EXTENSION)) else {
+                continue;
+            };
+            let name = format!("{index}-{page}");
+            let ino = self.upsert_raw_page(dir_ino, &format!("{uid}/{name}"), &name, fstat);
+            children.push(FuserChild::new(
+                ino,
+                index,
+                fuser::FileType::RegularFile,
+                self.nodes[ino].borrow().get_visible_name(),
+            ));
+        }
+        children
+    }
+
+    /// `.rm` stroke files of the pages `pages` (counted from 0) of document `ino`,
+    /// with their page index. Pages never written on are left out.
+    pub fn page_annotations(
+        &mut self,
+        ino: usize,
+        pages: RangeInclusive<usize>,
+    ) -> Result<Vec<(usize, Vec<u8>)>, RemarkableError> {
+        self.ensure_details(ino)?;
+        let (uid, page_ids) = {
+            let doc = self.nodes[ino].borrow();
+            (doc.get_unique().to_owned(), doc.get_page_ids())
+        };
+        let pages_dir = self.layout.pages_dir(&self.document_root, &uid);
+        let written = match self.session.readdir(&pages_dir) {
+            Ok(files) => files
+                .iter()
+                .filter_map(|f| Some(f.get_path().file_name()?.to_str()?.to_owned()))
+                .collect::<HashSet<_>>(),
+            Err(e) => {
+                debug!("no page folder for {uid} : {e}");
+                return Ok(vec![]);
+            }
+        };
+        let mut annotations = vec![];
+        for (index, page) in page_ids.iter().enumerate() {
+            let file = format!("{page}.{}", Node::RAW_PAGE_EXTENSION);
+            if !pages.contains(&index) || !written.contains(&file) {
+                continue;
+            }
+            let data = self
+                .session
+                .read_all(&pages_dir.join(&file))
+                .with_context(|| format!("reading page {index} of {uid}"))?;
+            annotations.push((index, data));
+        }
+        Ok(annotations)
+    }
+
+    /// gets (or adds) the node of a raw page file, with its latest stat. `key`
+    /// changes with the page index so that a moved page gets its new name
+    fn upsert_raw_page(
+        &mut self,
+        dir_ino: usize,
+        key: &str,
+        name: &str,
+        mut fstat: SshFileStat,
+    ) -> usize {
+        if let Some(&ino) = self.uid_map.get(key) {
+            self.nodes[ino].borrow_mut().update_target_fstat(&mut fstat);
+            return ino;
+        }
+        let ino = self.nodes.len();
+        self.nodes.push(RefCell::new(Node::new_alternate_payload(
+            ino,
+            dir_ino,
+            name,
+            Node::RAW_PAGE_EXTENSION,
+            fstat,
+        )));
+        self.uid_map.insert(key.to_owned(), ino);
+        ino
+    }
+}
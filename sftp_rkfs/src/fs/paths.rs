@@ -0,0 +1,249 @@
+use super::views::date_from_ms;
+use crate::names::{self, CollisionPolicy};
+use crate::nodes::Node;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+
+/// Item known to a `PathResolver`
+#[derive(Debug, Clone)]
+struct PathItem {
+    parent: String,
+    visible_name: String,
+    extension: Option<String>,
+    last_modified: Option<u64>,
+}
+
+/// Renders the visible path of items from their uids, with the names the
+/// mount presents : escaped, with their extension, and suffixed as the
+/// collision policy says when they share a name with another item of their
+/// collection. Names and paths are cached until an item of the collection
+/// changes.
+#[derive(Debug, Default)]
+pub struct PathResolver {
+    items: HashMap<String, PathItem>,
+    collisions: CollisionPolicy,
+    /// presented names of the children of a collection, by uid
+    names: RefCell<HashMap<String, HashMap<String, String>>>,
+    paths: RefCell<HashMap<String, String>>,
+}
+
+impl PathResolver {
+    pub fn new(collisions: CollisionPolicy) -> Self {
+        Self {
+            collisions,
+            ..Default::default()
+        }
+    }
+
+    /// Sets the policy naming the items that share a name in a collection
+    pub fn set_collision_policy(&mut self, collisions: CollisionPolicy) {
+        self.collisions = collisions;
+        self.names.get_mut().clear();
+        self.paths.get_mut().clear();
+    }
+
+    /// Adds or updates item `uid` of collection `parent`, `extension` being
+    /// that of its payload, if presented with one
+    pub fn insert(
+        &mut self,
+        uid: &str,
+        parent: &str,
+        visible_name: &str,
+        extension: Option<&str>,
+        last_modified: Option<u64>,
+    ) {
+        let item = PathItem {
+            parent: parent.to_owned(),
+            visible_name: visible_name.to_owned(),
+            extension: extension.map(str::to_owned),
+            last_modified,
+        };
+        if let Some(previous) = self.items.insert(uid.to_owned(), item) {
+            self.invalidate(&previous.parent);
+        }
+        self.invalidate(parent);
+    }
+
+    pub fn remove(&mut self, uid: &str) {
+        if let Some(previous) = self.items.remove(uid) {
+            self.invalidate(&previous.parent);
+        }
+    }
+
+    /// forgets the names of collection `parent`, and the paths, any of which
+    /// may go through it
+    fn invalidate(&mut self, parent: &str) {
+        self.names.get_mut().remove(parent);
+        self.paths.get_mut().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// name of `uid` in its collection
+    pub fn name_of(&self, uid: &str) -> Option<String> {
+        let parent = &self.items.get(uid)?.parent;
+        self.names
+            .borrow_mut()
+            .entry(parent.clone())
+            .or_insert_with(|| self.name_children(parent))
+            .get(uid)
+            .cloned()
+    }
+
+    /// Visible path of `uid`, slash separated and relative to the mount point,
+    /// trashed items being under `.Trash`
+    pub fn path_of(&self, uid: &str) -> String {
+        if let Some(path) = self.paths.borrow().get(uid) {
+            return path.clone();
+        }
+        let mut components = vec![];
+        let mut current = uid;
+        // parents chain is bounded by the item count, in case of a cycle
+        for _ in 0..=self.items.len() {
+            match current {
+                Node::ROOT_NODE_UID => break,
+                Node::TRASH_PARENT_UID => {
+                    components.push(Node::TRASH_NODE_PATH.to_owned());
+                    break;
+                }
+                _ => {}
+            }
+            let (Some(item), Some(name)) = (self.items.get(current), self.name_of(current)) else {
+                break;
+            };
+            components.push(name);
+            current = &item.parent;
+        }
+        components.reverse();
+        let path = components.join("/");
+        self.paths.borrow_mut().insert(uid.to_owned(), path.clone());
+        path
+    }
+
+    /// uid of the item at visible `path`, the root for an empty path
+    pub fn uid_at(&self, path: &str) -> Option<String> {
+        let mut current = Node::ROOT_NODE_UID.to_owned();
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            current = if current == Node::ROOT_NODE_UID && component == Node::TRASH_NODE_PATH {
+                Node::TRASH_PARENT_UID.to_owned()
+            } else {
+                self.items
+                    .iter()
+                    .filter(|(_, item)| item.parent == current)
+                    .map(|(uid, _)| uid)
+                    .find(|uid| self.name_of(uid).is_some_and(|name| name == component))?
+                    .clone()
+            };
+        }
+        Some(current)
+    }
+
+    /// Presented names of the children of `parent`. As in listings, where the
+    /// entry loaded first keeps the plain name, children are named in uid
+    /// order, the newest first when only the newest is listed
+    fn name_children(&self, parent: &str) -> HashMap<String, String> {
+        let mut children = self
+            .items
+            .iter()
+            .filter(|(_, item)| item.parent == parent)
+            .collect::<Vec<_>>();
+        children.sort_unstable_by_key(|(uid, _)| uid.as_str());
+        if self.collisions == CollisionPolicy::KeepNewest {
+            children.sort_by_key(|(_, item)| Reverse(item.last_modified));
+        }
+        let mut taken = HashSet::new();
+        let mut named = HashMap::new();
+        for (uid, item) in children {
+            let plain = names::presented(&item.visible_name, item.extension.as_deref())
+                .to_string_lossy()
+                .into_owned();
+            let name = if taken.contains(&plain) {
+                collision_name(
+                    self.collisions,
+                    &plain,
+                    uid,
+                    item.last_modified,
+                    item.extension.is_some(),
+                    &|n| taken.contains(n),
+                )
+            } else {
+                plain
+            };
+            taken.insert(name.clone());
+            named.insert(uid.clone(), name);
+        }
+        named
+    }
+}
+
+/// Name of item `uid` (modified at `last_modified`, ms) in a collection where
+/// `name` is taken, `taken` telling the names in use : suffixed with its
+/// modification date for `SuffixDate` when free, else with the start of its
+/// uid, or the whole uid when that name is taken too. Items the other policies
+/// leave out of listings are given the uid suffix.
+pub(crate) fn collision_name(
+    policy: CollisionPolicy,
+    name: &str,
+    uid: &str,
+    last_modified: Option<u64>,
+    has_extension: bool,
+    taken: &dyn Fn(&str) -> bool,
+) -> String {
+    if policy == CollisionPolicy::SuffixDate {
+        if let Some(ms) = last_modified {
+            let (year, month, day) = date_from_ms(ms);
+            let date = format!("{year:04}-{month:02}-{day:02}");
+            let dated = names::with_suffix(name, &date, has_extension);
+            if !taken(&dated) {
+                return dated;
+            }
+        }
+    }
+    let short = names::with_suffix(name, uid.get(..8).unwrap_or(uid), has_extension);
+    if taken(&short) {
+        names::with_suffix(name, uid, has_extension)
+    } else {
+        short
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_resolver() {
+        let mut paths = PathResolver::new(CollisionPolicy::SuffixUid);
+        paths.insert("w0000000", "", "Work", None, None);
+        paths.insert("a0000000", "w0000000", "Spec", Some("pdf"), None);
+        paths.insert("b0000000", "w0000000", "Spec", Some("pdf"), None);
+        paths.insert("c0000000", "trash", "Old/new", None, None);
+        assert_eq!(paths.path_of("a0000000"), "Work/Spec.pdf");
+        assert_eq!(paths.path_of("b0000000"), "Work/Spec (b0000000).pdf");
+        assert_eq!(paths.path_of("c0000000"), ".Trash/Old\\x2fnew");
+        assert_eq!(
+            paths.uid_at("/Work/Spec (b0000000).pdf").as_deref(),
+            Some("b0000000")
+        );
+        assert_eq!(paths.uid_at("Work/Nothing"), None);
+        // renaming the collection renames the paths below it
+        paths.insert("w0000000", "", "Job", None, None);
+        assert_eq!(paths.path_of("a0000000"), "Job/Spec.pdf");
+        paths.remove("a0000000");
+        assert_eq!(paths.path_of("b0000000"), "Job/Spec.pdf");
+
+        let mut paths = PathResolver::new(CollisionPolicy::SuffixDate);
+        paths.insert("a0000000", "", "Notes", None, Some(1_000));
+        paths.insert("b0000000", "", "Notes", None, Some(1_709_164_800_000));
+        assert_eq!(paths.name_of("b0000000").unwrap(), "Notes (2024-02-29)");
+        paths.set_collision_policy(CollisionPolicy::KeepNewest);
+        assert_eq!(paths.name_of("b0000000").unwrap(), "Notes");
+    }
+}
@@ -0,0 +1,164 @@
+use super::access::AccessTracker;
+use super::views::VirtualFile;
+use super::RemarkableFs;
+use crate::nodes::Node;
+use log::debug;
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+/// Progress of the payload reads of one open document
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Transfer {
+    started: Instant,
+    /// end of the furthest read so far, reads being mostly sequential
+    read: u64,
+    size: u64,
+    pub(crate) access: AccessTracker,
+}
+
+impl Transfer {
+    fn percent(&self) -> u64 {
+        (self.read * 100)
+            .checked_div(self.size)
+            .map_or(100, |p| p.min(100))
+    }
+
+    /// remaining time at the average rate since the transfer started
+    fn eta(&self, elapsed: Duration) -> Option<Duration> {
+        if self.read == 0 {
+            return None;
+        }
+        let remaining = self.size.saturating_sub(self.read) as f64 / self.read as f64;
+        Some(elapsed.mul_f64(remaining))
+    }
+
+    /// `<percent>% <read>/<size> bytes eta <seconds>s <pattern> <hits>/<reads> cached`,
+    /// eta `?` until data came
+    fn describe(&self, now: Instant, hits: u32) -> String {
+        let eta = self
+            .eta(now.saturating_duration_since(self.started))
+            .map(|d| format!("{}s", d.as_secs()))
+            .unwrap_or_else(|| "?".to_string());
+        format!(
+            "{}% {}/{} bytes eta {eta} {} {hits}/{} cached",
+            self.percent(),
+            self.read,
+            self.size,
+            self.access.pattern(),
+            self.access.reads()
+        )
+    }
+}
+
+impl RemarkableFs {
+    /// records that `len` bytes at `offset` of document `ino` were read
+    pub(crate) fn record_progress(&mut self, ino: usize, offset: u64, len: usize) {
+        let size = match self.get_node(ino) {
+            Some(node) => node.borrow().get_size(),
+            None => return,
+        };
+        let transfer = self.transfers.entry(ino).or_insert(Transfer {
+            started: Instant::now(),
+            read: 0,
+            size,
+            access: AccessTracker::default(),
+        });
+        transfer.size = size;
+        transfer.read = transfer.read.max(offset + len as u64);
+        transfer.access.record(offset, len);
+    }
+
+    /// the transfer of `ino` ended with its last file handle
+    pub(crate) fn end_progress(&mut self, ino: usize) {
+        if let Some(transfer) = self.transfers.remove(&ino) {
+            debug!(
+                "transfer of {ino} ended, {} with {} cached reads",
+                transfer.access.pattern(),
+                self.cache_hits(ino)
+            );
+        }
+        self.read_caches.borrow_mut().remove(&ino);
+    }
+
+    /// `<name>.progress` sidecar of a document in `parent`. Sidecars are not
+    /// listed, they are created when looked up so that any document can be
+    /// watched by appending the suffix to its path.
+    pub(crate) fn lookup_progress(&mut self, parent: usize, name: &str) -> Option<usize> {
+        let doc_name = name.strip_suffix(Node::PROGRESS_SUFFIX)?;
+        let doc_ino = self
+            .lookup_node(parent, doc_name)
+            .ok()
+            .flatten()
+            .map(|n| n.borrow().get_ino())
+            .filter(|&ino| self.is_document(ino))?;
+        let key = format!("{}{}", self.unique_id(doc_ino)?, Node::PROGRESS_SUFFIX);
+        if let Some(&ino) = self.uid_map.get(&key) {
+            return Some(ino);
+        }
+        let ino = self.nodes.len();
+        self.nodes
+            .push(RefCell::new(Node::new_virtual_file(ino, parent, name)));
+        self.virtual_files
+            .insert(ino, VirtualFile::Progress(doc_ino));
+        self.uid_map.insert(key, ino);
+        Some(ino)
+    }
+
+    /// progress of document `ino`, `idle` when it is not being read
+    pub(crate) fn progress_report(&self, ino: usize) -> String {
+        match self.transfers.get(&ino) {
+            Some(transfer) => format!(
+                "{}\n",
+                transfer.describe(Instant::now(), self.cache_hits(ino))
+            ),
+            None => "idle\n".to_string(),
+        }
+    }
+
+    /// `/.control/progress` : one line per document being read
+    pub(crate) fn transfers_report(&self) -> String {
+        let now = Instant::now();
+        let mut lines = self
+            .transfers
+            .iter()
+            .filter_map(|(&ino, transfer)| {
+                let name = self.get_node(ino)?.borrow().get_visible_name();
+                Some(format!(
+                    "{}\t{}\n",
+                    name.display(),
+                    transfer.describe(now, self.cache_hits(ino))
+                ))
+            })
+            .collect::<Vec<_>>();
+        lines.sort();
+        lines.concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        let started = Instant::now();
+        let mut transfer = Transfer {
+            started,
+            read: 0,
+            size: 4000,
+            access: AccessTracker::default(),
+        };
+        assert_eq!(
+            transfer.describe(started, 0),
+            "0% 0/4000 bytes eta ? unknown 0/0 cached"
+        );
+        transfer.read = 1000;
+        for offset in [0, 250, 500, 750] {
+            transfer.access.record(offset, 250);
+        }
+        assert_eq!(
+            transfer.describe(started + Duration::from_secs(2), 1),
+            "25% 1000/4000 bytes eta 6s sequential 1/4 cached"
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use super::views::VirtualFile;
+use super::RemarkableFs;
+use crate::nodes::{FuserChild, Node};
+use crate::RemarkableError;
+use log::{error, warn};
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+
+/// A listing entry whose files are broken, shown as `<uid>.corrupt`, or
+/// stored encrypted, shown as `<uid>.encrypted`
+#[derive(Debug, Clone)]
+pub(crate) struct Quarantined {
+    uid: String,
+    file: String,
+    reason: String,
+    encrypted: bool,
+}
+
+impl RemarkableFs {
+    /// why an encrypted item is only a placeholder
+    const XATTR_UNSUPPORTED: &'static str = "user.remarkable.unsupported";
+
+    /// Handles entry `file` of collection `parent` that could not be loaded.
+    /// Corrupt items get a `<uid>.corrupt` marker at `position`, describing
+    /// the failure, items that cannot be decrypted a `<uid>.encrypted` one;
+    /// transient failures are only logged, the entry is tried again when the
+    /// collection is listed next.
+    pub(crate) fn quarantine_entry(
+        &mut self,
+        parent: usize,
+        position: usize,
+        file: &str,
+        e: &RemarkableError,
+    ) -> Option<FuserChild> {
+        if !e.is_corrupt() {
+            warn!("entry {file} of {parent} skipped until next listing : {e}");
+            return None;
+        }
+        let uid = Path::new(file)
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or_default()
+            .to_owned();
+        let encrypted = e.is_encrypted();
+        let suffix = if encrypted {
+            warn!("{uid} shown as a placeholder : {e}");
+            Node::ENCRYPTED_SUFFIX
+        } else {
+            error!("{uid} quarantined, its files are corrupt : {e}");
+            Node::CORRUPT_SUFFIX
+        };
+        let key = format!("{uid}{suffix}");
+        let ino = match self.uid_map.get(&key) {
+            Some(&ino) => ino,
+            None => {
+                let ino = self.nodes.len();
+                self.nodes
+                    .push(RefCell::new(Node::new_virtual_file(ino, parent, &key)));
+                self.virtual_files.insert(ino, VirtualFile::Quarantine(ino));
+                self.uid_map.insert(key.clone(), ino);
+                ino
+            }
+        };
+        self.quarantined.insert(
+            ino,
+            Quarantined {
+                uid,
+                file: file.to_owned(),
+                reason: e.to_string(),
+                encrypted,
+            },
+        );
+        Some(FuserChild::new(
+            ino,
+            position,
+            fuser::FileType::RegularFile,
+            PathBuf::from(key),
+        ))
+    }
+
+    /// `<uid>` loaded fine after all, its marker goes away
+    pub(crate) fn release_quarantine(&mut self, uid: &str) {
+        for suffix in [Node::CORRUPT_SUFFIX, Node::ENCRYPTED_SUFFIX] {
+            if let Some(&ino) = self.uid_map.get(&format!("{uid}{suffix}")) {
+                self.quarantined.remove(&ino);
+            }
+        }
+    }
+
+    /// explanation of the placeholder `ino` of an encrypted item
+    pub(crate) fn quarantine_xattrs(&self, ino: usize) -> Option<(String, Vec<u8>)> {
+        let q = self.quarantined.get(&ino).filter(|q| q.encrypted)?;
+        Some((
+            Self::XATTR_UNSUPPORTED.to_string(),
+            q.reason.clone().into_bytes(),
+        ))
+    }
+
+    /// content of the `.corrupt` marker `ino`
+    pub(crate) fn quarantine_report(&self, ino: usize) -> String {
+        match self.quarantined.get(&ino) {
+            Some(q) => format!("uid: {}\nfile: {}\nerror: {}\n", q.uid, q.file, q.reason),
+            None => "recovered\n".to_string(),
+        }
+    }
+}
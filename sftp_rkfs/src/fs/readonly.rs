@@ -0,0 +1,89 @@
+use super::RemarkableFs;
+use log::{debug, warn};
+use std::time::{Duration, Instant};
+
+/// Writes refused since the hint about them was last logged
+#[derive(Debug, Default)]
+pub(crate) struct WriteRefusals {
+    last_hint: Option<Instant>,
+    refused: u64,
+}
+
+impl WriteRefusals {
+    /// minimum time between two hints
+    const HINT_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// counts a refusal at `now`, returns the refusals to report when a hint
+    /// is due
+    fn refuse(&mut self, now: Instant) -> Option<u64> {
+        self.refused += 1;
+        if self
+            .last_hint
+            .is_some_and(|last| now.duration_since(last) < Self::HINT_INTERVAL)
+        {
+            return None;
+        }
+        self.last_hint = Some(now);
+        Some(std::mem::take(&mut self.refused))
+    }
+}
+
+impl RemarkableFs {
+    /// Mounts read only : every write is refused, control files included
+    pub fn set_read_only(&mut self, enabled: bool) {
+        self.read_only = enabled;
+    }
+
+    /// EROFS for the write `operation` on `ino`, with a hint logged at most once
+    /// a minute so that a failing application does not flood the log
+    pub(crate) fn refuse_write(&mut self, operation: &str, ino: usize) -> libc::c_int {
+        debug!("{operation} refused for {ino}");
+        if let Some(refused) = self.write_refusals.refuse(Instant::now()) {
+            if self.read_only {
+                warn!("{refused} write(s) refused, the file system is mounted read only");
+            } else {
+                warn!(
+                    "{refused} write(s) refused ({operation} of {ino}) : only copies of pdf \
+                     and epub files, new collections, moves and deletions are supported. \
+                     Mount read only (--read-only) to have every write refused upfront"
+                );
+            }
+        }
+        libc::EROFS
+    }
+
+    /// refuses the write `operation` on `ino` when mounted read only
+    pub(crate) fn check_writable(
+        &mut self,
+        operation: &str,
+        ino: usize,
+    ) -> Result<(), libc::c_int> {
+        if self.read_only {
+            return Err(self.refuse_write(operation, ino));
+        }
+        Ok(())
+    }
+
+    /// Keeps the umask of the process that created `ino`, applied to its mode
+    pub(crate) fn record_umask(&mut self, ino: usize, umask: u32) {
+        self.umasks.insert(ino, (umask & 0o777) as u16);
+        self.attr_cache.borrow_mut().remove(&ino);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refusal_hint_rate() {
+        let start = Instant::now();
+        let mut refusals = WriteRefusals::default();
+        assert_eq!(refusals.refuse(start), Some(1));
+        assert_eq!(refusals.refuse(start + Duration::from_secs(1)), None);
+        assert_eq!(refusals.refuse(start + Duration::from_secs(30)), None);
+        // the next hint counts the refusals that were not reported
+        assert_eq!(refusals.refuse(start + Duration::from_secs(61)), Some(3));
+        assert_eq!(refusals.refuse(start + Duration::from_secs(62)), None);
+    }
+}
@@ -0,0 +1,70 @@
+use super::RemarkableFs;
+use crate::RemarkableError;
+use log::{error, info, warn};
+use std::time::{Duration, Instant};
+
+impl RemarkableFs {
+    /// pause after which the session is checked before a remote operation,
+    /// the tablet having possibly slept meanwhile
+    const IDLE_CHECK: Duration = Duration::from_secs(60);
+
+    /// Runs `op`, and once more on a new session if it failed because the
+    /// connection was lost (the tablet rebooted or slept). Files are opened
+    /// remotely on each read, so open file handles stay valid across the
+    /// reconnection; cached attributes are dropped as the tablet may have
+    /// changed meanwhile. After a pause or a failed reconnection, the session
+    /// is checked first, so that operations with side effects are not sent on
+    /// a dead one.
+    pub(crate) fn with_reconnect<T>(
+        &mut self,
+        what: &str,
+        mut op: impl FnMut(&mut Self) -> Result<T, RemarkableError>,
+    ) -> Result<T, RemarkableError> {
+        let idle = self
+            .last_remote_op
+            .is_some_and(|last| last.elapsed() > Self::IDLE_CHECK);
+        if self.disconnected || idle {
+            match self.session.ensure_connected() {
+                Ok(true) => self.reconnected(),
+                Ok(false) => self.disconnected = false,
+                Err(e) => {
+                    error!("still disconnected, {what} not run : {e}");
+                    self.disconnected = true;
+                    return Err(e);
+                }
+            }
+        }
+        let result = match op(self) {
+            Err(e) if self.is_stale(&e) => {
+                warn!("connection lost during {what} ({e}), reconnecting");
+                if let Err(reconnect) = self.session.reconnect() {
+                    error!("reconnection failed : {reconnect}");
+                    self.disconnected = true;
+                    return Err(e);
+                }
+                self.reconnected();
+                op(self)
+            }
+            result => result,
+        };
+        self.last_remote_op = Some(Instant::now());
+        result
+    }
+
+    /// drops what the former session left behind
+    pub(crate) fn reconnected(&mut self) {
+        info!("reconnected to the tablet");
+        self.disconnected = false;
+        self.attr_cache.borrow_mut().clear();
+        self.handles.borrow_mut().close_files();
+        self.read_coalescer = None;
+        self.renew_poller_runner();
+    }
+
+    /// does `e` come from a dead session ? Transport errors lose their ssh code
+    /// when reported through sftp file reads, the session is probed for those
+    fn is_stale(&self, e: &RemarkableError) -> bool {
+        e.is_connection_lost()
+            || (matches!(e.root(), RemarkableError::Transport(_)) && !self.session.is_alive())
+    }
+}
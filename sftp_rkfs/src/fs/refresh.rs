@@ -0,0 +1,269 @@
+use super::RemarkableFs;
+use crate::nodes::Node;
+use crate::sshutils::{CommandRunner, RunCommand};
+use log::{debug, info, warn};
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::time::Duration;
+
+/// Kernel cache entry made stale by a change on the tablet
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Invalidation {
+    /// attributes and data of a node
+    Inode(usize),
+    /// child of a collection under a name it no longer has
+    Entry(usize, OsString),
+}
+
+impl Invalidation {
+    fn send(&self, notifier: &fuser::Notifier) {
+        let sent = match self {
+            Invalidation::Inode(ino) => notifier.inval_inode(*ino as u64, 0, 0),
+            Invalidation::Entry(parent, name) => notifier.inval_entry(*parent as u64, name),
+        };
+        // the kernel may not know the node
+        if let Err(e) = sent {
+            debug!("{self:?} not invalidated : {e}");
+        }
+    }
+}
+
+/// Filesystem end of the channels to the thread polling the tablet for changes
+pub(crate) struct Refresher {
+    /// signalled when the library changed on the tablet
+    stale: Receiver<()>,
+    invalidations: Sender<Vec<Invalidation>>,
+    /// runners of the sessions replacing a lost one
+    runners: Sender<CommandRunner>,
+}
+
+/// Polls the tablet for changes of the metadata files every `interval`. On a
+/// change, the filesystem is woken to refresh its listings, and the kernel
+/// entries it made stale are invalidated, from this thread : the filesystem
+/// cannot notify the kernel while it answers a request
+pub(crate) struct Poller {
+    runner: CommandRunner,
+    /// prints a digest of the modification times of the metadata files
+    command: String,
+    interval: Duration,
+    mount_point: PathBuf,
+    stale: Sender<()>,
+    invalidations: Receiver<Vec<Invalidation>>,
+    runners: Receiver<CommandRunner>,
+}
+
+impl Poller {
+    /// time given to the filesystem to refresh once woken
+    const WAIT: Duration = Duration::from_secs(30);
+
+    pub(crate) fn spawn(self, notifier: fuser::Notifier) {
+        std::thread::spawn(move || self.run(notifier));
+    }
+
+    fn run(mut self, notifier: fuser::Notifier) {
+        let mut last = self.fingerprint();
+        loop {
+            std::thread::sleep(self.interval);
+            // the filesystem reconnected, the former session is gone
+            if let Some(runner) = self.runners.try_iter().last() {
+                self.runner = runner;
+            }
+            match self.invalidations.try_recv() {
+                Ok(late) => late.iter().for_each(|i| i.send(&notifier)),
+                Err(TryRecvError::Empty) => {}
+                // unmounted
+                Err(TryRecvError::Disconnected) => return,
+            }
+            let Some(fingerprint) = self.fingerprint() else {
+                continue;
+            };
+            if last.as_ref() == Some(&fingerprint) {
+                continue;
+            }
+            if last.replace(fingerprint).is_none() {
+                continue;
+            }
+            debug!("library changed on the tablet");
+            if self.stale.send(()).is_err() {
+                return;
+            }
+            // the filesystem refreshes before answering any request
+            if let Err(e) = std::fs::metadata(&self.mount_point) {
+                debug!("{:?} not woken : {e}", self.mount_point);
+            }
+            match self.invalidations.recv_timeout(Self::WAIT) {
+                Ok(stale) => stale.iter().for_each(|i| i.send(&notifier)),
+                Err(RecvTimeoutError::Timeout) => debug!("refresh still running"),
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    fn fingerprint(&self) -> Option<String> {
+        self.runner
+            .execute_cmd(&self.command)
+            .inspect_err(|e| debug!("tablet not polled : {e}"))
+            .ok()
+    }
+}
+
+impl RemarkableFs {
+    /// Polls the tablet for changes every `interval` once mounted, refreshing
+    /// the listings and the kernel caches when the library changed, so that
+    /// documents created on the tablet show up without listing them again.
+    /// Zero for no polling
+    pub fn set_refresh_interval(&mut self, interval: Duration) {
+        self.refresh_interval = (!interval.is_zero()).then_some(interval);
+    }
+
+    /// Sets up the channels of the background refresh, if polling, returning
+    /// the poller to spawn with the notifier of the mounted session
+    pub(crate) fn start_refresher(&mut self) -> Option<Poller> {
+        let interval = self.refresh_interval?;
+        let (stale_sender, stale) = mpsc::channel();
+        let (invalidations, receiver) = mpsc::channel();
+        let (runners, runner_receiver) = mpsc::channel();
+        self.refresher = Some(Refresher {
+            stale,
+            invalidations,
+            runners,
+        });
+        let glob = self.layout.metadata_glob(&self.document_root);
+        info!(
+            "polling the tablet for changes every {}s",
+            interval.as_secs()
+        );
+        Some(Poller {
+            runner: self.session.runner(),
+            command: format!("stat -c '%Y %n' {glob} 2> /dev/null | md5sum"),
+            interval,
+            mount_point: self.mount_point.clone(),
+            stale: stale_sender,
+            invalidations: receiver,
+            runners: runner_receiver,
+        })
+    }
+
+    /// hands the poller a runner of the current session, after a reconnection
+    pub(crate) fn renew_poller_runner(&self) {
+        if let Some(refresher) = &self.refresher {
+            let _ = refresher.runners.send(self.session.runner());
+        }
+    }
+
+    /// Refreshes the listed collections when the poller found the library
+    /// changed, handing it the kernel entries to invalidate
+    pub(crate) fn apply_refresh(&mut self) {
+        let Some(refresher) = &self.refresher else {
+            return;
+        };
+        if refresher.stale.try_iter().count() == 0 {
+            return;
+        }
+        let before = self.listed_entries();
+        if let Err(e) = self.refresh_subtree(Node::ROOT_NODE_INO) {
+            warn!("background refresh failed : {e}");
+        }
+        let listed = self.listings.keys().copied().collect::<Vec<_>>();
+        for ino in listed {
+            // entries are reloaded to find the modified ones
+            if let Err(e) = self.load_listing(ino) {
+                debug!("collection {ino} not reloaded : {e}");
+            }
+        }
+        let stale = stale_entries(&before, &self.listed_entries());
+        info!(
+            "library changed, {} kernel entries invalidated",
+            stale.len()
+        );
+        if let Some(refresher) = &self.refresher {
+            let _ = refresher.invalidations.send(stale);
+        }
+    }
+
+    /// (collection, name, generation) of the children of the listed
+    /// collections, by ino
+    fn listed_entries(&self) -> HashMap<usize, (usize, OsString, u64)> {
+        let mut entries = HashMap::new();
+        for &dir in self.listings.keys() {
+            let Some(node) = self.get_node(dir) else {
+                continue;
+            };
+            for child in node.borrow().get_children(0) {
+                let generation = self
+                    .get_node(child.ino())
+                    .map_or(0, |n| n.borrow().generation());
+                entries.insert(child.ino(), (dir, child.3, generation));
+            }
+        }
+        entries
+    }
+}
+
+/// kernel entries made stale going from the listed entries `before` to `after`
+fn stale_entries(
+    before: &HashMap<usize, (usize, OsString, u64)>,
+    after: &HashMap<usize, (usize, OsString, u64)>,
+) -> Vec<Invalidation> {
+    let mut stale = BTreeSet::new();
+    for (&ino, (dir, name, generation)) in before {
+        match after.get(&ino) {
+            Some((now_dir, now_name, now_generation)) if (now_dir, now_name) == (dir, name) => {
+                if now_generation != generation {
+                    stale.insert(Invalidation::Inode(ino));
+                }
+            }
+            moved => {
+                stale.insert(Invalidation::Entry(*dir, name.clone()));
+                stale.insert(Invalidation::Inode(*dir));
+                if let Some((now_dir, _, _)) = moved {
+                    stale.insert(Invalidation::Inode(*now_dir));
+                    stale.insert(Invalidation::Inode(ino));
+                }
+            }
+        }
+    }
+    for (ino, (dir, _, _)) in after {
+        if !before.contains_key(ino) {
+            stale.insert(Invalidation::Inode(*dir));
+        }
+    }
+    stale.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_entries() {
+        let entry = |dir, name: &str, generation| (dir, OsString::from(name), generation);
+        let before = HashMap::from([
+            (10, entry(1, "kept", 0)),
+            (11, entry(1, "edited", 0)),
+            (12, entry(1, "renamed", 0)),
+            (13, entry(5, "deleted", 0)),
+        ]);
+        let after = HashMap::from([
+            (10, entry(1, "kept", 0)),
+            (11, entry(1, "edited", 1)),
+            (12, entry(6, "moved", 1)),
+            (14, entry(7, "new", 0)),
+        ]);
+        assert_eq!(
+            stale_entries(&before, &after),
+            vec![
+                Invalidation::Inode(1),
+                Invalidation::Inode(5),
+                Invalidation::Inode(6),
+                Invalidation::Inode(7),
+                Invalidation::Inode(11),
+                Invalidation::Inode(12),
+                Invalidation::Entry(1, OsString::from("renamed")),
+                Invalidation::Entry(5, OsString::from("deleted")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,50 @@
+use super::RemarkableFs;
+use crate::nodes::RenderedSize;
+use crate::{FsError, RemarkableError};
+use log::debug;
+
+impl RemarkableFs {
+    /// rough size of a rendered notebook page, for estimates
+    const ESTIMATED_PAGE_SIZE: u64 = 96 * 1024;
+
+    /// Announces that document `ino` is presented rendered. Until its rendered
+    /// file is cached, its size is estimated from its page count so that copies
+    /// and progress bars have a size to work with. Returns the presented size.
+    pub fn expect_rendering(&mut self, ino: usize) -> Result<u64, RemarkableError> {
+        if let Err(e) = self.ensure_details(ino) {
+            debug!("page count of {ino} unavailable for the estimate : {e}");
+        }
+        let node = self.get_node(ino).ok_or(FsError::NodeNotFound(ino))?;
+        let mut node = node.borrow_mut();
+        if !matches!(node.get_rendered_size(), Some(RenderedSize::Cached(_))) {
+            let pages = node.get_page_count().unwrap_or(1).max(1);
+            let estimate =
+                (u64::from(pages) * Self::ESTIMATED_PAGE_SIZE).max(node.get_payload_size());
+            node.set_rendered_size(Some(RenderedSize::Estimated(estimate)));
+        }
+        Ok(node.get_size())
+    }
+
+    /// Records the size of the cached rendered file of document `ino`, reported
+    /// from then on. The node generation changes, so the next getattr sees it.
+    pub fn set_rendered_size(&mut self, ino: usize, bytes: u64) -> Result<(), RemarkableError> {
+        let node = self.get_node(ino).ok_or(FsError::NodeNotFound(ino))?;
+        node.borrow_mut()
+            .set_rendered_size(Some(RenderedSize::Cached(bytes)));
+        debug!("rendered size of {ino} is {bytes}");
+        Ok(())
+    }
+
+    /// Presents document `ino` as its payload again, once its rendered file is
+    /// dropped from the cache
+    pub fn forget_rendering(&mut self, ino: usize) {
+        if let Some(node) = self.get_node(ino) {
+            node.borrow_mut().set_rendered_size(None);
+        }
+    }
+
+    /// How the size of document `ino` is currently known, None for its payload
+    pub fn rendered_size(&self, ino: usize) -> Option<RenderedSize> {
+        self.get_node(ino)?.borrow().get_rendered_size()
+    }
+}
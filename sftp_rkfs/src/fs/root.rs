@@ -0,0 +1,182 @@
+use super::RemarkableFs;
+use crate::{names, ErrorContext, FsError, RemarkableError, RootProblem};
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+
+impl RemarkableFs {
+    /// Folders xochitl kept its documents in across firmwares and models, tried
+    /// in turn after the configured document root
+    const ROOT_CANDIDATES: [&'static str; 3] = [
+        "/home/root/.local/share/remarkable/xochitl",
+        // data partition, on models where it is not mounted on /home
+        "/data/home/root/.local/share/remarkable/xochitl",
+        "/home/root/.local/share/xochitl",
+    ];
+
+    /// Looks for the document root on the tablet: the configured one when it
+    /// holds documents, else the first candidate that does, else the first
+    /// existing one. The configured root is kept when none exists or the tablet
+    /// cannot be asked. Returns the root in use.
+    pub fn detect_document_root(&mut self) -> &Path {
+        let configured = names::remote_str(&self.document_root)
+            .trim_end_matches('/')
+            .to_owned();
+        let mut candidates = vec![configured.clone()];
+        candidates.extend(
+            Self::ROOT_CANDIDATES
+                .iter()
+                .map(|c| c.to_string())
+                .filter(|c| *c != configured),
+        );
+        match self.probe_roots(&candidates) {
+            Ok((build, Some(root))) => {
+                if root != self.document_root {
+                    info!("document root {root:?} found on the tablet (build {build})");
+                    self.document_root = root;
+                }
+            }
+            Ok((build, None)) => warn!(
+                "no document root found on the tablet (build {build}), keeping {:?}",
+                self.document_root
+            ),
+            Err(e) => warn!("document root not checked: {e}"),
+        }
+        &self.document_root
+    }
+
+    /// Checks that the document root exists on the tablet, is a folder and can
+    /// be read, so that a wrong root fails at mount rather than in every
+    /// listing. A root without documents is only warned about, a new tablet
+    /// having none
+    pub fn check_document_root(&self) -> Result<(), RemarkableError> {
+        let root = names::remote_str(&self.document_root).into_owned();
+        let out = self
+            .session
+            .execute_cmd(&check_root_cmd(&root))
+            .context("checking the document root")?;
+        match parse_root_check(&out) {
+            Some(Ok(true)) => Ok(()),
+            Some(Ok(false)) => {
+                warn!("document root {root} holds no documents");
+                Ok(())
+            }
+            Some(Err(problem)) => {
+                Err(FsError::DocumentRoot(self.document_root.clone(), problem).into())
+            }
+            None => {
+                warn!("document root {root} not checked: unexpected answer {out:?}");
+                Ok(())
+            }
+        }
+    }
+
+    /// Remote folder holding xochitl documents, as configured or detected at
+    /// connection
+    pub fn document_root(&self) -> &Path {
+        &self.document_root
+    }
+
+    /// build stamp of the firmware and the root picked among `candidates`
+    fn probe_roots(
+        &self,
+        candidates: &[String],
+    ) -> Result<(String, Option<PathBuf>), RemarkableError> {
+        let out = self
+            .session
+            .execute_cmd(&probe_roots_cmd(candidates))
+            .context("looking for the document root")?;
+        let (build, root) = parse_probe(&out);
+        Ok((build.unwrap_or("unknown").to_owned(), root))
+    }
+}
+
+/// prints the firmware build stamp, then `documents <dir>` or `empty <dir>` for
+/// each candidate folder that exists
+fn probe_roots_cmd(candidates: &[String]) -> String {
+    let dirs = candidates
+        .iter()
+        .map(|c| format!("'{}'", c.replace('\'', "'\\''")))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        "head -n 1 {} 2>/dev/null; echo; for d in {dirs}; do \
+         if ls \"$d\"/*.metadata > /dev/null 2>&1; then echo \"documents $d\"; \
+         elif [ -d \"$d\" ]; then echo \"empty $d\"; fi; done",
+        RemarkableFs::BUILD_FILE
+    )
+}
+
+/// prints `documents`, `empty`, or the problem of the folder `root`
+fn check_root_cmd(root: &str) -> String {
+    format!(
+        "d='{}'; if [ ! -e \"$d\" ]; then echo missing; \
+         elif [ ! -d \"$d\" ]; then echo file; \
+         elif [ ! -r \"$d\" ] || [ ! -x \"$d\" ]; then echo unreadable; \
+         elif ls \"$d\"/*.metadata > /dev/null 2>&1; then echo documents; \
+         else echo empty; fi",
+        root.replace('\'', "'\\''")
+    )
+}
+
+/// whether the root holds documents, from the `check_root_cmd` output, or its
+/// problem. None for an unexpected output
+fn parse_root_check(out: &str) -> Option<Result<bool, RootProblem>> {
+    match out.trim() {
+        "documents" => Some(Ok(true)),
+        "empty" => Some(Ok(false)),
+        "missing" => Some(Err(RootProblem::Missing)),
+        "file" => Some(Err(RootProblem::NotAFolder)),
+        "unreadable" => Some(Err(RootProblem::Unreadable)),
+        _ => None,
+    }
+}
+
+/// build stamp and chosen root of the `probe_roots_cmd` output, candidates
+/// holding documents first, in their order
+fn parse_probe(out: &str) -> (Option<&str>, Option<PathBuf>) {
+    let mut lines = out.lines().map(str::trim);
+    let build = lines.next().filter(|b| !b.is_empty());
+    let found = lines.collect::<Vec<_>>();
+    let root = ["documents ", "empty "].iter().find_map(|kind| {
+        found
+            .iter()
+            .find_map(|l| l.strip_prefix(kind))
+            .map(PathBuf::from)
+    });
+    (build, root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_probe() {
+        let out = "20231010164506\n\nempty /home/root/xochitl\ndocuments /data/xochitl\n";
+        assert_eq!(
+            parse_probe(out),
+            (Some("20231010164506"), Some(PathBuf::from("/data/xochitl")))
+        );
+        assert_eq!(
+            parse_probe("\n\nempty /a\nempty /b\n"),
+            (None, Some(PathBuf::from("/a")))
+        );
+        assert_eq!(
+            parse_probe("20231010164506\n\n"),
+            (Some("20231010164506"), None)
+        );
+        assert!(probe_roots_cmd(&["/it's".to_owned()]).contains("for d in '/it'\\''s'; do"));
+    }
+
+    #[test]
+    fn test_parse_root_check() {
+        assert_eq!(parse_root_check("documents\n"), Some(Ok(true)));
+        assert_eq!(parse_root_check("empty\n"), Some(Ok(false)));
+        assert_eq!(
+            parse_root_check("file\n"),
+            Some(Err(RootProblem::NotAFolder))
+        );
+        assert_eq!(parse_root_check("sh: syntax error\n"), None);
+        assert!(check_root_cmd("/it's").starts_with("d='/it'\\''s';"));
+    }
+}
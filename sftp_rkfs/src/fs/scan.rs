@@ -0,0 +1,582 @@
+use super::jobs::JobKind;
+use super::RemarkableFs;
+use crate::encryption::Protection;
+use crate::names;
+use crate::nodes::Node;
+use crate::sshutils::{Prefetch, SshFileStat};
+use crate::{FsError, RemarkableError};
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::time::{Duration, Instant};
+
+/// Outcome of a library scan
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ScanReport {
+    pub collections: usize,
+    /// listing entries loaded into nodes
+    pub entries: usize,
+    /// remote commands fetching entries
+    pub batches: usize,
+    /// depth of the collection tree, the root level being 1
+    pub levels: usize,
+    pub elapsed: Duration,
+}
+
+/// Listing entry `idx` of collection `ino`, with its metadata file
+type ScanEntry = (usize, usize, String);
+/// remote files to read and to stat
+type PrefetchPaths = (Vec<String>, Vec<String>);
+/// fetched batch, by index in its level
+type FetchedBatch = (usize, Result<Prefetch, RemarkableError>);
+/// payloads a document may have, stat'ed to tell its kind
+const PAYLOAD_EXTENSIONS: [&str; 2] = ["pdf", "epub"];
+
+/// A library scan in progress, kept by the filesystem so that it goes on
+/// between requests. Levels of the tree are scanned in turn, the root first.
+/// The entry batches of a level are fetched by background jobs and loaded in
+/// order as they arrive, a failed step being taken again at the next request.
+pub(crate) struct ScanState {
+    started: Instant,
+    report: ScanReport,
+    /// metadata files on the tablet when the scan started
+    total: Option<usize>,
+    scanned: HashSet<usize>,
+    /// collections found by the level being loaded, the next level to scan
+    found: Vec<usize>,
+    /// entry batches of the level being loaded
+    batches: Vec<Vec<ScanEntry>>,
+    /// batches not submitted yet
+    unsent: VecDeque<(usize, PrefetchPaths)>,
+    arrived: BTreeMap<usize, Result<Prefetch, RemarkableError>>,
+    /// next batch to load
+    next: usize,
+    loaded: usize,
+    /// percentage last logged
+    logged: usize,
+    /// whole library being fetched in a single command, see `set_bulk_load`
+    bulk: Option<Receiver<Result<Prefetch, RemarkableError>>>,
+    /// entries are loaded from the bulk fetch, not fetched in batches
+    bulk_loaded: bool,
+    sender: Sender<FetchedBatch>,
+    receiver: Receiver<FetchedBatch>,
+}
+
+impl ScanState {
+    /// a scan of a library of `total` metadata files, from the root level
+    fn new(total: Option<usize>) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            started: Instant::now(),
+            report: ScanReport::default(),
+            total,
+            scanned: HashSet::new(),
+            found: vec![Node::ROOT_NODE_INO, Node::TRASH_NODE_INO],
+            batches: vec![],
+            unsent: VecDeque::new(),
+            arrived: BTreeMap::new(),
+            next: 0,
+            loaded: 0,
+            logged: 0,
+            bulk: None,
+            bulk_loaded: false,
+            sender,
+            receiver,
+        }
+    }
+
+    /// share of the metadata files loaded, in percent
+    fn percent(&self) -> Option<usize> {
+        self.total
+            .filter(|&total| total > 0)
+            .map(|total| (self.loaded * 100 / total).min(100))
+    }
+
+    /// line of `/.control/jobs`
+    fn progress(&self) -> String {
+        let percent = self
+            .percent()
+            .map(|p| format!(" ({p}%)"))
+            .unwrap_or_default();
+        let total = self.total.map(|t| format!(" of {t}")).unwrap_or_default();
+        format!(
+            "scan: level {}, {} entries{total} loaded{percent}\n",
+            self.report.levels, self.loaded
+        )
+    }
+}
+
+impl RemarkableFs {
+    pub const DEFAULT_SCAN_BATCH_SIZE: usize = 64;
+    pub const DEFAULT_LISTING_CHANNELS: usize = 4;
+    /// time a request may spend loading scanned entries before it is served
+    const SCAN_STEP: Duration = Duration::from_millis(50);
+
+    /// Sets how many entry batches the scan at mount fetches at once, 0 leaving
+    /// collections to be loaded when first listed
+    pub fn set_scan_jobs(&mut self, jobs: usize) {
+        self.scan_jobs = jobs;
+    }
+
+    /// Sets how many listing entries each scan command fetches
+    pub fn set_scan_batch_size(&mut self, entries: usize) {
+        self.scan_batch_size = entries.max(1);
+    }
+
+    /// Sets how many channels fetch the entries of a collection being listed at
+    /// once, 0 fetching them entry by entry
+    pub fn set_listing_channels(&mut self, channels: usize) {
+        self.listing_channels = channels;
+    }
+
+    /// Fetches the metadata and content files of the whole library in a single
+    /// remote command when the scan starts, rather than in batches. It scans
+    /// the library at mount even without scan jobs
+    pub fn set_bulk_load(&mut self, enabled: bool) {
+        self.bulk_load = enabled;
+    }
+
+    /// Starts scanning the library if scan jobs or the bulk load are set, once
+    /// the root nodes exist. The scan goes on while requests are served, see
+    /// `advance_scan`
+    pub(crate) fn initial_scan(&mut self) {
+        if self.scan_jobs == 0 && !self.bulk_load {
+            return;
+        }
+        self.start_scan();
+        if let Some(total) = self.scan.as_ref().and_then(|s| s.total) {
+            info!("library scan started, {total} metadata files");
+        }
+    }
+
+    /// Loads what the scan in progress fetched since the previous request, for
+    /// `SCAN_STEP` at most, and moves on to the next level once a level is
+    /// loaded. The collections already scanned are served meanwhile, the others
+    /// being loaded when listed as without a scan
+    pub(crate) fn advance_scan(&mut self) {
+        if self.scan.is_none() {
+            return;
+        }
+        match self.scan_step(false) {
+            Ok(Some(report)) => info!(
+                "scanned {} entries of {} collections in {} levels, {} batches, {}ms",
+                report.entries,
+                report.collections,
+                report.levels,
+                report.batches,
+                report.elapsed.as_millis()
+            ),
+            Ok(None) => {}
+            Err(e) => warn!("library scan step failed, taken again at the next request : {e}"),
+        }
+    }
+
+    /// Loads every collection of the library, level by level, before returning.
+    /// The entries of a level are fetched in batches by `scan_jobs` threads, each
+    /// batch in a single remote command, while the batches already fetched are
+    /// parsed : the link stays busy while nodes are built.
+    pub fn scan_library(&mut self) -> Result<ScanReport, RemarkableError> {
+        self.start_scan();
+        loop {
+            match self.scan_step(true) {
+                Ok(Some(report)) => return Ok(report),
+                Ok(None) => {}
+                Err(e) => {
+                    self.scan = None;
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// progress of the scan in progress, or outcome of the last one, for
+    /// `/.control/jobs`
+    pub(crate) fn scan_progress(&self) -> String {
+        match (&self.scan, &self.last_scan) {
+            (Some(scan), _) => scan.progress(),
+            (None, Some(report)) => format!(
+                "scan: done, {} entries of {} collections in {}s\n",
+                report.entries,
+                report.collections,
+                report.elapsed.as_secs()
+            ),
+            (None, None) => String::new(),
+        }
+    }
+
+    /// (re)starts the scan from the root level
+    fn start_scan(&mut self) {
+        let total = match self.count_metadata_files() {
+            Ok(total) => Some(total),
+            Err(e) => {
+                debug!("library size unknown, no scan percentage : {e}");
+                None
+            }
+        };
+        let mut scan = ScanState::new(total);
+        if self.bulk_load {
+            scan.bulk = Some(self.submit_bulk_load());
+        }
+        self.scan = Some(scan);
+    }
+
+    /// submits the fetch of every metadata and content file, and the stat of
+    /// every metadata file and payload, as a background job
+    fn submit_bulk_load(&self) -> Receiver<Result<Prefetch, RemarkableError>> {
+        let root = &self.document_root;
+        let reads = vec![
+            self.layout.metadata_glob(root),
+            self.layout.content_glob(root),
+        ];
+        let mut stats = vec![self.layout.metadata_glob(root)];
+        stats.extend(
+            PAYLOAD_EXTENSIONS
+                .iter()
+                .map(|extension| self.layout.payload_glob(root, extension)),
+        );
+        let (sender, receiver) = mpsc::channel();
+        let runner = self.session.runner();
+        self.jobs.submit(JobKind::Scan, move |context| {
+            let fetched = Prefetch::fetch_globs(&runner, &reads, &stats);
+            if let Ok(prefetch) = &fetched {
+                context.charge(prefetch.content_bytes());
+            }
+            let _ = sender.send(fetched);
+        });
+        receiver
+    }
+
+    /// Keeps the files of the bulk fetch for the entries to load. Payloads not
+    /// found are recorded as missing, not stat'ed again one by one
+    fn load_bulk(&mut self, mut prefetch: Prefetch) {
+        let uids = prefetch
+            .paths_read()
+            .filter(|path| path.extension().is_some_and(|e| e == "metadata"))
+            .filter_map(|path| path.file_stem()?.to_str().map(str::to_owned))
+            .collect::<Vec<_>>();
+        info!("{} items fetched in bulk", uids.len());
+        prefetch.mark_missing(uids.iter().flat_map(|uid| {
+            PAYLOAD_EXTENSIONS.iter().map(|extension| {
+                self.layout
+                    .payload_path(&self.document_root, uid, extension)
+            })
+        }));
+        self.prefetched.borrow_mut().merge(prefetch);
+    }
+
+    /// metadata files in the document root
+    fn count_metadata_files(&self) -> Result<usize, RemarkableError> {
+        let root = names::remote_str(&self.document_root).into_owned();
+        let glob = self.layout.metadata_glob(Path::new(&root));
+        let out = self
+            .session
+            .execute_cmd(&format!("ls -1d {glob} 2> /dev/null | wc -l"))?;
+        out.trim()
+            .parse()
+            .map_err(|_| FsError::InvalidPath(format!("metadata files count {out:?}")).into())
+    }
+
+    /// Advances the scan, until it is done when `wait`, else for `SCAN_STEP` at
+    /// most. Returns the report once the scan is done
+    fn scan_step(&mut self, wait: bool) -> Result<Option<ScanReport>, RemarkableError> {
+        let Some(mut scan) = self.scan.take() else {
+            return Ok(None);
+        };
+        match self.scan_chunk(&mut scan, wait) {
+            Ok(true) => {
+                self.prefetched.replace(Prefetch::default());
+                let mut report = scan.report;
+                report.elapsed = scan.started.elapsed();
+                self.last_scan = Some(report);
+                Ok(Some(report))
+            }
+            Ok(false) => {
+                self.scan = Some(scan);
+                Ok(None)
+            }
+            Err(e) => {
+                self.scan = Some(scan);
+                Err(e)
+            }
+        }
+    }
+
+    /// loads the fetched batches of `scan` in order, returns true once the last
+    /// level is loaded
+    fn scan_chunk(&mut self, scan: &mut ScanState, wait: bool) -> Result<bool, RemarkableError> {
+        let deadline = Instant::now() + Self::SCAN_STEP;
+        if let Some(bulk) = &scan.bulk {
+            let fetched = if wait {
+                bulk.recv().ok()
+            } else {
+                match bulk.try_recv() {
+                    Ok(fetched) => Some(fetched),
+                    Err(TryRecvError::Empty) => return Ok(false),
+                    Err(TryRecvError::Disconnected) => None,
+                }
+            };
+            scan.bulk = None;
+            match fetched {
+                Some(Ok(prefetch)) => {
+                    self.load_bulk(prefetch);
+                    scan.bulk_loaded = true;
+                    scan.report.batches += 1;
+                }
+                Some(Err(e)) => warn!("library not fetched in bulk, fetching it in batches : {e}"),
+                None => {}
+            }
+        }
+        loop {
+            if scan.next == scan.batches.len() {
+                if scan.found.is_empty() {
+                    return Ok(true);
+                }
+                self.start_level(scan)?;
+                continue;
+            }
+            while let Some(fetched) = scan.arrived.remove(&scan.next) {
+                self.load_scan_batch(scan, fetched);
+                if !wait && Instant::now() >= deadline {
+                    return Ok(false);
+                }
+            }
+            if scan.next == scan.batches.len() {
+                continue;
+            }
+            let (idx, fetched) = if wait {
+                match scan.receiver.recv() {
+                    Ok(fetched) => fetched,
+                    Err(_) => return Ok(true),
+                }
+            } else {
+                match scan.receiver.try_recv() {
+                    Ok(fetched) => fetched,
+                    Err(TryRecvError::Empty) => return Ok(false),
+                    Err(TryRecvError::Disconnected) => return Ok(true),
+                }
+            };
+            scan.arrived.insert(idx, fetched);
+            self.submit_scan_batch(scan);
+        }
+    }
+
+    /// lists the collections found by the previous level and submits the
+    /// fetches of their entries, `scan_jobs` at once
+    fn start_level(&mut self, scan: &mut ScanState) -> Result<(), RemarkableError> {
+        let mut level = scan.found.clone();
+        let mut seen = HashSet::new();
+        level.retain(|&ino| !scan.scanned.contains(&ino) && seen.insert(ino));
+        // a failed listing leaves the level to the next step
+        self.list_collections(&level)?;
+        scan.found.clear();
+        scan.scanned.extend(&level);
+        let entries = level
+            .iter()
+            .flat_map(|&ino| {
+                self.listings[&ino]
+                    .entries
+                    .iter()
+                    .enumerate()
+                    .map(move |(idx, (_, file))| (ino, idx, file.clone()))
+            })
+            .collect::<Vec<ScanEntry>>();
+        scan.batches = entries
+            .chunks(self.scan_batch_size)
+            .map(|batch| batch.to_vec())
+            .collect();
+        scan.unsent = scan
+            .batches
+            .iter()
+            .map(|batch| self.prefetch_paths(batch))
+            .enumerate()
+            .collect();
+        scan.arrived.clear();
+        scan.next = 0;
+        scan.report.collections += level.len();
+        scan.report.entries += entries.len();
+        scan.report.levels += 1;
+        debug!(
+            "scan level {} : {} entries of {} collections",
+            scan.report.levels,
+            entries.len(),
+            level.len()
+        );
+        if scan.bulk_loaded {
+            // entries added since the bulk fetch are fetched when loaded
+            scan.unsent.clear();
+            scan.arrived = (0..scan.batches.len())
+                .map(|idx| (idx, Ok(Prefetch::default())))
+                .collect();
+            return Ok(());
+        }
+        scan.report.batches += scan.batches.len();
+        // at most `scan_jobs` fetched batches wait for loading
+        for _ in 0..self.scan_jobs.max(1) {
+            self.submit_scan_batch(scan);
+        }
+        Ok(())
+    }
+
+    /// submits the fetch of the next batch of the level as a background job
+    fn submit_scan_batch(&self, scan: &mut ScanState) {
+        let Some((idx, (reads, stats))) = scan.unsent.pop_front() else {
+            return;
+        };
+        let (sender, runner) = (scan.sender.clone(), self.session.runner());
+        self.jobs.submit(JobKind::Scan, move |context| {
+            let fetched = Prefetch::fetch(&runner, &reads, &stats);
+            if let Ok(prefetch) = &fetched {
+                context.charge(prefetch.content_bytes());
+            }
+            // the scan may have been restarted
+            let _ = sender.send((idx, fetched));
+        });
+    }
+
+    /// loads the next batch of the level into nodes, with what was fetched for it
+    fn load_scan_batch(
+        &mut self,
+        scan: &mut ScanState,
+        fetched: Result<Prefetch, RemarkableError>,
+    ) {
+        let next = scan.next;
+        match fetched {
+            Ok(prefetch) => self.prefetched.borrow_mut().merge(prefetch),
+            Err(e) => warn!("scan batch {next} not prefetched, loading it entry by entry : {e}"),
+        }
+        for &(ino, idx, _) in &scan.batches[next] {
+            for child in self.load_listing_entry(ino, idx) {
+                if child.2 == fuser::FileType::Directory
+                    && !self.is_document(child.ino())
+                    && !self.virtual_dirs.contains_key(&child.ino())
+                {
+                    scan.found.push(child.ino());
+                }
+            }
+        }
+        debug!("scan batch {next} loaded");
+        scan.next += 1;
+        scan.loaded += scan.batches[next].len();
+        if let Some(percent) = scan.percent().filter(|p| *p >= scan.logged + 10) {
+            info!(
+                "library scan {percent}% done, {} entries loaded",
+                scan.loaded
+            );
+            scan.logged = percent - percent % 10;
+        }
+    }
+
+    /// Prefetches the listing `entries` of collection `ino` not prefetched yet,
+    /// in batches fetched on `listing_channels` channels at once, before they
+    /// are loaded one by one
+    pub(crate) fn prefetch_entries(&mut self, ino: usize, entries: Range<usize>) {
+        if self.listing_channels == 0 {
+            return;
+        }
+        let Some(listing) = self.listings.get(&ino) else {
+            return;
+        };
+        let prefetched = self.prefetched.borrow();
+        let entries = listing
+            .entries
+            .iter()
+            .enumerate()
+            .skip(entries.start)
+            .take(entries.len())
+            .filter(|(_, (_, file))| !prefetched.holds_stat(Path::new(file)))
+            .map(|(idx, (_, file))| (ino, idx, file.clone()))
+            .collect::<Vec<ScanEntry>>();
+        drop(prefetched);
+        if entries.is_empty() {
+            return;
+        }
+        let started = Instant::now();
+        let batches = entries
+            .chunks(self.scan_batch_size)
+            .map(|batch| self.prefetch_paths(batch))
+            .collect::<Vec<_>>();
+        let fetched = self
+            .session
+            .prefetch_batches(batches, self.listing_channels);
+        self.prefetched.borrow_mut().merge(fetched);
+        debug!(
+            "{} entries of collection {ino} prefetched in {}ms",
+            entries.len(),
+            started.elapsed().as_millis()
+        );
+    }
+
+    /// files read and stat'ed to load `batch` : metadata and content files, pdf
+    /// and epub payloads
+    fn prefetch_paths(&self, batch: &[ScanEntry]) -> PrefetchPaths {
+        let (mut reads, mut stats) = (vec![], vec![]);
+        for (_, _, file) in batch {
+            let uid = Path::new(file)
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or_default();
+            let content = self.layout.content_path(&self.document_root, uid);
+            reads.push(file.clone());
+            reads.push(names::remote_str(&content).into_owned());
+            stats.push(file.clone());
+            for extension in PAYLOAD_EXTENSIONS {
+                let payload = self
+                    .layout
+                    .payload_path(&self.document_root, uid, extension);
+                stats.push(names::remote_str(&payload).into_owned());
+            }
+        }
+        (reads, stats)
+    }
+
+    /// content of the remote file `path`, prefetched or read now
+    pub(crate) fn read_remote(&self, path: &Path) -> Result<String, RemarkableError> {
+        let data = match self.prefetched.borrow_mut().take_contents(path) {
+            // prefetched as text, an encrypted file is read again as it is
+            Some(content)
+                if !content.contains(char::REPLACEMENT_CHARACTER)
+                    && Protection::of(content.as_bytes()) == Protection::Plain =>
+            {
+                return Ok(content)
+            }
+            _ => self.session.read_all(path)?,
+        };
+        self.decode_remote(path, data)
+    }
+
+    /// stat of the remote file `path`, prefetched or made now
+    pub(crate) fn stat_remote(&self, path: &str) -> Result<SshFileStat, RemarkableError> {
+        match self.prefetched.borrow_mut().take_stat(&PathBuf::from(path)) {
+            Some(Some(fstat)) => Ok(fstat),
+            Some(None) => Err(RemarkableError::from(std::io::Error::from(
+                std::io::ErrorKind::NotFound,
+            ))
+            .context(format!("stat {path}"))),
+            None => self.session.stat(path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_progress() {
+        let mut scan = ScanState::new(Some(400));
+        scan.report.levels = 2;
+        scan.loaded = 100;
+        assert_eq!(scan.percent(), Some(25));
+        assert_eq!(
+            scan.progress(),
+            "scan: level 2, 100 entries of 400 loaded (25%)\n"
+        );
+        // documents added during the scan
+        scan.loaded = 500;
+        assert_eq!(scan.percent(), Some(100));
+        scan.total = None;
+        assert_eq!(scan.progress(), "scan: level 2, 500 entries loaded\n");
+    }
+}
@@ -0,0 +1,144 @@
+use super::RemarkableFs;
+use crate::nodes::{CONTENT_FIELDS, METADATA_FIELDS};
+use log::{debug, warn};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fmt::Write;
+
+/// Json file of an item, checked against the fields the mount parses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum SchemaFile {
+    Metadata,
+    Content,
+}
+
+impl SchemaFile {
+    fn known_fields(self) -> &'static [&'static str] {
+        match self {
+            SchemaFile::Metadata => &METADATA_FIELDS,
+            SchemaFile::Content => &CONTENT_FIELDS,
+        }
+    }
+}
+
+impl fmt::Display for SchemaFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SchemaFile::Metadata => "metadata",
+            SchemaFile::Content => "content",
+        })
+    }
+}
+
+/// Top level fields of the metadata and content files that the mount does not
+/// parse, with the items they were found in, so that the fields added by new
+/// firmwares show up instead of being ignored
+#[derive(Debug, Default)]
+pub(crate) struct SchemaReport {
+    /// uids of the items by (file, unknown field)
+    unknown: BTreeMap<(SchemaFile, String), BTreeSet<String>>,
+    checked: usize,
+}
+
+impl SchemaReport {
+    /// items listed for each field, the others being counted
+    const LISTED: usize = 10;
+
+    /// Records the unknown fields of `json`, the `file` of item `uid`. A field
+    /// is warned about the first time it is found, logged in debug after
+    fn check(&mut self, file: SchemaFile, uid: &str, json: &str) {
+        self.checked += 1;
+        // invalid json is reported by the parser
+        let Ok(serde_json::Value::Object(fields)) = serde_json::from_str(json) else {
+            return;
+        };
+        for field in fields.keys() {
+            if file.known_fields().contains(&field.as_str()) {
+                continue;
+            }
+            let uids = self.unknown.entry((file, field.clone())).or_default();
+            if uids.is_empty() {
+                warn!("unknown field {field:?} in the {file} of {uid}");
+            } else {
+                debug!("unknown field {field:?} in the {file} of {uid}");
+            }
+            uids.insert(uid.to_owned());
+        }
+    }
+
+    fn report(&self) -> String {
+        let mut report = format!(
+            "checked: {} files\nunknown fields: {}\n",
+            self.checked,
+            self.unknown.len()
+        );
+        for ((file, field), uids) in &self.unknown {
+            let _ = writeln!(report, "{file} {field}: {} items", uids.len());
+            for uid in uids.iter().take(Self::LISTED) {
+                let _ = writeln!(report, "  {uid}");
+            }
+            if uids.len() > Self::LISTED {
+                let _ = writeln!(report, "  and {} more", uids.len() - Self::LISTED);
+            }
+        }
+        report
+    }
+}
+
+impl RemarkableFs {
+    /// Checks the metadata and content files against the fields the mount
+    /// parses, reporting the others in `/.control/schema`. For developers
+    /// following the changes of the tablet firmware
+    pub fn set_strict_schema(&mut self, enabled: bool) {
+        self.schema.replace(enabled.then(SchemaReport::default));
+    }
+
+    pub(crate) fn strict_schema(&self) -> bool {
+        self.schema.borrow().is_some()
+    }
+
+    /// records the unknown fields of `json`, the `file` of item `uid`, when
+    /// checking the schema
+    pub(crate) fn check_schema(&self, file: SchemaFile, uid: &str, json: &str) {
+        if let Some(report) = self.schema.borrow_mut().as_mut() {
+            report.check(file, uid, json);
+        }
+    }
+
+    /// unknown fields found so far, for `/.control/schema`
+    pub(crate) fn schema_report(&self) -> String {
+        match &*self.schema.borrow() {
+            Some(report) => report.report(),
+            None => "schema not checked\n".to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_report() {
+        let mut report = SchemaReport::default();
+        report.check(
+            SchemaFile::Metadata,
+            "a",
+            r#"{"visibleName": "A", "parent": "", "lastOpened": "1"}"#,
+        );
+        report.check(
+            SchemaFile::Metadata,
+            "b",
+            r#"{"visibleName": "B", "lastOpened": "2", "new": 1}"#,
+        );
+        report.check(SchemaFile::Content, "a", r#"{"fileType": "pdf", "new": 1}"#);
+        report.check(SchemaFile::Content, "c", "not json");
+        assert_eq!(
+            report.report(),
+            "checked: 4 files\nunknown fields: 3\n\
+             metadata lastOpened: 2 items\n  a\n  b\n\
+             metadata new: 1 items\n  b\n\
+             content new: 1 items\n  a\n"
+        );
+    }
+}
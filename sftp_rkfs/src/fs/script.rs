@@ -0,0 +1,290 @@
+use super::views::date_from_ms;
+use super::RemarkableFs;
+use crate::nodes::FuserChild;
+use crate::{RemarkableError, SchemaError};
+use log::{debug, warn};
+use rhai::{Dynamic, Engine, FnPtr, Map, AST};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// A virtual directory defined in a views script : the documents for which
+/// `filter` returns true, presented under the names returned by `rename`
+#[derive(Debug, Clone)]
+pub(crate) struct ScriptedView {
+    pub name: String,
+    filter: Option<FnPtr>,
+    rename: Option<FnPtr>,
+    script: Arc<Script>,
+}
+
+/// The compiled views script, evaluating the functions of its views
+struct Script {
+    engine: Engine,
+    ast: AST,
+    /// does the script read document tags, which are only in content files ?
+    uses_tags: bool,
+}
+
+impl std::fmt::Debug for Script {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Script")
+            .field("uses_tags", &self.uses_tags)
+            .finish_non_exhaustive()
+    }
+}
+
+/// What a views script can test and show about a document
+#[derive(Debug, Default)]
+pub(crate) struct DocFacts {
+    /// visible name, with extension
+    pub name: String,
+    pub extension: String,
+    pub tags: Vec<String>,
+    pub modified_ms: Option<u64>,
+    pub created_ms: Option<u64>,
+}
+
+/// views declared while the script runs
+type Declared = Arc<Mutex<Vec<(String, Option<FnPtr>, Option<FnPtr>)>>>;
+
+impl ScriptedView {
+    /// bounds the work of a single script call, so that a looping script
+    /// cannot hang the mount
+    const MAX_OPERATIONS: u64 = 100_000;
+
+    /// Runs a views script, written in Rhai, each call to `view` defining a
+    /// folder of `/.views` :
+    ///
+    /// `view("Work", |doc| "work" in doc.tags && !doc.name.contains("draft"),
+    ///       |doc| `${doc.modified} ${doc.stem}.${doc.ext}`);`
+    ///
+    /// The optional filter and rename functions get the document as a map of
+    /// `name`, `stem`, `ext`, `tags` (array) and `modified` and `created`
+    /// (YYYY-MM-DD, comparable as strings). Views without filter show every
+    /// document, views without rename keep document names.
+    pub(crate) fn parse_script(script: &str) -> Result<Vec<Self>, RemarkableError> {
+        let invalid = |e: String| SchemaError::Invalid(format!("views script: {e}"));
+        let declared = Declared::default();
+        let mut engine = Engine::new();
+        engine.set_max_operations(Self::MAX_OPERATIONS);
+        engine.on_print(|text| debug!("views script: {text}"));
+        let views = declared.clone();
+        engine.register_fn("view", move |name: &str| {
+            views.lock().unwrap().push((name.to_owned(), None, None));
+        });
+        let views = declared.clone();
+        engine.register_fn("view", move |name: &str, filter: FnPtr| {
+            views
+                .lock()
+                .unwrap()
+                .push((name.to_owned(), Some(filter), None));
+        });
+        let views = declared.clone();
+        engine.register_fn("view", move |name: &str, filter: FnPtr, rename: FnPtr| {
+            views
+                .lock()
+                .unwrap()
+                .push((name.to_owned(), Some(filter), Some(rename)));
+        });
+        let ast = engine.compile(script).map_err(|e| invalid(e.to_string()))?;
+        engine.run_ast(&ast).map_err(|e| invalid(e.to_string()))?;
+        let declared = std::mem::take(&mut *declared.lock().unwrap());
+        let script = Arc::new(Script {
+            engine,
+            ast,
+            uses_tags: script.contains("tags"),
+        });
+        declared
+            .into_iter()
+            .map(|(name, filter, rename)| {
+                if name.is_empty() || name.contains('/') {
+                    return Err(invalid(format!("invalid view name {name:?}")).into());
+                }
+                Ok(Self {
+                    name,
+                    filter,
+                    rename,
+                    script: script.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// does `doc` belong to the view ? Script errors leave it out
+    pub(crate) fn matches(&self, doc: &DocFacts) -> bool {
+        let Some(filter) = &self.filter else {
+            return true;
+        };
+        filter
+            .call::<bool>(&self.script.engine, &self.script.ast, (doc.to_map(),))
+            .inspect_err(|e| warn!("view {} filter failed on {} : {e}", self.name, doc.name))
+            .unwrap_or(false)
+    }
+
+    /// name of `doc` in the view, its own name when the rename fails
+    pub(crate) fn display_name(&self, doc: &DocFacts) -> String {
+        let Some(rename) = &self.rename else {
+            return doc.name.clone();
+        };
+        rename
+            .call::<String>(&self.script.engine, &self.script.ast, (doc.to_map(),))
+            .map(|name| name.replace('/', "_"))
+            .ok()
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| {
+                warn!("view {} rename failed on {}", self.name, doc.name);
+                doc.name.clone()
+            })
+    }
+
+    /// do the view functions need the document tags ?
+    pub(crate) fn uses_tags(&self) -> bool {
+        self.script.uses_tags
+    }
+}
+
+impl RemarkableFs {
+    /// children of the scripted view `index` : loaded documents outside the trash
+    /// matching its filter, under their renamed names
+    pub(crate) fn scripted_children(&mut self, index: usize) -> Vec<FuserChild> {
+        let Some(view) = self.scripted_views.get(index).cloned() else {
+            return vec![];
+        };
+        let docs = self
+            .nodes
+            .iter()
+            .map(|n| n.borrow())
+            .filter(|n| n.has_content_file() && !n.is_trashed())
+            .map(|n| n.get_ino())
+            .filter(|ino| !self.virtual_files.contains_key(ino))
+            .collect::<Vec<_>>();
+        let mut children = vec![];
+        for ino in docs {
+            if view.uses_tags() {
+                if let Err(e) = self.ensure_details(ino) {
+                    warn!("tags of {ino} unavailable for view {} : {e}", view.name);
+                }
+            }
+            let (facts, kind) = {
+                let node = self.nodes[ino].borrow();
+                let facts = DocFacts {
+                    name: node.get_visible_name().to_string_lossy().into_owned(),
+                    extension: node.get_extension().unwrap_or_default().to_owned(),
+                    tags: node.get_tags(),
+                    modified_ms: node.get_last_modified_ms(),
+                    created_ms: node.get_created_ms(),
+                };
+                (facts, node.get_kind_for_fuser())
+            };
+            if !view.matches(&facts) {
+                continue;
+            }
+            children.push(FuserChild::new(
+                ino,
+                children.len(),
+                kind,
+                PathBuf::from(view.display_name(&facts)),
+            ));
+        }
+        // renames may collide
+        let children = self.resolve_collisions(children);
+        debug!("view {} shows {} documents", view.name, children.len());
+        children
+    }
+}
+
+impl DocFacts {
+    /// the document as seen by the script functions
+    fn to_map(&self) -> Map {
+        let stem = self
+            .name
+            .strip_suffix(&format!(".{}", self.extension))
+            .unwrap_or(&self.name);
+        let mut map = Map::new();
+        map.insert("name".into(), self.name.clone().into());
+        map.insert("stem".into(), stem.to_owned().into());
+        map.insert("ext".into(), self.extension.clone().into());
+        map.insert(
+            "tags".into(),
+            self.tags
+                .iter()
+                .cloned()
+                .map(Dynamic::from)
+                .collect::<rhai::Array>()
+                .into(),
+        );
+        map.insert("modified".into(), format_date(self.modified_ms).into());
+        map.insert("created".into(), format_date(self.created_ms).into());
+        map
+    }
+}
+
+fn format_date(ms: Option<u64>) -> String {
+    ms.map(date_from_ms)
+        .map(|(y, m, d)| format!("{y:04}-{m:02}-{d:02}"))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc(name: &str, tags: &[&str]) -> DocFacts {
+        DocFacts {
+            name: name.to_string(),
+            extension: name
+                .rsplit_once('.')
+                .map(|(_, e)| e)
+                .unwrap_or("")
+                .to_string(),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            // 2024-02-29T12:00:00Z
+            modified_ms: Some(1_709_208_000_000),
+            created_ms: None,
+        }
+    }
+
+    #[test]
+    fn test_views_script() {
+        let views = ScriptedView::parse_script(
+            r#"
+            // work documents
+            view("Work",
+                |doc| "work" in doc.tags && !(doc.name.to_lower().contains("draft") || doc.ext == "epub"),
+                |doc| `${doc.modified} ${doc.stem}.${doc.ext}`);
+            view("Recent", |doc| doc.modified >= "2024-03-01");
+            view("All");
+            "#,
+        )
+        .unwrap();
+        assert_eq!(views.len(), 3);
+        let (work, recent, all) = (&views[0], &views[1], &views[2]);
+        assert!(work.uses_tags());
+        let spec = doc("Spec.pdf", &["work"]);
+        assert!(work.matches(&spec));
+        assert!(!work.matches(&doc("Spec DRAFT.pdf", &["work"])));
+        assert!(!work.matches(&doc("Book.epub", &["work"])));
+        assert!(!work.matches(&doc("Spec.pdf", &[])));
+        assert!(!recent.matches(&spec));
+        assert!(all.matches(&spec));
+        assert_eq!(work.display_name(&spec), "2024-02-29 Spec.pdf");
+        assert_eq!(recent.display_name(&spec), "Spec.pdf");
+    }
+
+    #[test]
+    fn test_script_errors() {
+        let error = |script: &str| ScriptedView::parse_script(script).unwrap_err().to_string();
+        assert!(error("view(\"A\", |doc| doc.name ==").contains("views script"));
+        assert!(error("view(\"A/B\")").contains("invalid view name"));
+        assert!(error("colour(\"red\")").contains("colour"));
+        // runaway or failing functions leave documents out
+        let views = ScriptedView::parse_script(
+            "view(\"Loop\", |doc| { loop {} }); view(\"Bad\", |doc| doc.size > 3, |doc| 42);",
+        )
+        .unwrap();
+        let spec = doc("Spec.pdf", &[]);
+        assert!(!views[0].matches(&spec) && !views[1].matches(&spec));
+        assert_eq!(views[1].display_name(&spec), "Spec.pdf");
+        assert!(!views[0].uses_tags());
+    }
+}
@@ -0,0 +1,147 @@
+use super::{DirListing, RemarkableFs};
+use crate::nodes::FuserChild;
+use crate::RemarkableError;
+use std::cmp::{Ordering, Reverse};
+use std::time::SystemTime;
+
+/// Order of the entries of collection listings
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SortPolicy {
+    /// order of the tablet listing, entries being loaded as they are handed out
+    #[default]
+    Tablet,
+    /// by name, ignoring case
+    Name,
+    /// most recently modified first
+    Modified,
+    /// pinned (favorite) entries first, then by name
+    PinnedFirst,
+}
+
+impl std::str::FromStr for SortPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "tablet" => Ok(Self::Tablet),
+            "name" => Ok(Self::Name),
+            "modified" => Ok(Self::Modified),
+            "pinned" => Ok(Self::PinnedFirst),
+            _ => Err(format!(
+                "unknown sort policy {policy}, expected tablet, name, modified or pinned"
+            )),
+        }
+    }
+}
+
+/// what a listing entry is sorted on
+struct SortKey {
+    name: String,
+    modified: SystemTime,
+    pinned: bool,
+}
+
+impl SortPolicy {
+    fn compare(self, a: &SortKey, b: &SortKey) -> Ordering {
+        let by_name = || a.name.cmp(&b.name);
+        match self {
+            Self::Tablet => Ordering::Equal,
+            Self::Name => by_name(),
+            Self::Modified => Reverse(a.modified)
+                .cmp(&Reverse(b.modified))
+                .then_with(by_name),
+            Self::PinnedFirst => Reverse(a.pinned).cmp(&Reverse(b.pinned)).then_with(by_name),
+        }
+    }
+}
+
+impl RemarkableFs {
+    /// Sets the order of collection listings. Sorting loads every entry of a
+    /// collection before the first is handed out
+    pub fn set_sort_policy(&mut self, policy: SortPolicy) {
+        self.sort_policy = policy;
+    }
+
+    /// Hands the children of collection `ino` from readdir position `offset` on
+    /// to `add` in the order of the sort policy, until it returns true. The order
+    /// is kept until the collection is listed again
+    pub(crate) fn readdir_sorted(
+        &mut self,
+        ino: usize,
+        offset: usize,
+        add: &mut dyn FnMut(&FuserChild) -> bool,
+    ) -> Result<(), RemarkableError> {
+        let sorted = match self.listings.get(&ino).and_then(|l| l.sorted.clone()) {
+            Some(sorted) => sorted,
+            None => {
+                let sorted = self.sorted_children(ino)?;
+                if let Some(listing) = self.listings.get_mut(&ino) {
+                    listing.sorted = Some(sorted.clone());
+                }
+                sorted
+            }
+        };
+        for child in sorted.iter().filter(|c| c.1 >= offset) {
+            if add(child) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// children of collection `ino` sorted, their positions renumbered in order
+    fn sorted_children(&mut self, ino: usize) -> Result<Vec<FuserChild>, RemarkableError> {
+        self.load_listing(ino)?;
+        let Some(dir) = self.get_node(ino) else {
+            return Ok(vec![]);
+        };
+        let children = dir.borrow().get_children(DirListing::FIRST_POSITION);
+        let mut keyed = children
+            .into_iter()
+            .map(|child| {
+                let node = self.get_node(child.ino()).map(|n| n.borrow());
+                let key = SortKey {
+                    name: child.3.to_string_lossy().to_lowercase(),
+                    modified: node
+                        .as_ref()
+                        .map_or(SystemTime::UNIX_EPOCH, |n| n.get_mtime()),
+                    pinned: node.as_ref().is_some_and(|n| n.is_pinned()),
+                };
+                (key, child)
+            })
+            .collect::<Vec<_>>();
+        keyed.sort_by(|(a, _), (b, _)| self.sort_policy.compare(a, b));
+        Ok(keyed
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (_, child))| {
+                FuserChild(child.0, DirListing::FIRST_POSITION + idx, child.2, child.3)
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_sort_policy() {
+        let key = |name: &str, secs, pinned| SortKey {
+            name: name.to_owned(),
+            modified: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            pinned,
+        };
+        let mut keys = [key("b", 30, false), key("c", 10, true), key("a", 20, false)];
+        let names = |keys: &[SortKey]| keys.iter().map(|k| k.name.as_str()).collect::<String>();
+        keys.sort_by(|a, b| SortPolicy::Name.compare(a, b));
+        assert_eq!(names(&keys), "abc");
+        keys.sort_by(|a, b| SortPolicy::Modified.compare(a, b));
+        assert_eq!(names(&keys), "bac");
+        keys.sort_by(|a, b| SortPolicy::PinnedFirst.compare(a, b));
+        assert_eq!(names(&keys), "cab");
+        assert_eq!("pinned".parse(), Ok(SortPolicy::PinnedFirst));
+        assert!("size".parse::<SortPolicy>().is_err());
+    }
+}
@@ -0,0 +1,95 @@
+use super::views::date_from_ms;
+use super::RemarkableFs;
+use crate::nodes::Node;
+use crate::RemarkableError;
+use std::time::{Duration, SystemTime};
+
+/// A document or collection moved to the trash
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrashedItem {
+    pub ino: usize,
+    pub uid: String,
+    pub name: String,
+    pub is_document: bool,
+    /// when it was trashed, the tablet stamps `lastModified` when moving an item
+    /// to the trash
+    pub deleted: SystemTime,
+}
+
+impl TrashedItem {
+    /// time spent in the trash as of `now`
+    pub fn age(&self, now: SystemTime) -> Duration {
+        now.duration_since(self.deleted).unwrap_or_default()
+    }
+}
+
+impl RemarkableFs {
+    const XATTR_DELETED: &'static str = "user.remarkable.deleted";
+
+    /// Items at the top of the trash, oldest deletion first
+    pub fn trashed_items(&mut self) -> Result<Vec<TrashedItem>, RemarkableError> {
+        let mut children = vec![];
+        self.node_readdir(Node::TRASH_NODE_INO, 0, &mut |child| {
+            children.push(child.ino());
+            false
+        })?;
+        let mut items = children
+            .into_iter()
+            .filter_map(|ino| {
+                let node = self.get_node(ino)?.borrow();
+                if !node.is_trashed() {
+                    return None;
+                }
+                Some(TrashedItem {
+                    ino: node.get_ino(),
+                    uid: node.get_unique().to_owned(),
+                    name: node.get_visible_name().to_string_lossy().into_owned(),
+                    is_document: node.is_document(),
+                    deleted: self.deletion_time(node.get_ino())?,
+                })
+            })
+            .collect::<Vec<_>>();
+        items.sort_by_key(|item| item.deleted);
+        Ok(items)
+    }
+
+    /// when the trashed node `ino` was deleted, None when it is not in the trash
+    pub fn deletion_time(&self, ino: usize) -> Option<SystemTime> {
+        let node = self.get_node(ino)?.borrow();
+        let ms = node.get_last_modified_ms().filter(|_| node.is_trashed())?;
+        SystemTime::UNIX_EPOCH.checked_add(Duration::from_millis(ms))
+    }
+
+    /// `user.remarkable.deleted` attribute of trashed nodes
+    pub(crate) fn trash_xattrs(&self, ino: usize) -> Option<(String, Vec<u8>)> {
+        let node = self.get_node(ino)?.borrow();
+        let ms = node.get_last_modified_ms().filter(|_| node.is_trashed())?;
+        Some((
+            Self::XATTR_DELETED.to_string(),
+            iso_timestamp(ms).into_bytes(),
+        ))
+    }
+}
+
+/// UTC ISO 8601 timestamp (2024-02-29T12:00:00Z) of `ms` since epoch
+fn iso_timestamp(ms: u64) -> String {
+    let (year, month, day) = date_from_ms(ms);
+    let seconds = ms / 1000 % 86_400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_iso_timestamp() {
+        assert_eq!(iso_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(iso_timestamp(1_709_208_123_456), "2024-02-29T12:02:03Z");
+    }
+}
@@ -0,0 +1,108 @@
+use super::RemarkableFs;
+use crate::{ErrorContext, FsError, RemarkableError};
+use log::debug;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Metadata files of the children of every collection, from a single remote
+/// command printing the parent of each item. Collections are listed from it
+/// until it is older than the refresh interval, rather than with a grep of the
+/// whole library for each of them.
+#[derive(Debug)]
+pub(crate) struct TreeIndex {
+    /// metadata files by parent uid, in listing order
+    children: HashMap<String, Vec<String>>,
+    built: Instant,
+}
+
+impl TreeIndex {
+    /// parses `grep -o -H` lines : `<metadata file>:"parent": "<uid>"`
+    fn parse(out: &str) -> Self {
+        let mut children = HashMap::<String, Vec<String>>::new();
+        for line in out.lines() {
+            let Some((file, matched)) = line.rsplit_once(":\"parent\"") else {
+                continue;
+            };
+            let Some(parent) = matched.split('"').nth(1) else {
+                continue;
+            };
+            children
+                .entry(parent.to_owned())
+                .or_default()
+                .push(file.to_owned());
+        }
+        Self {
+            children,
+            built: Instant::now(),
+        }
+    }
+
+    fn children(&self, parent: &str) -> Vec<String> {
+        self.children.get(parent).cloned().unwrap_or_default()
+    }
+}
+
+impl RemarkableFs {
+    pub const DEFAULT_TREE_REFRESH: Duration = Duration::from_secs(10);
+
+    /// Sets how long collections are listed from the tree index before it is
+    /// fetched again, zero fetching it for every listing
+    pub fn set_tree_refresh(&mut self, interval: Duration) {
+        self.tree_refresh = interval;
+    }
+
+    /// drops the tree index, for a listing that must show the changes the
+    /// mount just made
+    pub(crate) fn forget_tree_index(&self) {
+        self.tree_index.replace(None);
+    }
+
+    /// Metadata files of the children of each of `parents` (uids), from the
+    /// tree index, fetched again when older than the refresh interval
+    pub(crate) fn indexed_children(
+        &self,
+        parents: &[String],
+    ) -> Result<Vec<Vec<String>>, RemarkableError> {
+        let mut index = self.tree_index.borrow_mut();
+        let index = match index.take() {
+            Some(fresh) if fresh.built.elapsed() < self.tree_refresh => index.insert(fresh),
+            _ => index.insert(self.fetch_tree_index()?),
+        };
+        Ok(parents.iter().map(|p| index.children(p)).collect())
+    }
+
+    fn fetch_tree_index(&self) -> Result<TreeIndex, RemarkableError> {
+        let Some(root) = self.document_root.to_str() else {
+            return Err(
+                FsError::InvalidPath(format!("document root {:?}", self.document_root)).into(),
+            );
+        };
+        let glob = self.layout.metadata_glob(std::path::Path::new(root));
+        let out = self
+            .session
+            .execute_cmd(&format!(r#"grep -o -H '"parent": *"[^"]*"' {glob}"#))
+            .context("listing the parent of every item")?;
+        let index = TreeIndex::parse(&out);
+        debug!("tree index of {} collections fetched", index.children.len());
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tree_index() {
+        let out = "/x/a.metadata:\"parent\": \"\"\n\
+                   /x/b.metadata:\"parent\": \"a\"\n\
+                   /x/c.metadata:\"parent\":\"\"\n\
+                   /x/d.metadata:\"parent\": \"trash\"\n\
+                   garbage\n";
+        let index = TreeIndex::parse(out);
+        assert_eq!(index.children(""), vec!["/x/a.metadata", "/x/c.metadata"]);
+        assert_eq!(index.children("a"), vec!["/x/b.metadata"]);
+        assert_eq!(index.children("trash"), vec!["/x/d.metadata"]);
+        assert!(index.children("b").is_empty());
+    }
+}
@@ -0,0 +1,190 @@
+use super::changes::TreeSnapshot;
+use super::RemarkableFs;
+use crate::nodes::Node;
+use crate::{ErrorContext, RemarkableError};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// An item of a collection, as listed by `rmkmount ls`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListedItem {
+    pub uid: String,
+    pub name: String,
+    pub is_document: bool,
+    /// documents in the item and its sub collections, 1 for a document
+    pub documents: usize,
+    /// storage used by the item and everything below it, when sizes were given
+    pub bytes: Option<u64>,
+}
+
+impl RemarkableFs {
+    const XATTR_CHILD_COUNT: &'static str = "user.remarkable.child_count";
+    const XATTR_DEPTH: &'static str = "user.remarkable.depth";
+
+    /// Storage used by the files of each item (metadata, payload, pages...) by
+    /// uid, measured by a single `du` on the tablet. Assumes the flat xochitl
+    /// layout.
+    pub fn disk_usage(&self) -> Result<HashMap<String, u64>, RemarkableError> {
+        let root = self.document_root.to_string_lossy();
+        let out = self
+            .session
+            .execute_cmd(&format!(
+                "du -sk {}/* 2> /dev/null",
+                root.trim_end_matches('/')
+            ))
+            .with_context(|| format!("measuring the storage used in {root}"))?;
+        Ok(parse_du(&out))
+    }
+
+    /// `user.remarkable.depth` (0 for the root) and `user.remarkable.child_count`
+    /// attributes of collections. The count is only given once the collection was
+    /// listed, it is never worth a round trip.
+    pub(crate) fn collection_xattrs(&self, ino: usize) -> Vec<(String, Vec<u8>)> {
+        let mut xattrs = vec![];
+        if self.is_document(ino)
+            || self.virtual_dirs.contains_key(&ino)
+            || self.virtual_files.contains_key(&ino)
+        {
+            return xattrs;
+        }
+        if let Some(depth) = self.depth(ino) {
+            xattrs.push((
+                Self::XATTR_DEPTH.to_string(),
+                depth.to_string().into_bytes(),
+            ));
+        }
+        if let Some(listing) = self.listings.get(&ino) {
+            xattrs.push((
+                Self::XATTR_CHILD_COUNT.to_string(),
+                listing.entries.len().to_string().into_bytes(),
+            ));
+        }
+        xattrs
+    }
+
+    /// collections between node `ino` and the root, None when not attached to it
+    fn depth(&self, ino: usize) -> Option<usize> {
+        let mut current = ino;
+        // parents chain is bounded by the node count, in case of a cycle
+        for depth in 0..self.nodes.len() {
+            if current == Node::ROOT_NODE_INO {
+                return Some(depth);
+            }
+            current = self.get_node(current)?.borrow().get_parent();
+        }
+        None
+    }
+}
+
+impl TreeSnapshot {
+    /// uid of the item at visible `path`, the root for an empty path
+    pub fn uid_at(&self, path: &str) -> Option<String> {
+        self.paths().uid_at(path)
+    }
+
+    pub fn is_document(&self, uid: &str) -> bool {
+        self.items.get(uid).is_some_and(|item| item.is_document())
+    }
+
+    /// Items of collection `uid`, collections first. With the `sizes` of
+    /// `disk_usage`, each item reports the storage used by everything below it.
+    pub fn list(&self, uid: &str, sizes: Option<&HashMap<String, u64>>) -> Vec<ListedItem> {
+        let totals = self.totals(sizes.unwrap_or(&HashMap::new()));
+        let mut items = self
+            .items
+            .iter()
+            .filter(|(_, item)| item.parent == uid)
+            .map(|(child, item)| {
+                let (documents, bytes) = totals.get(child.as_str()).copied().unwrap_or_default();
+                ListedItem {
+                    uid: child.clone(),
+                    name: item.visible_name.clone(),
+                    is_document: item.is_document(),
+                    documents,
+                    bytes: sizes.map(|_| bytes),
+                }
+            })
+            .collect::<Vec<_>>();
+        items.sort_by(|a, b| (a.is_document, &a.name).cmp(&(b.is_document, &b.name)));
+        items
+    }
+
+    /// (documents, bytes) of every item including everything below it, the root
+    /// and the trash included
+    fn totals(&self, sizes: &HashMap<String, u64>) -> HashMap<&str, (usize, u64)> {
+        let mut totals = HashMap::<&str, (usize, u64)>::new();
+        for (uid, item) in &self.items {
+            let documents = usize::from(item.is_document());
+            let bytes = sizes.get(uid).copied().unwrap_or(0);
+            let mut current = uid.as_str();
+            // parents chain is bounded by the item count, in case of a cycle
+            for _ in 0..=self.items.len() {
+                let total = totals.entry(current).or_default();
+                total.0 += documents;
+                total.1 += bytes;
+                match self.items.get(current) {
+                    Some(item) => current = &item.parent,
+                    None => break,
+                }
+            }
+        }
+        totals
+    }
+}
+
+/// bytes per uid from `du -sk` lines, the files and folders of a uid summed
+fn parse_du(out: &str) -> HashMap<String, u64> {
+    let mut sizes = HashMap::new();
+    for line in out.lines() {
+        let Some((kib, path)) = line.split_once('\t') else {
+            continue;
+        };
+        let (Ok(kib), Some(name)) = (kib.parse::<u64>(), Path::new(path).file_name()) else {
+            continue;
+        };
+        let name = name.to_string_lossy();
+        let uid = name.split('.').next().unwrap_or_default();
+        *sizes.entry(uid.to_owned()).or_default() += kib * 1024;
+    }
+    sizes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn item(uid: &str, name: &str, parent: &str, kind: &str) -> (PathBuf, String) {
+        (
+            PathBuf::from(format!("/r/{uid}.metadata")),
+            format!(
+                r#"{{"lastModified": "1", "parent": "{parent}", "type": "{kind}", "visibleName": "{name}"}}"#
+            ),
+        )
+    }
+
+    #[test]
+    fn test_list_with_sizes() {
+        let snapshot = TreeSnapshot::parse(&[
+            item("w", "Work", "", "CollectionType"),
+            item("p", "Papers", "w", "CollectionType"),
+            item("a", "Spec", "p", "DocumentType"),
+            item("b", "Notes", "w", "DocumentType"),
+            item("c", "Draft", "", "DocumentType"),
+        ]);
+        let sizes = parse_du("8\t/r/a.pdf\n4\t/r/a\n1\t/r/b.metadata\n2\t/r/c.epub\njunk\n");
+        assert_eq!(sizes["a"], 12 * 1024);
+        let root = snapshot.list("", Some(&sizes));
+        let summary = root
+            .iter()
+            .map(|i| (i.name.as_str(), i.documents, i.bytes))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![("Work", 2, Some(13 * 1024)), ("Draft", 1, Some(2 * 1024))]
+        );
+        assert_eq!(snapshot.uid_at("/Work/Papers").as_deref(), Some("p"));
+        assert_eq!(snapshot.list("p", None)[0].bytes, None);
+        assert!(snapshot.is_document("a"));
+    }
+}
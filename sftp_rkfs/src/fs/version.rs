@@ -0,0 +1,120 @@
+use super::RemarkableFs;
+use log::warn;
+
+/// Model and firmware of the tablet, looked up once for `/.version`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DeviceVersions {
+    model: String,
+    firmware: String,
+}
+
+impl RemarkableFs {
+    /// transport used to reach the tablet
+    const TRANSPORT: &'static str = "sftp (ssh2 0.9, libssh2)";
+
+    /// Sets the program using the library, reported by `/.version`
+    /// (e.g. "rmkmount 0.1.0", default: none)
+    pub fn set_client_version(&mut self, version: &str) {
+        self.client_version = Some(version.to_owned());
+    }
+
+    /// model and firmware of the tablet, asked over ssh until both are known
+    fn device_versions(&self) -> DeviceVersions {
+        if let Some(versions) = self.device_versions.borrow().as_ref() {
+            return versions.clone();
+        }
+        let model = self.device_info().map(|info| info.model);
+        let firmware = self.firmware_version();
+        match (model, firmware) {
+            (Ok(model), Ok(firmware)) => {
+                let versions = DeviceVersions { model, firmware };
+                *self.device_versions.borrow_mut() = Some(versions.clone());
+                versions
+            }
+            (model, firmware) => {
+                if let Some(e) = model.as_ref().err().or(firmware.as_ref().err()) {
+                    warn!("unable to identify the tablet for /.version : {e}");
+                }
+                DeviceVersions {
+                    model: model.unwrap_or_else(|_| "unknown".to_owned()),
+                    firmware: firmware.unwrap_or_else(|_| "unknown".to_owned()),
+                }
+            }
+        }
+    }
+
+    /// `/.version` content, one `key: value` line each so that bug reports
+    /// describe the setup and scripts can check minimum versions
+    pub(crate) fn version_report(&self) -> String {
+        let server = self.session.server_banner();
+        format_version(
+            self.client_version.as_deref(),
+            server.as_deref(),
+            &self.device_versions(),
+            &enabled_features(),
+        )
+    }
+}
+
+/// cargo features sftp_rkfs was built with
+fn enabled_features() -> Vec<&'static str> {
+    let mut features = vec![];
+    if cfg!(feature = "scripting") {
+        features.push("scripting");
+    }
+    features
+}
+
+fn format_version(
+    client: Option<&str>,
+    server: Option<&str>,
+    device: &DeviceVersions,
+    features: &[&str],
+) -> String {
+    let mut report = String::new();
+    if let Some(client) = client {
+        report.push_str(&format!("client: {client}\n"));
+    }
+    report.push_str(&format!(
+        "sftp_rkfs: {}\ntransport: {}\nserver: {}\nmodel: {}\nfirmware: {}\nfeatures: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        RemarkableFs::TRANSPORT,
+        server.unwrap_or("unknown"),
+        device.model,
+        device.firmware,
+        if features.is_empty() {
+            "none".to_owned()
+        } else {
+            features.join(",")
+        }
+    ));
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_version() {
+        let device = DeviceVersions {
+            model: "reMarkable 2.0".to_owned(),
+            firmware: "3.11.2.5".to_owned(),
+        };
+        let report = format_version(
+            Some("rmkmount 0.1.0"),
+            Some("SSH-2.0-dropbear_2022.83"),
+            &device,
+            &["scripting"],
+        );
+        assert!(report.starts_with("client: rmkmount 0.1.0\nsftp_rkfs: "));
+        assert!(report.contains("\nserver: SSH-2.0-dropbear_2022.83\n"));
+        assert!(
+            report.ends_with("model: reMarkable 2.0\nfirmware: 3.11.2.5\nfeatures: scripting\n")
+        );
+        let report = format_version(None, None, &device, &[]);
+        assert!(report.starts_with("sftp_rkfs: "));
+        assert!(report.contains("\nserver: unknown\n"));
+        assert!(report.ends_with("features: none\n"));
+    }
+}
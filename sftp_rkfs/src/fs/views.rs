@@ -0,0 +1,401 @@
+use super::RemarkableFs;
+use crate::nodes::{FuserChild, Node};
+use log::debug;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+/// Virtual directories built from already-loaded metadata, they do not exist on the tablet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum VirtualDir {
+    /// `/by-date` : one folder per year
+    ByDate,
+    /// `/by-date/YYYY` : one folder per month
+    Year(i32),
+    /// `/by-date/YYYY/MM` : documents of that month
+    Month(i32, u32),
+    /// `/.control` : files triggering actions when written
+    Control,
+    /// `<name>.pages` : raw `.rm` page files of the document at the given inode
+    Pages(usize),
+    /// `/.views` : one folder per view of the views script
+    #[cfg(feature = "scripting")]
+    Scripts,
+    /// `/.views/<name>` : documents of the scripted view at the given index
+    #[cfg(feature = "scripting")]
+    Script(usize),
+}
+
+/// Virtual files whose content is generated on each read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum VirtualFile {
+    /// `/.health` : mount status for scripts and service managers
+    Health,
+    /// `/.control/refresh` : writing a visible path refreshes that subtree
+    Refresh,
+    /// `/.control/progress` : progress of every document being read
+    Transfers,
+    /// `<name>.progress` : progress of reading the document at the given inode
+    Progress(usize),
+    /// `/.xdg-volume-info` : label and icon of the mount for Gio
+    VolumeInfo,
+    /// `/.version` : versions of the library, transport and tablet
+    Version,
+    /// `/.control/jobs` : state of the background jobs, `pause` or `resume`
+    /// written to it
+    Jobs,
+    /// `/.control/schema` : unknown json fields found in metadata and content
+    /// files, listed when checking the schema
+    Schema,
+    /// `<uid>.corrupt` or `<uid>.encrypted` : why the item could not be loaded, by marker inode
+    Quarantine(usize),
+}
+
+impl VirtualFile {
+    /// can the file be written to ? Written data is handled by `control_write`
+    pub(crate) fn is_writable(self) -> bool {
+        matches!(self, VirtualFile::Refresh | VirtualFile::Jobs)
+    }
+}
+
+impl RemarkableFs {
+    /// adds the top level virtual view nodes, called once root and trash nodes exist
+    pub(crate) fn init_views(&mut self) {
+        self.nodes.push(RefCell::new(Node::new_virtual_dir(
+            Node::BY_DATE_NODE_INO,
+            Node::ROOT_NODE_INO,
+            Node::BY_DATE_NODE_PATH,
+        )));
+        self.virtual_dirs
+            .insert(Node::BY_DATE_NODE_INO, VirtualDir::ByDate);
+        self.nodes.push(RefCell::new(Node::new_virtual_file(
+            Node::HEALTH_NODE_INO,
+            Node::ROOT_NODE_INO,
+            Node::HEALTH_NODE_PATH,
+        )));
+        self.virtual_files
+            .insert(Node::HEALTH_NODE_INO, VirtualFile::Health);
+        self.nodes.push(RefCell::new(Node::new_virtual_dir(
+            Node::CONTROL_NODE_INO,
+            Node::ROOT_NODE_INO,
+            Node::CONTROL_NODE_PATH,
+        )));
+        self.virtual_dirs
+            .insert(Node::CONTROL_NODE_INO, VirtualDir::Control);
+        self.nodes.push(RefCell::new(Node::new_virtual_file(
+            Node::REFRESH_NODE_INO,
+            Node::CONTROL_NODE_INO,
+            Node::REFRESH_NODE_PATH,
+        )));
+        self.virtual_files
+            .insert(Node::REFRESH_NODE_INO, VirtualFile::Refresh);
+        self.nodes.push(RefCell::new(Node::new_virtual_file(
+            Node::PROGRESS_NODE_INO,
+            Node::CONTROL_NODE_INO,
+            Node::PROGRESS_NODE_PATH,
+        )));
+        self.virtual_files
+            .insert(Node::PROGRESS_NODE_INO, VirtualFile::Transfers);
+        self.nodes.push(RefCell::new(Node::new_virtual_file(
+            Node::VOLUME_INFO_NODE_INO,
+            Node::ROOT_NODE_INO,
+            Node::VOLUME_INFO_NODE_PATH,
+        )));
+        self.virtual_files
+            .insert(Node::VOLUME_INFO_NODE_INO, VirtualFile::VolumeInfo);
+        self.nodes.push(RefCell::new(Node::new_virtual_file(
+            Node::VERSION_NODE_INO,
+            Node::ROOT_NODE_INO,
+            Node::VERSION_NODE_PATH,
+        )));
+        self.virtual_files
+            .insert(Node::VERSION_NODE_INO, VirtualFile::Version);
+        self.nodes.push(RefCell::new(Node::new_virtual_file(
+            Node::JOBS_NODE_INO,
+            Node::CONTROL_NODE_INO,
+            Node::JOBS_NODE_PATH,
+        )));
+        self.virtual_files
+            .insert(Node::JOBS_NODE_INO, VirtualFile::Jobs);
+        self.nodes.push(RefCell::new(Node::new_virtual_file(
+            Node::SCHEMA_NODE_INO,
+            Node::CONTROL_NODE_INO,
+            Node::SCHEMA_NODE_PATH,
+        )));
+        self.virtual_files
+            .insert(Node::SCHEMA_NODE_INO, VirtualFile::Schema);
+        #[cfg(feature = "scripting")]
+        if !self.scripted_views.is_empty() {
+            self.virtual_dir_ino(
+                Node::ROOT_NODE_INO,
+                Node::VIEWS_NODE_PATH,
+                VirtualDir::Scripts,
+            );
+        }
+    }
+
+    /// top level virtual views, listed before the tablet collections of the root node
+    /// at readdir positions below `DirListing::FIRST_POSITION`
+    pub(crate) fn root_views(&self) -> Vec<FuserChild> {
+        #[allow(unused_mut)]
+        let mut views = vec![
+            FuserChild::new(
+                Node::BY_DATE_NODE_INO,
+                0,
+                fuser::FileType::Directory,
+                PathBuf::from(Node::BY_DATE_NODE_PATH),
+            ),
+            FuserChild::new(
+                Node::HEALTH_NODE_INO,
+                1,
+                fuser::FileType::RegularFile,
+                PathBuf::from(Node::HEALTH_NODE_PATH),
+            ),
+            FuserChild::new(
+                Node::CONTROL_NODE_INO,
+                2,
+                fuser::FileType::Directory,
+                PathBuf::from(Node::CONTROL_NODE_PATH),
+            ),
+            FuserChild::new(
+                Node::VOLUME_INFO_NODE_INO,
+                4,
+                fuser::FileType::RegularFile,
+                PathBuf::from(Node::VOLUME_INFO_NODE_PATH),
+            ),
+            FuserChild::new(
+                Node::VERSION_NODE_INO,
+                5,
+                fuser::FileType::RegularFile,
+                PathBuf::from(Node::VERSION_NODE_PATH),
+            ),
+        ];
+        #[cfg(feature = "scripting")]
+        if let Some((&ino, _)) = self
+            .virtual_dirs
+            .iter()
+            .find(|(_, v)| **v == VirtualDir::Scripts)
+        {
+            views.push(FuserChild::new(
+                ino,
+                3,
+                fuser::FileType::Directory,
+                PathBuf::from(Node::VIEWS_NODE_PATH),
+            ));
+        }
+        views
+    }
+
+    /// current content of a virtual file
+    pub(crate) fn virtual_file_content(&self, file: VirtualFile) -> Vec<u8> {
+        match file {
+            VirtualFile::Health => self.health_report().into_bytes(),
+            VirtualFile::Refresh => vec![],
+            VirtualFile::Transfers => self.transfers_report().into_bytes(),
+            VirtualFile::Progress(doc) => self.progress_report(doc).into_bytes(),
+            VirtualFile::VolumeInfo => self.volume_info().into_bytes(),
+            VirtualFile::Version => self.version_report().into_bytes(),
+            VirtualFile::Jobs => (self.jobs.report() + self.scan_progress().as_str()).into_bytes(),
+            VirtualFile::Schema => self.schema_report().into_bytes(),
+            VirtualFile::Quarantine(marker) => self.quarantine_report(marker).into_bytes(),
+        }
+    }
+
+    /// lists the children of a virtual directory
+    pub(crate) fn view_children(&mut self, ino: usize, view: VirtualDir) -> Vec<FuserChild> {
+        let dated = self
+            .nodes
+            .iter()
+            .filter_map(|n| {
+                let n = n.borrow();
+                if n.is_document() && !n.is_trashed() {
+                    node_date(&n).map(|d| (d, n.get_ino()))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        debug!("{} dated documents for view {view:?}", dated.len());
+        let dirs = match view {
+            VirtualDir::ByDate => dated
+                .iter()
+                .map(|((y, _), _)| *y)
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .map(|y| (format!("{y:04}"), VirtualDir::Year(y)))
+                .collect::<Vec<_>>(),
+            VirtualDir::Year(year) => dated
+                .iter()
+                .filter(|((y, _), _)| *y == year)
+                .map(|((_, m), _)| *m)
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .map(|m| (format!("{m:02}"), VirtualDir::Month(year, m)))
+                .collect::<Vec<_>>(),
+            VirtualDir::Pages(doc) => return self.raw_page_children(doc),
+            #[cfg(feature = "scripting")]
+            VirtualDir::Scripts => self
+                .scripted_views
+                .iter()
+                .enumerate()
+                .map(|(i, v)| (v.name.clone(), VirtualDir::Script(i)))
+                .collect::<Vec<_>>(),
+            #[cfg(feature = "scripting")]
+            VirtualDir::Script(index) => return self.scripted_children(index),
+            VirtualDir::Control => {
+                let mut files = vec![
+                    FuserChild::new(
+                        Node::REFRESH_NODE_INO,
+                        0,
+                        fuser::FileType::RegularFile,
+                        PathBuf::from(Node::REFRESH_NODE_PATH),
+                    ),
+                    FuserChild::new(
+                        Node::PROGRESS_NODE_INO,
+                        1,
+                        fuser::FileType::RegularFile,
+                        PathBuf::from(Node::PROGRESS_NODE_PATH),
+                    ),
+                    FuserChild::new(
+                        Node::JOBS_NODE_INO,
+                        2,
+                        fuser::FileType::RegularFile,
+                        PathBuf::from(Node::JOBS_NODE_PATH),
+                    ),
+                ];
+                if self.strict_schema() {
+                    files.push(FuserChild::new(
+                        Node::SCHEMA_NODE_INO,
+                        3,
+                        fuser::FileType::RegularFile,
+                        PathBuf::from(Node::SCHEMA_NODE_PATH),
+                    ));
+                }
+                return files;
+            }
+            VirtualDir::Month(year, month) => {
+                let docs = dated
+                    .iter()
+                    .filter(|(d, _)| *d == (year, month))
+                    .enumerate()
+                    .map(|(o, (_, doc))| {
+                        let doc = self.nodes[*doc].borrow();
+                        FuserChild::new(
+                            doc.get_ino(),
+                            o,
+                            doc.get_kind_for_fuser(),
+                            doc.get_visible_name(),
+                        )
+                    })
+                    .collect();
+                return self.resolve_collisions(docs);
+            }
+        };
+        dirs.into_iter()
+            .enumerate()
+            .map(|(o, (name, sub_view))| {
+                let sub_ino = self.virtual_dir_ino(ino, &name, sub_view);
+                FuserChild::new(sub_ino, o, fuser::FileType::Directory, PathBuf::from(name))
+            })
+            .collect()
+    }
+
+    /// gets (or allocates) the inode of a virtual sub directory
+    fn virtual_dir_ino(&mut self, parent: usize, name: &str, view: VirtualDir) -> usize {
+        if let Some((&ino, _)) = self.virtual_dirs.iter().find(|(_, v)| **v == view) {
+            ino
+        } else {
+            let ino = self.nodes.len();
+            self.nodes
+                .push(RefCell::new(Node::new_virtual_dir(ino, parent, name)));
+            self.virtual_dirs.insert(ino, view);
+            ino
+        }
+    }
+}
+
+/// (year, month) a document is filed under : a date found in its visible name
+/// first, then its last modification time, then its creation time
+fn node_date(node: &Node) -> Option<(i32, u32)> {
+    let name = node.get_visible_name();
+    date_from_name(&name.to_string_lossy()).or_else(|| {
+        node.get_last_modified_ms()
+            .filter(|&ms| ms > 0)
+            .or(node.get_created_ms())
+            .map(year_month_from_ms)
+    })
+}
+
+/// finds a `YYYY-MM` (or `YYYYMM`) date in a document name, such as "2024-03-12 Meeting"
+fn date_from_name(name: &str) -> Option<(i32, u32)> {
+    let bytes = name.as_bytes();
+    (0..bytes.len()).find_map(|i| {
+        let digits = |from: usize, len: usize| -> Option<u32> {
+            let s = bytes.get(from..from + len)?;
+            if s.iter().all(u8::is_ascii_digit) {
+                std::str::from_utf8(s).ok()?.parse().ok()
+            } else {
+                None
+            }
+        };
+        if i > 0 && bytes[i - 1].is_ascii_digit() {
+            return None;
+        }
+        let year = digits(i, 4)?;
+        let month_at = if bytes.get(i + 4) == Some(&b'-') {
+            i + 5
+        } else {
+            i + 4
+        };
+        let month = digits(month_at, 2)?;
+        if (1970..2100).contains(&year) && (1..=12).contains(&month) {
+            Some((year as i32, month))
+        } else {
+            None
+        }
+    })
+}
+
+/// converts a timestamp in ms since epoch into a UTC (year, month)
+fn year_month_from_ms(ms: u64) -> (i32, u32) {
+    let (year, month, _) = date_from_ms(ms);
+    (year, month)
+}
+
+/// converts a timestamp in ms since epoch into a UTC (year, month, day)
+pub fn date_from_ms(ms: u64) -> (i32, u32, u32) {
+    // days to civil date, from Howard Hinnant's date algorithms
+    let z = (ms / 86_400_000) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year as i32, month as u32, day as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_year_month_from_ms() {
+        assert_eq!(year_month_from_ms(0), (1970, 1));
+        // 2024-02-29T12:00:00Z
+        assert_eq!(year_month_from_ms(1_709_208_000_000), (2024, 2));
+        assert_eq!(date_from_ms(1_709_208_000_000), (2024, 2, 29));
+        // 2023-12-31T23:59:59Z
+        assert_eq!(year_month_from_ms(1_704_067_199_000), (2023, 12));
+    }
+
+    #[test]
+    fn test_date_from_name() {
+        assert_eq!(date_from_name("2024-03-12 Meeting"), Some((2024, 3)));
+        assert_eq!(date_from_name("Notes 202311"), Some((2023, 11)));
+        assert_eq!(date_from_name("Invoice 12024-13"), None);
+        assert_eq!(date_from_name("Quick sheets"), None);
+    }
+}
@@ -0,0 +1,60 @@
+use super::RemarkableFs;
+
+impl RemarkableFs {
+    pub(crate) const DEFAULT_VOLUME_NAME: &'static str = "reMarkable";
+    /// freedesktop device icon closest to an e-ink tablet
+    const VOLUME_ICON: &'static str = "pda";
+
+    /// `x-gvfs-*` mount options naming the mount and giving it a device icon in
+    /// GNOME Files. fusermount may leave them out of the mount table, the
+    /// `/.xdg-volume-info` file carries the same label and icon.
+    pub(crate) fn volume_options(&self) -> Vec<fuser::MountOption> {
+        [
+            format!("x-gvfs-name={}", escape_option(&self.volume_name)),
+            format!("x-gvfs-icon={}", Self::VOLUME_ICON),
+            format!("x-gvfs-symbolic-icon={}-symbolic", Self::VOLUME_ICON),
+            "x-gvfs-show".to_owned(),
+        ]
+        .into_iter()
+        .map(fuser::MountOption::CUSTOM)
+        .collect()
+    }
+
+    /// `/.xdg-volume-info` content, read by Gio for the label of mounts
+    pub(crate) fn volume_info(&self) -> String {
+        format!(
+            "[Volume Info]\nName={}\nIcon={}\n",
+            self.volume_name.replace('\n', " "),
+            Self::VOLUME_ICON
+        )
+    }
+}
+
+/// percent encoding of a mount option value, gvfs decodes it as an URI part.
+/// Keeps commas and spaces from splitting the option list.
+fn escape_option(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::escape_option;
+
+    #[test]
+    fn test_escape_option() {
+        assert_eq!(escape_option("reMarkable"), "reMarkable");
+        assert_eq!(
+            escape_option("Ana's rM 2, work"),
+            "Ana%27s%20rM%202%2C%20work"
+        );
+        assert_eq!(escape_option("Tablette é"), "Tablette%20%C3%A9");
+    }
+}
@@ -0,0 +1,160 @@
+use super::RemarkableFs;
+use log::{debug, error};
+use std::time::Instant;
+
+/// Rate of the changes sent to the tablet (creations, moves, deletions), so
+/// that a runaway application does not wear its flash out or keep restarting
+/// its user interface
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WriteLimit {
+    /// changes per minute, 0 for no limit
+    pub per_minute: u32,
+    /// changes made at once above the rate
+    pub burst: u32,
+    /// changes refused in a row after which the mount turns read only, 0 never
+    /// stopping
+    pub stop_after: u32,
+}
+
+impl Default for WriteLimit {
+    fn default() -> Self {
+        Self {
+            per_minute: 120,
+            burst: 60,
+            stop_after: 500,
+        }
+    }
+}
+
+/// Verdict of the `MutationBudget` on a change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Verdict {
+    Allowed,
+    Refused,
+    /// refused, and too many were : writes are to be stopped
+    Stopped,
+}
+
+/// Token bucket of the changes : `burst` tokens, refilled at the rate of the
+/// limit, one taken per change
+#[derive(Debug)]
+pub(crate) struct MutationBudget {
+    limit: WriteLimit,
+    tokens: f64,
+    last: Option<Instant>,
+    /// changes refused since the last allowed one
+    refused: u32,
+}
+
+impl MutationBudget {
+    pub(crate) fn new(limit: WriteLimit) -> Self {
+        Self {
+            limit,
+            tokens: f64::from(limit.burst.max(1)),
+            last: None,
+            refused: 0,
+        }
+    }
+
+    fn take(&mut self, now: Instant) -> Verdict {
+        if self.limit.per_minute == 0 {
+            return Verdict::Allowed;
+        }
+        if let Some(last) = self.last {
+            let refill =
+                now.duration_since(last).as_secs_f64() * f64::from(self.limit.per_minute) / 60.0;
+            self.tokens = (self.tokens + refill).min(f64::from(self.limit.burst.max(1)));
+        }
+        self.last = Some(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            self.refused = 0;
+            return Verdict::Allowed;
+        }
+        self.refused += 1;
+        if self.limit.stop_after != 0 && self.refused >= self.limit.stop_after {
+            Verdict::Stopped
+        } else {
+            Verdict::Refused
+        }
+    }
+}
+
+impl RemarkableFs {
+    /// Limits the rate of the changes sent to the tablet (default:
+    /// `WriteLimit::default()`)
+    pub fn set_write_limit(&mut self, limit: WriteLimit) {
+        self.mutations = MutationBudget::new(limit);
+    }
+
+    /// Takes a change `operation` on `ino` from the budget : EAGAIN when the
+    /// rate is exceeded, and once too many were refused in a row, the mount is
+    /// switched to read only until remounted, the stop being reported in
+    /// `/.health`
+    pub(crate) fn check_mutation(
+        &mut self,
+        operation: &str,
+        ino: usize,
+    ) -> Result<(), libc::c_int> {
+        match self.mutations.take(Instant::now()) {
+            Verdict::Allowed => Ok(()),
+            Verdict::Refused => {
+                debug!("{operation} of {ino} refused, write rate exceeded");
+                Err(libc::EAGAIN)
+            }
+            Verdict::Stopped => {
+                let message = format!(
+                    "{} changes refused in a row over {} per minute, the mount is now read \
+                     only until remounted",
+                    self.mutations.refused, self.mutations.limit.per_minute
+                );
+                error!("write rate exceeded ({operation} of {ino}) : {message}");
+                self.record_failure("write limit", message);
+                self.read_only = true;
+                Err(self.refuse_write(operation, ino))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_mutation_budget() {
+        let start = Instant::now();
+        let mut budget = MutationBudget::new(WriteLimit {
+            per_minute: 60,
+            burst: 2,
+            stop_after: 3,
+        });
+        assert_eq!(budget.take(start), Verdict::Allowed);
+        assert_eq!(budget.take(start), Verdict::Allowed);
+        assert_eq!(budget.take(start), Verdict::Refused);
+        // one change a second comes back
+        assert_eq!(
+            budget.take(start + Duration::from_secs(1)),
+            Verdict::Allowed
+        );
+        assert_eq!(
+            budget.take(start + Duration::from_secs(1)),
+            Verdict::Refused
+        );
+        assert_eq!(
+            budget.take(start + Duration::from_secs(1)),
+            Verdict::Refused
+        );
+        assert_eq!(
+            budget.take(start + Duration::from_secs(1)),
+            Verdict::Stopped
+        );
+
+        let mut unlimited = MutationBudget::new(WriteLimit {
+            per_minute: 0,
+            ..WriteLimit::default()
+        });
+        assert!((0..1000).all(|_| unlimited.take(start) == Verdict::Allowed));
+    }
+}
@@ -0,0 +1,139 @@
+use crate::auth::AuthProvider;
+use crate::sshutils::split_port;
+use crate::{RemarkableError, TransportError};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::{debug, info, warn};
+use ssh2::{CheckResult, HostKeyType, KnownHostFileKind};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Checks the host key `session` got from `host` (as given to connect, with
+/// an optional port overriding `port`), whose SHA256 fingerprint is
+/// `fingerprint`. A `pinned` fingerprint must match. Otherwise the key must be
+/// the one listed in `~/.ssh/known_hosts`; a host not listed there is asked to
+/// `provider`, and added when accepted.
+pub(crate) fn verify(
+    session: &ssh2::Session,
+    fingerprint: &str,
+    host: &str,
+    port: u16,
+    pinned: Option<&str>,
+    provider: &mut dyn AuthProvider,
+) -> Result<(), RemarkableError> {
+    if let Some(pinned) = pinned {
+        if pinned != fingerprint {
+            return Err(TransportError::HostKeyMismatch {
+                host: host.to_owned(),
+                expected: pinned.to_owned(),
+                presented: fingerprint.to_owned(),
+            }
+            .into());
+        }
+        debug!("host key of {host} is the pinned one");
+        return Ok(());
+    }
+    let (key, kind) = session
+        .host_key()
+        .ok_or_else(|| TransportError::NoHostKey(host.to_owned()))?;
+    let (name, port) = split_port(host, port)?;
+    let entry = known_hosts_name(name, port);
+    let file = known_hosts_file();
+    let mut known = session.known_hosts()?;
+    if let Some(file) = file.as_deref().filter(|f| f.is_file()) {
+        if let Err(e) = known.read_file(file, KnownHostFileKind::OpenSSH) {
+            warn!("{file:?} not entirely read : {e}");
+        }
+    }
+    match known.check_port(name, port, key) {
+        CheckResult::Match => {
+            debug!("host key of {entry} found in known_hosts");
+            Ok(())
+        }
+        CheckResult::Mismatch => Err(TransportError::HostKeyChanged {
+            host: entry,
+            fingerprint: fingerprint.to_owned(),
+        }
+        .into()),
+        CheckResult::NotFound => {
+            if !provider.accept_host_key(host, fingerprint) {
+                return Err(TransportError::HostKeyRejected {
+                    host: entry,
+                    fingerprint: fingerprint.to_owned(),
+                }
+                .into());
+            }
+            match (file, known_hosts_line(&entry, kind, key)) {
+                (Some(file), Some(line)) => match append_line(&file, &line) {
+                    Ok(()) => info!("host key of {entry} added to {file:?}"),
+                    Err(e) => warn!("host key of {entry} not added to {file:?} : {e}"),
+                },
+                _ => warn!("host key of {entry} accepted, but not added to known_hosts"),
+            }
+            Ok(())
+        }
+        CheckResult::Failure => Err(TransportError::Io(std::io::Error::other(format!(
+            "checking the host key of {entry} against known_hosts"
+        )))
+        .into()),
+    }
+}
+
+/// `~/.ssh/known_hosts`
+fn known_hosts_file() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".ssh").join("known_hosts"))
+}
+
+/// name of `host` in known_hosts, bracketed with the port when not 22
+fn known_hosts_name(host: &str, port: u16) -> String {
+    if port == 22 {
+        host.to_owned()
+    } else {
+        format!("[{host}]:{port}")
+    }
+}
+
+/// known_hosts line of the raw `key` of type `kind`, None for unknown types
+fn known_hosts_line(name: &str, kind: HostKeyType, key: &[u8]) -> Option<String> {
+    let kind = match kind {
+        HostKeyType::Rsa => "ssh-rsa",
+        HostKeyType::Dss => "ssh-dss",
+        HostKeyType::Ecdsa256 => "ecdsa-sha2-nistp256",
+        HostKeyType::Ecdsa384 => "ecdsa-sha2-nistp384",
+        HostKeyType::Ecdsa521 => "ecdsa-sha2-nistp521",
+        HostKeyType::Ed25519 => "ssh-ed25519",
+        HostKeyType::Unknown => return None,
+    };
+    Some(format!("{name} {kind} {}", STANDARD.encode(key)))
+}
+
+fn append_line(file: &Path, line: &str) -> std::io::Result<()> {
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let content = std::fs::read(file).unwrap_or_default();
+    let mut out = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file)?;
+    if !content.is_empty() && !content.ends_with(b"\n") {
+        out.write_all(b"\n")?;
+    }
+    writeln!(out, "{line}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_hosts_line() {
+        assert_eq!(known_hosts_name("10.11.99.1", 22), "10.11.99.1");
+        assert_eq!(known_hosts_name("fe80::1%3", 2222), "[fe80::1%3]:2222");
+        assert_eq!(
+            known_hosts_line("10.11.99.1", HostKeyType::Ed25519, b"Ma").as_deref(),
+            Some("10.11.99.1 ssh-ed25519 TWE=")
+        );
+        assert_eq!(known_hosts_line("rm", HostKeyType::Unknown, b"M"), None);
+    }
+}
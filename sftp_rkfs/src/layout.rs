@@ -0,0 +1,71 @@
+use std::path::{Path, PathBuf};
+
+/// Where the files of a document live under the remote document root.
+/// xochitl stores everything flat as `<uid>.<extension>` plus a `<uid>/` page
+/// folder, community OS forks may arrange them differently.
+pub trait StorageLayout: Send {
+    /// `.metadata` json file of document or collection `uid`
+    fn metadata_path(&self, root: &Path, uid: &str) -> PathBuf;
+    /// `.content` json file of document or collection `uid`
+    fn content_path(&self, root: &Path, uid: &str) -> PathBuf;
+    /// payload of document `uid` (`pdf`, `epub`...)
+    fn payload_path(&self, root: &Path, uid: &str, extension: &str) -> PathBuf;
+    /// folder holding the `.rm` page files of document `uid`
+    fn pages_dir(&self, root: &Path, uid: &str) -> PathBuf;
+    /// folder holding the page thumbnails of document `uid`
+    fn thumbnails_dir(&self, root: &Path, uid: &str) -> PathBuf;
+    /// shell glob matching every metadata file, used in remote commands
+    fn metadata_glob(&self, root: &Path) -> String;
+    /// shell glob matching every content file, used in remote commands
+    fn content_glob(&self, root: &Path) -> String;
+    /// shell glob matching every payload with `extension`, used in remote
+    /// commands
+    fn payload_glob(&self, root: &Path, extension: &str) -> String {
+        self.payload_path(root, "*", extension)
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// Default layout of the reMarkable stock firmware
+#[derive(Debug, Default, Clone, Copy)]
+pub struct XochitlLayout;
+
+impl XochitlLayout {
+    fn file(root: &Path, uid: &str, extension: &str) -> PathBuf {
+        root.join(format!("{uid}.{extension}"))
+    }
+}
+
+impl StorageLayout for XochitlLayout {
+    fn metadata_path(&self, root: &Path, uid: &str) -> PathBuf {
+        Self::file(root, uid, "metadata")
+    }
+
+    fn content_path(&self, root: &Path, uid: &str) -> PathBuf {
+        Self::file(root, uid, "content")
+    }
+
+    fn payload_path(&self, root: &Path, uid: &str, extension: &str) -> PathBuf {
+        Self::file(root, uid, extension)
+    }
+
+    fn pages_dir(&self, root: &Path, uid: &str) -> PathBuf {
+        root.join(uid)
+    }
+
+    fn thumbnails_dir(&self, root: &Path, uid: &str) -> PathBuf {
+        Self::file(root, uid, "thumbnails")
+    }
+
+    fn metadata_glob(&self, root: &Path) -> String {
+        format!(
+            "{}/*.metadata",
+            root.to_string_lossy().trim_end_matches('/')
+        )
+    }
+
+    fn content_glob(&self, root: &Path) -> String {
+        format!("{}/*.content", root.to_string_lossy().trim_end_matches('/'))
+    }
+}
@@ -1,31 +1,37 @@
-use crate::fs::RemarkableFs;
+use crate::auth::{AuthProvider, IdentityAuth, PasswordAuth};
+use crate::encryption::DecryptionProvider;
+use crate::fs::{JobBudget, PermissionPolicy, RemarkableFs, SortPolicy, WriteLimit, WritePolicy};
+use crate::layout::StorageLayout;
+use crate::names::{CollisionPolicy, NamePolicy};
 use crate::sshutils::SshWrapper;
-use thiserror::Error;
+use crate::url::SshUrl;
+use std::path::Path;
 
 #[cfg(test)]
 use std::sync::Once;
 
+pub mod auth;
+pub mod cache;
+pub mod encryption;
+mod error;
 pub mod fs;
+mod hostkeys;
+pub mod layout;
+pub mod names;
 mod nodes;
+pub mod rkids;
+mod rmdoc;
+mod share;
 mod sshutils;
+mod trace;
+mod upload;
+mod url;
 
-#[derive(Debug, Error)]
-pub enum RemarkableError {
-    #[error(transparent)]
-    Ssh2Error(#[from] ssh2::Error),
-    #[error(transparent)]
-    IoError(#[from] std::io::Error),
-    #[error(transparent)]
-    JsonError(#[from] serde_json::Error),
-    #[error("Duplicated node")]
-    NodeDuplicated,
-    #[error("Node not found {0}")]
-    NodeNotFound(usize),
-    #[error("Node io error {0}")]
-    NodeIoError(libc::c_int),
-    #[error("RemarkableFs Error : {0}")]
-    RkError(String),
-}
+pub use error::{
+    BuildError, ErrorContext, FsError, RemarkableError, RenderError, RootProblem, SchemaError,
+    TransportError,
+};
+pub use sshutils::{CommandOutput, SocketOptions, SshConfig, SshHostConfig};
 
 pub struct RemarkableFsBuilder {
     _host: Option<String>,
@@ -34,6 +40,51 @@ pub struct RemarkableFsBuilder {
     _password: Option<String>,
     _mountpoint: Option<std::path::PathBuf>,
     _document_root: Option<std::path::PathBuf>,
+    _detect_root: Option<bool>,
+    _permissions: Option<PermissionPolicy>,
+    _layout: Option<Box<dyn StorageLayout>>,
+    _decryption: Option<Box<dyn DecryptionProvider>>,
+    _detail_budget: Option<usize>,
+    _name_policy: Option<NamePolicy>,
+    _collision_policy: Option<CollisionPolicy>,
+    _sort_policy: Option<SortPolicy>,
+    _ghost_grace: Option<std::time::Duration>,
+    _tree_refresh: Option<std::time::Duration>,
+    _show_ghosts: Option<bool>,
+    _raw_pages: Option<bool>,
+    _coalesce_reads: Option<bool>,
+    _write_policy: Option<WritePolicy>,
+    _read_only: Option<bool>,
+    _volume_name: Option<String>,
+    _client_version: Option<String>,
+    _socket_options: Option<SocketOptions>,
+    _command_interval: Option<std::time::Duration>,
+    _reconnect_attempts: Option<u32>,
+    _keepalive_interval: Option<std::time::Duration>,
+    _connect_timeout: Option<std::time::Duration>,
+    _io_timeout: Option<std::time::Duration>,
+    _retries: Option<u32>,
+    _scan_jobs: Option<usize>,
+    _job_budget: Option<JobBudget>,
+    _write_limit: Option<WriteLimit>,
+    _disk_cache: Option<(std::path::PathBuf, u64)>,
+    _spool_dir: Option<std::path::PathBuf>,
+    _scan_batch_size: Option<usize>,
+    _bulk_load: Option<bool>,
+    _strict_schema: Option<bool>,
+    _refresh_interval: Option<std::time::Duration>,
+    _listing_channels: Option<usize>,
+    _control_socket: Option<std::path::PathBuf>,
+    _shared_socket: Option<std::path::PathBuf>,
+    _max_clock_skew: Option<std::time::Duration>,
+    _auth: Option<Box<dyn AuthProvider>>,
+    _identity_file: Option<std::path::PathBuf>,
+    _passphrase: Option<String>,
+    _use_agent: Option<bool>,
+    _host_key: Option<String>,
+    _ssh_config: Option<bool>,
+    #[cfg(feature = "scripting")]
+    _views_script: Option<String>,
 }
 
 impl RemarkableFsBuilder {
@@ -48,20 +99,80 @@ impl RemarkableFsBuilder {
         Self {
             _mountpoint: None,
             _document_root: None,
+            _detect_root: None,
             _host: None,
             _port: None,
             _user: None,
             _password: None,
+            _permissions: None,
+            _layout: None,
+            _decryption: None,
+            _detail_budget: None,
+            _name_policy: None,
+            _collision_policy: None,
+            _sort_policy: None,
+            _ghost_grace: None,
+            _tree_refresh: None,
+            _show_ghosts: None,
+            _raw_pages: None,
+            _coalesce_reads: None,
+            _write_policy: None,
+            _read_only: None,
+            _volume_name: None,
+            _client_version: None,
+            _socket_options: None,
+            _command_interval: None,
+            _reconnect_attempts: None,
+            _keepalive_interval: None,
+            _connect_timeout: None,
+            _io_timeout: None,
+            _retries: None,
+            _scan_jobs: None,
+            _job_budget: None,
+            _write_limit: None,
+            _disk_cache: None,
+            _spool_dir: None,
+            _scan_batch_size: None,
+            _bulk_load: None,
+            _strict_schema: None,
+            _refresh_interval: None,
+            _listing_channels: None,
+            _control_socket: None,
+            _shared_socket: None,
+            _max_clock_skew: None,
+            _auth: None,
+            _identity_file: None,
+            _passphrase: None,
+            _use_agent: None,
+            _host_key: None,
+            _ssh_config: None,
+            #[cfg(feature = "scripting")]
+            _views_script: None,
         }
     }
 
-    pub fn mountpoint(mut self, mountpoint: &str) -> Self {
-        self._mountpoint = Some(std::path::PathBuf::from(mountpoint));
+    /// builder for the tablet at `url`, such as
+    /// `ssh://root@10.11.99.1:22/home/root/.local/share/remarkable/xochitl/`.
+    /// User, password, port and document root may be left out, `sftp://` is
+    /// accepted too
+    pub fn from_url(url: &str) -> Result<Self, BuildError> {
+        let url = SshUrl::parse(url).map_err(BuildError::InvalidUrl)?;
+        let mut builder = Self::new().host(url.host);
+        builder._port = url.port;
+        builder._user = url.user;
+        builder._password = url.password;
+        builder._document_root = url.path.map(std::path::PathBuf::from);
+        Ok(builder)
+    }
+
+    /// sets the mountpoint, any path (`&str`, `PathBuf`, `OsStr`...) is accepted
+    pub fn mountpoint(mut self, mountpoint: impl AsRef<Path>) -> Self {
+        self._mountpoint = Some(mountpoint.as_ref().to_path_buf());
         self
     }
 
-    pub fn host(mut self, host: &str) -> Self {
-        self._host = Some(host.to_owned());
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self._host = Some(host.into());
         self
     }
 
@@ -70,53 +181,576 @@ impl RemarkableFsBuilder {
         self
     }
 
-    pub fn user(mut self, user: &str) -> Self {
-        self._user = Some(user.to_owned());
+    pub fn user(mut self, user: impl Into<String>) -> Self {
+        self._user = Some(user.into());
+        self
+    }
+
+    pub fn password(mut self, password: impl Into<String>) -> Self {
+        self._password = Some(password.into());
+        self
+    }
+
+    /// sets ownership and modes presented by the mount (default: 0444 documents,
+    /// 0555 collections owned by the mounting user)
+    pub fn permissions(mut self, permissions: PermissionPolicy) -> Self {
+        self._permissions = Some(permissions);
+        self
+    }
+
+    /// sets how documents are laid out under the document root (default: xochitl)
+    pub fn layout(mut self, layout: impl StorageLayout + 'static) -> Self {
+        self._layout = Some(Box::new(layout));
+        self
+    }
+
+    /// decrypts the metadata and content files stored encrypted (default: none,
+    /// such items are shown as `<uid>.encrypted` placeholders)
+    pub fn decryption_provider(mut self, provider: impl DecryptionProvider + 'static) -> Self {
+        self._decryption = Some(Box::new(provider));
+        self
+    }
+
+    /// sets the memory (in bytes) kept for parsed document contents before the
+    /// least recently used ones are dropped (default: 64 MiB)
+    pub fn detail_budget(mut self, bytes: usize) -> Self {
+        self._detail_budget = Some(bytes);
+        self
+    }
+
+    /// sets how names from the tablet that are not valid file names are handled
+    /// (default: shown escaped)
+    pub fn name_policy(mut self, policy: NamePolicy) -> Self {
+        self._name_policy = Some(policy);
+        self
+    }
+
+    /// sets how entries of a folder sharing a name are listed (default: the
+    /// start of their uid is appended)
+    pub fn collision_policy(mut self, policy: CollisionPolicy) -> Self {
+        self._collision_policy = Some(policy);
+        self
+    }
+
+    /// sets the order of collection listings (default: as listed by the tablet)
+    pub fn sort_policy(mut self, policy: SortPolicy) -> Self {
+        self._sort_policy = Some(policy);
+        self
+    }
+
+    /// sets how long collections are listed from the index of the whole tree,
+    /// fetched in a single command, before it is fetched again. Changes made
+    /// through the mount are listed at once (default: 10 seconds)
+    pub fn tree_refresh(mut self, interval: std::time::Duration) -> Self {
+        self._tree_refresh = Some(interval);
+        self
+    }
+
+    /// sets how long items missing from the listing of their collection are
+    /// kept, in case they come back (default: 10 minutes)
+    pub fn ghost_grace(mut self, grace: std::time::Duration) -> Self {
+        self._ghost_grace = Some(grace);
+        self
+    }
+
+    /// lists the items missing from their collection as `<name>.ghost` until
+    /// their grace period ends (default: hidden)
+    pub fn show_ghosts(mut self, enabled: bool) -> Self {
+        self._show_ghosts = Some(enabled);
+        self
+    }
+
+    /// exposes the `.rm` stroke files of each document in a `<name>.pages`
+    /// folder next to it (default: hidden)
+    pub fn raw_pages(mut self, enabled: bool) -> Self {
+        self._raw_pages = Some(enabled);
+        self
+    }
+
+    /// serves the reads of documents issued together (the kernel readahead
+    /// next to the application reads) with one request per range of adjacent
+    /// reads, from a thread of its own. They bypass the read cache
+    /// (default: false)
+    pub fn coalesce_reads(mut self, enabled: bool) -> Self {
+        self._coalesce_reads = Some(enabled);
+        self
+    }
+
+    /// sets when files copied into the mount are uploaded (default: at each
+    /// close, written through)
+    pub fn write_policy(mut self, policy: WritePolicy) -> Self {
+        self._write_policy = Some(policy);
+        self
+    }
+
+    /// mounts read only, control files included (default: false)
+    pub fn read_only(mut self, enabled: bool) -> Self {
+        self._read_only = Some(enabled);
+        self
+    }
+
+    /// label of the mount in file managers such as GNOME Files (default:
+    /// "reMarkable")
+    pub fn volume_name(mut self, name: impl Into<String>) -> Self {
+        self._volume_name = Some(name.into());
+        self
+    }
+
+    /// sets the program using the library, reported by `/.version` (e.g.
+    /// "rmkmount 0.1.0", default: none)
+    pub fn client_version(mut self, version: impl Into<String>) -> Self {
+        self._client_version = Some(version.into());
+        self
+    }
+
+    /// sets TCP tuning of the ssh connection (default: Nagle off, system
+    /// keepalive and buffers)
+    pub fn socket_options(mut self, options: SocketOptions) -> Self {
+        self._socket_options = Some(options);
+        self
+    }
+
+    /// sets the minimum delay between two remote commands (default: none)
+    pub fn command_interval(mut self, interval: std::time::Duration) -> Self {
+        self._command_interval = Some(interval);
+        self
+    }
+
+    /// sets how many connections are tried when the tablet dropped the
+    /// session, waiting longer after each failure (default: 4)
+    pub fn reconnect_attempts(mut self, attempts: u32) -> Self {
+        self._reconnect_attempts = Some(attempts);
+        self
+    }
+
+    /// sends ssh keepalive messages every `interval` the session is idle, so
+    /// that the tablet keeps it open, zero for none (default: none)
+    pub fn keepalive_interval(mut self, interval: std::time::Duration) -> Self {
+        self._keepalive_interval = Some(interval);
+        self
+    }
+
+    /// gives up connecting to the tablet after `timeout`, zero for the system
+    /// timeout (default: system)
+    pub fn connect_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self._connect_timeout = Some(timeout);
+        self
+    }
+
+    /// fails remote operations after `timeout` without an answer of the tablet,
+    /// with EIO once reconnecting failed too, rather than hanging the mount.
+    /// Zero for no limit (default: none)
+    pub fn io_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self._io_timeout = Some(timeout);
+        self
+    }
+
+    /// tries a failed first connection `retries` more times, waiting 1, 2, 4...
+    /// seconds in between (default: 0)
+    pub fn retries(mut self, retries: u32) -> Self {
+        self._retries = Some(retries);
+        self
+    }
+
+    /// scans the whole library from the mount on, while requests are served,
+    /// fetching `jobs` batches of entries at once within the job budget. The
+    /// progress is shown in `/.control/jobs` (default: 0, collections are
+    /// loaded when first listed)
+    pub fn scan_jobs(mut self, jobs: usize) -> Self {
+        self._scan_jobs = Some(jobs);
+        self
+    }
+
+    /// sets the limits of the background jobs such as the library scan: jobs
+    /// running at once, bandwidth, and the request rate above which they wait
+    /// for the filesystem to be idle (default: 4 jobs, no bandwidth limit, 200
+    /// requests per second)
+    pub fn job_budget(mut self, budget: JobBudget) -> Self {
+        self._job_budget = Some(budget);
+        self
+    }
+
+    /// limits the rate of the creations, moves and deletions sent to the
+    /// tablet, the mount turning read only when an application keeps
+    /// exceeding it (default: 120 per minute, bursts of 60, stopping after 500
+    /// refusals in a row)
+    pub fn write_limit(mut self, limit: WriteLimit) -> Self {
+        self._write_limit = Some(limit);
+        self
+    }
+
+    /// keeps the document blocks read in `<dir>/<device>`, the device being
+    /// named by its serial number (or its host), `capacity` bytes at most, the
+    /// least recently used blocks being dropped beyond (default: no disk cache)
+    pub fn disk_cache(mut self, dir: impl AsRef<Path>, capacity: u64) -> Self {
+        self._disk_cache = Some((dir.as_ref().to_path_buf(), capacity));
+        self
+    }
+
+    /// spools the files copied into the mount, until they are uploaded, in a
+    /// private folder created in `dir` (default: the system temporary folder)
+    pub fn spool_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self._spool_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// sets how many listing entries each scan command fetches (default: 64)
+    pub fn scan_batch_size(mut self, entries: usize) -> Self {
+        self._scan_batch_size = Some(entries);
+        self
+    }
+
+    /// starts the scan by fetching the metadata and content files of the whole
+    /// library in a single remote command, the scan falling back to batches
+    /// when it fails (default: false)
+    pub fn bulk_load(mut self, enabled: bool) -> Self {
+        self._bulk_load = Some(enabled);
+        self
+    }
+
+    /// reports the json fields of metadata and content files the mount does
+    /// not parse in `/.control/schema`, warning about each new one
+    /// (default: false)
+    pub fn strict_schema(mut self, enabled: bool) -> Self {
+        self._strict_schema = Some(enabled);
+        self
+    }
+
+    /// polls the tablet for changes every `interval` once mounted, so that
+    /// documents created on the tablet show up on their own, zero for never
+    /// (default: never)
+    pub fn refresh_interval(mut self, interval: std::time::Duration) -> Self {
+        self._refresh_interval = Some(interval);
         self
     }
 
-    pub fn password(mut self, password: &str) -> Self {
-        self._password = Some(password.to_owned());
+    /// fetches the entries of a collection being listed on `channels` ssh
+    /// channels at once, 0 for entry by entry (default: 4)
+    pub fn listing_channels(mut self, channels: usize) -> Self {
+        self._listing_channels = Some(channels);
         self
     }
 
-    /// sets document root from povided &str path:
-    pub fn document_root(mut self, path: &str) -> Self {
-        self._document_root = Some(std::path::PathBuf::from(path));
+    /// serves the connection to other processes on the unix socket `path`,
+    /// see `share_connection` (default: not shared)
+    pub fn control_socket(mut self, path: impl AsRef<Path>) -> Self {
+        self._control_socket = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// runs commands and reads on the connection of the mount serving the
+    /// control socket `path` instead of opening one, when a mount answers
+    /// there. Writes are then refused (default: own connection)
+    pub fn share_connection(mut self, path: impl AsRef<Path>) -> Self {
+        self._shared_socket = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// sets the tablet clock to the host time at connection when they are more
+    /// than `skew` apart (default: clock left alone)
+    pub fn max_clock_skew(mut self, skew: std::time::Duration) -> Self {
+        self._max_clock_skew = Some(skew);
+        self
+    }
+
+    /// sets the credentials source used to authenticate, instead of the password
+    pub fn auth_provider(mut self, provider: Box<dyn AuthProvider>) -> Self {
+        self._auth = Some(provider);
+        self
+    }
+
+    /// authenticates with the private key in `path` first, the password or auth
+    /// provider being used when the tablet refuses it
+    pub fn identity_file(mut self, path: impl AsRef<Path>) -> Self {
+        self._identity_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// sets the passphrase of the identity file (default: none)
+    pub fn passphrase(mut self, passphrase: impl Into<String>) -> Self {
+        self._passphrase = Some(passphrase.into());
+        self
+    }
+
+    /// authenticates with the keys of the ssh agent (ssh-agent, gpg-agent) before
+    /// the identity file and password (default: false)
+    pub fn use_agent(mut self, enabled: bool) -> Self {
+        self._use_agent = Some(enabled);
+        self
+    }
+
+    /// only accepts the tablet presenting the host key of SHA256 `fingerprint`
+    /// (as printed by `ssh-keygen -l`), instead of checking ~/.ssh/known_hosts
+    pub fn host_key_fingerprint(mut self, fingerprint: impl Into<String>) -> Self {
+        self._host_key = Some(fingerprint.into());
+        self
+    }
+
+    /// resolves the host as an alias of `~/.ssh/config`, its HostName, Port,
+    /// User and IdentityFile filling the settings not given (default: false)
+    pub fn ssh_config(mut self, enabled: bool) -> Self {
+        self._ssh_config = Some(enabled);
+        self
+    }
+
+    /// defines the `/.views` folders from a views script (default: none)
+    #[cfg(feature = "scripting")]
+    pub fn views_script(mut self, script: impl Into<String>) -> Self {
+        self._views_script = Some(script.into());
+        self
+    }
+
+    /// sets the document root on the tablet, an absolute path
+    pub fn document_root(mut self, path: impl AsRef<Path>) -> Self {
+        self._document_root = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// looks for the document root on the tablet at connection, the one given
+    /// to `document_root` being kept when it holds documents (default: true)
+    pub fn detect_document_root(mut self, enabled: bool) -> Self {
+        self._detect_root = Some(enabled);
         self
     }
 
     /// builds a new RemarkableF struct creates the underlying ssh2 session
     /// Builder is consumed after this step
     pub fn build(self) -> Result<RemarkableFs, RemarkableError> {
+        self.try_build().map_err(RemarkableError::from)
+    }
+
+    /// like `build`, telling invalid settings apart from connection failures
+    pub fn try_build(self) -> Result<RemarkableFs, BuildError> {
+        if self._mountpoint.is_none() {
+            return Err(BuildError::MissingMountpoint);
+        }
+        self.validate()?;
+        Ok(self.connect()?)
+    }
+
+    /// checks the settings that do not need the tablet
+    fn validate(&self) -> Result<(), BuildError> {
+        if self._host.as_ref().is_some_and(|h| h.trim().is_empty()) {
+            return Err(BuildError::EmptyHost);
+        }
+        if self._user.as_ref().is_some_and(|u| u.is_empty()) {
+            return Err(BuildError::EmptyUser);
+        }
+        if let Some(identity) = self._identity_file.as_ref().filter(|p| !p.is_file()) {
+            return Err(BuildError::MissingIdentity(identity.clone()));
+        }
+        match &self._document_root {
+            Some(root) if !root.is_absolute() => {
+                Err(BuildError::RelativeDocumentRoot(root.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// settings of the `config` entries matching the host, for those not given
+    fn with_ssh_config(mut self, config: &SshConfig) -> Self {
+        let alias = self
+            ._host
+            .clone()
+            .unwrap_or(RemarkableFsBuilder::RK_ADDRESS.to_string());
+        let resolved = config.resolve(&alias);
+        if let Some(host_name) = resolved.host_name {
+            log::debug!("{alias} is {host_name} according to the ssh config");
+            self._host = Some(host_name);
+        }
+        self._port = self._port.or(resolved.port);
+        self._user = self._user.or(resolved.user);
+        if self._identity_file.is_none() {
+            self._identity_file = resolved.identity_files.into_iter().find(|p| p.is_file());
+        }
+        self
+    }
+
+    /// connects to the tablet without requiring a mountpoint, for one-shot
+    /// operations (export, import...) that do not mount the filesystem
+    pub fn connect(mut self) -> Result<RemarkableFs, RemarkableError> {
+        if self._ssh_config.unwrap_or(false) {
+            self = self.with_ssh_config(&SshConfig::load());
+        }
+        self.validate()?;
         let mut session = SshWrapper::new()?;
 
-        let host_addr = format!(
-            "{}:{}",
-            self._host
-                .unwrap_or(RemarkableFsBuilder::RK_ADDRESS.to_string()),
-            self._port.unwrap_or(RemarkableFsBuilder::RK_PORT)
-        );
-        session.connect(&host_addr)?.authenticate(
-            &self
-                ._user
-                .unwrap_or(RemarkableFsBuilder::RK_USR.to_string()),
-            &self
-                ._password
-                .unwrap_or(RemarkableFsBuilder::RK_PWD.to_string()),
-        )?;
-        if let Some(mountpoint) = &self._mountpoint {
-            Ok(RemarkableFs::new(
-                session,
-                std::path::PathBuf::from(mountpoint),
-                self._document_root
-                    .unwrap_or(RemarkableFsBuilder::RK_ROOTPATH.into()),
-            ))
-        } else {
-            Err(RemarkableError::RkError(
-                "Mountpoint not provided".to_string(),
+        let host = self
+            ._host
+            .unwrap_or(RemarkableFsBuilder::RK_ADDRESS.to_string());
+        let port = self._port.unwrap_or(RemarkableFsBuilder::RK_PORT);
+        let socket_options = self._socket_options.unwrap_or_default();
+        let mut auth = self._auth.unwrap_or_else(|| {
+            Box::new(PasswordAuth(
+                self._password
+                    .unwrap_or(RemarkableFsBuilder::RK_PWD.to_string()),
             ))
+        });
+        if let Some(identity_file) = self._identity_file {
+            auth = Box::new(IdentityAuth {
+                identity_file,
+                passphrase: self._passphrase,
+                fallback: auth,
+            });
+        }
+        if let Some(interval) = self._keepalive_interval {
+            session.set_keepalive_interval(interval);
+        }
+        if let Some(timeout) = self._connect_timeout {
+            session.set_connect_timeout(timeout);
+        }
+        if let Some(timeout) = self._io_timeout {
+            session.set_io_timeout(timeout);
+        }
+        if let Some(retries) = self._retries {
+            session.set_connect_retries(retries);
+        }
+        let shared = match &self._shared_socket {
+            Some(path) => match session.share(path) {
+                Ok(()) => true,
+                Err(e) => {
+                    log::debug!("connecting directly: {e}");
+                    false
+                }
+            },
+            None => false,
+        };
+        if !shared {
+            session.connect(&host, port, &socket_options)?;
+        }
+        if let Some(interval) = self._command_interval {
+            session.set_command_interval(interval);
+        }
+        if let Some(attempts) = self._reconnect_attempts {
+            session.set_reconnect_attempts(attempts);
+        }
+        if let Some(enabled) = self._use_agent {
+            session.set_use_agent(enabled);
+        }
+        if let Some(fingerprint) = self._host_key {
+            session.set_host_key_fingerprint(fingerprint);
+        }
+        if !shared {
+            session.login(
+                &self
+                    ._user
+                    .unwrap_or(RemarkableFsBuilder::RK_USR.to_string()),
+                auth,
+            )?;
+        }
+        let mut rfs = RemarkableFs::new(
+            session,
+            self._mountpoint.unwrap_or_default(),
+            self._document_root
+                .unwrap_or(RemarkableFsBuilder::RK_ROOTPATH.into()),
+        );
+        if self._detect_root.unwrap_or(true) {
+            rfs.detect_document_root();
+        }
+        if let Some(permissions) = self._permissions {
+            rfs.set_permission_policy(permissions);
+        }
+        if let Some(layout) = self._layout {
+            rfs.set_layout(layout);
+        }
+        if let Some(provider) = self._decryption {
+            rfs.set_decryption_provider(provider);
+        }
+        if let Some(bytes) = self._detail_budget {
+            rfs.set_detail_budget(bytes);
+        }
+        if let Some(policy) = self._name_policy {
+            rfs.set_name_policy(policy);
+        }
+        if let Some(policy) = self._collision_policy {
+            rfs.set_collision_policy(policy);
+        }
+        if let Some(policy) = self._sort_policy {
+            rfs.set_sort_policy(policy);
+        }
+        if let Some(grace) = self._ghost_grace {
+            rfs.set_ghost_grace(grace);
+        }
+        if let Some(interval) = self._tree_refresh {
+            rfs.set_tree_refresh(interval);
+        }
+        if let Some(enabled) = self._show_ghosts {
+            rfs.set_show_ghosts(enabled);
+        }
+        if let Some(enabled) = self._raw_pages {
+            rfs.set_raw_pages(enabled);
+        }
+        if let Some(enabled) = self._coalesce_reads {
+            rfs.set_coalesce_reads(enabled);
+        }
+        if let Some(policy) = self._write_policy {
+            rfs.set_write_policy(policy);
         }
+        if let Some(enabled) = self._read_only {
+            rfs.set_read_only(enabled);
+        }
+        if let Some(name) = &self._volume_name {
+            rfs.set_volume_name(name);
+        }
+        if let Some(version) = &self._client_version {
+            rfs.set_client_version(version);
+        }
+        if let Some(jobs) = self._scan_jobs {
+            rfs.set_scan_jobs(jobs);
+        }
+        if let Some(budget) = self._job_budget {
+            rfs.set_job_budget(budget);
+        }
+        if let Some(limit) = self._write_limit {
+            rfs.set_write_limit(limit);
+        }
+        if let Some((dir, capacity)) = self._disk_cache {
+            let device = match rfs.device_info() {
+                Ok(fs::DeviceInfo {
+                    device_id: Some(id),
+                    ..
+                }) => id,
+                _ => host.clone(),
+            };
+            let dir = dir.join(names::file_safe(&device));
+            match cache::DiskCache::open(&dir, capacity) {
+                Ok(cache) => rfs.set_disk_cache(cache),
+                Err(e) => log::warn!("disk cache {dir:?} not used: {e}"),
+            }
+        }
+        if let Some(dir) = self._spool_dir {
+            rfs.set_spool_dir(dir);
+        }
+        if let Some(entries) = self._scan_batch_size {
+            rfs.set_scan_batch_size(entries);
+        }
+        if let Some(enabled) = self._bulk_load {
+            rfs.set_bulk_load(enabled);
+        }
+        if let Some(enabled) = self._strict_schema {
+            rfs.set_strict_schema(enabled);
+        }
+        if let Some(interval) = self._refresh_interval {
+            rfs.set_refresh_interval(interval);
+        }
+        if let Some(channels) = self._listing_channels {
+            rfs.set_listing_channels(channels);
+        }
+        if let Some(path) = &self._control_socket {
+            if let Err(e) = rfs.serve_connection(path) {
+                log::warn!("connection not shared: {e}");
+            }
+        }
+        if let Some(skew) = self._max_clock_skew {
+            if let Err(e) = rfs.sync_clock(skew) {
+                log::warn!("tablet clock not checked: {e}");
+            }
+        }
+        #[cfg(feature = "scripting")]
+        if let Some(script) = &self._views_script {
+            rfs.set_views_script(script)?;
+        }
+        Ok(rfs)
     }
 }
 
@@ -176,6 +810,49 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_try_build_validation() {
+        let missing = RemarkableFsBuilder::new().try_build();
+        assert!(matches!(missing, Err(BuildError::MissingMountpoint)));
+        let relative = RemarkableFsBuilder::new()
+            .mountpoint(std::path::PathBuf::from(TEST_MOUNTPOINT))
+            .document_root("xochitl")
+            .try_build();
+        assert!(matches!(
+            relative,
+            Err(BuildError::RelativeDocumentRoot(root)) if root == Path::new("xochitl")
+        ));
+        let no_key = RemarkableFsBuilder::new()
+            .mountpoint(TEST_MOUNTPOINT)
+            .identity_file("/nonexistent/id_ed25519")
+            .try_build();
+        assert!(matches!(no_key, Err(BuildError::MissingIdentity(_))));
+        let empty_host = RemarkableFsBuilder::new().host(String::new()).connect();
+        assert!(matches!(
+            empty_host.err().as_ref().and_then(|e| e.fs_error()),
+            Some(FsError::InvalidPath(_))
+        ));
+    }
+
+    #[test]
+    fn test_from_url() {
+        let builder = RemarkableFsBuilder::from_url(
+            "ssh://root@10.11.99.1:2222/home/root/.local/share/remarkable/xochitl/",
+        )
+        .unwrap();
+        assert_eq!(builder._host.as_deref(), Some("10.11.99.1"));
+        assert_eq!(builder._port, Some(2222));
+        assert_eq!(builder._user.as_deref(), Some("root"));
+        assert_eq!(
+            builder._document_root.as_deref(),
+            Some(Path::new(RemarkableFsBuilder::RK_ROOTPATH))
+        );
+        assert!(matches!(
+            RemarkableFsBuilder::from_url("10.11.99.1"),
+            Err(BuildError::InvalidUrl(_))
+        ));
+    }
+
     #[test]
     fn test_connect_and_readdir() {
         init();
@@ -0,0 +1,158 @@
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+/// What to do with names from the tablet that cannot be presented as file names
+/// as is: control characters, `/`, empty, `.` and `..` names
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum NamePolicy {
+    /// offending characters are shown escaped as `\xNN`, entries stay addressable
+    #[default]
+    Lossy,
+    /// entries with such names are left out of listings
+    Strict,
+}
+
+/// What to do when several entries of a folder have the same name. The entry
+/// loaded first keeps the plain name
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CollisionPolicy {
+    /// others get the start of their uid appended : `Spec (0b5d7d31).pdf`
+    #[default]
+    SuffixUid,
+    /// others get their modification date appended : `Spec (2024-02-29).pdf`,
+    /// or their uid when that name is taken too
+    SuffixDate,
+    /// only the most recently modified entry is listed
+    KeepNewest,
+    /// others are left out of listings and logged as errors
+    Error,
+}
+
+impl std::str::FromStr for CollisionPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "uid" => Ok(Self::SuffixUid),
+            "date" => Ok(Self::SuffixDate),
+            "newest" => Ok(Self::KeepNewest),
+            "error" => Ok(Self::Error),
+            _ => Err(format!(
+                "unknown collision policy {policy}, expected uid, date, newest or error"
+            )),
+        }
+    }
+}
+
+/// `name` with ` (suffix)` inserted before its extension, if it has one
+pub(crate) fn with_suffix(name: &str, suffix: &str, has_extension: bool) -> String {
+    match name
+        .rsplit_once('.')
+        .filter(|(stem, _)| has_extension && !stem.is_empty())
+    {
+        Some((stem, ext)) => format!("{stem} ({suffix}).{ext}"),
+        None => format!("{name} ({suffix})"),
+    }
+}
+
+/// `name` with characters other than ASCII letters, digits, `.`, `-` and `_`
+/// replaced, for local file names built from device names
+pub fn file_safe(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// can `name` be presented as a file name without escaping ?
+pub(crate) fn is_clean(name: &str) -> bool {
+    !matches!(name, "" | "." | "..") && !name.chars().any(needs_escape)
+}
+
+fn needs_escape(c: char) -> bool {
+    c == '/' || c.is_control()
+}
+
+/// file name presented for the device name `name`. Presented names are always
+/// valid UTF-8, so looking them up never needs the reverse conversion. An empty
+/// name is shown as `\x00`.
+pub(crate) fn escape(name: &str) -> Cow<'_, str> {
+    if is_clean(name) {
+        return Cow::Borrowed(name);
+    }
+    if name.is_empty() {
+        return Cow::Borrowed("\\x00");
+    }
+    if matches!(name, "." | "..") {
+        return Cow::Owned(name.bytes().map(|b| format!("\\x{b:02x}")).collect());
+    }
+    let mut escaped = String::with_capacity(name.len() + 8);
+    for c in name.chars() {
+        if needs_escape(c) {
+            let mut buf = [0; 4];
+            for b in c.encode_utf8(&mut buf).bytes() {
+                escaped.push_str(&format!("\\x{b:02x}"));
+            }
+        } else {
+            escaped.push(c);
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// file name presented for an item named `name` on the device, whose payload
+/// has `extension`, if presented with one
+pub(crate) fn presented(name: &str, extension: Option<&str>) -> PathBuf {
+    let mut presented = PathBuf::from(escape(name).as_ref());
+    if let Some(extension) = extension {
+        presented.set_extension(extension);
+    }
+    presented
+}
+
+/// name received from the kernel, `None` when it is not UTF-8 and therefore
+/// cannot match any presented name
+pub(crate) fn from_os(name: &OsStr) -> Option<&str> {
+    name.to_str()
+}
+
+/// remote path as expected by the ssh layer, non UTF-8 parts are replaced
+pub(crate) fn remote_str(path: &Path) -> Cow<'_, str> {
+    path.to_string_lossy()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("Notes 2024"), "Notes 2024");
+        assert_eq!(escape("Caf\u{e9}"), "Caf\u{e9}");
+        assert_eq!(escape("a/b"), "a\\x2fb");
+        assert_eq!(escape("bell\u{7}"), "bell\\x07");
+        assert_eq!(escape(".."), "\\x2e\\x2e");
+        assert_eq!(escape(""), "\\x00");
+        assert!(!is_clean("tab\there"));
+        assert_eq!(file_safe("fe80::1%wlan0"), "fe80__1_wlan0");
+        assert_eq!(file_safe("RM110-313-12345"), "RM110-313-12345");
+    }
+
+    #[test]
+    fn test_with_suffix() {
+        assert_eq!(
+            with_suffix("Spec.pdf", "0b5d7d31", true),
+            "Spec (0b5d7d31).pdf"
+        );
+        assert_eq!(
+            with_suffix("v1.2", "2024-02-29", false),
+            "v1.2 (2024-02-29)"
+        );
+        assert_eq!(with_suffix(".pdf", "x", true), ".pdf (x)");
+        assert_eq!("newest".parse(), Ok(CollisionPolicy::KeepNewest));
+        assert!("oldest".parse::<CollisionPolicy>().is_err());
+    }
+}
@@ -1,5 +1,6 @@
+use crate::names;
 use crate::sshutils::SshFileStat;
-use crate::RemarkableError;
+use crate::{FsError, RemarkableError};
 
 use log::{debug, error, warn};
 use serde::Deserialize;
@@ -20,7 +21,7 @@ enum RkOrientation {
     Landscape,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
 #[serde(rename_all = "lowercase")]
 enum RkFileType {
     EPUB,
@@ -57,6 +58,21 @@ struct RkMetadata {
     visible_name: String,
 }
 
+/// json fields of a metadata file parsed into `RkMetadata`
+pub(crate) const METADATA_FIELDS: [&str; 11] = [
+    "deleted",
+    "lastModified",
+    "createdTime",
+    "metadatamodified",
+    "modified",
+    "parent",
+    "pinned",
+    "synced",
+    "type",
+    "version",
+    "visibleName",
+];
+
 impl RkMetadata {
     fn default_version() -> i32 {
         0
@@ -87,6 +103,12 @@ struct RkPage {
     template: RkTimestamp,
 }
 
+/// document tag, set from the tablet since firmware 3.0
+#[derive(Deserialize, Debug)]
+struct RkTag {
+    name: String,
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 struct RkCPages {
@@ -141,14 +163,38 @@ struct RkContents {
     #[serde(default = "RkContents::default_format_version")]
     format_version: i16,
     page_count: u16,
+    #[serde(default)]
+    tags: Vec<RkTag>,
 }
 
+/// json fields of a content file parsed into `RkContents`
+pub(crate) const CONTENT_FIELDS: [&str; 17] = [
+    "cPages",
+    "pages",
+    "coverPageNumber",
+    "customZoomCenterX",
+    "customZoomCenterY",
+    "customZoomOrientation",
+    "customZoomPageHeight",
+    "customZoomPageWidth",
+    "customZoomScale",
+    "fileType",
+    "fontName",
+    "lineHeight",
+    "margins",
+    "orientation",
+    "formatVersion",
+    "pageCount",
+    "tags",
+];
+
 impl RkContents {
     fn default_format_version() -> i16 {
         1
     }
 }
 
+/// readdir entry : (ino, readdir position, kind, name)
 #[derive(Debug, Clone)]
 pub struct FuserChild(
     pub usize,
@@ -158,8 +204,8 @@ pub struct FuserChild(
 );
 
 impl FuserChild {
-    pub fn new(ino: usize, size: usize, kind: fuser::FileType, name: PathBuf) -> Self {
-        Self(ino, size, kind, name.into())
+    pub fn new(ino: usize, position: usize, kind: fuser::FileType, name: PathBuf) -> Self {
+        Self(ino, position, kind, name.into())
     }
 
     pub fn ino(&self) -> usize {
@@ -175,6 +221,26 @@ pub struct Node {
     parent: usize,
     children: Vec<FuserChild>,
     handles: u64,
+    generation: u64,
+    /// file type from content, kept when the parsed content is evicted
+    file_type: Option<RkFileType>,
+    /// size of the content json the parsed content was built from, 0 once evicted
+    details_size: usize,
+    /// tick of the last use of the parsed content, for LRU eviction
+    last_used: u64,
+    /// payload extension for nodes not described by a content file (alternate payloads)
+    payload_extension: Option<&'static str>,
+    /// size of the rendered file presented instead of the payload
+    rendered_size: Option<RenderedSize>,
+}
+
+/// Size of a document presented rendered, rather than as its payload
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenderedSize {
+    /// guessed from the page count, until the rendered file is cached
+    Estimated(u64),
+    /// size of the rendered file in the cache
+    Cached(u64),
 }
 
 impl Node {
@@ -186,8 +252,37 @@ impl Node {
     pub const TRASH_NODE_UID: &'static str = ".Trash";
     pub const TRASH_NODE_PATH: &'static str = ".Trash";
     pub const TRASH_NODE_INO: usize = Self::ROOT_NODE_INO + 1;
-
-    const CONTENT_EXTENSION: &'static str = "content";
+    pub const TRASH_PARENT_UID: &'static str = "trash";
+    pub const BY_DATE_NODE_PATH: &'static str = "by-date";
+    pub const BY_DATE_NODE_INO: usize = Self::TRASH_NODE_INO + 1;
+    pub const HEALTH_NODE_PATH: &'static str = ".health";
+    pub const HEALTH_NODE_INO: usize = Self::BY_DATE_NODE_INO + 1;
+    pub const CONTROL_NODE_PATH: &'static str = ".control";
+    pub const CONTROL_NODE_INO: usize = Self::HEALTH_NODE_INO + 1;
+    pub const REFRESH_NODE_PATH: &'static str = "refresh";
+    pub const REFRESH_NODE_INO: usize = Self::CONTROL_NODE_INO + 1;
+    pub const PROGRESS_NODE_PATH: &'static str = "progress";
+    pub const PROGRESS_NODE_INO: usize = Self::REFRESH_NODE_INO + 1;
+    pub const VOLUME_INFO_NODE_PATH: &'static str = ".xdg-volume-info";
+    pub const VOLUME_INFO_NODE_INO: usize = Self::PROGRESS_NODE_INO + 1;
+    pub const VERSION_NODE_PATH: &'static str = ".version";
+    pub const VERSION_NODE_INO: usize = Self::VOLUME_INFO_NODE_INO + 1;
+    pub const JOBS_NODE_PATH: &'static str = "jobs";
+    pub const JOBS_NODE_INO: usize = Self::VERSION_NODE_INO + 1;
+    pub const SCHEMA_NODE_PATH: &'static str = "schema";
+    pub const SCHEMA_NODE_INO: usize = Self::JOBS_NODE_INO + 1;
+    /// folder of the scripted views, allocated when a views script is set
+    #[cfg(feature = "scripting")]
+    pub const VIEWS_NODE_PATH: &'static str = ".views";
+    /// suffix of the unlisted sidecar reporting read progress of a document
+    pub const PROGRESS_SUFFIX: &'static str = ".progress";
+    /// suffix of the marker standing for an item whose files are corrupt
+    pub const CORRUPT_SUFFIX: &'static str = ".corrupt";
+    /// suffix of the placeholder standing for an item stored encrypted
+    pub const ENCRYPTED_SUFFIX: &'static str = ".encrypted";
+    pub const PAGINATED_SUFFIX: &'static str = ".paginated";
+    pub const PAGES_SUFFIX: &'static str = ".pages";
+    pub const RAW_PAGE_EXTENSION: &'static str = "rm";
 
     pub fn new(ino: usize, filestat: SshFileStat) -> Self {
         Self {
@@ -198,6 +293,12 @@ impl Node {
             parent: 0,
             children: vec![],
             handles: 0,
+            generation: 0,
+            file_type: None,
+            details_size: 0,
+            last_used: 0,
+            payload_extension: None,
+            rendered_size: None,
         }
     }
 
@@ -210,6 +311,12 @@ impl Node {
             parent: 0,
             children: vec![],
             handles: 0,
+            generation: 0,
+            file_type: None,
+            details_size: 0,
+            last_used: 0,
+            payload_extension: None,
+            rendered_size: None,
         }
     }
 
@@ -222,6 +329,68 @@ impl Node {
             parent: Self::ROOT_NODE_INO,
             children: vec![],
             handles: 0,
+            generation: 0,
+            file_type: None,
+            details_size: 0,
+            last_used: 0,
+            payload_extension: None,
+            rendered_size: None,
+        }
+    }
+
+    /// builds a directory node that does not exist on the tablet (virtual views)
+    pub fn new_virtual_dir(ino: usize, parent: usize, name: &str) -> Self {
+        Self {
+            ino,
+            metadata: Some(RkMetadata::from_str(name)),
+            content: None,
+            filestat: SshFileStat::build_from_special_path(name),
+            parent,
+            children: vec![],
+            handles: 0,
+            generation: 0,
+            file_type: None,
+            details_size: 0,
+            last_used: 0,
+            payload_extension: None,
+            rendered_size: None,
+        }
+    }
+
+    /// builds a file node whose content is generated by the filesystem
+    pub fn new_virtual_file(ino: usize, parent: usize, name: &str) -> Self {
+        let mut node = Self::new_virtual_dir(ino, parent, name);
+        if let Some(metadata) = node.metadata.as_mut() {
+            metadata.type_ = RkNodeType::DocumentType;
+        }
+        node
+    }
+
+    /// builds a read-only document node whose payload is `<uid>.<extension>`, for
+    /// files xochitl keeps next to a document (e.g. the paginated pdf of an epub)
+    pub fn new_alternate_payload(
+        ino: usize,
+        parent: usize,
+        visible_name: &str,
+        extension: &'static str,
+        filestat: SshFileStat,
+    ) -> Self {
+        let mut metadata = RkMetadata::from_str(visible_name);
+        metadata.type_ = RkNodeType::DocumentType;
+        Self {
+            ino,
+            metadata: Some(metadata),
+            content: None,
+            filestat,
+            parent,
+            children: vec![],
+            handles: 0,
+            generation: 0,
+            file_type: None,
+            details_size: 0,
+            last_used: 0,
+            payload_extension: Some(extension),
+            rendered_size: None,
         }
     }
 
@@ -240,19 +409,17 @@ impl Node {
                 parent,
                 children: vec![],
                 handles: 0,
+                generation: 0,
+                file_type: None,
+                details_size: 0,
+                last_used: 0,
+                payload_extension: None,
+                rendered_size: None,
             }),
-            Err(e) => Err(RemarkableError::JsonError(e)),
+            Err(e) => Err(e.into()),
         }
     }
 
-    pub fn root_children(_ino: usize) -> Vec<SshFileStat> {
-        /*        if ino == Self::ROOT_NODE_INO {
-            debug!("this node is Root, adding Trash child");
-            vec![SshFileStat::build_from_special_path(Node::TRASH_NODE_PATH)]
-        } else {*/
-        vec![]
-        // }
-    }
     /// is this node the root node ?
     pub fn is_root(&self) -> bool {
         self.ino == Self::ROOT_NODE_INO
@@ -274,6 +441,61 @@ impl Node {
         }
     }
 
+    /// does the content tell the file type of this document ?
+    pub fn has_file_type(&self) -> bool {
+        self.file_type.is_some()
+    }
+
+    /// Types a document whose content file is missing or tells no file type from
+    /// its payload found on the tablet, `extension` being pdf or epub. The node
+    /// is then handled as an alternate payload, without content to reload
+    pub fn infer_payload(&mut self, extension: &'static str) {
+        self.file_type = match extension {
+            "pdf" => Some(RkFileType::PDF),
+            "epub" => Some(RkFileType::EPUB),
+            _ => None,
+        };
+        self.payload_extension = Some(extension);
+        self.generation += 1;
+    }
+
+    /// is this a document described by a `.content` file (not an alternate payload) ?
+    pub fn has_content_file(&self) -> bool {
+        self.is_document() && self.payload_extension.is_none()
+    }
+
+    /// is this document an epub ?
+    pub fn is_epub(&self) -> bool {
+        self.file_type == Some(RkFileType::EPUB)
+    }
+
+    /// is this item pinned (marked as favorite) on the tablet ?
+    pub fn is_pinned(&self) -> bool {
+        self.metadata.as_ref().is_some_and(|m| m.pinned)
+    }
+
+    /// is this document in the tablet trash ?
+    pub fn is_trashed(&self) -> bool {
+        self.metadata
+            .as_ref()
+            .is_some_and(|m| m.parent == Self::TRASH_PARENT_UID)
+    }
+
+    /// last modification time from metadata, in ms since epoch
+    pub fn get_last_modified_ms(&self) -> Option<u64> {
+        self.metadata.as_ref().map(|m| m.last_modified)
+    }
+
+    /// creation time from metadata, in ms since epoch
+    pub fn get_created_ms(&self) -> Option<u64> {
+        self.metadata.as_ref().and_then(|m| m.created_time)
+    }
+
+    /// get node generation, bumped each time metadata, content or stat change
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
     /// get handle count to current node
     pub fn handles(&self) -> u64 {
         self.handles
@@ -284,7 +506,7 @@ impl Node {
             self.handles += 1;
             Ok(self.handles)
         } else {
-            Err(RemarkableError::NodeIoError(libc::EACCES))
+            Err(FsError::NodeIoError(libc::EACCES).into())
         }
     }
     /// release a handle on current node
@@ -293,7 +515,7 @@ impl Node {
             self.handles -= 1;
             Ok(self.handles)
         } else {
-            Err(RemarkableError::NodeIoError(libc::EINVAL))
+            Err(FsError::NodeIoError(libc::EINVAL).into())
         }
     }
     /// gets the number of links to the node
@@ -306,11 +528,17 @@ impl Node {
     }
 
     pub fn get_visible_name(&self) -> PathBuf {
-        let mut res = PathBuf::from(self.get_basename().unwrap_or(Self::INVALID_NODE_NAME));
-        if let Some(ext) = self.get_extension() {
-            res.set_extension(ext);
+        let basename = self.get_basename().unwrap_or(Self::INVALID_NODE_NAME);
+        if self.is_root() {
+            PathBuf::from(basename)
+        } else {
+            names::presented(basename, self.get_extension())
         }
-        res
+    }
+
+    /// can the base name be presented without escaping ?
+    pub fn has_clean_name(&self) -> bool {
+        self.is_root() || self.get_basename().is_some_and(names::is_clean)
     }
 
     /// get node base name
@@ -330,33 +558,14 @@ impl Node {
 
     /// get node extension if any
     pub fn get_extension(&self) -> Option<&str> {
-        match &self.content {
-            Some(RkContentChoice::HasSome(c)) => match c.file_type {
-                RkFileType::PDF => Some("pdf"),
-                RkFileType::EPUB => Some("epub"),
-                RkFileType::Lines | RkFileType::Notebook => None, //Some("rm"),
-            },
-            _ => None,
+        if self.payload_extension.is_some() {
+            return self.payload_extension;
         }
-    }
-
-    /// get content json file path
-    pub fn get_content_path(&self, document_root: &PathBuf) -> PathBuf {
-        let mut res = PathBuf::from(document_root);
-        res.push(self.get_unique());
-        res.set_extension(Self::CONTENT_EXTENSION);
-        res
-    }
-
-    /// get content file name for pdf & epub
-    pub fn get_target_file_path(&self, document_root: &PathBuf) -> Option<PathBuf> {
-        if let Some(ext) = self.get_extension() {
-            let mut res = PathBuf::from(document_root);
-            res.push(self.get_unique());
-            res.set_extension(ext);
-            Some(res)
-        } else {
-            None
+        match self.file_type {
+            Some(RkFileType::PDF) => Some("pdf"),
+            Some(RkFileType::EPUB) => Some("epub"),
+            Some(RkFileType::Lines | RkFileType::Notebook) => None, //Some("rm"),
+            None => None,
         }
     }
 
@@ -373,19 +582,41 @@ impl Node {
         self.filestat.get_path()
     }
 
-    /// TODO: return real size from contents !
+    /// size presented : the rendered file first, then the payload
     pub fn get_size(&self) -> u64 {
+        match self.rendered_size {
+            Some(RenderedSize::Cached(size) | RenderedSize::Estimated(size)) => size,
+            None => self.get_payload_size(),
+        }
+    }
+
+    pub fn get_rendered_size(&self) -> Option<RenderedSize> {
+        self.rendered_size
+    }
+
+    /// changes the presented size, cached attributes are refreshed
+    pub fn set_rendered_size(&mut self, size: Option<RenderedSize>) {
+        if self.rendered_size != size {
+            self.rendered_size = size;
+            self.generation += 1;
+        }
+    }
+
+    /// TODO: return real size from contents !
+    pub fn get_payload_size(&self) -> u64 {
         match &self.metadata {
             Some(m) => match m.type_ {
                 RkNodeType::DocumentType => {
-                    if let Some(RkContentChoice::HasSome(c)) = &self.content {
-                        match c.file_type {
-                            RkFileType::PDF | RkFileType::EPUB => self.filestat.size().unwrap_or(0),
+                    if self.payload_extension.is_some() {
+                        self.filestat.size().unwrap_or(0)
+                    } else {
+                        match self.file_type {
+                            Some(RkFileType::PDF | RkFileType::EPUB) => {
+                                self.filestat.size().unwrap_or(0)
+                            }
                             // TODO : implement size or lines files
                             _ => 0,
                         }
-                    } else {
-                        0
                     }
                 }
                 _ => self.filestat.size().unwrap_or(0),
@@ -441,14 +672,29 @@ impl Node {
         self.parent = parent;
     }
 
-    pub fn get_children(&self, iofs: usize) -> &[FuserChild] {
-        &self.children[iofs..]
+    /// children whose readdir position is `position` or later
+    pub fn get_children(&self, position: usize) -> Vec<FuserChild> {
+        self.children
+            .iter()
+            .filter(|c| c.1 >= position)
+            .cloned()
+            .collect()
     }
 
     pub fn get_children_ino(&self) -> Vec<usize> {
         self.children.iter().map(|c| c.ino()).collect::<Vec<_>>()
     }
 
+    /// inode of the child listed as `name`, which differs from the child own
+    /// name in renaming views
+    pub fn get_child_named(&self, name: &str) -> Option<usize> {
+        self.children.iter().find(|c| c.3 == name).map(|c| c.ino())
+    }
+
+    pub fn remove_child(&mut self, ino: usize) {
+        self.children.retain(|c| c.ino() != ino);
+    }
+
     pub fn set_children(&mut self, children: &mut Vec<FuserChild>) {
         /*    let mut all_children = (self.children, children).concat();
         all_children.sort();
@@ -457,6 +703,26 @@ impl Node {
         self.children = std::mem::take(children);
     }
 
+    /// adds or replaces the child at readdir position `child.1`, children stay
+    /// ordered by position
+    pub fn upsert_child(&mut self, child: FuserChild) {
+        match self.children.binary_search_by_key(&child.1, |c| c.1) {
+            Ok(idx) => self.children[idx] = child,
+            Err(idx) => self.children.insert(idx, child),
+        }
+    }
+
+    /// moves the node to collection `parent` (uid `parent_uid`) as `visible_name`,
+    /// once its metadata file was rewritten accordingly
+    pub fn relocate(&mut self, parent: usize, parent_uid: &str, visible_name: &str) {
+        if let Some(metadata) = self.metadata.as_mut() {
+            metadata.parent = parent_uid.to_owned();
+            metadata.visible_name = visible_name.to_owned();
+        }
+        self.parent = parent;
+        self.generation += 1;
+    }
+
     pub fn needs_updating(&self, newfstat: &SshFileStat) -> bool {
         (!self.is_root())
             && (!self.is_trash())
@@ -473,12 +739,13 @@ impl Node {
             Ok(m) => {
                 self.parent = parent_ino;
                 self.metadata = Some(m);
+                self.generation += 1;
                 std::mem::swap(&mut self.filestat, newfstat);
                 Ok(self)
             }
             Err(e) => {
                 error!("invalid metadata: {}", e);
-                Err(RemarkableError::JsonError(e))
+                Err(e.into())
             }
         }
     }
@@ -486,19 +753,80 @@ impl Node {
     pub fn update_content(&mut self, contents: &str) -> Result<&Self, RemarkableError> {
         match serde_json::from_str(contents) {
             Ok(c) => {
+                self.file_type = match &c {
+                    RkContentChoice::HasSome(c) => Some(c.file_type),
+                    RkContentChoice::Emtpy {} => None,
+                };
                 self.content = Some(c);
+                self.details_size = contents.len();
+                self.generation += 1;
                 Ok(self)
             }
             Err(e) => {
                 error!("invalid contents: {}", e);
-                Err(RemarkableError::JsonError(e))
+                Err(e.into())
             }
         }
     }
 
+    /// is the parsed content in memory ?
+    pub fn has_details(&self) -> bool {
+        self.content.is_some()
+    }
+
+    /// approximate memory used by the parsed content
+    pub fn details_size(&self) -> usize {
+        self.details_size
+    }
+
+    /// tick of the last use of the parsed content
+    pub fn last_used(&self) -> u64 {
+        self.last_used
+    }
+
+    /// marks the parsed content as used at `tick`
+    pub fn touch(&mut self, tick: u64) {
+        self.last_used = tick;
+    }
+
+    /// drops the parsed content, keeping name, parent and type. Returns the freed size
+    pub fn evict_details(&mut self) -> usize {
+        self.content = None;
+        std::mem::take(&mut self.details_size)
+    }
+
+    /// page count from content, if loaded
+    pub fn get_page_count(&self) -> Option<u16> {
+        match &self.content {
+            Some(RkContentChoice::HasSome(c)) => Some(c.page_count),
+            _ => None,
+        }
+    }
+
+    /// names of the document tags, from content if loaded
+    pub fn get_tags(&self) -> Vec<String> {
+        match &self.content {
+            Some(RkContentChoice::HasSome(c)) => c.tags.iter().map(|t| t.name.clone()).collect(),
+            _ => vec![],
+        }
+    }
+
+    /// ids of the pages in document order, from content if loaded
+    pub fn get_page_ids(&self) -> Vec<String> {
+        match &self.content {
+            Some(RkContentChoice::HasSome(c)) => match (&c.c_pages, &c.pages) {
+                (Some(c_pages), _) => c_pages.pages.iter().map(|p| p.id.clone()).collect(),
+                (None, Some(pages)) => pages.clone(),
+                (None, None) => vec![],
+            },
+            _ => vec![],
+        }
+    }
+
     pub fn update_target_fstat(&mut self, filestat: &mut SshFileStat) -> &Self {
         // TODO : FIXME this has impacts on update_metadata test since it relies on filestat !!
         std::mem::swap(&mut self.filestat, filestat);
+        self.generation += 1;
         self
     }
 }
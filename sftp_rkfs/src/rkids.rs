@@ -0,0 +1,85 @@
+use crate::layout::{StorageLayout, XochitlLayout};
+use std::path::{Path, PathBuf};
+
+/// A new uid for a document or collection. xochitl names items after lowercase
+/// hyphenated v4 UUIDs, the files of an item being siblings sharing that name.
+pub fn new_uid() -> String {
+    uuid::Uuid::new_v4().hyphenated().to_string()
+}
+
+/// is `uid` a uid as written by xochitl (hyphenated, lowercase) ?
+pub fn is_uid(uid: &str) -> bool {
+    uuid::Uuid::try_parse(uid).is_ok_and(|u| u.hyphenated().to_string() == uid)
+}
+
+/// uid and extension (possibly empty) of a document root entry such as
+/// `<uid>.metadata` or `<uid>`, None when the name does not start with a uid
+pub fn split_file_name(name: &str) -> Option<(&str, &str)> {
+    let (uid, extension) = name.split_once('.').unwrap_or((name, ""));
+    is_uid(uid).then_some((uid, extension))
+}
+
+/// The remote files making up item `uid`. Not all of them exist : collections
+/// have no pages, and pages never written on have no `.rm` file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ItemFiles {
+    pub metadata: PathBuf,
+    pub content: PathBuf,
+    pub pagedata: PathBuf,
+    /// folder of the `.rm` page files
+    pub pages_dir: PathBuf,
+    pub thumbnails_dir: PathBuf,
+}
+
+impl ItemFiles {
+    /// files of `uid` under `root` with the stock firmware layout
+    pub fn new(root: &Path, uid: &str) -> Self {
+        Self::with_layout(&XochitlLayout, root, uid)
+    }
+
+    pub fn with_layout(layout: &dyn StorageLayout, root: &Path, uid: &str) -> Self {
+        Self {
+            metadata: layout.metadata_path(root, uid),
+            content: layout.content_path(root, uid),
+            pagedata: layout.payload_path(root, uid, "pagedata"),
+            pages_dir: layout.pages_dir(root, uid),
+            thumbnails_dir: layout.thumbnails_dir(root, uid),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UID: &str = "0b5d7d31-6f7a-4c55-9a4e-6b6e9f6c1a01";
+
+    #[test]
+    fn test_uids() {
+        assert!(is_uid(&new_uid()));
+        assert!(is_uid(UID));
+        assert!(!is_uid(&UID.to_uppercase()));
+        assert!(!is_uid(&UID.replace('-', "")));
+        assert!(!is_uid("trash"));
+        assert_eq!(
+            split_file_name(&format!("{UID}.metadata")),
+            Some((UID, "metadata"))
+        );
+        assert_eq!(split_file_name(UID), Some((UID, "")));
+        assert_eq!(split_file_name("notes.txt"), None);
+    }
+
+    #[test]
+    fn test_item_files() {
+        let files = ItemFiles::new(Path::new("/r"), UID);
+        assert_eq!(
+            files.metadata,
+            Path::new("/r").join(format!("{UID}.metadata"))
+        );
+        assert_eq!(
+            files.pagedata,
+            Path::new("/r").join(format!("{UID}.pagedata"))
+        );
+        assert_eq!(files.pages_dir, Path::new("/r").join(UID));
+    }
+}
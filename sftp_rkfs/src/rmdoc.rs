@@ -0,0 +1,395 @@
+use crate::fs::RemarkableFs;
+use crate::layout::StorageLayout;
+use crate::nodes::Node;
+use crate::{rkids, ErrorContext, FsError, RemarkableError, SchemaError};
+use log::{debug, info, warn};
+use std::io::{Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// `.rmdoc` bundles are plain zip archives holding every file of a document
+/// (`<uid>.metadata`, `<uid>.content`, payload, `.pagedata` and the `<uid>/` page
+/// folder) named as xochitl stores them, and mapped to the storage layout of the
+/// mount on export and import.
+impl RemarkableFs {
+    const RMDOC_METADATA_EXTENSION: &'static str = "metadata";
+    /// `<uid>.*` files of a bundle
+    const RMDOC_EXTENSIONS: [&'static str; 6] =
+        ["metadata", "content", "pagedata", "local", "pdf", "epub"];
+
+    /// Lists files making up a document, as named in a bundle : `<uid>.*` files
+    /// and the content of the page folder as `<uid>/*`. Thumbnails and caches are
+    /// left out as xochitl regenerates them.
+    fn rmdoc_remote_files(&self, uid: &str) -> Result<Vec<PathBuf>, RemarkableError> {
+        let root = self.document_root();
+        let mut files = vec![];
+        for extension in Self::RMDOC_EXTENSIONS {
+            let file = PathBuf::from(format!("{uid}.{extension}"));
+            if self
+                .session()
+                .exists(&rmdoc_remote_path(self.layout(), root, uid, &file))?
+            {
+                files.push(file);
+            }
+        }
+        let pages_dir = self.layout().pages_dir(root, uid);
+        if self.session().exists(&pages_dir)? {
+            let pages = self.session().readdir(&pages_dir)?;
+            files.extend(
+                pages
+                    .into_iter()
+                    .filter(|p| !p.is_dir())
+                    .map(|p| PathBuf::from(uid).join(p.get_path().file_name().unwrap())),
+            );
+        }
+        Ok(files)
+    }
+
+    /// Exports the document at visible path `path` into an `.rmdoc` bundle at `output`
+    pub fn export_rmdoc(&mut self, path: &str, output: &Path) -> Result<(), RemarkableError> {
+        let ino = self.resolve_path(path)?;
+        if !self.is_document(ino) {
+            return Err(FsError::NotADocument(path.to_owned()).into());
+        }
+        let uid = self.unique_id(ino).ok_or(FsError::NodeNotFound(ino))?;
+        let files = self
+            .rmdoc_remote_files(&uid)
+            .with_context(|| format!("listing files of {path} ({uid})"))?;
+        info!("exporting {path} ({uid}) : {} files", files.len());
+
+        let writer =
+            std::fs::File::create(output).with_context(|| format!("creating {output:?}"))?;
+        write_rmdoc(writer, &files, |file| {
+            debug!("adding {file:?} to {output:?}");
+            self.session().read_all(&rmdoc_remote_path(
+                self.layout(),
+                self.document_root(),
+                &uid,
+                file,
+            ))
+        })
+        .with_context(|| format!("writing {output:?}"))
+    }
+
+    /// Uploads an `.rmdoc` bundle into the collection at visible path `collection`
+    /// (root collection when `None`), and returns the uid of the imported document.
+    /// The `.metadata` file is uploaded last so xochitl never sees a partial document.
+    pub fn import_rmdoc(
+        &mut self,
+        bundle: &Path,
+        collection: Option<&str>,
+    ) -> Result<String, RemarkableError> {
+        let parent_uid = match collection {
+            Some(c) => {
+                let ino = self.resolve_path(c)?;
+                if self.is_document(ino) {
+                    return Err(FsError::NotACollection(c.to_owned()).into());
+                }
+                self.unique_id(ino).ok_or(FsError::NodeNotFound(ino))?
+            }
+            None => Node::ROOT_NODE_UID.to_string(),
+        };
+        let mut zip = std::fs::File::open(bundle)
+            .map_err(RemarkableError::from)
+            .and_then(|f| Ok(zip::ZipArchive::new(f)?))
+            .with_context(|| format!("opening {bundle:?}"))?;
+        let RmdocEntries {
+            uid,
+            metadata_name,
+            files,
+        } = rmdoc_entries(&mut zip).with_context(|| format!("checking {bundle:?}"))?;
+        let root = self.document_root().to_owned();
+        let metadata_path = self.layout().metadata_path(&root, &uid);
+        if self.session().exists(&metadata_path)? {
+            warn!("document {uid} already exists on the tablet");
+            return Err(FsError::NodeDuplicated.into());
+        }
+
+        for (idx, relpath) in files {
+            let mut entry = zip
+                .by_index(idx)
+                .with_context(|| format!("reading entry {idx} of {bundle:?}"))?;
+            let target = rmdoc_remote_path(self.layout(), &root, &uid, &relpath);
+            // folders between the document root and the file, outermost first
+            let mut dirs: Vec<&Path> = target
+                .ancestors()
+                .skip(1)
+                .take_while(|dir| dir.starts_with(&root) && *dir != root)
+                .collect();
+            dirs.reverse();
+            for dir in dirs {
+                self.session().mkdir(dir)?;
+            }
+            let mut data = vec![];
+            entry.read_to_end(&mut data)?;
+            debug!("uploading {target:?} ({} bytes)", data.len());
+            self.session()
+                .write_all(&target, &data)
+                .with_context(|| format!("importing {bundle:?}"))?;
+        }
+
+        // re-home the document under the requested collection
+        let mut metadata = String::new();
+        zip.by_name(&metadata_name)
+            .map_err(RemarkableError::from)
+            .and_then(|mut m| Ok(m.read_to_string(&mut metadata)?))
+            .with_context(|| format!("reading {metadata_name} of {bundle:?}"))?;
+        let metadata = rehome_metadata(&metadata, &parent_uid)
+            .with_context(|| format!("parsing {metadata_name} of {bundle:?}"))?;
+        self.session().write_all(&metadata_path, &metadata)?;
+        info!("imported {bundle:?} as {uid}");
+        Ok(uid)
+    }
+}
+
+/// Writes an `.rmdoc` bundle of `files`, named as in the bundle, their content
+/// being read with `read`
+fn write_rmdoc(
+    writer: impl Write + Seek,
+    files: &[PathBuf],
+    mut read: impl FnMut(&Path) -> Result<Vec<u8>, RemarkableError>,
+) -> Result<(), RemarkableError> {
+    let mut zip = zip::ZipWriter::new(writer);
+    let options = zip::write::SimpleFileOptions::default();
+    for file in files {
+        let data = read(file)?;
+        zip.start_file(file.to_string_lossy(), options)
+            .with_context(|| format!("adding {file:?}"))?;
+        zip.write_all(&data)
+            .with_context(|| format!("adding {file:?}"))?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// The entries of an `.rmdoc` bundle, all checked to be files of the bundled
+/// document
+#[derive(Debug)]
+struct RmdocEntries {
+    uid: String,
+    /// `<uid>.metadata`, uploaded last
+    metadata_name: String,
+    /// index and path in the bundle of the other files
+    files: Vec<(usize, PathBuf)>,
+}
+
+/// Checks the entries of `zip` : a bundle holds a single document, whose files
+/// are `<uid>.*` or in its `<uid>/` page folder. Anything else, such as files
+/// of other documents or paths escaping the document root, refuses the whole
+/// bundle before anything is uploaded.
+fn rmdoc_entries<R: Read + Seek>(
+    zip: &mut zip::ZipArchive<R>,
+) -> Result<RmdocEntries, RemarkableError> {
+    let metadata_name = zip
+        .file_names()
+        .find(|n| {
+            !n.contains('/')
+                && Path::new(n).extension() == Some(RemarkableFs::RMDOC_METADATA_EXTENSION.as_ref())
+        })
+        .map(str::to_owned)
+        .ok_or(SchemaError::Invalid("no metadata entry".to_owned()))?;
+    let uid = Path::new(&metadata_name)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .filter(|uid| rkids::is_uid(uid))
+        .ok_or(SchemaError::Invalid(format!(
+            "{metadata_name} is not named after a document uid"
+        )))?
+        .to_owned();
+    let mut files = vec![];
+    for idx in 0..zip.len() {
+        let entry = zip.by_index(idx)?;
+        let relpath = entry
+            .enclosed_name()
+            .filter(|relpath| is_document_file(&uid, relpath))
+            .ok_or(SchemaError::Invalid(format!(
+                "{} is not a file of document {uid}",
+                entry.name()
+            )))?;
+        if !entry.is_dir() && relpath != Path::new(&metadata_name) {
+            files.push((idx, relpath));
+        }
+    }
+    Ok(RmdocEntries {
+        uid,
+        metadata_name,
+        files,
+    })
+}
+
+/// is `relpath` one of the files of document `uid` : `<uid>.*`, or below the
+/// `<uid>/` page folder or a `<uid>.*/` folder ?
+fn is_document_file(uid: &str, relpath: &Path) -> bool {
+    let mut components = relpath.components();
+    let Some(Component::Normal(first)) = components.next() else {
+        return false;
+    };
+    let Some(first) = first.to_str() else {
+        return false;
+    };
+    first
+        .strip_prefix(uid)
+        .is_some_and(|rest| rest.starts_with('.') || rest.is_empty() && components.next().is_some())
+}
+
+/// Remote path of `relpath`, a file of document `uid` named as in a bundle, in
+/// `layout` : `<uid>.<extension>` files, the `<uid>/` page folder and
+/// `<uid>.<extension>/` folders such as thumbnails
+fn rmdoc_remote_path(
+    layout: &dyn StorageLayout,
+    root: &Path,
+    uid: &str,
+    relpath: &Path,
+) -> PathBuf {
+    let mut components = relpath.components();
+    let first = components
+        .next()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let base = match first.strip_prefix(uid).and_then(|r| r.strip_prefix('.')) {
+        None => layout.pages_dir(root, uid),
+        Some("metadata") => layout.metadata_path(root, uid),
+        Some("content") => layout.content_path(root, uid),
+        Some("thumbnails") => layout.thumbnails_dir(root, uid),
+        Some(extension) => layout.payload_path(root, uid, extension),
+    };
+    let rest = components.as_path();
+    if rest.as_os_str().is_empty() {
+        base
+    } else {
+        base.join(rest)
+    }
+}
+
+/// `metadata` moved to collection `parent_uid`
+fn rehome_metadata(metadata: &str, parent_uid: &str) -> Result<Vec<u8>, RemarkableError> {
+    let mut metadata: serde_json::Value = serde_json::from_str(metadata)?;
+    metadata["parent"] = serde_json::Value::String(parent_uid.to_owned());
+    Ok(serde_json::to_string_pretty(&metadata)?.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::XochitlLayout;
+    use std::io::Cursor;
+
+    const UID: &str = "0a8e8e4e-7b5a-4d4e-9c1e-0e6b0a4c2d11";
+
+    fn bundle(names: &[String]) -> zip::ZipArchive<Cursor<Vec<u8>>> {
+        let files: Vec<PathBuf> = names.iter().map(PathBuf::from).collect();
+        let mut data = Cursor::new(vec![]);
+        write_rmdoc(&mut data, &files, |file| {
+            Ok(file.to_string_lossy().into_owned().into_bytes())
+        })
+        .unwrap();
+        zip::ZipArchive::new(data).unwrap()
+    }
+
+    #[test]
+    fn test_export_import() {
+        let names = [
+            format!("{UID}.content"),
+            format!("{UID}/0d6f1a2b.rm"),
+            format!("{UID}.metadata"),
+            format!("{UID}.thumbnails/0d6f1a2b.png"),
+        ];
+        let mut zip = bundle(&names);
+        let entries = rmdoc_entries(&mut zip).unwrap();
+        assert_eq!(entries.uid, UID);
+        assert_eq!(entries.metadata_name, format!("{UID}.metadata"));
+        let files: Vec<_> = entries.files.iter().map(|(_, p)| p.clone()).collect();
+        assert_eq!(
+            files,
+            [&names[0], &names[1], &names[3]]
+                .map(PathBuf::from)
+                .to_vec()
+        );
+        let mut page = String::new();
+        zip.by_index(entries.files[1].0)
+            .unwrap()
+            .read_to_string(&mut page)
+            .unwrap();
+        assert_eq!(page, names[1]);
+
+        let metadata = rehome_metadata(r#"{"parent":"","visibleName":"Spec"}"#, "trash").unwrap();
+        let metadata: serde_json::Value = serde_json::from_slice(&metadata).unwrap();
+        assert_eq!(metadata["parent"], "trash");
+        assert_eq!(metadata["visibleName"], "Spec");
+    }
+
+    /// every file of a document in a `<uid>/` folder
+    struct FolderLayout;
+
+    impl StorageLayout for FolderLayout {
+        fn metadata_path(&self, root: &Path, uid: &str) -> PathBuf {
+            root.join(uid).join("metadata.json")
+        }
+        fn content_path(&self, root: &Path, uid: &str) -> PathBuf {
+            root.join(uid).join("content.json")
+        }
+        fn payload_path(&self, root: &Path, uid: &str, extension: &str) -> PathBuf {
+            root.join(uid).join(format!("document.{extension}"))
+        }
+        fn pages_dir(&self, root: &Path, uid: &str) -> PathBuf {
+            root.join(uid).join("pages")
+        }
+        fn thumbnails_dir(&self, root: &Path, uid: &str) -> PathBuf {
+            root.join(uid).join("thumbnails")
+        }
+        fn metadata_glob(&self, root: &Path) -> String {
+            format!("{}/*/metadata.json", root.to_string_lossy())
+        }
+        fn content_glob(&self, root: &Path) -> String {
+            format!("{}/*/content.json", root.to_string_lossy())
+        }
+    }
+
+    #[test]
+    fn test_remote_path() {
+        let root = Path::new("/home/root/.local/share/remarkable/xochitl");
+        let remote = |layout: &dyn StorageLayout, relpath: String| {
+            rmdoc_remote_path(layout, root, UID, Path::new(&relpath))
+        };
+        for relpath in [
+            format!("{UID}.metadata"),
+            format!("{UID}.pdf"),
+            format!("{UID}/0d6f1a2b.rm"),
+            format!("{UID}.thumbnails/0d6f1a2b.png"),
+        ] {
+            assert_eq!(remote(&XochitlLayout, relpath.clone()), root.join(relpath));
+        }
+        let folder = root.join(UID);
+        for (relpath, path) in [
+            (format!("{UID}.metadata"), "metadata.json"),
+            (format!("{UID}.content"), "content.json"),
+            (format!("{UID}.pdf"), "document.pdf"),
+            (format!("{UID}.pagedata"), "document.pagedata"),
+            (format!("{UID}/0d6f1a2b.rm"), "pages/0d6f1a2b.rm"),
+            (
+                format!("{UID}.thumbnails/0d6f1a2b.png"),
+                "thumbnails/0d6f1a2b.png",
+            ),
+        ] {
+            assert_eq!(remote(&FolderLayout, relpath), folder.join(path));
+        }
+    }
+
+    #[test]
+    fn test_import_foreign_entries() {
+        let other = "5b1c4f0e-2d3a-4b6c-8e9f-1a2b3c4d5e6f";
+        for foreign in [
+            format!("{other}.content"),
+            format!("{other}/0d6f1a2b.rm"),
+            format!("{UID}x.content"),
+            UID.to_owned(),
+            format!("../{UID}.content"),
+            format!("/{UID}.content"),
+            "xochitl.conf".to_owned(),
+        ] {
+            let mut zip = bundle(&[format!("{UID}.metadata"), foreign.clone()]);
+            assert!(rmdoc_entries(&mut zip).is_err(), "{foreign} accepted");
+        }
+        assert!(rmdoc_entries(&mut bundle(&[format!("{UID}.content")])).is_err());
+        assert!(rmdoc_entries(&mut bundle(&["Spec.metadata".to_owned()])).is_err());
+    }
+}
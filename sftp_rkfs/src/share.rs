@@ -0,0 +1,329 @@
+use crate::sshutils::CommandRunner;
+use crate::{CommandOutput, RemarkableError, TransportError};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+/// Request sent on the control socket, as a JSON line
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Request {
+    /// host key of the tablet, checked by clients as for a connection of
+    /// their own
+    Hello,
+    Exec {
+        command: String,
+    },
+    Read {
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+    },
+    Stat {
+        path: PathBuf,
+    },
+}
+
+/// Answer to a request, a JSON line followed by `len` bytes : the standard
+/// output of a command, the bytes read
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+struct Reply {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(default)]
+    not_found: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fingerprint: Option<String>,
+    #[serde(default)]
+    status: i32,
+    #[serde(default)]
+    stderr: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stat: Option<Stat>,
+    #[serde(default)]
+    len: u64,
+}
+
+/// sftp attributes of a remote file
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq)]
+struct Stat {
+    size: Option<u64>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    perm: Option<u32>,
+    atime: Option<u64>,
+    mtime: Option<u64>,
+}
+
+impl From<&ssh2::FileStat> for Stat {
+    fn from(stat: &ssh2::FileStat) -> Self {
+        Self {
+            size: stat.size,
+            uid: stat.uid,
+            gid: stat.gid,
+            perm: stat.perm,
+            atime: stat.atime,
+            mtime: stat.mtime,
+        }
+    }
+}
+
+impl From<Stat> for ssh2::FileStat {
+    fn from(stat: Stat) -> Self {
+        Self {
+            size: stat.size,
+            uid: stat.uid,
+            gid: stat.gid,
+            perm: stat.perm,
+            atime: stat.atime,
+            mtime: stat.mtime,
+        }
+    }
+}
+
+/// Serves the connection of a mount to other processes on a unix socket, so
+/// that commands run while the tablet is mounted do not open a second ssh
+/// session. Only commands, file reads and stats are served. The socket is
+/// removed when dropped.
+pub(crate) struct ShareServer {
+    path: PathBuf,
+}
+
+impl ShareServer {
+    /// Listens on `path`, requests being run with `runner`. The socket is only
+    /// accessible to the user, it runs commands as the tablet user
+    pub(crate) fn spawn(
+        path: &Path,
+        runner: CommandRunner,
+        fingerprint: Option<String>,
+    ) -> std::io::Result<Self> {
+        // left behind by a mount that did not exit cleanly
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        info!("connection shared on {path:?}");
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let (runner, fingerprint) = (runner.clone(), fingerprint.clone());
+                        std::thread::spawn(move || serve(stream, &runner, fingerprint.as_deref()));
+                    }
+                    Err(e) => warn!("control socket connection failed: {e}"),
+                }
+            }
+        });
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for ShareServer {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            debug!("control socket {:?} left behind: {e}", self.path);
+        }
+    }
+}
+
+/// answers the requests of a client until it leaves
+fn serve(stream: UnixStream, runner: &CommandRunner, fingerprint: Option<&str>) {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = &stream;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                debug!("control socket client lost: {e}");
+                break;
+            }
+        }
+        let (reply, data) = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => answer(request, runner, fingerprint),
+            Err(e) => (failed(&e.into()), vec![]),
+        };
+        if let Err(e) = send(&mut writer, reply, &data) {
+            debug!("control socket client lost: {e}");
+            break;
+        }
+    }
+}
+
+fn answer(request: Request, runner: &CommandRunner, fingerprint: Option<&str>) -> (Reply, Vec<u8>) {
+    debug!("shared connection request {request:?}");
+    let answered = match request {
+        Request::Hello => Ok((
+            Reply {
+                fingerprint: fingerprint.map(str::to_owned),
+                ..Default::default()
+            },
+            vec![],
+        )),
+        Request::Exec { command } => runner.run_command(&command).map(|out| {
+            let reply = Reply {
+                status: out.status,
+                stderr: out.stderr,
+                ..Default::default()
+            };
+            (reply, out.stdout.into_bytes())
+        }),
+        Request::Read { path, offset, len } => runner
+            .read_range(&path, offset, len)
+            .map(|data| (Reply::default(), data)),
+        Request::Stat { path } => runner.stat(&path).map(|stat| {
+            let reply = Reply {
+                stat: Some(Stat::from(&stat)),
+                ..Default::default()
+            };
+            (reply, vec![])
+        }),
+    };
+    answered.unwrap_or_else(|e| (failed(&e), vec![]))
+}
+
+fn failed(e: &RemarkableError) -> Reply {
+    Reply {
+        error: Some(e.to_string()),
+        not_found: e.is_not_found(),
+        ..Default::default()
+    }
+}
+
+fn send(out: &mut impl Write, mut reply: Reply, data: &[u8]) -> std::io::Result<()> {
+    reply.len = data.len() as u64;
+    let mut line = serde_json::to_string(&reply).map_err(std::io::Error::other)?;
+    line.push('\n');
+    out.write_all(line.as_bytes())?;
+    out.write_all(data)?;
+    out.flush()
+}
+
+fn receive(input: &mut impl BufRead) -> std::io::Result<(Reply, Vec<u8>)> {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    let reply: Reply = serde_json::from_str(&line).map_err(std::io::Error::other)?;
+    let mut data = vec![0; reply.len as usize];
+    input.read_exact(&mut data)?;
+    Ok((reply, data))
+}
+
+/// Connection of a running mount, reached through its control socket
+pub(crate) struct SharedConnection {
+    stream: RefCell<BufReader<UnixStream>>,
+    fingerprint: Option<String>,
+}
+
+impl SharedConnection {
+    /// Connects to the mount serving the control socket `path`
+    pub(crate) fn connect(path: &Path) -> Result<Self, RemarkableError> {
+        let stream = UnixStream::connect(path)?;
+        let mut shared = Self {
+            stream: RefCell::new(BufReader::new(stream)),
+            fingerprint: None,
+        };
+        let (hello, _) = shared.request(&Request::Hello)?;
+        shared.fingerprint = hello.fingerprint;
+        Ok(shared)
+    }
+
+    /// host key of the tablet the mount is connected to
+    pub(crate) fn fingerprint(&self) -> Option<String> {
+        self.fingerprint.clone()
+    }
+
+    fn request(&self, request: &Request) -> Result<(Reply, Vec<u8>), RemarkableError> {
+        let mut stream = self.stream.borrow_mut();
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+        stream.get_mut().write_all(line.as_bytes())?;
+        let (reply, data) = receive(&mut *stream)?;
+        match reply.error {
+            Some(error) if reply.not_found => {
+                Err(std::io::Error::new(std::io::ErrorKind::NotFound, error).into())
+            }
+            Some(error) => Err(TransportError::Shared(error).into()),
+            None => Ok((reply, data)),
+        }
+    }
+
+    pub(crate) fn run_command(&self, command: &str) -> Result<CommandOutput, RemarkableError> {
+        let (reply, stdout) = self.request(&Request::Exec {
+            command: command.to_owned(),
+        })?;
+        Ok(CommandOutput {
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+            stderr: reply.stderr,
+            status: reply.status,
+        })
+    }
+
+    /// `len` bytes at `offset` of the remote file `path`, fewer when it ends
+    /// before
+    pub(crate) fn read_range(
+        &self,
+        path: &Path,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>, RemarkableError> {
+        let request = Request::Read {
+            path: path.to_path_buf(),
+            offset,
+            len,
+        };
+        Ok(self.request(&request)?.1)
+    }
+
+    pub(crate) fn stat(&self, path: &Path) -> Result<ssh2::FileStat, RemarkableError> {
+        let (reply, _) = self.request(&Request::Stat {
+            path: path.to_path_buf(),
+        })?;
+        Ok(reply.stat.unwrap_or_default().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reply_framing() {
+        let mut wire = vec![];
+        let reply = Reply {
+            status: 1,
+            stderr: "no such file".to_owned(),
+            ..Default::default()
+        };
+        send(&mut wire, reply, b"partial\noutput").unwrap();
+        send(&mut wire, Reply::default(), b"").unwrap();
+        let mut input = &wire[..];
+        let (reply, data) = receive(&mut input).unwrap();
+        assert_eq!((reply.status, reply.len), (1, 14));
+        assert_eq!(data, b"partial\noutput");
+        assert_eq!(receive(&mut input).unwrap(), (Reply::default(), vec![]));
+        assert!(receive(&mut input).is_err());
+        let request = Request::Read {
+            path: PathBuf::from("/x/a.pdf"),
+            offset: 4,
+            len: 8,
+        };
+        let line = serde_json::to_string(&request).unwrap();
+        assert_eq!(line, r#"{"read":{"path":"/x/a.pdf","offset":4,"len":8}}"#);
+        assert_eq!(serde_json::from_str::<Request>(&line).unwrap(), request);
+    }
+}
@@ -1,13 +1,808 @@
-use crate::RemarkableError;
-use log::{debug, info};
+use crate::auth::{AuthProvider, InteractivePrompter};
+use crate::hostkeys;
+use crate::share::SharedConnection;
+use crate::trace;
+use crate::{ErrorContext, FsError, RemarkableError, TransportError};
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use base64::Engine;
+use log::{debug, info, warn};
+use std::cell::{Cell, Ref, RefCell};
+use std::collections::HashMap;
 use std::ffi::OsStr;
-use std::io::{Read, Seek};
-use std::net::TcpStream;
+use std::io::{Read, Seek, Write};
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6, TcpStream, ToSocketAddrs};
 use std::path::{Path, PathBuf};
-use std::time::{Duration, SystemTime};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
 
 pub struct SshWrapper {
     session: ssh2::Session,
+    /// host given to connect, passed to authentication providers
+    host: String,
+    port: u16,
+    options: SocketOptions,
+    /// user and credentials of the last successful authentication, kept to
+    /// reconnect after the tablet dropped the connection
+    credentials: Option<(String, Box<dyn AuthProvider>)>,
+    /// connections tried by `reconnect` before giving up
+    reconnect_attempts: u32,
+    /// connections tried again by `connect` after a failure
+    connect_retries: u32,
+    /// time given to the TCP connection to the tablet, None for the system one
+    connect_timeout: Option<Duration>,
+    /// time a blocking ssh call waits for the tablet, None for no limit
+    io_timeout: Option<Duration>,
+    /// period of the ssh keepalive messages, None for none
+    keepalive_interval: Option<Duration>,
+    /// stops the keepalive thread of the session when dropped
+    keepalive: Option<mpsc::Sender<()>>,
+    /// minimum delay between two remote commands, zero for no throttling
+    command_interval: Duration,
+    last_command: Cell<Option<Instant>>,
+    /// offer the keys of the ssh agent before any other credential
+    use_agent: bool,
+    /// only host key accepted, instead of the ones of known_hosts
+    pinned_host_key: Option<String>,
+    /// sftp channel shared by file operations, opened on first use and dropped
+    /// once it fails
+    sftp: RefCell<Option<ssh2::Sftp>>,
+    /// connection of a running mount used instead of the session, if any
+    shared: Option<SharedConnection>,
+}
+
+/// Small remote commands run in a single shell invocation, saving a round trip
+/// per command. Outputs are told apart by a delimiter line; when they cannot be,
+/// the commands are run one by one instead.
+#[derive(Debug, Default)]
+pub struct RemoteBatch {
+    commands: Vec<String>,
+}
+
+/// Output and exit status of a remote command
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub status: i32,
+}
+
+/// Anything remote commands can be run on : the session itself, or a
+/// `CommandRunner` taken from it for another thread
+pub trait RunCommand {
+    fn execute_cmd(&self, command: &str) -> Result<String, RemarkableError>;
+}
+
+/// Runs remote commands and file reads on the session it was taken from, from
+/// another thread. Its commands are not throttled.
+#[derive(Clone)]
+pub struct CommandRunner {
+    session: ssh2::Session,
+}
+
+/// A remote file kept open between reads. Reads following each other need no
+/// seek, the sftp layer reading ahead for them. The file is closed when dropped
+pub struct RemoteFile {
+    path: PathBuf,
+    file: ssh2::File,
+    /// offset the next read starts at without a seek
+    position: u64,
+}
+
+/// Remote files read and stat'ed ahead of their use, in a single remote command
+#[derive(Debug, Default)]
+pub struct Prefetch {
+    contents: HashMap<PathBuf, String>,
+    /// stat of every requested path, None for the missing ones
+    stats: HashMap<PathBuf, Option<SshFileStat>>,
+}
+
+/// TCP tuning of the connection to the tablet
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+    /// disables Nagle's algorithm so that small sftp requests leave immediately
+    pub nodelay: bool,
+    /// idle time before TCP keepalive probes, also used as the probe interval.
+    /// None leaves keepalive off
+    pub keepalive: Option<Duration>,
+    /// SO_SNDBUF size in bytes, None keeps the system default
+    pub send_buffer: Option<usize>,
+    /// SO_RCVBUF size in bytes, None keeps the system default
+    pub recv_buffer: Option<usize>,
+}
+
+impl Default for SocketOptions {
+    /// interactive use : Nagle off, system keepalive and buffers
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+            send_buffer: None,
+            recv_buffer: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// applies the options to a connected stream
+    fn apply(&self, tcp: &TcpStream) -> std::io::Result<()> {
+        use std::os::fd::AsRawFd;
+        let fd = tcp.as_raw_fd();
+        tcp.set_nodelay(self.nodelay)?;
+        if let Some(idle) = self.keepalive {
+            let secs = idle.as_secs().clamp(1, libc::c_int::MAX as u64) as libc::c_int;
+            set_socket_option(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+            #[cfg(target_os = "linux")]
+            set_socket_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, secs)?;
+            #[cfg(target_os = "macos")]
+            set_socket_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPALIVE, secs)?;
+            set_socket_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, secs)?;
+        }
+        if let Some(size) = self.send_buffer {
+            set_socket_option(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, buffer_size(size))?;
+        }
+        if let Some(size) = self.recv_buffer {
+            set_socket_option(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, buffer_size(size))?;
+        }
+        Ok(())
+    }
+}
+
+fn buffer_size(size: usize) -> libc::c_int {
+    size.min(libc::c_int::MAX as usize) as libc::c_int
+}
+
+/// sets an integer socket option on `fd`
+fn set_socket_option(
+    fd: std::os::fd::RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> std::io::Result<()> {
+    // SAFETY: fd is an open socket borrowed from a live TcpStream and value is a
+    // c_int living for the whole call, as setsockopt expects for these options
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Settings of the OpenSSH client configuration that apply to a host
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SshHostConfig {
+    /// real host name when the host given is an alias
+    pub host_name: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    /// private keys, in the order they are tried
+    pub identity_files: Vec<PathBuf>,
+}
+
+/// The `Host` sections of an OpenSSH client configuration (`~/.ssh/config`),
+/// resolved as `ssh` does : for each setting, the first value of the sections
+/// matching the host wins. `Match` sections and `Include` are not supported.
+#[derive(Debug, Clone, Default)]
+pub struct SshConfig {
+    sections: Vec<HostSection>,
+}
+
+/// A `Host` line and the settings following it
+#[derive(Debug, Clone)]
+struct HostSection {
+    patterns: Vec<String>,
+    /// (lowercase keyword, value)
+    settings: Vec<(String, String)>,
+}
+
+impl SshConfig {
+    /// `~/.ssh/config`, empty when missing or unreadable
+    pub fn load() -> Self {
+        std::env::var_os("HOME")
+            .map(|h| PathBuf::from(h).join(".ssh").join("config"))
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .map(|text| Self::parse(&text))
+            .unwrap_or_default()
+    }
+
+    pub fn parse(text: &str) -> Self {
+        // settings before the first Host line apply to all hosts
+        let mut sections = vec![HostSection {
+            patterns: vec!["*".to_string()],
+            settings: vec![],
+        }];
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (keyword, value) = match line.find(|c: char| c.is_whitespace() || c == '=') {
+                Some(at) => (
+                    &line[..at],
+                    line[at..].trim_start_matches(|c: char| c.is_whitespace() || c == '='),
+                ),
+                None => (line, ""),
+            };
+            let keyword = keyword.to_lowercase();
+            match keyword.as_str() {
+                "host" => sections.push(HostSection {
+                    patterns: value.split_whitespace().map(str::to_owned).collect(),
+                    settings: vec![],
+                }),
+                // never matching, their criteria are not evaluated
+                "match" => sections.push(HostSection {
+                    patterns: vec![],
+                    settings: vec![],
+                }),
+                _ => {
+                    if let Some(section) = sections.last_mut() {
+                        section
+                            .settings
+                            .push((keyword, value.trim_matches('"').to_owned()));
+                    }
+                }
+            }
+        }
+        Self { sections }
+    }
+
+    /// Settings for connecting to `host`
+    pub fn resolve(&self, host: &str) -> SshHostConfig {
+        let mut config = SshHostConfig::default();
+        let home = std::env::var("HOME").unwrap_or_default();
+        let settings = self
+            .sections
+            .iter()
+            .filter(|section| host_matches(&section.patterns, host))
+            .flat_map(|section| &section.settings);
+        for (keyword, value) in settings {
+            match keyword.as_str() {
+                "hostname" if config.host_name.is_none() => {
+                    config.host_name = Some(value.replace("%h", host))
+                }
+                "port" if config.port.is_none() => config.port = value.parse().ok(),
+                "user" if config.user.is_none() => config.user = Some(value.clone()),
+                "identityfile" => {
+                    let path = match value.strip_prefix("~/") {
+                        Some(rest) => format!("{home}/{rest}"),
+                        None => value.replace("%d", &home).replace("%h", host),
+                    };
+                    config.identity_files.push(PathBuf::from(path));
+                }
+                _ => {}
+            }
+        }
+        config
+    }
+}
+
+/// does `host` match one of `patterns` and none of the negated (`!`) ones ?
+fn host_matches(patterns: &[String], host: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns {
+        match pattern.strip_prefix('!') {
+            Some(negated) if wildcard_match(negated, host) => return false,
+            Some(_) => {}
+            None => matched |= wildcard_match(pattern, host),
+        }
+    }
+    matched
+}
+
+/// `*` and `?` wildcard matching, case insensitive as host names are
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.to_lowercase().chars().collect::<Vec<_>>();
+    let text = text.to_lowercase().chars().collect::<Vec<_>>();
+    // positions to resume from after the last `*`
+    let (mut p, mut t, mut star) = (0, 0, None);
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((sp, st)) => {
+                    p = sp + 1;
+                    t = st + 1;
+                    star = Some((sp, st + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Addresses to try for `host`: a host name, an IPv4 address or an IPv6 literal
+/// with an optional `%scope` (interface name or index, as in `fe80::1%wlan0`).
+/// Any of them may carry a `:port` overriding `port`, IPv6 ones when bracketed
+/// (`[fe80::1%wlan0]:2222`).
+pub(crate) fn resolve_host(host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+    let (host, port) = split_port(host, port)?;
+    let (ip, scope) = match host.split_once('%') {
+        Some((ip, scope)) => (ip, Some(scope)),
+        None => (host, None),
+    };
+    if let Ok(ip) = ip.parse::<Ipv6Addr>() {
+        let scope_id = scope.map(scope_index).transpose()?.unwrap_or(0);
+        return Ok(vec![SocketAddr::V6(SocketAddrV6::new(
+            ip, port, 0, scope_id,
+        ))]);
+    }
+    if scope.is_some() {
+        return Err(invalid_host(host, "scope on a non IPv6 address"));
+    }
+    Ok((host, port).to_socket_addrs()?.collect())
+}
+
+/// splits an optional `:port` off `host`
+pub(crate) fn split_port(host: &str, port: u16) -> std::io::Result<(&str, u16)> {
+    let parse_port = |p: &str| p.parse().map_err(|_| invalid_host(host, "invalid port"));
+    if let Some(rest) = host.strip_prefix('[') {
+        let (inner, after) = rest
+            .split_once(']')
+            .ok_or_else(|| invalid_host(host, "missing ]"))?;
+        return match after.strip_prefix(':') {
+            Some(p) => Ok((inner, parse_port(p)?)),
+            None if after.is_empty() => Ok((inner, port)),
+            None => Err(invalid_host(host, "unexpected text after ]")),
+        };
+    }
+    // a single colon separates a port, several make an IPv6 literal
+    match host.split_once(':') {
+        Some((name, p)) if !p.contains(':') => Ok((name, parse_port(p)?)),
+        _ => Ok((host, port)),
+    }
+}
+
+/// interface index of an IPv6 scope given by name or number
+fn scope_index(scope: &str) -> std::io::Result<u32> {
+    if let Ok(index) = scope.parse() {
+        return Ok(index);
+    }
+    let name = std::ffi::CString::new(scope).map_err(|_| invalid_host(scope, "invalid scope"))?;
+    // SAFETY: name is a NUL terminated string living for the whole call
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(invalid_host(scope, "unknown network interface")),
+        index => Ok(index),
+    }
+}
+
+impl RemoteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// queues `command`, returns the index of its output in the `run` results
+    pub fn push(&mut self, command: &str) -> usize {
+        self.commands.push(command.to_owned());
+        self.commands.len() - 1
+    }
+
+    /// shell script running each command in a subshell, its output followed by
+    /// a `delimiter` line
+    fn script(&self, delimiter: &str) -> String {
+        self.commands
+            .iter()
+            .map(|command| format!("( {command}\n)\nprintf '\\n%s\\n' '{delimiter}'\n"))
+            .collect()
+    }
+
+    /// Runs the queued commands, returns their outputs in push order. The batch
+    /// counts as a single command for the session throttle.
+    pub fn run(&self, session: &dyn RunCommand) -> Result<Vec<String>, RemarkableError> {
+        if self.commands.len() < 2 {
+            return self.run_singly(session);
+        }
+        let delimiter = format!("--rmkmount-batch-{}--", crate::rkids::new_uid());
+        let out = session.execute_cmd(&self.script(&delimiter))?;
+        match split_batch_output(&out, &delimiter, self.commands.len()) {
+            Some(outputs) => Ok(outputs),
+            None => {
+                warn!(
+                    "could not split the output of {} batched commands, running them one by one",
+                    self.commands.len()
+                );
+                self.run_singly(session)
+            }
+        }
+    }
+
+    fn run_singly(&self, session: &dyn RunCommand) -> Result<Vec<String>, RemarkableError> {
+        self.commands
+            .iter()
+            .map(|command| session.execute_cmd(command))
+            .collect()
+    }
+}
+
+impl RunCommand for SshWrapper {
+    fn execute_cmd(&self, command: &str) -> Result<String, RemarkableError> {
+        SshWrapper::execute_cmd(self, command)
+    }
+}
+
+impl RunCommand for CommandRunner {
+    fn execute_cmd(&self, command: &str) -> Result<String, RemarkableError> {
+        trace::ssh_call(format_args!("exec `{command}` from a runner"));
+        run_on(&self.session, command)
+    }
+}
+
+impl CommandRunner {
+    /// Reads `len` bytes at `offset` of the remote file `path`, fewer when it
+    /// ends before
+    pub fn read_range(
+        &self,
+        path: &Path,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>, RemarkableError> {
+        trace::ssh_call(format_args!(
+            "read {len} bytes at {offset} of {path:?} from a runner"
+        ));
+        let context = || format!("reading {len} bytes at {offset} of {path:?}");
+        let mut file = self
+            .session
+            .sftp()?
+            .open(path)
+            .with_context(|| format!("opening {path:?}"))?;
+        file.seek(std::io::SeekFrom::Start(offset))
+            .with_context(context)?;
+        let capacity = len.min(SshWrapper::TRANSFER_CHUNK_SIZE as u64);
+        let mut buf = Vec::with_capacity(capacity as usize);
+        file.take(len).read_to_end(&mut buf).with_context(context)?;
+        Ok(buf)
+    }
+
+    /// Runs `command` and returns its output streams and exit status
+    pub fn run_command(&self, command: &str) -> Result<CommandOutput, RemarkableError> {
+        trace::ssh_call(format_args!("run `{command}` from a runner"));
+        run_output_on(&self.session, command)
+    }
+
+    pub fn stat(&self, path: &Path) -> Result<ssh2::FileStat, RemarkableError> {
+        trace::ssh_call(format_args!("stat {path:?} from a runner"));
+        let stat = self
+            .session
+            .sftp()?
+            .stat(path)
+            .with_context(|| format!("stat {path:?}"))?;
+        Ok(stat)
+    }
+}
+
+impl RemoteFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads `buf.len()` bytes at `offset`, checking `cancelled` between chunks
+    pub fn read_at(
+        &mut self,
+        offset: u64,
+        buf: &mut [u8],
+        cancelled: &dyn Fn() -> bool,
+    ) -> Result<(), RemarkableError> {
+        trace::ssh_call(format_args!(
+            "read {} bytes at {offset} of open {:?}",
+            buf.len(),
+            self.path
+        ));
+        if offset != self.position {
+            self.file
+                .seek(std::io::SeekFrom::Start(offset))
+                .with_context(|| format!("seeking {offset} in {:?}", self.path))?;
+        }
+        // unknown until the read completes
+        self.position = u64::MAX;
+        read_chunks(&mut self.file, &self.path, offset, buf, cancelled)?;
+        self.position = offset + buf.len() as u64;
+        Ok(())
+    }
+}
+
+impl Prefetch {
+    /// Reads the files `reads` and stats the files `stats` in one remote command.
+    /// Missing files are left out instead of failing the whole prefetch.
+    pub fn fetch(
+        shell: &dyn RunCommand,
+        reads: &[String],
+        stats: &[String],
+    ) -> Result<Self, RemarkableError> {
+        let mut prefetch = Self::fetch_args(shell, &quote_paths(reads), &quote_paths(stats))?;
+        for path in stats {
+            prefetch.stats.entry(PathBuf::from(path)).or_insert(None);
+        }
+        Ok(prefetch)
+    }
+
+    /// Reads the files matching the shell globs `reads` and stats those matching
+    /// `stats` in one remote command, a whole library in a single round trip.
+    /// Missing files are not known, see `mark_missing`
+    pub fn fetch_globs(
+        shell: &dyn RunCommand,
+        reads: &[String],
+        stats: &[String],
+    ) -> Result<Self, RemarkableError> {
+        Self::fetch_args(shell, &reads.join(" "), &stats.join(" "))
+    }
+
+    /// reads and stats the files given as arguments to the remote commands
+    fn fetch_args(
+        shell: &dyn RunCommand,
+        reads: &str,
+        stats: &str,
+    ) -> Result<Self, RemarkableError> {
+        let delimiter = files_delimiter();
+        let mut batch = RemoteBatch::new();
+        batch.push(&format!(
+            "{} < /dev/null 2> /dev/null",
+            read_files_cmd(reads, &delimiter)
+        ));
+        batch.push(&format!(
+            "stat -c '%s %X %Y %f %u %g %n' {stats} < /dev/null 2> /dev/null"
+        ));
+        let outputs = batch.run(shell)?;
+        let mut prefetch = Self {
+            contents: split_delimited_files(&outputs[0], &delimiter)
+                .into_iter()
+                .collect(),
+            stats: HashMap::new(),
+        };
+        for fstat in parse_stat_lines(&outputs[1]) {
+            prefetch.stats.insert(fstat.0.clone(), Some(fstat));
+        }
+        Ok(prefetch)
+    }
+
+    /// records the `paths` that were not stat'ed as missing
+    pub fn mark_missing(&mut self, paths: impl IntoIterator<Item = PathBuf>) {
+        for path in paths {
+            self.stats.entry(path).or_insert(None);
+        }
+    }
+
+    /// files read
+    pub fn paths_read(&self) -> impl Iterator<Item = &Path> {
+        self.contents.keys().map(PathBuf::as_path)
+    }
+
+    /// adds the files fetched by `other`
+    pub fn merge(&mut self, other: Prefetch) {
+        self.contents.extend(other.contents);
+        self.stats.extend(other.stats);
+    }
+
+    /// was `path` stat'ed, and its stat not handed out yet ?
+    pub fn holds_stat(&self, path: &Path) -> bool {
+        self.stats.contains_key(path)
+    }
+
+    /// size of the file contents read
+    pub fn content_bytes(&self) -> u64 {
+        self.contents.values().map(|c| c.len() as u64).sum()
+    }
+
+    /// content of `path` if it was read, handed out once
+    pub fn take_contents(&mut self, path: &Path) -> Option<String> {
+        self.contents.remove(path)
+    }
+
+    /// stat of `path` if it was requested, None inside when it does not exist
+    pub fn take_stat(&mut self, path: &Path) -> Option<Option<SshFileStat>> {
+        self.stats.remove(path)
+    }
+}
+
+/// outputs of the `count` commands of a batch, None when the delimiters do not
+/// match the commands
+fn split_batch_output(out: &str, delimiter: &str, count: usize) -> Option<Vec<String>> {
+    let separator = format!("\n{delimiter}\n");
+    let mut outputs = out
+        .split(separator.as_str())
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    // the last delimiter ends the output
+    (outputs.pop()?.is_empty() && outputs.len() == count).then_some(outputs)
+}
+
+/// random line prefix framing the files printed by `read_files_cmd`, which
+/// no file content can contain
+fn files_delimiter() -> String {
+    format!("--rmkmount-file-{}--", crate::rkids::new_uid())
+}
+
+/// remote command printing every file matching `glob`, each after a
+/// `<delimiter> <path>` line
+fn read_files_cmd(glob: &str, delimiter: &str) -> String {
+    format!(
+        "for f in {glob}; do [ -f \"$f\" ] && printf '%s %s\\n' '{delimiter}' \"$f\" && cat \"$f\"; done"
+    )
+}
+
+/// `paths` single-quoted for the remote shell
+fn quote_paths(paths: &[String]) -> String {
+    paths
+        .iter()
+        .map(|path| format!("'{}'", path.replace('\'', r"'\''")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// parses `stat -c '%s %X %Y %f %u %g %n'` lines : size, access and modification
+/// times, raw mode in hex, owner, group and path
+fn parse_stat_lines(out: &str) -> Vec<SshFileStat> {
+    out.lines()
+        .filter_map(|line| {
+            let fields = line.splitn(7, ' ').collect::<Vec<_>>();
+            let [size, atime, mtime, mode, uid, gid, path] = fields[..] else {
+                return None;
+            };
+            let fstat = SshFileStatBuilder::new()
+                .filesize(size.parse().ok()?)
+                .atime(atime.parse().ok()?)
+                .mtime(mtime.parse().ok()?)
+                .perm(u64::from_str_radix(mode, 16).ok()?)
+                .uid(uid.parse().ok()?)
+                .gid(gid.parse().ok()?)
+                .build();
+            Some(SshFileStat(PathBuf::from(path), fstat))
+        })
+        .collect()
+}
+
+/// parses `<size> <sha256>  <path>` lines
+fn parse_checksums(out: &str) -> HashMap<PathBuf, (u64, String)> {
+    out.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ' ');
+            let size = fields.next()?.parse().ok()?;
+            let hash = fields.next()?.to_owned();
+            let path = fields.next()?.trim_start_matches([' ', '*']);
+            Some((PathBuf::from(path), (size, hash)))
+        })
+        .collect()
+}
+
+/// splits the output of `read_files_cmd` into (path, content) pairs
+fn split_delimited_files(out: &str, delimiter: &str) -> Vec<(PathBuf, String)> {
+    out.split(&format!("{delimiter} "))
+        .skip(1)
+        .filter_map(|block| {
+            let (path, content) = block.split_once('\n')?;
+            Some((PathBuf::from(path), content.trim().to_owned()))
+        })
+        .collect()
+}
+
+/// runs `command` in a new channel of `session`, returns its output
+fn run_on(session: &ssh2::Session, command: &str) -> Result<String, RemarkableError> {
+    let mut channel = session.channel_session()?;
+    channel
+        .exec(command)
+        .with_context(|| format!("running `{command}`"))?;
+    let mut s = String::new();
+    channel
+        .read_to_string(&mut s)
+        .with_context(|| format!("reading output of `{command}`"))?;
+    Ok(s)
+}
+
+/// Reads `buf` from `file` at its current position, `offset` of `path`. In
+/// chunks, so that an interrupted caller does not wait for the rest
+fn read_chunks(
+    file: &mut ssh2::File,
+    path: &Path,
+    offset: u64,
+    buf: &mut [u8],
+    cancelled: &dyn Fn() -> bool,
+) -> Result<(), RemarkableError> {
+    let size = buf.len();
+    for (index, chunk) in buf.chunks_mut(SshWrapper::READ_CHUNK).enumerate() {
+        if cancelled() {
+            return Err(FsError::NodeIoError(libc::EINTR).into());
+        }
+        file.read_exact(chunk).with_context(|| {
+            format!("reading {size} bytes at {offset} of {path:?} (chunk {index})")
+        })?;
+    }
+    Ok(())
+}
+
+/// output streams and exit status of `command`, read once it is done
+fn run_output_on(session: &ssh2::Session, command: &str) -> Result<CommandOutput, RemarkableError> {
+    let context = || format!("running `{command}`");
+    let mut channel = session.channel_session().with_context(context)?;
+    channel.exec(command).with_context(context)?;
+    let (mut stdout, mut stderr) = (vec![], vec![]);
+    channel.read_to_end(&mut stdout).with_context(context)?;
+    channel
+        .stderr()
+        .read_to_end(&mut stderr)
+        .with_context(context)?;
+    channel.wait_close().with_context(context)?;
+    Ok(CommandOutput {
+        stdout: String::from_utf8_lossy(&stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        status: channel.exit_status().with_context(context)?,
+    })
+}
+
+/// Connects to the first reachable of `addrs`, each given `timeout` if any
+fn connect_tcp(addrs: &[SocketAddr], timeout: Option<Duration>) -> std::io::Result<TcpStream> {
+    let Some(timeout) = timeout else {
+        return TcpStream::connect(addrs);
+    };
+    let mut failure = std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address");
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => failure = e,
+        }
+    }
+    Err(failure)
+}
+
+/// libssh2 timeout of blocking calls, in ms, 0 for none
+fn timeout_ms(timeout: Option<Duration>) -> u32 {
+    timeout.map_or(0, |t| t.as_millis().clamp(1, u128::from(u32::MAX)) as u32)
+}
+
+/// Sends keepalive messages on `session` from a thread, every `interval` the
+/// session was idle, until the returned sender is dropped or a message fails
+fn spawn_keepalive(session: ssh2::Session, interval: Duration) -> Option<mpsc::Sender<()>> {
+    let secs = interval.as_secs().clamp(1, u64::from(u32::MAX)) as u32;
+    session.set_keepalive(false, secs);
+    let (stop, stopped) = mpsc::channel::<()>();
+    let spawned = std::thread::Builder::new()
+        .name("ssh-keepalive".to_owned())
+        .spawn(move || {
+            let mut wait = interval;
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(wait) {
+                // seconds until the session is idle for the interval again
+                match session.keepalive_send() {
+                    Ok(next) => wait = Duration::from_secs(u64::from(next.max(1))),
+                    Err(e) => {
+                        debug!("keepalive not sent, stopping : {e}");
+                        return;
+                    }
+                }
+            }
+        });
+    match spawned {
+        Ok(_) => Some(stop),
+        Err(e) => {
+            warn!("ssh keepalive not started : {e}");
+            None
+        }
+    }
+}
+
+/// delay before reconnection `attempt` (from 1) is tried again, doubling from
+/// one second up to half a minute
+fn reconnect_delay(attempt: u32) -> Duration {
+    Duration::from_secs(1 << attempt.saturating_sub(1).min(5)).min(Duration::from_secs(30))
+}
+
+fn invalid_host(host: &str, reason: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("{host}: {reason}"),
+    )
 }
 
 pub struct SshFileStatBuilder {
@@ -128,6 +923,24 @@ impl SshFileStat {
             .build();
         Self(PathBuf::from(special), new_stat)
     }
+
+    /// stat of a file being written through the mount, not on the tablet yet
+    pub fn build_pending_file(key: &str, size: u64) -> Self {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let new_stat = SshFileStatBuilder::new()
+            .atime(now)
+            .mtime(now)
+            .perm(0o644)
+            .uid(0)
+            .gid(0)
+            .filesize(size)
+            .set_reg()
+            .build();
+        Self(PathBuf::from(key), new_stat)
+    }
     /// convert ssh2::FileStat times to values compatible with fuser::FileAttr
     pub fn get_time_from(fstat_time: Option<u64>) -> SystemTime {
         SystemTime::checked_add(
@@ -161,6 +974,11 @@ impl SshFileStat {
         self.0.extension() == Some(OsStr::new("contents"))
     }
 
+    /// is the remote entry a directory ?
+    pub fn is_dir(&self) -> bool {
+        self.1.is_dir()
+    }
+
     pub fn perm(&self) -> u16 {
         if let Some(perm) = self.1.perm {
             (perm & 0o777) as u16
@@ -197,50 +1015,595 @@ impl SshFileStat {
 }
 
 impl SshWrapper {
+    /// bytes read at once by `read_as_bytes` between two cancellation checks
+    const READ_CHUNK: usize = 32 * 1024;
+
+    pub const TRANSFER_CHUNK_SIZE: usize = 256 * 1024;
+
+    pub const DEFAULT_RECONNECT_ATTEMPTS: u32 = 4;
+
     pub fn new() -> Result<Self, RemarkableError> {
         let new_session = ssh2::Session::new()?;
         Ok(Self {
             session: new_session,
+            host: String::new(),
+            port: 0,
+            options: SocketOptions::default(),
+            credentials: None,
+            reconnect_attempts: Self::DEFAULT_RECONNECT_ATTEMPTS,
+            connect_retries: 0,
+            connect_timeout: None,
+            io_timeout: None,
+            keepalive_interval: None,
+            keepalive: None,
+            command_interval: Duration::ZERO,
+            last_command: Cell::new(None),
+            use_agent: false,
+            pinned_host_key: None,
+            sftp: RefCell::new(None),
+            shared: None,
         })
     }
 
-    /// Connect the TCP Stream to provided host address and add it to the session
-    pub fn connect(&mut self, host_address: &str) -> Result<&Self, RemarkableError> {
-        match TcpStream::connect(host_address) {
-            Err(_) => Err(RemarkableError::Ssh2Error(ssh2::Error::from_errno(
-                ssh2::ErrorCode::Session(libssh2_sys::LIBSSH2_ERROR_SOCKET_TIMEOUT),
-            ))),
-            Ok(tcp) => {
-                self.session.set_tcp_stream(tcp);
-                match self.session.handshake() {
-                    Ok(_) => Ok(self),
-                    Err(e) => Err(RemarkableError::Ssh2Error(e)),
+    /// Runs commands and reads on the connection of the mount serving the
+    /// control socket `path` rather than on a connection of its own. Other file
+    /// operations are refused
+    pub fn share(&mut self, path: &Path) -> Result<(), RemarkableError> {
+        let shared =
+            SharedConnection::connect(path).with_context(|| format!("connecting to {path:?}"))?;
+        info!("using the connection shared on {path:?}");
+        self.shared = Some(shared);
+        Ok(())
+    }
+
+    /// is the connection the one of a running mount ?
+    pub fn is_shared(&self) -> bool {
+        self.shared.is_some()
+    }
+
+    /// Connect the TCP Stream to `host` (see `resolve_host`) and add it to the
+    /// session. Socket `options` are best effort : a refused option is only logged.
+    /// Failed connections are tried again up to the connect retries, after a
+    /// growing delay
+    pub fn connect(
+        &mut self,
+        host: &str,
+        port: u16,
+        options: &SocketOptions,
+    ) -> Result<&Self, RemarkableError> {
+        self.host = host.to_owned();
+        self.port = port;
+        self.options = *options;
+        let mut attempt = 0;
+        loop {
+            match self.open_session() {
+                Err(e) if attempt < self.connect_retries => {
+                    attempt += 1;
+                    let delay = reconnect_delay(attempt);
+                    warn!("connection to {host}:{port} failed ({e}), trying again in {delay:?}");
+                    std::thread::sleep(delay);
+                    self.session = ssh2::Session::new()?;
                 }
+                result => return result.map(|_| &*self),
             }
         }
     }
 
-    /// Authenticates with username and password
-    pub fn authenticate(&self, username: &str, password: &str) -> Result<&Self, RemarkableError> {
-        self.session.userauth_password(username, password)?;
+    /// connects the session to the host and port given to `connect`, within the
+    /// timeouts, up to the handshake
+    fn open_session(&mut self) -> Result<(), RemarkableError> {
+        let host_address = format!("{}:{}", self.host, self.port);
+        let tcp = resolve_host(&self.host, self.port)
+            .and_then(|addrs| connect_tcp(&addrs, self.connect_timeout))
+            .map_err(|e| TransportError::Connect(host_address.clone(), e))?;
+        if let Err(e) = self.options.apply(&tcp) {
+            let options = self.options;
+            warn!("socket options {options:?} not applied to {host_address}: {e}");
+        }
+        self.session.set_tcp_stream(tcp);
+        self.session.set_timeout(timeout_ms(self.io_timeout));
+        self.session
+            .handshake()
+            .map_err(|e| RemarkableError::from(e).context(host_address.as_str()))?;
+        if let Some(interval) = self.keepalive_interval {
+            self.keepalive = spawn_keepalive(self.session.clone(), interval);
+        }
+        Ok(())
+    }
+
+    /// Authenticates as `username` with the credentials of `provider` : ssh agent
+    /// keys first when enabled, then private key, then password, then
+    /// keyboard-interactive, as far as the tablet offers them. The host key is
+    /// verified first, no credential is sent to an untrusted host.
+    pub fn authenticate(
+        &self,
+        username: &str,
+        provider: &mut dyn AuthProvider,
+    ) -> Result<&Self, RemarkableError> {
+        let fingerprint = self
+            .host_key_fingerprint()
+            .ok_or_else(|| TransportError::NoHostKey(self.host.clone()))?;
+        hostkeys::verify(
+            &self.session,
+            &fingerprint,
+            &self.host,
+            self.port,
+            self.pinned_host_key.as_deref(),
+            provider,
+        )?;
+        let context = || format!("authenticating as {username}");
+        let methods = self
+            .session
+            .auth_methods(username)
+            .with_context(context)?
+            .to_owned();
+        debug!("authentication methods offered : {methods}");
+        if self.session.authenticated() {
+            return Ok(self);
+        }
+        let mut last_error = None;
+        if self.use_agent && methods.contains("publickey") {
+            if let Err(e) = self.agent_userauth(username) {
+                debug!("ssh agent authentication failed : {e}");
+                last_error = Some(e);
+            }
+        }
+        if !self.session.authenticated() && methods.contains("publickey") {
+            if let Some((key, passphrase)) = provider.identity(&self.host, username) {
+                if let Err(e) =
+                    self.session
+                        .userauth_pubkey_file(username, None, &key, passphrase.as_deref())
+                {
+                    debug!("public key authentication with {key:?} failed : {e}");
+                    last_error = Some(e);
+                }
+            }
+        }
+        if !self.session.authenticated() && methods.contains("password") {
+            if let Some(password) = provider.password(&self.host, username) {
+                if let Err(e) = self.session.userauth_password(username, &password) {
+                    debug!("password authentication failed : {e}");
+                    last_error = Some(e);
+                }
+            }
+        }
+        if !self.session.authenticated() && methods.contains("keyboard-interactive") {
+            let mut prompter = InteractivePrompter {
+                host: &self.host,
+                provider,
+            };
+            if let Err(e) = self
+                .session
+                .userauth_keyboard_interactive(username, &mut prompter)
+            {
+                debug!("keyboard-interactive authentication failed : {e}");
+                last_error = Some(e);
+            }
+        }
+        if self.session.authenticated() {
+            return Ok(self);
+        }
+        match last_error {
+            Some(e) => Err(RemarkableError::from(e).context(context())),
+            None => Err(RemarkableError::from(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("no usable authentication method among {methods}"),
+            ))
+            .context(context())),
+        }
+    }
+
+    /// Authenticates as `username` with the keys of the running ssh agent
+    /// (ssh-agent, or gpg-agent with ssh support) found through SSH_AUTH_SOCK
+    pub fn authenticate_agent(&self, username: &str) -> Result<&Self, RemarkableError> {
+        self.agent_userauth(username).map_err(|e| {
+            RemarkableError::from(e)
+                .context(format!("authenticating as {username} with the ssh agent"))
+        })?;
         Ok(self)
     }
 
+    /// offers each key of the ssh agent in turn, until one is accepted
+    fn agent_userauth(&self, username: &str) -> Result<(), ssh2::Error> {
+        let mut agent = self.session.agent()?;
+        agent.connect()?;
+        agent.list_identities()?;
+        let mut result = Err(ssh2::Error::new(
+            ssh2::ErrorCode::Session(libssh2_sys::LIBSSH2_ERROR_AUTHENTICATION_FAILED),
+            "no key in the ssh agent",
+        ));
+        for identity in agent.identities()? {
+            result = agent.userauth(username, &identity);
+            match &result {
+                Ok(()) => {
+                    debug!("authenticated with agent key {}", identity.comment());
+                    break;
+                }
+                Err(e) => debug!("agent key {} refused : {e}", identity.comment()),
+            }
+        }
+        if let Err(e) = agent.disconnect() {
+            debug!("ssh agent disconnection failed : {e}");
+        }
+        result
+    }
+
+    /// Authenticates like `authenticate`, then keeps `provider` so that `reconnect`
+    /// can authenticate again
+    pub fn login(
+        &mut self,
+        username: &str,
+        mut provider: Box<dyn AuthProvider>,
+    ) -> Result<&Self, RemarkableError> {
+        self.authenticate(username, provider.as_mut())?;
+        self.credentials = Some((username.to_owned(), provider));
+        Ok(self)
+    }
+
+    /// Replaces the session by a new one to the same host, authenticated with
+    /// the credentials given to `login`. Remote files opened on the former
+    /// session are gone, callers open them again. Failed connections are
+    /// tried again after a growing delay, the tablet taking a few seconds to
+    /// rejoin the network after sleeping, up to the reconnect attempts.
+    pub fn reconnect(&mut self) -> Result<(), RemarkableError> {
+        let Some((username, mut provider)) = self.credentials.take() else {
+            return Err(FsError::Unsupported("reconnecting before login".to_string()).into());
+        };
+        let mut attempt = 0;
+        let result = loop {
+            info!("reconnecting to {}:{}", self.host, self.port);
+            *self.sftp.get_mut() = None;
+            let result = ssh2::Session::new()
+                .map_err(RemarkableError::from)
+                .and_then(|session| {
+                    self.session = session;
+                    self.open_session()?;
+                    self.authenticate(&username, provider.as_mut()).map(|_| ())
+                });
+            attempt += 1;
+            match result {
+                Err(e) if attempt < self.reconnect_attempts => {
+                    let delay = reconnect_delay(attempt);
+                    warn!("reconnection failed ({e}), trying again in {delay:?}");
+                    std::thread::sleep(delay);
+                }
+                result => break result,
+            }
+        };
+        self.credentials = Some((username, provider));
+        result
+    }
+
+    /// Sets how many connections `reconnect` tries before giving up, at
+    /// least one (default: 4)
+    pub fn set_reconnect_attempts(&mut self, attempts: u32) {
+        self.reconnect_attempts = attempts.max(1);
+    }
+
+    /// Tries connections failing in `connect` `retries` more times (default:
+    /// none)
+    pub fn set_connect_retries(&mut self, retries: u32) {
+        self.connect_retries = retries;
+    }
+
+    /// Gives up TCP connections to the tablet after `timeout`, zero for the
+    /// system timeout (default: system). Applies to the connections made
+    /// afterwards
+    pub fn set_connect_timeout(&mut self, timeout: Duration) {
+        self.connect_timeout = (!timeout.is_zero()).then_some(timeout);
+    }
+
+    /// Fails ssh and sftp calls after `timeout` without an answer of the
+    /// tablet, zero for no limit (default: none), so that a tablet gone
+    /// without closing the connection does not hang the mount. The failure
+    /// counts as a lost connection
+    pub fn set_io_timeout(&mut self, timeout: Duration) {
+        self.io_timeout = (!timeout.is_zero()).then_some(timeout);
+        self.session.set_timeout(timeout_ms(self.io_timeout));
+    }
+
+    /// Sends ssh keepalive messages every `interval` while the session is idle,
+    /// from a thread of its own, tablets connected by USB dropping idle
+    /// sessions after a few minutes. Zero for none (default: none). Applies to
+    /// the connections made afterwards
+    pub fn set_keepalive_interval(&mut self, interval: Duration) {
+        self.keepalive_interval = (!interval.is_zero()).then_some(interval);
+    }
+
+    /// Checks that the session still reaches the tablet with a no-op remote
+    /// command, a round trip. Unlike `is_alive`, failures of the tablet are
+    /// told apart from the loss of the connection
+    pub fn check_health(&self) -> Result<(), RemarkableError> {
+        if let Some(shared) = &self.shared {
+            return shared.run_command("true").map(|_| ());
+        }
+        run_on(&self.session, "true").map(|_| ())
+    }
+
+    /// Reconnects if the session fails its health check, telling whether it
+    /// did
+    pub fn ensure_connected(&mut self) -> Result<bool, RemarkableError> {
+        match self.check_health() {
+            Ok(()) => Ok(false),
+            Err(e) => {
+                warn!("session unhealthy ({e}), reconnecting");
+                self.reconnect().map(|_| true)
+            }
+        }
+    }
+
+    /// SHA256 fingerprint of the host key, written as OpenSSH does
+    /// (`SHA256:` then the unpadded base64 digest). None before the handshake
+    pub fn host_key_fingerprint(&self) -> Option<String> {
+        if let Some(shared) = &self.shared {
+            return shared.fingerprint();
+        }
+        let hash = self.session.host_key_hash(ssh2::HashType::Sha256)?;
+        Some(format!("SHA256:{}", STANDARD_NO_PAD.encode(hash)))
+    }
+
+    /// identification string of the ssh server (e.g. "SSH-2.0-dropbear_2022.83").
+    /// None before the handshake
+    pub fn server_banner(&self) -> Option<String> {
+        self.session.banner().map(str::to_owned)
+    }
+
+    /// Can the session still reach the tablet ? Opens an sftp channel, so only
+    /// worth calling after a failure
+    pub fn is_alive(&self) -> bool {
+        self.shared.is_some() || self.session.sftp().is_ok()
+    }
+
+    /// Accepts only the host key of SHA256 `fingerprint`, known_hosts being
+    /// ignored (default: the host key must be in known_hosts)
+    pub fn set_host_key_fingerprint(&mut self, fingerprint: String) {
+        self.pinned_host_key = Some(fingerprint);
+    }
+
+    /// Offers the keys of the ssh agent when authenticating (default: no)
+    pub fn set_use_agent(&mut self, enabled: bool) {
+        self.use_agent = enabled;
+    }
+
+    /// Sets the minimum delay between two remote commands, so that bursts of
+    /// commands do not hog the tablet (default: none)
+    pub fn set_command_interval(&mut self, interval: Duration) {
+        self.command_interval = interval;
+    }
+
+    /// waits until the command interval has passed since the last command
+    fn throttle(&self) {
+        if let Some(last) = self.last_command.get() {
+            let wait = self.command_interval.saturating_sub(last.elapsed());
+            if !wait.is_zero() {
+                debug!("throttling remote command for {wait:?}");
+                std::thread::sleep(wait);
+            }
+        }
+        self.last_command.set(Some(Instant::now()));
+    }
+
+    /// the sftp channel, opened if there is none
+    fn sftp(&self) -> Result<Ref<'_, ssh2::Sftp>, RemarkableError> {
+        if self.shared.is_some() {
+            return Err(FsError::Unsupported(
+                "file operations other than reads on a shared connection".to_string(),
+            )
+            .into());
+        }
+        if self.sftp.borrow().is_none() {
+            trace::ssh_call(format_args!("open sftp channel"));
+            let sftp = self.session.sftp().context("opening the sftp channel")?;
+            *self.sftp.borrow_mut() = Some(sftp);
+        }
+        Ok(Ref::map(self.sftp.borrow(), |sftp| {
+            sftp.as_ref().expect("sftp channel opened above")
+        }))
+    }
+
+    /// result of `op`, a file operation. The sftp channel is dropped when the
+    /// operation lost it, the next one opening a new channel
+    fn on_sftp<T>(
+        &self,
+        op: impl FnOnce() -> Result<T, RemarkableError>,
+    ) -> Result<T, RemarkableError> {
+        let result = op();
+        if result.as_ref().is_err_and(|e| e.is_connection_lost()) {
+            if let Ok(mut sftp) = self.sftp.try_borrow_mut() {
+                debug!("sftp channel dropped after a failure");
+                *sftp = None;
+            }
+        }
+        result
+    }
+
     /// Executes a command and returns the result as a string
     pub fn execute_cmd(&self, command: &str) -> Result<String, RemarkableError> {
-        let mut channel = self.session.channel_session()?;
-        channel.exec(command)?;
-        let mut s = String::new();
-        channel.read_to_string(&mut s)?;
-        Ok(s)
+        trace::ssh_call(format_args!("exec `{command}`"));
+        if let Some(shared) = &self.shared {
+            return Ok(shared.run_command(command)?.stdout);
+        }
+        self.throttle();
+        run_on(&self.session, command)
+    }
+
+    /// Runs `command` and returns its output streams and exit status. A command
+    /// failing on the tablet is not an error. Output is read once the command
+    /// is done, so only for commands printing a moderate amount.
+    pub fn run_command(&self, command: &str) -> Result<CommandOutput, RemarkableError> {
+        trace::ssh_call(format_args!("run `{command}`"));
+        if let Some(shared) = &self.shared {
+            return shared.run_command(command);
+        }
+        self.throttle();
+        run_output_on(&self.session, command)
+    }
+
+    /// Runs `command` and copies its output, stderr merged, into `out` as it
+    /// comes, until the command exits. For long running commands such as
+    /// `journalctl -f`. Returns the exit status.
+    pub fn stream_command(
+        &self,
+        command: &str,
+        out: &mut dyn Write,
+    ) -> Result<i32, RemarkableError> {
+        trace::ssh_call(format_args!("stream `{command}`"));
+        let context = || format!("streaming `{command}`");
+        if let Some(shared) = &self.shared {
+            // the mount answers once the command is done
+            let output = shared.run_command(command)?;
+            out.write_all(output.stdout.as_bytes())
+                .and_then(|_| out.write_all(output.stderr.as_bytes()))
+                .with_context(context)?;
+            return Ok(output.status);
+        }
+        self.throttle();
+        let mut channel = self.session.channel_session().with_context(context)?;
+        channel
+            .handle_extended_data(ssh2::ExtendedData::Merge)
+            .with_context(context)?;
+        channel.exec(command).with_context(context)?;
+        let mut buffer = [0u8; 8192];
+        loop {
+            let read = channel.read(&mut buffer).with_context(context)?;
+            if read == 0 {
+                break;
+            }
+            out.write_all(&buffer[..read]).with_context(context)?;
+            out.flush().with_context(context)?;
+        }
+        channel.wait_close().with_context(context)?;
+        channel.exit_status().with_context(context)
+    }
+
+    /// Handle running commands on this session from another thread
+    pub fn runner(&self) -> CommandRunner {
+        CommandRunner {
+            session: self.session.clone(),
+        }
+    }
+
+    /// Fetches the (reads, stats) `batches` with `Prefetch::fetch`, on up to
+    /// `channels` channels of the session at once, so that their round trips
+    /// overlap. Failed batches are left out, their files being fetched when
+    /// used. Nothing is fetched on a shared connection
+    pub fn prefetch_batches(
+        &self,
+        batches: Vec<(Vec<String>, Vec<String>)>,
+        channels: usize,
+    ) -> Prefetch {
+        let mut prefetch = Prefetch::default();
+        if self.shared.is_some() {
+            return prefetch;
+        }
+        let workers = channels.clamp(1, batches.len().max(1));
+        let queue = Mutex::new(batches.into_iter());
+        let next = || queue.lock().unwrap_or_else(|e| e.into_inner()).next();
+        let fetched = std::thread::scope(|scope| {
+            let workers = (0..workers)
+                .map(|_| {
+                    let runner = self.runner();
+                    scope.spawn(move || {
+                        let mut fetched = vec![];
+                        while let Some((reads, stats)) = next() {
+                            match Prefetch::fetch(&runner, &reads, &stats) {
+                                Ok(batch) => fetched.push(batch),
+                                Err(e) => debug!("{} files not prefetched : {e}", stats.len()),
+                            }
+                        }
+                        fetched
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .flat_map(|w| w.join().unwrap_or_default())
+                .collect::<Vec<_>>()
+        });
+        for batch in fetched {
+            prefetch.merge(batch);
+        }
+        prefetch
+    }
+
+    /// Opens a channel to `host:port` as reached from the tablet, `origin` being
+    /// the local peer reported to the tablet
+    pub fn open_tunnel(
+        &self,
+        host: &str,
+        port: u16,
+        origin: SocketAddr,
+    ) -> Result<ssh2::Channel, RemarkableError> {
+        trace::ssh_call(format_args!("tunnel to {host}:{port}"));
+        let origin_ip = origin.ip().to_string();
+        let channel = self
+            .session
+            .channel_direct_tcpip(host, port, Some((&origin_ip, origin.port())))
+            .with_context(|| format!("forwarding to {host}:{port}"))?;
+        Ok(channel)
+    }
+
+    /// Switches session io between blocking and non-blocking, the latter is
+    /// only used to serve several forwarded channels from one thread
+    pub fn set_blocking(&self, blocking: bool) {
+        self.session.set_blocking(blocking);
+    }
+
+    /// Reads every file matching the shell `glob` in a single remote command,
+    /// returns (path, content) pairs
+    pub fn read_files(&self, glob: &str) -> Result<Vec<(PathBuf, String)>, RemarkableError> {
+        let delimiter = files_delimiter();
+        let out = self
+            .execute_cmd(&read_files_cmd(glob, &delimiter))
+            .with_context(|| format!("reading {glob}"))?;
+        Ok(split_delimited_files(&out, &delimiter))
+    }
+
+    /// Reads the files matching each of `globs`, all in a single remote command
+    pub fn read_file_sets(
+        &self,
+        globs: &[&str],
+    ) -> Result<Vec<Vec<(PathBuf, String)>>, RemarkableError> {
+        let delimiter = files_delimiter();
+        let mut batch = RemoteBatch::new();
+        for glob in globs {
+            batch.push(&read_files_cmd(glob, &delimiter));
+        }
+        let outputs = batch
+            .run(self)
+            .with_context(|| format!("reading {}", globs.join(" ")))?;
+        Ok(outputs
+            .iter()
+            .map(|out| split_delimited_files(out, &delimiter))
+            .collect())
+    }
+
+    /// Renames (moves) a remote file or folder
+    pub fn rename(&self, from: &Path, to: &Path) -> Result<(), RemarkableError> {
+        self.on_sftp(|| {
+            trace::ssh_call(format_args!("rename {from:?} {to:?}"));
+            self.sftp()?
+                .rename(from, to, None)
+                .with_context(|| format!("moving {from:?} to {to:?}"))?;
+            Ok(())
+        })
     }
 
     /// Reads the given path
     pub fn stat(&self, path: &str) -> Result<SshFileStat, RemarkableError> {
-        let my_sftp = self.session.sftp()?;
-        let fstat = my_sftp.stat(Path::new(path))?;
-        debug!("{path} {fstat:?}");
-        Ok(SshFileStat(PathBuf::from(path), fstat))
+        self.on_sftp(|| {
+            trace::ssh_call(format_args!("stat {path}"));
+            if let Some(shared) = &self.shared {
+                let fstat = shared.stat(Path::new(path))?;
+                return Ok(SshFileStat(PathBuf::from(path), fstat));
+            }
+            let my_sftp = self.sftp()?;
+            let fstat = my_sftp
+                .stat(Path::new(path))
+                .with_context(|| format!("stat {path}"))?;
+            debug!("{path} {fstat:?}");
+            Ok(SshFileStat(PathBuf::from(path), fstat))
+        })
     }
     /// Reads contents of the folder at given Path
     /// and returns a Vec of (Path, FileStat) sorted by filename
@@ -272,32 +1635,50 @@ impl SshWrapper {
     /// Reads contents of the folder at given Path
     /// and returns a Vec of (Path, FileStat) sorted by filename
     pub fn readdir(&self, path: &Path) -> Result<Vec<SshFileStat>, RemarkableError> {
-        let mut result = self.session.sftp()?.readdir(path)?;
-        result.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
-        Ok(result.into_iter().map(|x| SshFileStat(x.0, x.1)).collect())
+        self.on_sftp(|| {
+            trace::ssh_call(format_args!("readdir {path:?}"));
+            let mut result = self
+                .sftp()?
+                .readdir(path)
+                .with_context(|| format!("listing {path:?}"))?;
+            result.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+            Ok(result.into_iter().map(|x| SshFileStat(x.0, x.1)).collect())
+        })
     }
 
     /// Reads file content as string (for json parsing)
     pub fn read_as_string(&self, path: &Path) -> Result<String, RemarkableError> {
-        //Box<dyn Error>> {
-        let mut fopen = self.session.sftp()?.open(path)?;
-        let mut str_result = String::new();
-        /*
-        let szbyte = fopen.stat()?.size;
-        match szbyte {
-            Some(sz) => {
-                str_result.reserve(sz as usize);
-                unsafe {
-                    let mut str_buf = str_result.as_bytes_mut();
-                    //fopen.read_to_string(&mut str_result)?;
-                    fopen.read(str_buf, szbyte);
-                }
-                Ok(str_result)
+        self.on_sftp(|| {
+            trace::ssh_call(format_args!("read {path:?}"));
+            if let Some(shared) = &self.shared {
+                let data = shared.read_range(path, 0, u64::MAX)?;
+                return Ok(String::from_utf8_lossy(&data).into_owned());
             }
-            None => Err("Cannot stat file".into()),
-        }*/
-        fopen.read_to_string(&mut str_result)?;
-        Ok(str_result)
+            //Box<dyn Error>> {
+            let mut fopen = self
+                .sftp()?
+                .open(path)
+                .with_context(|| format!("opening {path:?}"))?;
+            let mut str_result = String::new();
+            /*
+            let szbyte = fopen.stat()?.size;
+            match szbyte {
+                Some(sz) => {
+                    str_result.reserve(sz as usize);
+                    unsafe {
+                        let mut str_buf = str_result.as_bytes_mut();
+                        //fopen.read_to_string(&mut str_result)?;
+                        fopen.read(str_buf, szbyte);
+                    }
+                    Ok(str_result)
+                }
+                None => Err("Cannot stat file".into()),
+            }*/
+            fopen
+                .read_to_string(&mut str_result)
+                .with_context(|| format!("reading {path:?}"))?;
+            Ok(str_result)
+        })
     }
 
     /// Reads a chunk of data with given size & offset from PathBuf
@@ -307,13 +1688,342 @@ impl SshWrapper {
         offset: u64,
         size: u64,
         buf: &mut [u8],
+        cancelled: &dyn Fn() -> bool,
+    ) -> Result<u64, RemarkableError> {
+        self.on_sftp(|| {
+            trace::ssh_call(format_args!("read {size} bytes at {offset} of {path:?}"));
+            if let Some(shared) = &self.shared {
+                let data = shared.read_range(path, offset, buf.len() as u64)?;
+                if data.len() != buf.len() {
+                    return Err(FsError::NodeIoError(libc::EOF).into());
+                }
+                buf.copy_from_slice(&data);
+                return Ok(size);
+            }
+            let mut fopen = self
+                .sftp()?
+                .open(path)
+                .with_context(|| format!("opening {path:?}"))?;
+            if let Ok(offset) = fopen.seek(std::io::SeekFrom::Start(offset)) {
+                // dropping the handle closes the remote file
+                read_chunks(&mut fopen, path, offset, buf, cancelled)?;
+                Ok(size)
+            } else {
+                Err(FsError::NodeIoError(libc::EOF).into())
+            }
+        })
+    }
+
+    /// Opens the remote file `path` for several reads, see `RemoteFile`
+    pub fn open_file(&self, path: &Path) -> Result<RemoteFile, RemarkableError> {
+        self.on_sftp(|| {
+            trace::ssh_call(format_args!("open {path:?}"));
+            let file = self
+                .sftp()?
+                .open(path)
+                .with_context(|| format!("opening {path:?}"))?;
+            Ok(RemoteFile {
+                path: path.to_path_buf(),
+                file,
+                position: 0,
+            })
+        })
+    }
+
+    /// Reads a whole remote file as bytes
+    pub fn read_all(&self, path: &Path) -> Result<Vec<u8>, RemarkableError> {
+        self.on_sftp(|| {
+            trace::ssh_call(format_args!("read {path:?}"));
+            if let Some(shared) = &self.shared {
+                return shared.read_range(path, 0, u64::MAX);
+            }
+            let mut fopen = self
+                .sftp()?
+                .open(path)
+                .with_context(|| format!("opening {path:?}"))?;
+            let mut buf = vec![];
+            fopen
+                .read_to_end(&mut buf)
+                .with_context(|| format!("reading {path:?}"))?;
+            Ok(buf)
+        })
+    }
+
+    /// Creates (or truncates) a remote file and writes `data` into it
+    pub fn write_all(&self, path: &Path, data: &[u8]) -> Result<(), RemarkableError> {
+        self.on_sftp(|| {
+            trace::ssh_call(format_args!("write {path:?}"));
+            let mut fcreate = self
+                .sftp()?
+                .create(path)
+                .with_context(|| format!("creating {path:?}"))?;
+            fcreate
+                .write_all(data)
+                .with_context(|| format!("writing {path:?}"))?;
+            Ok(())
+        })
+    }
+
+    /// Streams `reader` into a new remote file by chunks, calling `progress` with
+    /// the total number of bytes written after each chunk
+    pub fn write_from_reader(
+        &self,
+        path: &Path,
+        reader: &mut dyn Read,
+        progress: &mut dyn FnMut(u64),
     ) -> Result<u64, RemarkableError> {
-        let mut fopen = self.session.sftp()?.open(path)?;
-        if let Ok(offset) = fopen.seek(std::io::SeekFrom::Start(offset)) {
-            fopen.read_exact(buf)?;
-            Ok(size)
+        self.on_sftp(|| {
+            trace::ssh_call(format_args!("write {path:?}"));
+            let mut fcreate = self
+                .sftp()?
+                .create(path)
+                .with_context(|| format!("creating {path:?}"))?;
+            let mut buf = vec![0; Self::TRANSFER_CHUNK_SIZE];
+            let mut written = 0;
+            loop {
+                let sz = reader.read(&mut buf)?;
+                if sz == 0 {
+                    break;
+                }
+                fcreate
+                    .write_all(&buf[..sz])
+                    .with_context(|| format!("writing {path:?} at {written}"))?;
+                written += sz as u64;
+                progress(written);
+            }
+            Ok(written)
+        })
+    }
+
+    /// Creates a remote directory, succeeding if it already exists
+    pub fn mkdir(&self, path: &Path) -> Result<(), RemarkableError> {
+        self.on_sftp(|| {
+            trace::ssh_call(format_args!("mkdir {path:?}"));
+            let my_sftp = self.sftp()?;
+            if my_sftp.stat(path).map(|s| s.is_dir()).unwrap_or(false) {
+                Ok(())
+            } else {
+                my_sftp
+                    .mkdir(path, 0o755)
+                    .with_context(|| format!("creating folder {path:?}"))?;
+                Ok(())
+            }
+        })
+    }
+
+    /// Removes a remote file
+    pub fn remove_file(&self, path: &Path) -> Result<(), RemarkableError> {
+        self.on_sftp(|| {
+            trace::ssh_call(format_args!("unlink {path:?}"));
+            self.sftp()?
+                .unlink(path)
+                .with_context(|| format!("removing {path:?}"))?;
+            Ok(())
+        })
+    }
+
+    /// Size and SHA-256 of each of the remote `files`, computed on the tablet in
+    /// a single command. Missing files are left out
+    pub fn checksums(
+        &self,
+        files: &[PathBuf],
+    ) -> Result<HashMap<PathBuf, (u64, String)>, RemarkableError> {
+        let paths = files
+            .iter()
+            .map(|f| f.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        let out = self.execute_cmd(&format!(
+            r#"for f in {}; do s=$(stat -c %s "$f") && sha256sum "$f" | sed "s/^/$s /"; done 2> /dev/null"#,
+            quote_paths(&paths)
+        ))?;
+        Ok(parse_checksums(&out))
+    }
+
+    /// Moves `from` over `to`, replacing it at once : sftp renames refuse
+    /// existing targets
+    pub fn replace_file(&self, from: &Path, to: &Path) -> Result<(), RemarkableError> {
+        let paths = [from, to].map(|p| p.to_string_lossy().into_owned());
+        let out = self.execute_cmd(&format!(
+            "mv -f {} 2>&1 && echo replaced",
+            quote_paths(&paths)
+        ))?;
+        if out.lines().any(|l| l == "replaced") {
+            Ok(())
         } else {
-            Err(RemarkableError::NodeIoError(libc::EOF))
+            Err(
+                RemarkableError::from(std::io::Error::other(out.trim().to_owned()))
+                    .context(format!("moving {from:?} to {to:?}")),
+            )
         }
     }
+
+    /// Does the remote path exist ?
+    pub fn exists(&self, path: &Path) -> Result<bool, RemarkableError> {
+        self.on_sftp(|| {
+            trace::ssh_call(format_args!("stat {path:?}"));
+            if let Some(shared) = &self.shared {
+                return Ok(shared.stat(path).is_ok());
+            }
+            Ok(self.sftp()?.stat(path).is_ok())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reconnect_delay() {
+        let delays = (1..=7).map(reconnect_delay).collect::<Vec<_>>();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30].map(Duration::from_secs));
+    }
+
+    #[test]
+    fn test_resolve_host() {
+        let v4 = resolve_host("10.11.99.1", 22).unwrap();
+        assert_eq!(v4, vec!["10.11.99.1:22".parse().unwrap()]);
+        let v4 = resolve_host("10.11.99.1:2222", 22).unwrap();
+        assert_eq!(v4[0].port(), 2222);
+        let v6 = resolve_host("fe80::1%3", 22).unwrap();
+        assert_eq!(v6, vec!["[fe80::1%3]:22".parse().unwrap()]);
+        let v6 = resolve_host("[fe80::1%3]:2222", 22).unwrap();
+        assert_eq!(v6, vec!["[fe80::1%3]:2222".parse().unwrap()]);
+        let v6 = resolve_host("[::1]", 22).unwrap();
+        assert_eq!(v6, vec!["[::1]:22".parse().unwrap()]);
+        assert!(resolve_host("[fe80::1", 22).is_err());
+        assert!(resolve_host("10.11.99.1%3", 22).is_err());
+    }
+
+    #[test]
+    fn test_ssh_config() {
+        let config = SshConfig::parse(
+            "# tablets\n\
+             Host remarkable rm\n\
+             \tHostName 10.11.99.1\n\
+             \tUser root\n\
+             \tIdentityFile ~/.ssh/id_remarkable\n\
+             Host rm-wifi\n\
+             HostName=192.168.1.20\n\
+             Port 2222\n\
+             Host *.lan !printer.lan\n\
+             User admin\n\
+             Host *\n\
+             User nobody\n\
+             IdentityFile \"/keys/%h\"\n",
+        );
+        let rm = config.resolve("remarkable");
+        assert_eq!(rm.host_name.as_deref(), Some("10.11.99.1"));
+        assert_eq!(rm.user.as_deref(), Some("root"));
+        assert_eq!(rm.port, None);
+        assert_eq!(rm.identity_files.len(), 2);
+        assert!(rm.identity_files[0].ends_with(".ssh/id_remarkable"));
+        assert_eq!(rm.identity_files[1], PathBuf::from("/keys/remarkable"));
+        let wifi = config.resolve("rm-wifi");
+        assert_eq!(wifi.host_name.as_deref(), Some("192.168.1.20"));
+        assert_eq!(wifi.port, Some(2222));
+        assert_eq!(wifi.user.as_deref(), Some("nobody"));
+        assert_eq!(config.resolve("tablet.LAN").user.as_deref(), Some("admin"));
+        assert_eq!(
+            config.resolve("printer.lan").user.as_deref(),
+            Some("nobody")
+        );
+        assert_eq!(config.resolve("10.11.99.1").host_name, None);
+        assert!(wildcard_match("rm-?", "rm-2"));
+        assert!(wildcard_match("*a*b", "xxaxxb"));
+        assert!(!wildcard_match("*a*b", "xxaxxbc"));
+    }
+
+    #[test]
+    fn test_split_batch_output() {
+        let batch = RemoteBatch {
+            commands: vec!["echo a".to_string(), "true".to_string()],
+        };
+        assert_eq!(
+            batch.script("--d--"),
+            "( echo a\n)\nprintf '\\n%s\\n' '--d--'\n( true\n)\nprintf '\\n%s\\n' '--d--'\n"
+        );
+        let out = "a\n\n--d--\n\n--d--\n";
+        assert_eq!(
+            split_batch_output(out, "--d--", 2),
+            Some(vec!["a\n".to_string(), String::new()])
+        );
+        assert_eq!(split_batch_output(out, "--d--", 3), None);
+        assert_eq!(
+            split_batch_output("a\n\n--d--\ntruncated", "--d--", 2),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_stat_lines() {
+        assert_eq!(
+            quote_paths(&["/r/a b".to_string(), "/r/it's".to_string()]),
+            r"'/r/a b' '/r/it'\''s'"
+        );
+        let stats = parse_stat_lines("1234 1700000000 1700000100 81a4 0 0 /r/a b.pdf\nbroken\n");
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].get_path(), &PathBuf::from("/r/a b.pdf"));
+        assert_eq!(stats[0].size(), Some(1234));
+        assert_eq!(stats[0].mtime(), Some(1700000100));
+        assert_eq!(stats[0].perm(), 0o644);
+        assert!(!stats[0].is_dir());
+    }
+
+    #[test]
+    fn test_parse_checksums() {
+        let sums = parse_checksums("3 ba7816bf  /r/a b.pdf\nbroken\n");
+        assert_eq!(
+            sums.get(Path::new("/r/a b.pdf")),
+            Some(&(3, "ba7816bf".to_string()))
+        );
+        assert_eq!(sums.len(), 1);
+    }
+
+    #[test]
+    fn test_split_delimited_files() {
+        let delimiter = "--rmkmount-file-0a8e8e4e--";
+        let out = format!(
+            "{delimiter} /r/a.metadata\n{{\"visibleName\": \"Plan ==> Done\"}}\n\
+             {delimiter} /r/b.metadata\n{{}}"
+        );
+        assert_eq!(
+            split_delimited_files(&out, delimiter),
+            vec![
+                (
+                    PathBuf::from("/r/a.metadata"),
+                    "{\"visibleName\": \"Plan ==> Done\"}".to_string()
+                ),
+                (PathBuf::from("/r/b.metadata"), "{}".to_string()),
+            ]
+        );
+        assert!(split_delimited_files("", delimiter).is_empty());
+    }
+
+    #[test]
+    fn test_prefetch_merge() {
+        let stats = parse_stat_lines("10 0 0 81a4 0 0 /r/a.metadata\n");
+        let mut prefetch = Prefetch::default();
+        prefetch.merge(Prefetch {
+            contents: [(PathBuf::from("/r/a.metadata"), "{}".to_string())].into(),
+            stats: [(PathBuf::from("/r/a.pdf"), None)].into(),
+        });
+        prefetch.merge(Prefetch {
+            contents: HashMap::new(),
+            stats: [(PathBuf::from("/r/a.metadata"), stats.into_iter().next())].into(),
+        });
+        assert!(prefetch.holds_stat(Path::new("/r/a.pdf")));
+        assert_eq!(prefetch.content_bytes(), 2);
+        assert!(prefetch
+            .take_stat(Path::new("/r/a.metadata"))
+            .is_some_and(|s| s.is_some()));
+        assert!(!prefetch.holds_stat(Path::new("/r/a.metadata")));
+        // payloads a bulk fetch did not stat
+        prefetch.mark_missing([PathBuf::from("/r/b.pdf")]);
+        assert!(prefetch.holds_stat(Path::new("/r/b.pdf")));
+        assert_eq!(
+            prefetch.paths_read().collect::<Vec<_>>(),
+            vec![Path::new("/r/a.metadata")]
+        );
+    }
 }
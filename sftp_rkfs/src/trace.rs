@@ -0,0 +1,93 @@
+use log::{log, log_enabled, trace, Level};
+use std::cell::Cell;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+thread_local! {
+    /// (trace id, ssh calls so far) of the request served by this thread, id 0
+    /// outside of any request
+    static CURRENT: Cell<(u64, u32)> = const { Cell::new((0, 0)) };
+}
+
+/// Serving of one FUSE request, identified by the kernel request id. The ssh
+/// calls made until it is dropped are logged with that id, and counted in the
+/// summary logged at the end, so that a slow operation can be tied to the round
+/// trips it caused.
+pub(crate) struct TraceScope {
+    operation: &'static str,
+    id: u64,
+    started: Instant,
+    /// scope of the enclosing request, restored on drop
+    outer: (u64, u32),
+}
+
+impl TraceScope {
+    /// operations lasting longer are summarized at debug level even without ssh calls
+    const SLOW: Duration = Duration::from_millis(100);
+
+    pub(crate) fn enter(operation: &'static str, id: u64) -> Self {
+        let outer = CURRENT.with(|c| c.replace((id, 0)));
+        Self {
+            operation,
+            id,
+            started: Instant::now(),
+            outer,
+        }
+    }
+}
+
+impl Drop for TraceScope {
+    fn drop(&mut self) {
+        let (_, calls) = CURRENT.with(|c| c.replace(self.outer));
+        let elapsed = self.started.elapsed();
+        let level = if calls > 0 || elapsed >= Self::SLOW {
+            Level::Debug
+        } else {
+            Level::Trace
+        };
+        log!(
+            level,
+            "[{}] {} took {}ms, {calls} ssh calls",
+            self.id,
+            self.operation,
+            elapsed.as_millis()
+        );
+    }
+}
+
+/// Records an ssh round trip of the current request, logged at trace level
+pub(crate) fn ssh_call(call: fmt::Arguments<'_>) {
+    let id = CURRENT.with(|c| {
+        let (id, calls) = c.get();
+        c.set((id, calls + 1));
+        id
+    });
+    if log_enabled!(Level::Trace) {
+        trace!("[{id}] ssh {call}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calls() -> (u64, u32) {
+        CURRENT.with(Cell::get)
+    }
+
+    #[test]
+    fn test_trace_scopes() {
+        {
+            let _outer = TraceScope::enter("lookup", 7);
+            ssh_call(format_args!("stat /a"));
+            {
+                let _inner = TraceScope::enter("getattr", 8);
+                ssh_call(format_args!("stat /b"));
+                assert_eq!(calls(), (8, 1));
+            }
+            ssh_call(format_args!("stat /c"));
+            assert_eq!(calls(), (7, 2));
+        }
+        assert_eq!(calls(), (0, 0));
+    }
+}
@@ -0,0 +1,353 @@
+use crate::fs::RemarkableFs;
+use crate::nodes::Node;
+use crate::rkids;
+use crate::{ErrorContext, FsError, RemarkableError};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Remote files a push is about to write, with their expected size and hash.
+/// It is written before the upload and removed once the document is visible,
+/// so a journal left behind points at the files of an interrupted push.
+#[derive(Debug, Serialize, Deserialize)]
+struct PushJournal {
+    uid: String,
+    files: Vec<JournalEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalEntry {
+    path: PathBuf,
+    size: u64,
+    sha256: String,
+}
+
+impl JournalEntry {
+    fn of_bytes(path: PathBuf, data: &[u8]) -> Self {
+        Self {
+            path,
+            size: data.len() as u64,
+            sha256: format!("{:x}", Sha256::digest(data)),
+        }
+    }
+}
+
+/// size and SHA-256 of everything `reader` yields, in hex as `sha256sum`
+/// prints it on the tablet
+fn sha256_of(reader: &mut dyn std::io::Read) -> std::io::Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let size = std::io::copy(reader, &mut hasher)?;
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+/// Uploads of local PDF/EPUB files as new xochitl documents
+impl RemarkableFs {
+    pub(crate) const UPLOAD_EXTENSIONS: [&'static str; 2] = ["pdf", "epub"];
+    /// extension of push journals, next to the pushed files
+    pub(crate) const JOURNAL_EXTENSION: &'static str = "push-journal";
+    /// suffix of a `.metadata` file until it is renamed to make its item visible
+    pub(crate) const STAGED_SUFFIX: &'static str = ".part";
+
+    /// Uploads a local PDF or EPUB `file` into the collection at visible path
+    /// `collection` (root collection when `None`) as document `uid`, from
+    /// `rkids::new_uid`. `progress` is called with the number of payload bytes
+    /// sent so far.
+    /// The files to write are journaled first, and each upload is checked against
+    /// the journal (size and SHA-256) before the `.metadata` file is renamed into
+    /// place : xochitl never sees a partial document, even if the connection dies.
+    /// Pushing again under the same uid after a failure resumes the push instead
+    /// of creating a second document.
+    pub fn push_document(
+        &mut self,
+        file: &Path,
+        collection: Option<&str>,
+        uid: &str,
+        progress: &mut dyn FnMut(u64),
+    ) -> Result<(), RemarkableError> {
+        let ext = file
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_lowercase)
+            .filter(|e| Self::UPLOAD_EXTENSIONS.contains(&e.as_str()))
+            .ok_or(FsError::Unsupported(format!(
+                "{file:?}: only pdf and epub files can be pushed"
+            )))?;
+        let visible_name = file
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let parent_uid = match collection {
+            Some(c) => {
+                let ino = self.resolve_path(c)?;
+                if self.is_document(ino) {
+                    return Err(FsError::NotACollection(c.to_owned()).into());
+                }
+                self.unique_id(ino).ok_or(FsError::NodeNotFound(ino))?
+            }
+            None => Node::ROOT_NODE_UID.to_string(),
+        };
+        self.push_file(file, &parent_uid, &visible_name, &ext, uid, progress)
+    }
+
+    /// Uploads the local `file` as document `uid` named `visible_name` in
+    /// collection `parent_uid`, with payload extension `ext`, as described for
+    /// `push_document`.
+    pub(crate) fn push_file(
+        &mut self,
+        file: &Path,
+        parent_uid: &str,
+        visible_name: &str,
+        ext: &str,
+        uid: &str,
+        progress: &mut dyn FnMut(u64),
+    ) -> Result<(), RemarkableError> {
+        let root = self.document_root();
+        let payload_path = self.layout().payload_path(root, uid, ext);
+        let content_path = self.layout().content_path(root, uid);
+        let staged_path = self.staged_metadata_path(uid);
+        let journal_path = self
+            .layout()
+            .payload_path(root, uid, Self::JOURNAL_EXTENSION);
+
+        let content = serde_json::json!({
+            "fileType": ext,
+            "fontName": "",
+            "lineHeight": -1,
+            "margins": 100,
+            "orientation": "portrait",
+            "pageCount": 0,
+            "formatVersion": 1,
+        })
+        .to_string();
+        let metadata = new_metadata(parent_uid, visible_name, "DocumentType");
+        let (size, sha256) = std::fs::File::open(file)
+            .and_then(|mut f| sha256_of(&mut f))
+            .with_context(|| format!("reading {file:?}"))?;
+        if self
+            .session()
+            .exists(&self.layout().metadata_path(root, uid))?
+        {
+            info!("{file:?} already pushed as {uid}");
+            return Ok(());
+        }
+        if self.session().exists(&journal_path)?
+            && self.resume_push(&journal_path, &payload_path, &sha256)?
+        {
+            info!("push of {file:?} as {uid} resumed");
+            return Ok(());
+        }
+        let journal = PushJournal {
+            uid: uid.to_owned(),
+            files: vec![
+                JournalEntry {
+                    path: payload_path.clone(),
+                    size,
+                    sha256,
+                },
+                JournalEntry::of_bytes(content_path.clone(), content.as_bytes()),
+                JournalEntry::of_bytes(staged_path.clone(), &metadata),
+            ],
+        };
+        self.session()
+            .write_all(&journal_path, &serde_json::to_vec(&journal)?)
+            .context("writing the push journal")?;
+
+        info!("pushing {file:?} as {uid}.{ext}");
+        let mut reader = std::fs::File::open(file).with_context(|| format!("opening {file:?}"))?;
+        self.session()
+            .write_from_reader(&payload_path, &mut reader, progress)
+            .with_context(|| format!("pushing {file:?} as {uid}"))?;
+        self.session()
+            .write_all(&content_path, content.as_bytes())?;
+        self.session().write_all(&staged_path, &metadata)?;
+
+        self.verify_journal(&journal)
+            .with_context(|| format!("pushing {file:?} as {uid}"))?;
+        self.publish_push(uid, &journal_path)
+    }
+
+    /// Renames the verified staged `.metadata` of `uid` into place and removes
+    /// its push journal
+    fn publish_push(&self, uid: &str, journal_path: &Path) -> Result<(), RemarkableError> {
+        self.session().rename(
+            &self.staged_metadata_path(uid),
+            &self.layout().metadata_path(self.document_root(), uid),
+        )?;
+        if let Err(e) = self.session().remove_file(journal_path) {
+            warn!("push journal of {uid} left behind : {e}");
+        }
+        Ok(())
+    }
+
+    /// Picks up the push interrupted after writing the journal at `journal_path`.
+    /// When every journaled file made it to the tablet, and the payload at
+    /// `payload_path` is still the `sha256` one, the document is made visible.
+    /// Otherwise the files written so far are removed, and false is returned for
+    /// the push to start over.
+    fn resume_push(
+        &self,
+        journal_path: &Path,
+        payload_path: &Path,
+        sha256: &str,
+    ) -> Result<bool, RemarkableError> {
+        let journal: PushJournal = serde_json::from_slice(&self.session().read_all(journal_path)?)
+            .context("reading the push journal")?;
+        let same_payload = journal
+            .files
+            .iter()
+            .any(|f| f.path == payload_path && f.sha256 == sha256);
+        if same_payload && self.verify_journal(&journal).is_ok() {
+            self.publish_push(&journal.uid, journal_path)?;
+            return Ok(true);
+        }
+        debug!("cleaning up the interrupted push of {}", journal.uid);
+        for entry in &journal.files {
+            if self.session().exists(&entry.path)? {
+                self.session().remove_file(&entry.path)?;
+            }
+        }
+        self.session().remove_file(journal_path)?;
+        Ok(false)
+    }
+
+    /// Checks every file of `journal` on the tablet against its expected size
+    /// and hash
+    fn verify_journal(&self, journal: &PushJournal) -> Result<(), RemarkableError> {
+        let paths = journal
+            .files
+            .iter()
+            .map(|f| f.path.clone())
+            .collect::<Vec<_>>();
+        let sums = self.session().checksums(&paths)?;
+        for entry in &journal.files {
+            match sums.get(&entry.path) {
+                Some((size, sha256)) if *size == entry.size && *sha256 == entry.sha256 => {
+                    debug!("{:?} verified", entry.path);
+                }
+                Some((size, _)) => {
+                    return Err(FsError::InvalidPath(format!(
+                        "{:?} differs from what was sent ({size} bytes, {} expected)",
+                        entry.path, entry.size
+                    ))
+                    .into())
+                }
+                None => {
+                    return Err(FsError::InvalidPath(format!(
+                        "{:?} missing after upload",
+                        entry.path
+                    ))
+                    .into())
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// where the `.metadata` file of `uid` is written before being renamed
+    pub(crate) fn staged_metadata_path(&self, uid: &str) -> PathBuf {
+        let mut path = self
+            .layout()
+            .metadata_path(self.document_root(), uid)
+            .into_os_string();
+        path.push(Self::STAGED_SUFFIX);
+        path.into()
+    }
+
+    /// Makes sure every collection along the visible path `path` exists, creating
+    /// the missing ones and reusing existing collections by name. Returns the uid
+    /// of the last collection.
+    pub fn ensure_collection(&mut self, path: &str) -> Result<String, RemarkableError> {
+        let mut parent_uid = Node::ROOT_NODE_UID.to_string();
+        let mut current = String::new();
+        for name in path.split('/').filter(|c| !c.is_empty()) {
+            current = format!("{current}/{name}");
+            match self.resolve_path(&current) {
+                Ok(ino) if self.is_document(ino) => {
+                    return Err(FsError::NotACollection(current).into());
+                }
+                Ok(ino) => {
+                    parent_uid = self.unique_id(ino).ok_or(FsError::NodeNotFound(ino))?;
+                }
+                Err(e) if matches!(e.fs_error(), Some(FsError::NodeNotFound(_))) => {
+                    parent_uid = self
+                        .create_collection(&parent_uid, name)
+                        .with_context(|| format!("creating collection {current}"))?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(parent_uid)
+    }
+
+    /// Creates the collection `name` in collection `parent_uid` on the tablet,
+    /// returns its uid
+    pub(crate) fn create_collection(
+        &self,
+        parent_uid: &str,
+        name: &str,
+    ) -> Result<String, RemarkableError> {
+        let uid = rkids::new_uid();
+        info!("creating collection {name} in {parent_uid:?} as {uid}");
+        self.session().write_all(
+            &self.layout().content_path(self.document_root(), &uid),
+            b"{}",
+        )?;
+        self.publish_metadata(&uid, &new_metadata(parent_uid, name, "CollectionType"))?;
+        Ok(uid)
+    }
+
+    /// writes the `.metadata` file of a newly created item under a staging name,
+    /// then renames it so that xochitl never reads it partially written
+    fn publish_metadata(&self, uid: &str, metadata: &[u8]) -> Result<(), RemarkableError> {
+        let staged = self.staged_metadata_path(uid);
+        self.session().write_all(&staged, metadata)?;
+        self.session().rename(
+            &staged,
+            &self.layout().metadata_path(self.document_root(), uid),
+        )
+    }
+}
+
+/// `.metadata` content of a newly created document or collection. Indented like
+/// xochitl writes it : collections are listed by grepping `"parent": "<uid>"`
+fn new_metadata(parent_uid: &str, visible_name: &str, node_type: &str) -> Vec<u8> {
+    let last_modified = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let metadata = serde_json::json!({
+        "deleted": false,
+        "lastModified": last_modified.to_string(),
+        "metadatamodified": false,
+        "modified": false,
+        "parent": parent_uid,
+        "pinned": false,
+        "synced": false,
+        "type": node_type,
+        "version": 0,
+        "visibleName": visible_name,
+    });
+    serde_json::to_vec_pretty(&metadata).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha256_of() {
+        assert_eq!(
+            sha256_of(&mut &b"abc"[..]).unwrap(),
+            (
+                3,
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_owned()
+            )
+        );
+        assert_eq!(
+            JournalEntry::of_bytes(PathBuf::from("/r/a.pdf"), b"").sha256,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+}
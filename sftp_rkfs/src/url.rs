@@ -0,0 +1,116 @@
+use crate::sshutils::split_port;
+
+/// Parts of an `ssh://[user[:password]@]host[:port][/document/root]` url
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct SshUrl {
+    pub(crate) user: Option<String>,
+    pub(crate) password: Option<String>,
+    pub(crate) host: String,
+    pub(crate) port: Option<u16>,
+    pub(crate) path: Option<String>,
+}
+
+impl SshUrl {
+    const SCHEMES: [&'static str; 2] = ["ssh://", "sftp://"];
+
+    /// splits `url`, the reason it is invalid otherwise. Its parts are percent
+    /// decoded, a host that cannot be (`fe80::1%wlan0`) is taken as is
+    pub(crate) fn parse(url: &str) -> Result<Self, &'static str> {
+        let rest = Self::SCHEMES
+            .iter()
+            .find_map(|scheme| url.strip_prefix(scheme))
+            .ok_or("expected ssh:// or sftp://")?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], Some(&rest[slash..])),
+            None => (rest, None),
+        };
+        let (userinfo, host) = match authority.rsplit_once('@') {
+            Some((userinfo, host)) => (Some(userinfo), host),
+            None => (None, authority),
+        };
+        let (user, password) = match userinfo.map(|u| u.split_once(':').unwrap_or((u, ""))) {
+            Some((user, password)) => (
+                Some(decode(user).ok_or("invalid user")?).filter(|u| !u.is_empty()),
+                Some(decode(password).ok_or("invalid password")?).filter(|p| !p.is_empty()),
+            ),
+            None => (None, None),
+        };
+        // 0 stands for no port, it is not a port ssh listens on
+        let (host, port) = split_port(host, 0).map_err(|_| "invalid port")?;
+        if host.is_empty() {
+            return Err("missing host");
+        }
+        let path = path
+            .filter(|p| *p != "/")
+            .map(|p| decode(p).ok_or("invalid path"))
+            .transpose()?;
+        Ok(Self {
+            user,
+            password,
+            host: decode(host).unwrap_or_else(|| host.to_owned()),
+            port: Some(port).filter(|p| *p != 0),
+            path,
+        })
+    }
+}
+
+/// `text` with its `%XX` escapes decoded, None when they are invalid
+fn decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            SshUrl::parse("ssh://root@10.11.99.1:22/home/root/.local/share/remarkable/xochitl/"),
+            Ok(SshUrl {
+                user: Some("root".to_owned()),
+                password: None,
+                host: "10.11.99.1".to_owned(),
+                port: Some(22),
+                path: Some("/home/root/.local/share/remarkable/xochitl/".to_owned()),
+            })
+        );
+        assert_eq!(
+            SshUrl::parse("sftp://me:p%40ss@[fe80::1%25wlan0]:2222"),
+            Ok(SshUrl {
+                user: Some("me".to_owned()),
+                password: Some("p@ss".to_owned()),
+                host: "fe80::1%wlan0".to_owned(),
+                port: Some(2222),
+                path: None,
+            })
+        );
+        assert_eq!(
+            SshUrl::parse("ssh://remarkable/"),
+            Ok(SshUrl {
+                host: "remarkable".to_owned(),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            SshUrl::parse("http://10.11.99.1"),
+            Err("expected ssh:// or sftp://")
+        );
+        assert_eq!(SshUrl::parse("ssh://root@:22"), Err("missing host"));
+        assert_eq!(SshUrl::parse("ssh://host:port"), Err("invalid port"));
+        assert_eq!(SshUrl::parse("ssh://host/%zz"), Err("invalid path"));
+    }
+}